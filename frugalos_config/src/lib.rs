@@ -17,6 +17,9 @@ extern crate protobuf_codec;
 extern crate frugalos_raft;
 extern crate raftlog;
 extern crate rendezvous_hash;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 #[macro_use]
 extern crate slog;
 #[macro_use]
@@ -31,9 +34,13 @@ macro_rules! dump {
 pub use self::error::{Error, ErrorKind};
 pub use machine::DeviceGroup;
 pub use rpc::RpcServer;
+pub use schema::{
+    BucketOptions, ChecksumAlgorithm, Compression, HedgePolicy, VersioningPolicy, WriteQuorum,
+};
 pub use service::{Event, Service, ServiceHandle};
 
 pub mod cluster;
+pub mod schema;
 
 mod builder;
 mod config;
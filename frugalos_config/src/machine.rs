@@ -2,14 +2,32 @@ use libfrugalos::entity::bucket::{Bucket, BucketId};
 use libfrugalos::entity::device::{Device, DeviceId};
 use libfrugalos::entity::server::{Server, ServerId};
 
+use schema::BucketOptions;
+
 #[derive(Debug, Clone)]
 pub enum Command {
-    PutBucket { bucket: Bucket },
-    DeleteBucket { id: BucketId },
-    PutDevice { device: Device },
-    DeleteDevice { id: DeviceId },
-    PutServer { server: Server },
-    DeleteServer { id: ServerId },
+    PutBucket {
+        bucket: Bucket,
+    },
+    DeleteBucket {
+        id: BucketId,
+    },
+    PutDevice {
+        device: Device,
+    },
+    DeleteDevice {
+        id: DeviceId,
+    },
+    PutServer {
+        server: Server,
+    },
+    DeleteServer {
+        id: ServerId,
+    },
+    PutBucketOptions {
+        bucket_id: BucketId,
+        options: BucketOptions,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -19,6 +37,9 @@ pub struct Snapshot {
     pub devices: Vec<Device>,
     pub servers: Vec<Server>,
     pub segment_tables: Vec<SegmentTable>,
+
+    /// 付加的な設定が明示的に行われているバケツ群。
+    pub bucket_options: Vec<(BucketId, BucketOptions)>,
 }
 impl Snapshot {
     pub fn initial(server: Server) -> Self {
@@ -32,6 +53,7 @@ impl Snapshot {
             devices: Vec::new(),
             servers: vec![server],
             segment_tables: Vec::new(),
+            bucket_options: Vec::new(),
         }
     }
 }
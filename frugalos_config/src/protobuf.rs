@@ -4,8 +4,8 @@ use libfrugalos::entity::device::{
     Device, FileDevice, MemoryDevice, SegmentAllocationPolicy, VirtualDevice, Weight,
 };
 use libfrugalos::entity::server::Server;
-use protobuf_codec::field::branch::{Branch2, Branch3, Branch6};
-use protobuf_codec::field::num::{F1, F2, F3, F4, F5, F6};
+use protobuf_codec::field::branch::{Branch2, Branch3, Branch7};
+use protobuf_codec::field::num::{F1, F2, F3, F4, F5, F6, F7};
 use protobuf_codec::message::{MessageDecode, MessageEncode};
 use protobuf_codec::scalar::{
     DoubleDecoder, DoubleEncoder, StringDecoder, StringEncoder, Uint32Decoder, Uint32Encoder,
@@ -14,6 +14,9 @@ use protobuf_codec::scalar::{
 use trackable::error::ErrorKindExt;
 
 use machine::{Command, DeviceGroup, NextSeqNo, Segment, SegmentTable, Snapshot};
+use schema::{
+    BucketOptions, ChecksumAlgorithm, Compression, HedgePolicy, VersioningPolicy, WriteQuorum,
+};
 
 //
 // https://github.com/frugalos/frugalos/blob/master/frugalos_config/schema/config.proto
@@ -351,15 +354,17 @@ pub fn command_decoder() -> impl MessageDecode<Item = Command> {
         (F3, put_device_decoder(), message),
         (F4, delete_device_decoder(), message),
         (F5, put_server_decoder(), message),
-        (F6, delete_server_decoder(), message)
+        (F6, delete_server_decoder(), message),
+        (F7, put_bucket_options_decoder(), message)
     )];
     base.map(|x| match x {
-        Branch6::A(bucket) => Command::PutBucket { bucket },
-        Branch6::B(id) => Command::DeleteBucket { id },
-        Branch6::C(device) => Command::PutDevice { device },
-        Branch6::D(id) => Command::DeleteDevice { id },
-        Branch6::E(server) => Command::PutServer { server },
-        Branch6::F(id) => Command::DeleteServer { id },
+        Branch7::A(bucket) => Command::PutBucket { bucket },
+        Branch7::B(id) => Command::DeleteBucket { id },
+        Branch7::C(device) => Command::PutDevice { device },
+        Branch7::D(id) => Command::DeleteDevice { id },
+        Branch7::E(server) => Command::PutServer { server },
+        Branch7::F(id) => Command::DeleteServer { id },
+        Branch7::G((bucket_id, options)) => Command::PutBucketOptions { bucket_id, options },
     })
 }
 
@@ -387,6 +392,10 @@ pub fn delete_server_decoder() -> impl MessageDecode<Item = String> {
     protobuf_message_decoder![(F1, StringDecoder::new())]
 }
 
+pub fn put_bucket_options_decoder() -> impl MessageDecode<Item = (String, BucketOptions)> {
+    bucket_options_decoder()
+}
+
 pub fn command_encoder() -> impl SizedEncode<Item = Command> + MessageEncode<Item = Command> {
     let base = protobuf_message_encoder![(
         required_oneof,
@@ -395,15 +404,17 @@ pub fn command_encoder() -> impl SizedEncode<Item = Command> + MessageEncode<Ite
         (F3, put_device_encoder(), message),
         (F4, delete_device_encoder(), message),
         (F5, put_server_encoder(), message),
-        (F6, delete_server_encoder(), message)
+        (F6, delete_server_encoder(), message),
+        (F7, put_bucket_options_encoder(), message)
     )];
     base.map_from(|x: Command| match x {
-        Command::PutBucket { bucket } => Branch6::A(bucket),
-        Command::DeleteBucket { id } => Branch6::B(id),
-        Command::PutDevice { device } => Branch6::C(device),
-        Command::DeleteDevice { id } => Branch6::D(id),
-        Command::PutServer { server } => Branch6::E(server),
-        Command::DeleteServer { id } => Branch6::F(id),
+        Command::PutBucket { bucket } => Branch7::A(bucket),
+        Command::DeleteBucket { id } => Branch7::B(id),
+        Command::PutDevice { device } => Branch7::C(device),
+        Command::DeleteDevice { id } => Branch7::D(id),
+        Command::PutServer { server } => Branch7::E(server),
+        Command::DeleteServer { id } => Branch7::F(id),
+        Command::PutBucketOptions { bucket_id, options } => Branch7::G((bucket_id, options)),
     })
 }
 
@@ -431,13 +442,20 @@ pub fn delete_server_encoder() -> impl SizedEncode<Item = String> + MessageEncod
     protobuf_message_encoder![(F1, StringEncoder::new())]
 }
 
+pub fn put_bucket_options_encoder(
+) -> impl SizedEncode<Item = (String, BucketOptions)> + MessageEncode<Item = (String, BucketOptions)>
+{
+    bucket_options_encoder()
+}
+
 pub fn snapshot_decoder() -> impl MessageDecode<Item = Snapshot> {
     let base = protobuf_message_decoder![
         (F1, next_seqno_decoder(), message),
         (F2, bucket_decoder(), repeated_message),
         (F3, device_decoder(), repeated_message),
         (F4, server_decoder(), repeated_message),
-        (F5, segment_table_decoder(), repeated_message)
+        (F5, segment_table_decoder(), repeated_message),
+        (F6, bucket_options_decoder(), repeated_message)
     ];
     let base = protobuf_message_decoder![(F1, base, required_message)];
 
@@ -447,6 +465,7 @@ pub fn snapshot_decoder() -> impl MessageDecode<Item = Snapshot> {
         devices: x.2,
         servers: x.3,
         segment_tables: x.4,
+        bucket_options: x.5,
     })
 }
 
@@ -456,7 +475,8 @@ pub fn snapshot_encoder() -> impl MessageEncode<Item = Snapshot> {
         (F2, bucket_encoder(), repeated_message),
         (F3, device_encoder(), repeated_message),
         (F4, server_encoder(), repeated_message),
-        (F5, segment_table_encoder(), repeated_unsized_message)
+        (F5, segment_table_encoder(), repeated_unsized_message),
+        (F6, bucket_options_encoder(), repeated_message)
     ];
     let base = protobuf_message_encoder![(F1, base, required_unsized_message)];
 
@@ -467,10 +487,139 @@ pub fn snapshot_encoder() -> impl MessageEncode<Item = Snapshot> {
             x.devices,
             x.servers,
             x.segment_tables,
+            x.bucket_options,
+        )
+    })
+}
+
+pub fn bucket_options_decoder() -> impl MessageDecode<Item = (String, BucketOptions)> {
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, Uint32Decoder::new()),
+        (F3, hedge_policy_decoder(), message),
+        (F4, Uint32Decoder::new()),
+        (F5, Uint32Decoder::new()),
+        (F6, Uint32Decoder::new()),
+        (F7, Uint32Decoder::new()),
+        (F8, versioning_policy_decoder(), message)
+    ];
+    base.try_map(|x| -> Result<_> {
+        let write_quorum = match x.1 {
+            0 => WriteQuorum::One,
+            1 => WriteQuorum::Majority,
+            2 => WriteQuorum::All,
+            n => track_panic!(ErrorKind::InvalidInput, "Unknown write quorum: {}", n),
+        };
+        let checksum_algorithm = match x.3 {
+            0 => ChecksumAlgorithm::Adler32,
+            1 => ChecksumAlgorithm::Crc32c,
+            2 => ChecksumAlgorithm::XxHash64,
+            n => track_panic!(ErrorKind::InvalidInput, "Unknown checksum algorithm: {}", n),
+        };
+        let compression = match x.4 {
+            0 => Compression::None,
+            1 => Compression::Zstd,
+            2 => Compression::Lz4,
+            n => track_panic!(ErrorKind::InvalidInput, "Unknown compression: {}", n),
+        };
+        let options = BucketOptions {
+            write_quorum,
+            hedge_policy: x.2.unwrap_or_else(Default::default),
+            checksum_algorithm,
+            compression,
+            encryption_key_id: if x.5 == 0 { None } else { Some(x.5) },
+            inline_threshold: x.6,
+            versioning: x.7.unwrap_or_else(Default::default),
+        };
+        Ok((x.0, options))
+    })
+}
+
+pub fn bucket_options_encoder(
+) -> impl SizedEncode<Item = (String, BucketOptions)> + MessageEncode<Item = (String, BucketOptions)>
+{
+    let base = protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, Uint32Encoder::new()),
+        (F3, hedge_policy_encoder(), required_message),
+        (F4, Uint32Encoder::new()),
+        (F5, Uint32Encoder::new()),
+        (F6, Uint32Encoder::new()),
+        (F7, Uint32Encoder::new()),
+        (F8, versioning_policy_encoder(), required_message)
+    ];
+    base.map_from(|(id, options): (String, BucketOptions)| {
+        let write_quorum = match options.write_quorum {
+            WriteQuorum::One => 0,
+            WriteQuorum::Majority => 1,
+            WriteQuorum::All => 2,
+        };
+        let checksum_algorithm = match options.checksum_algorithm {
+            ChecksumAlgorithm::Adler32 => 0,
+            ChecksumAlgorithm::Crc32c => 1,
+            ChecksumAlgorithm::XxHash64 => 2,
+        };
+        let compression = match options.compression {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        };
+        (
+            id,
+            write_quorum,
+            options.hedge_policy,
+            checksum_algorithm,
+            compression,
+            options.encryption_key_id.unwrap_or(0),
+            options.inline_threshold,
+            options.versioning,
         )
     })
 }
 
+pub fn hedge_policy_decoder() -> impl MessageDecode<Item = HedgePolicy> {
+    let base = protobuf_message_decoder![
+        (F1, Uint32Decoder::new()),
+        (F2, Uint64Decoder::new()),
+        (F3, Uint32Decoder::new())
+    ];
+    base.map(|x| HedgePolicy {
+        extra_requests: x.0 as u8,
+        delay_millis: x.1,
+        delay_percentile: if x.2 == 0 { None } else { Some(x.2 as u8) },
+    })
+}
+
+pub fn hedge_policy_encoder(
+) -> impl SizedEncode<Item = HedgePolicy> + MessageEncode<Item = HedgePolicy> {
+    let base = protobuf_message_encoder![
+        (F1, Uint32Encoder::new()),
+        (F2, Uint64Encoder::new()),
+        (F3, Uint32Encoder::new())
+    ];
+    base.map_from(|x: HedgePolicy| {
+        (
+            u32::from(x.extra_requests),
+            x.delay_millis,
+            x.delay_percentile.map_or(0, u32::from),
+        )
+    })
+}
+
+pub fn versioning_policy_decoder() -> impl MessageDecode<Item = VersioningPolicy> {
+    let base = protobuf_message_decoder![(F1, Uint32Decoder::new()), (F2, Uint64Decoder::new())];
+    base.map(|x| VersioningPolicy {
+        max_versions: x.0,
+        max_age: if x.1 == 0 { None } else { Some(x.1) },
+    })
+}
+
+pub fn versioning_policy_encoder(
+) -> impl SizedEncode<Item = VersioningPolicy> + MessageEncode<Item = VersioningPolicy> {
+    let base = protobuf_message_encoder![(F1, Uint32Encoder::new()), (F2, Uint64Encoder::new())];
+    base.map_from(|x: VersioningPolicy| (x.max_versions, x.max_age.unwrap_or(0)))
+}
+
 pub fn next_seqno_decoder() -> impl MessageDecode<Item = NextSeqNo> {
     let base = protobuf_message_decoder![
         (F1, Uint32Decoder::new()),
@@ -556,4 +705,40 @@ mod tests {
         ];
         track_try_unwrap!(command_decoder().decode_from_bytes(&input));
     }
+
+    #[test]
+    fn put_bucket_options_command_works() {
+        let options = BucketOptions {
+            write_quorum: WriteQuorum::Majority,
+            hedge_policy: HedgePolicy {
+                extra_requests: 1,
+                delay_millis: 100,
+                delay_percentile: Some(95),
+            },
+            checksum_algorithm: ChecksumAlgorithm::Crc32c,
+            compression: Compression::Zstd,
+            encryption_key_id: Some(1),
+            inline_threshold: 1024,
+            versioning: VersioningPolicy {
+                max_versions: 3,
+                max_age: Some(86400),
+            },
+        };
+        let command = Command::PutBucketOptions {
+            bucket_id: "foo".to_owned(),
+            options: options.clone(),
+        };
+        let bytes = track_try_unwrap!(command_encoder().encode_into_bytes(command));
+        let command = track_try_unwrap!(command_decoder().decode_from_bytes(&bytes));
+        if let Command::PutBucketOptions {
+            bucket_id,
+            options: decoded,
+        } = command
+        {
+            assert_eq!(bucket_id, "foo");
+            assert_eq!(decoded, options);
+        } else {
+            panic!("Unexpected command: {:?}", command);
+        }
+    }
 }
@@ -6,6 +6,7 @@ use libfrugalos::entity::server::{Server, ServerId};
 use libfrugalos::schema::config as spec;
 
 use error::to_rpc_error;
+use schema::{GetBucketOptionsRpc, PutBucketOptionsRequest, PutBucketOptionsRpc};
 use service::ServiceHandle;
 
 /// RPC サーバ。
@@ -31,6 +32,8 @@ impl RpcServer {
         builder.add_call_handler::<spec::GetBucketRpc, _>(this.clone());
         builder.add_call_handler::<spec::PutBucketRpc, _>(this.clone());
         builder.add_call_handler::<spec::DeleteBucketRpc, _>(this.clone());
+        builder.add_call_handler::<GetBucketOptionsRpc, _>(this.clone());
+        builder.add_call_handler::<PutBucketOptionsRpc, _>(this.clone());
     }
 }
 impl HandleCall<spec::GetLeaderRpc> for RpcServer {
@@ -143,3 +146,23 @@ impl HandleCall<spec::DeleteBucketRpc> for RpcServer {
         )
     }
 }
+impl HandleCall<GetBucketOptionsRpc> for RpcServer {
+    fn handle_call(&self, bucket: BucketId) -> Reply<GetBucketOptionsRpc> {
+        Reply::future(
+            self.service
+                .get_bucket_options(bucket)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<PutBucketOptionsRpc> for RpcServer {
+    fn handle_call(&self, req: PutBucketOptionsRequest) -> Reply<PutBucketOptionsRpc> {
+        Reply::future(
+            self.service
+                .put_bucket_options(req.bucket_id, req.options)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
@@ -0,0 +1,203 @@
+//! `libfrugalos`には含まれない、構成管理固有のRPCのスキーマ定義。
+use bytecodec::bincode_codec::{BincodeDecoder, BincodeEncoder};
+use fibers_rpc::{Call, ProcedureId};
+use libfrugalos;
+use libfrugalos::entity::bucket::BucketId;
+
+/// バケツへのPUT時に、成功を返すまでに保存の完了を待つ配置先の数の指定。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WriteQuorum {
+    /// 最小限の配置先(複製なら一つ、ErasureCodingならデータフラグメント数分)への保存を待つ。
+    #[serde(rename = "one")]
+    One,
+
+    /// 過半数の配置先への保存を待つ。
+    #[serde(rename = "majority")]
+    Majority,
+
+    /// 全ての配置先への保存を待つ。
+    #[serde(rename = "all")]
+    All,
+}
+impl Default for WriteQuorum {
+    fn default() -> Self {
+        WriteQuorum::One
+    }
+}
+
+/// GET時のフラグメント取得要求のヘッジ方針(ErasureCodingを用いるバケツのみが対象)。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HedgePolicy {
+    /// 最初から必要数(データフラグメント数)に加えて発行しておく取得要求の数。
+    #[serde(default)]
+    pub extra_requests: u8,
+
+    /// 必要数のフラグメントが揃わない場合に、取得候補を一つ増やすまでの待ち時間(ミリ秒)。
+    #[serde(default = "default_hedge_delay_millis")]
+    pub delay_millis: u64,
+
+    /// 指定された場合には、直近のフラグメント取得の遅延のこのパーセンタイル値(`1..=100`)を待ち時間とする。
+    ///
+    /// ただし`delay_millis`を上限とする。
+    #[serde(default)]
+    pub delay_percentile: Option<u8>,
+}
+impl Default for HedgePolicy {
+    fn default() -> Self {
+        HedgePolicy {
+            extra_requests: 0,
+            delay_millis: default_hedge_delay_millis(),
+            delay_percentile: None,
+        }
+    }
+}
+
+fn default_hedge_delay_millis() -> u64 {
+    2000
+}
+
+/// バケツのバージョン管理の設定。
+///
+/// デフォルトではバージョン管理は無効であり、上書きないし削除されたバージョンは即座に破棄される。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersioningPolicy {
+    /// オブジェクト毎に保持する過去バージョンの最大数。
+    ///
+    /// `0`の場合にはバージョン管理が無効となる。
+    #[serde(default)]
+    pub max_versions: u32,
+
+    /// 過去バージョンを保持する最大期間(上書きないし削除されてからの秒数)。
+    ///
+    /// 指定された場合には、`max_versions`の範囲内であっても、この期間を過ぎたバージョンは破棄される。
+    #[serde(default)]
+    pub max_age: Option<u64>,
+}
+
+/// 新たに書き込むlumpに付与するチェックサムのアルゴリズム。
+///
+/// 既存のlumpは、書き込み時のアルゴリズムで検証されるので、変更後も引き続き読み込み可能。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChecksumAlgorithm {
+    /// Adler-32(従来の形式)。
+    #[serde(rename = "adler32")]
+    Adler32,
+
+    /// CRC-32C。
+    #[serde(rename = "crc32c")]
+    Crc32c,
+
+    /// xxHash(64bit)。
+    #[serde(rename = "xxhash64")]
+    XxHash64,
+}
+impl Default for ChecksumAlgorithm {
+    fn default() -> Self {
+        ChecksumAlgorithm::Adler32
+    }
+}
+
+/// オブジェクトの中身の圧縮方式。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    /// 圧縮しない。
+    #[serde(rename = "none")]
+    None,
+
+    /// Zstandard。
+    #[serde(rename = "zstd")]
+    Zstd,
+
+    /// LZ4。
+    #[serde(rename = "lz4")]
+    Lz4,
+}
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+/// `libfrugalos`のバケツ定義には含まれない、バケツ単位の付加的な設定。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketOptions {
+    /// PUT時の書き込みクォーラム。
+    #[serde(default)]
+    pub write_quorum: WriteQuorum,
+
+    /// GET時のフラグメント取得要求のヘッジ方針。
+    #[serde(default)]
+    pub hedge_policy: HedgePolicy,
+
+    /// 新たに書き込むlumpに付与するチェックサムのアルゴリズム。
+    #[serde(default)]
+    pub checksum_algorithm: ChecksumAlgorithm,
+
+    /// 新たに書き込むオブジェクトの中身の圧縮方式。
+    #[serde(default)]
+    pub compression: Compression,
+
+    /// 新たに書き込むlumpの暗号化に使用する鍵のID。
+    ///
+    /// `None`の場合には暗号化は行われない。
+    #[serde(default)]
+    pub encryption_key_id: Option<u32>,
+
+    /// このサイズ(バイト)未満のオブジェクトの中身を、ストレージではなくMDSに保存するための閾値。
+    ///
+    /// `0`の場合にはインライン化は行われない。
+    #[serde(default)]
+    pub inline_threshold: u32,
+
+    /// バージョン管理の設定。
+    ///
+    /// 変更は各セグメントのMDSのリーダによって、それぞれのRaftログを経由して反映される。
+    #[serde(default)]
+    pub versioning: VersioningPolicy,
+}
+
+/// バケツの付加的な設定を取得するためのRPC。
+///
+/// バケツが存在しない場合には`None`が返される。
+#[derive(Debug)]
+pub struct GetBucketOptionsRpc;
+impl Call for GetBucketOptionsRpc {
+    const ID: ProcedureId = ProcedureId(0x0102_0000);
+    const NAME: &'static str = "frugalos.config.bucket.get_options";
+
+    type Req = BucketId;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+
+    type Res = libfrugalos::Result<Option<BucketOptions>>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+}
+
+/// バケツの付加的な設定を更新するためのRPC。
+///
+/// リーダ以外のノードに発行された場合には`NotLeader`エラーとなる。
+#[derive(Debug)]
+pub struct PutBucketOptionsRpc;
+impl Call for PutBucketOptionsRpc {
+    const ID: ProcedureId = ProcedureId(0x0102_0001);
+    const NAME: &'static str = "frugalos.config.bucket.put_options";
+
+    type Req = PutBucketOptionsRequest;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+
+    type Res = libfrugalos::Result<BucketOptions>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+}
+
+/// `PutBucketOptionsRpc`の要求。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PutBucketOptionsRequest {
+    /// 対象バケツのID。
+    pub bucket_id: BucketId,
+
+    /// 新しい設定。
+    pub options: BucketOptions,
+}
@@ -16,6 +16,7 @@ use std::collections::{BTreeMap, VecDeque};
 use std::mem;
 use std::net::SocketAddr;
 use std::path::Path;
+use trackable::error::ErrorKindExt;
 
 use builder::SegmentTableBuilder;
 use cluster;
@@ -23,6 +24,7 @@ use config::server_to_frugalos_raft_node;
 use machine::{Command, DeviceGroup, NextSeqNo, SegmentTable, Snapshot};
 use protobuf;
 use rpc;
+use schema::BucketOptions;
 use {Error, ErrorKind, Result};
 
 type RaftEvent = raftlog::Event;
@@ -49,6 +51,7 @@ pub struct Service {
     devices: BTreeMap<DeviceId, Device>,
     servers: BTreeMap<ServerId, Server>,
     segment_tables: BTreeMap<BucketId, SegmentTable>,
+    bucket_options: BTreeMap<BucketId, BucketOptions>,
 
     next_seqno: NextSeqNo,
     events: VecDeque<Event>,
@@ -96,6 +99,7 @@ impl Service {
             devices: BTreeMap::new(),
             servers: BTreeMap::new(),
             segment_tables: BTreeMap::new(),
+            bucket_options: BTreeMap::new(),
 
             next_seqno: NextSeqNo::default(),
             events: VecDeque::new(),
@@ -200,6 +204,9 @@ impl Service {
             Command::DeleteDevice { id } => self.handle_delete_device(proposal_id, id),
             Command::PutBucket { bucket } => self.handle_put_bucket(proposal_id, bucket),
             Command::DeleteBucket { id } => self.handle_delete_bucket(proposal_id, &id),
+            Command::PutBucketOptions { bucket_id, options } => {
+                self.handle_put_bucket_options(proposal_id, bucket_id, options)
+            }
         }
         Ok(())
     }
@@ -257,6 +264,41 @@ impl Service {
             reply.exit(Ok(deleted))
         }
     }
+    fn handle_put_bucket_options(
+        &mut self,
+        proposal_id: ProposalId,
+        bucket_id: BucketId,
+        options: BucketOptions,
+    ) {
+        let result = if self.buckets.contains_key(&bucket_id) {
+            info!(
+                self.logger,
+                "Bucket options are updated: {}",
+                dump!(bucket_id, options)
+            );
+            self.bucket_options
+                .insert(bucket_id.clone(), options.clone());
+            self.events.push_back(Event::PutBucketOptions {
+                bucket_id,
+                options: options.clone(),
+            });
+            Ok(options)
+        } else {
+            // NOTE: 提案後にバケツが削除された場合
+            warn!(
+                self.logger,
+                "Referred bucket does not exist: {}",
+                dump!(proposal_id, bucket_id)
+            );
+            let e = ErrorKind::InvalidInput.cause(format!("No such bucket: {:?}", bucket_id));
+            Err(track!(Error::from(e)))
+        };
+        if let Some(Proposal::PutBucketOptions { reply, .. }) =
+            self.pop_committed_proposal(proposal_id)
+        {
+            reply.exit(result);
+        }
+    }
     fn handle_put_device(&mut self, proposal_id: ProposalId, mut device: Device) {
         // NOTE: 更新可能なのは仮想デバイスのみ
         if let Some(d) = self.devices.get(device.id()).cloned() {
@@ -397,6 +439,7 @@ impl Service {
         let deleted = if let Some(bucket) = self.buckets.remove(id) {
             info!(self.logger, "Bucket is deleted: {}", dump!(id, bucket));
             self.delete_segment_table(&bucket);
+            self.bucket_options.remove(id);
             self.events.push_back(Event::DeleteBucket(bucket.clone()));
             Some(bucket)
         } else {
@@ -417,6 +460,7 @@ impl Service {
         let old_buckets = mem::replace(&mut self.buckets, Default::default());
         let old_devices = mem::replace(&mut self.devices, Default::default());
         let old_servers = mem::replace(&mut self.servers, Default::default());
+        let old_bucket_options = mem::replace(&mut self.bucket_options, Default::default());
         self.buckets = snapshot
             .buckets
             .into_iter()
@@ -437,6 +481,7 @@ impl Service {
             .into_iter()
             .map(|s| (s.bucket_id.clone(), s))
             .collect();
+        self.bucket_options = snapshot.bucket_options.into_iter().collect();
         info!(
             self.logger,
             "Snapshot is loaded: {}",
@@ -478,6 +523,15 @@ impl Service {
                 });
             }
         }
+        for (bucket_id, options) in &self.bucket_options {
+            if old_bucket_options.get(bucket_id) == Some(options) {
+                continue;
+            }
+            self.events.push_back(Event::PutBucketOptions {
+                bucket_id: bucket_id.clone(),
+                options: options.clone(),
+            });
+        }
 
         track!(self.sync_servers())?;
         Ok(())
@@ -494,6 +548,11 @@ impl Service {
             devices: self.devices.values().cloned().collect(),
             servers: self.servers.values().cloned().collect(),
             segment_tables: self.segment_tables.values().cloned().collect(),
+            bucket_options: self
+                .bucket_options
+                .iter()
+                .map(|(id, o)| (id.clone(), o.clone()))
+                .collect(),
         };
         let snapshot = track!(protobuf::snapshot_encoder().encode_into_bytes(snapshot))?;
         track!(self.rlog.install_snapshot(self.next_commit_index, snapshot))?;
@@ -583,6 +642,37 @@ impl Service {
                     }
                 }
             }
+            Request::GetBucketOptions { id, reply } => {
+                let options = if self.buckets.contains_key(&id) {
+                    Some(self.bucket_options.get(&id).cloned().unwrap_or_default())
+                } else {
+                    None
+                };
+                reply.exit(Ok(options));
+            }
+            Request::PutBucketOptions { id, options, reply } => {
+                if !self.buckets.contains_key(&id) {
+                    let e = ErrorKind::InvalidInput.cause(format!("No such bucket: {:?}", id));
+                    reply.exit(Err(track!(Error::from(e))));
+                    return Ok(());
+                }
+                if options.versioning.max_age == Some(0) {
+                    let e = ErrorKind::InvalidInput.cause("`versioning.max_age` must be positive");
+                    reply.exit(Err(track!(Error::from(e))));
+                    return Ok(());
+                }
+                let command = Command::PutBucketOptions {
+                    bucket_id: id,
+                    options,
+                };
+                match track!(self.propose_command(command)) {
+                    Err(e) => reply.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        let proposal = Proposal::PutBucketOptions { proposal_id, reply };
+                        self.proposals.push_back(proposal);
+                    }
+                }
+            }
         }
         Ok(())
     }
@@ -733,6 +823,10 @@ pub enum Event {
         segment_no: u16,
         groups: Vec<DeviceGroup>,
     },
+    PutBucketOptions {
+        bucket_id: BucketId,
+        options: BucketOptions,
+    },
 }
 
 #[derive(Debug)]
@@ -785,6 +879,15 @@ enum Request {
         id: BucketId,
         reply: Reply<Option<Bucket>>,
     },
+    GetBucketOptions {
+        id: BucketId,
+        reply: Reply<Option<BucketOptions>>,
+    },
+    PutBucketOptions {
+        id: BucketId,
+        options: BucketOptions,
+        reply: Reply<BucketOptions>,
+    },
 }
 type Reply<T> = oneshot::Monitored<T, Error>;
 
@@ -814,6 +917,10 @@ enum Proposal {
         proposal_id: ProposalId,
         reply: Reply<Option<Bucket>>,
     },
+    PutBucketOptions {
+        proposal_id: ProposalId,
+        reply: Reply<BucketOptions>,
+    },
 }
 impl Proposal {
     pub fn id(&self) -> ProposalId {
@@ -824,6 +931,7 @@ impl Proposal {
             Proposal::DeleteDevice { proposal_id, .. } => proposal_id,
             Proposal::PutBucket { proposal_id, .. } => proposal_id,
             Proposal::DeleteBucket { proposal_id, .. } => proposal_id,
+            Proposal::PutBucketOptions { proposal_id, .. } => proposal_id,
         }
     }
 }
@@ -953,4 +1061,29 @@ impl ServiceHandle {
         let _ = self.request_tx.send(request);
         response
     }
+
+    /// バケツの付加的な設定を取得する。
+    ///
+    /// バケツが存在しない場合には`None`が返される。
+    pub fn get_bucket_options(
+        &self,
+        id: BucketId,
+    ) -> impl Future<Item = Option<BucketOptions>, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::GetBucketOptions { id, reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// バケツの付加的な設定を更新する。
+    pub fn put_bucket_options(
+        &self,
+        id: BucketId,
+        options: BucketOptions,
+    ) -> impl Future<Item = BucketOptions, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::PutBucketOptions { id, options, reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
 }
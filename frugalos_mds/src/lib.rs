@@ -26,6 +26,9 @@ extern crate frugalos_raft;
 extern crate raftlog;
 extern crate rand;
 extern crate rustracing;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 #[macro_use]
 extern crate slog;
 #[macro_use]
@@ -43,5 +46,7 @@ mod protobuf;
 mod server;
 mod service;
 
+pub mod schema;
+
 /// クレート固有の`Result`型.
 pub type Result<T> = ::std::result::Result<T, Error>;
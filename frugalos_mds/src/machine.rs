@@ -4,9 +4,13 @@ use libfrugalos::entity::object::{Metadata, ObjectId, ObjectPrefix, ObjectSummar
 use libfrugalos::expect::Expect;
 use libfrugalos::time::Seconds;
 use patricia_tree::PatriciaMap;
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::iter;
+use std::mem;
 
-use {Error, Result};
+use schema::{BatchResult, ObjectPage, ObjectVersionEntry, UserMetadata, VersioningPolicy};
+use {Error, ErrorKind, Result};
 
 /// ノードの状態を管理するための状態機械.
 #[derive(Debug, Clone)]
@@ -17,12 +21,48 @@ pub struct Machine {
     //   二つを分けた方がメモリ消費量が抑えられると期待されるため
     id_to_version: PatriciaMap<ObjectVersion>,
     id_to_data: HashMap<ObjectId, Vec<u8>>,
+
+    // 利用者定義のメタデータを持つオブジェクトのみを保持する
+    id_to_user_metadata: HashMap<ObjectId, UserMetadata>,
+
+    // 有効期限(UNIXエポックからの秒数)を持つオブジェクトのみを保持する
+    // (`expirations`は、期限切れのオブジェクトを効率的に列挙するための索引)
+    id_to_expires_at: HashMap<ObjectId, u64>,
+    expirations: BTreeSet<(u64, ObjectId)>,
+
+    // バージョン管理が有効な場合に、オブジェクト毎の過去バージョン群を(古い順に)保持する
+    // (`history_owners`はバージョンから所有者を引くための索引、
+    // `superseded`は保持期間を過ぎたバージョンを効率的に列挙するための索引)
+    versioning: VersioningPolicy,
+    id_to_history: HashMap<ObjectId, Vec<PreviousVersion>>,
+    history_owners: BTreeMap<ObjectVersion, ObjectId>,
+    superseded: BTreeSet<(u64, ObjectVersion)>,
+
+    // 直近に適用されたコマンドの提案時刻(UNIXエポックからの秒数)
+    clock: u64,
+
+    // コミット位置からバージョンへのずらし幅
+    // (一つのコマンドで複数のバージョンが割り当てられる度に増加する)
+    version_offset: u64,
+
+    // 状態機械から完全に取り除かれ、中身を削除しても良くなったバージョン群
+    discarded: Vec<ObjectVersion>,
 }
 impl Machine {
     pub fn new() -> Self {
         Machine {
             id_to_version: PatriciaMap::new(),
             id_to_data: HashMap::new(),
+            id_to_user_metadata: HashMap::new(),
+            id_to_expires_at: HashMap::new(),
+            expirations: BTreeSet::new(),
+            versioning: VersioningPolicy::default(),
+            id_to_history: HashMap::new(),
+            history_owners: BTreeMap::new(),
+            superseded: BTreeSet::new(),
+            clock: 0,
+            version_offset: 0,
+            discarded: Vec::new(),
         }
     }
     pub fn from_snapshot(snapshot: Snapshot) -> Self {
@@ -30,56 +70,149 @@ impl Machine {
             Snapshot::Assoc(snapshot) => {
                 let mut id_to_version = PatriciaMap::new();
                 let mut id_to_data = HashMap::new();
-                for (id, metadata) in snapshot {
+                let mut id_to_user_metadata = HashMap::new();
+                let mut id_to_expires_at = HashMap::new();
+                let mut expirations = BTreeSet::new();
+                for (id, metadata, user_metadata, expires_at) in snapshot.objects {
                     if !metadata.data.is_empty() {
                         id_to_data.insert(id.clone(), metadata.data);
                     }
+                    if !user_metadata.is_empty() {
+                        id_to_user_metadata.insert(id.clone(), user_metadata);
+                    }
+                    if let Some(expires_at) = expires_at {
+                        id_to_expires_at.insert(id.clone(), expires_at);
+                        expirations.insert((expires_at, id.clone()));
+                    }
                     id_to_version.insert(id, metadata.version);
                 }
-                Machine {
+                let mut machine = Machine {
                     id_to_version,
                     id_to_data,
+                    id_to_user_metadata,
+                    id_to_expires_at,
+                    expirations,
+                    ..Machine::new()
+                };
+                machine.versioning = snapshot.versioning;
+                machine.version_offset = snapshot.version_offset;
+                for (id, previous) in snapshot.history {
+                    machine.push_history(id, previous);
                 }
+                machine
             }
-            Snapshot::Patricia(id_to_version) => Machine {
-                id_to_version,
-                id_to_data: HashMap::new(),
+            Snapshot::Patricia(snapshot) => Machine {
+                id_to_version: snapshot.id_to_version,
+                versioning: snapshot.versioning,
+                version_offset: snapshot.version_offset,
+                ..Machine::new()
             },
         }
     }
     pub fn to_snapshot(&self) -> Snapshot {
-        if self.id_to_data.is_empty() {
-            Snapshot::Patricia(self.id_to_version.clone())
+        if self.id_to_data.is_empty()
+            && self.id_to_user_metadata.is_empty()
+            && self.id_to_expires_at.is_empty()
+            && self.id_to_history.is_empty()
+        {
+            // NOTE: バージョン管理の設定とバージョンのずらし幅は、コンパクトな形式でも保持される
+            Snapshot::Patricia(PatriciaSnapshot {
+                id_to_version: self.id_to_version.clone(),
+                versioning: self.versioning.clone(),
+                version_offset: self.version_offset,
+            })
         } else {
-            // 典型的にはメタデータバケツの場合にここにくる
-            let assoc = self
+            // 典型的にはメタデータバケツの場合(あるいは利用者定義のメタデータや有効期限、
+            // 過去バージョンが存在する場合)にここにくる
+            let objects = self
                 .id_to_version
                 .iter()
                 .map(|(object_id, &version)| {
                     let object_id = String::from_utf8(object_id).unwrap();
                     let data = self.get_data(&object_id);
-                    (object_id.clone(), Metadata { version, data })
+                    let user_metadata = self.get_user_metadata(&object_id);
+                    let expires_at = self.expires_at(&object_id);
+                    (
+                        object_id.clone(),
+                        Metadata { version, data },
+                        user_metadata,
+                        expires_at,
+                    )
                 })
                 .collect();
-            Snapshot::Assoc(assoc)
+            let history = self
+                .id_to_history
+                .iter()
+                .flat_map(|(id, history)| history.iter().map(move |p| (id.clone(), p.clone())))
+                .collect();
+            Snapshot::Assoc(AssocSnapshot {
+                objects,
+                history,
+                versioning: self.versioning.clone(),
+                version_offset: self.version_offset,
+            })
         }
     }
     pub fn len(&self) -> usize {
         self.id_to_version.len()
     }
+    /// コミット位置`commit`のコマンドに、`count`個の連続したバージョンを割り当てる.
+    ///
+    /// 割り当てられたバージョン群の先頭が返される.
+    /// 後続のコミット位置には、ここで割り当てられたものよりも大きなバージョンが対応付けられる.
+    pub fn allocate_versions(&mut self, commit: u64, count: u64) -> ObjectVersion {
+        let first = ObjectVersion(commit + self.version_offset);
+        self.version_offset += count.saturating_sub(1);
+        first
+    }
     pub fn put(
         &mut self,
         object_id: ObjectId,
         metadata: Metadata,
+        user_metadata: UserMetadata,
         expect: &Expect,
     ) -> Result<Option<ObjectVersion>> {
         track!(self.check_version(&object_id, &expect))?;
-        if metadata.data.is_empty() {
-            self.id_to_data.remove(&object_id);
-        } else {
+        let old = self.supersede(&object_id);
+        if !metadata.data.is_empty() {
             self.id_to_data.insert(object_id.clone(), metadata.data);
         }
-        Ok(self.id_to_version.insert(object_id, metadata.version))
+        if !user_metadata.is_empty() {
+            self.id_to_user_metadata
+                .insert(object_id.clone(), user_metadata);
+        }
+        self.id_to_version.insert(object_id, metadata.version);
+        Ok(old)
+    }
+    /// オブジェクトに有効期限(UNIXエポックからの秒数)を設定する.
+    ///
+    /// 有効期限はオブジェクトが上書きないし削除されるまで保持される.
+    /// オブジェクトが存在しない場合には何も行われない.
+    pub fn set_expiration(&mut self, object_id: &ObjectId, expires_at: u64) {
+        if self.id_to_version.get(object_id).is_none() {
+            return;
+        }
+        self.clear_expiration(object_id);
+        self.id_to_expires_at.insert(object_id.clone(), expires_at);
+        self.expirations.insert((expires_at, object_id.clone()));
+    }
+    /// オブジェクトの有効期限を返す.
+    pub fn expires_at(&self, object_id: &ObjectId) -> Option<u64> {
+        self.id_to_expires_at.get(object_id).cloned()
+    }
+    /// 時刻`now`の時点で有効期限が切れているオブジェクト群を、期限が古い順に最大`limit`個まで返す.
+    pub fn expired_objects(&self, now: u64, limit: usize) -> Vec<ObjectSummary> {
+        self.expirations
+            .iter()
+            .take_while(|&&(expires_at, _)| expires_at <= now)
+            .filter_map(|&(_, ref id)| {
+                self.id_to_version.get(id).map(|&version| ObjectSummary {
+                    id: id.clone(),
+                    version,
+                })
+            })
+            .take(limit)
+            .collect()
     }
     pub fn delete(
         &mut self,
@@ -87,8 +220,136 @@ impl Machine {
         expect: &Expect,
     ) -> Result<Option<ObjectVersion>> {
         track!(self.check_version(object_id, &expect))?;
-        self.id_to_data.remove(object_id);
-        Ok(self.id_to_version.remove(object_id))
+        Ok(self.supersede(object_id))
+    }
+    /// オブジェクトのIDを`from`から`to`に変更する.
+    ///
+    /// バージョン(および中身)はそのまま引き継がれるので、データの移動は発生しない.
+    /// `expect`は変更先のオブジェクトに対して検査され、変更先に既存のオブジェクトがあれば、
+    /// 上書きと同様に(バージョン管理の設定に従って)取り除かれる.
+    ///
+    /// 変更元が存在しない場合には`None`が、それ以外の場合には変更後のオブジェクトのバージョンと、
+    /// 上書きされたオブジェクトのバージョンの組が返される.
+    pub fn rename(
+        &mut self,
+        from: &ObjectId,
+        to: ObjectId,
+        expect: &Expect,
+    ) -> Result<Option<(ObjectVersion, Option<ObjectVersion>)>> {
+        track!(self.check_version(&to, &expect))?;
+        let version = if let Some(&version) = self.id_to_version.get(from) {
+            version
+        } else {
+            return Ok(None);
+        };
+        if *from == to {
+            return Ok(Some((version, None)));
+        }
+
+        let data = self.id_to_data.remove(from);
+        let user_metadata = self.id_to_user_metadata.remove(from);
+        let expires_at = self.expires_at(from);
+        self.clear_expiration(from);
+        self.id_to_version.remove(from);
+
+        let old = self.supersede(&to);
+        if let Some(data) = data {
+            self.id_to_data.insert(to.clone(), data);
+        }
+        if let Some(user_metadata) = user_metadata {
+            self.id_to_user_metadata.insert(to.clone(), user_metadata);
+        }
+        self.id_to_version.insert(to.clone(), version);
+        if let Some(expires_at) = expires_at {
+            self.set_expiration(&to, expires_at);
+        }
+        Ok(Some((version, old)))
+    }
+    /// 複数のオブジェクトの保存・削除を、全て適用されるか何も適用されないかのいずれかとなるように実行する.
+    ///
+    /// 各操作の`expect`は、トランザクション内で先行する操作が適用された後の状態に対して検査され、
+    /// 一つでも満たされない場合には、何も変更せずにエラーが返される.
+    /// 保存操作には、コミット位置`commit`から割り当てられた連続したバージョンが、先頭から順に使用される.
+    ///
+    /// 結果は操作毎に、`BatchResult::Put`ないし`BatchResult::Delete`として返される.
+    /// 参照系の操作を含めることはできない.
+    pub fn apply_transaction(
+        &mut self,
+        commit: u64,
+        items: Vec<BatchItem>,
+    ) -> Result<Vec<BatchResult>> {
+        // 適用前に、全ての操作の条件を検査する
+        let first_version = commit + self.version_offset;
+        let mut puts = 0;
+        let mut updated = HashMap::new();
+        for item in &items {
+            let (object_id, expect, version) = match *item {
+                BatchItem::Put {
+                    ref object_id,
+                    ref expect,
+                    ..
+                } => {
+                    puts += 1;
+                    (
+                        object_id,
+                        expect,
+                        Some(ObjectVersion(first_version + puts - 1)),
+                    )
+                }
+                BatchItem::Delete {
+                    ref object_id,
+                    ref expect,
+                } => (object_id, expect, None),
+                BatchItem::Get { .. } | BatchItem::Head { .. } => track_panic!(
+                    ErrorKind::InvalidInput,
+                    "Read operations cannot be included in a transaction: {:?}",
+                    item
+                ),
+            };
+            let current = match updated.get(object_id) {
+                Some(&current) => current,
+                None => self.id_to_version.get(object_id).cloned(),
+            };
+            track!(
+                expect.validate(current).map_err(Error::from),
+                "object_id={:?}",
+                object_id
+            )?;
+            updated.insert(object_id.clone(), version);
+        }
+
+        let mut next_version = self.allocate_versions(commit, puts);
+        let mut results = Vec::with_capacity(items.len());
+        for item in items {
+            match item {
+                BatchItem::Put {
+                    object_id,
+                    userdata,
+                    user_metadata,
+                    expires_at,
+                    ..
+                } => {
+                    let version = next_version;
+                    next_version = ObjectVersion(version.0 + 1);
+                    let metadata = Metadata {
+                        version,
+                        data: userdata,
+                    };
+                    let old =
+                        track!(self.put(object_id.clone(), metadata, user_metadata, &Expect::Any))?;
+                    if let Some(expires_at) = expires_at {
+                        self.set_expiration(&object_id, expires_at);
+                    }
+                    results.push(BatchResult::Put(version, old));
+                }
+                BatchItem::Delete { object_id, .. } => {
+                    let old = track!(self.delete(&object_id, &Expect::Any))?;
+                    results.push(BatchResult::Delete(old));
+                }
+                BatchItem::Get { .. } | BatchItem::Head { .. } => unreachable!(),
+            }
+        }
+        Ok(results)
     }
     pub fn delete_version(
         &mut self,
@@ -96,6 +357,11 @@ impl Machine {
     ) -> Result<Option<ObjectVersion>> {
         use std::string::String;
 
+        if let Some(owner_id) = self.history_owners.get(&object_version).cloned() {
+            self.remove_history(&owner_id, |p| p.version == object_version);
+            return Ok(Some(object_version));
+        }
+
         let owner_id: Option<Vec<u8>> = self
             .id_to_version
             .iter()
@@ -105,18 +371,39 @@ impl Machine {
         if let Some(owner_id) = owner_id {
             let owner_id: ObjectId = track!(String::from_utf8(owner_id).map_err(Error::from))?;
             self.id_to_data.remove(&owner_id);
-            return Ok(self.id_to_version.remove(&owner_id));
+            self.id_to_user_metadata.remove(&owner_id);
+            self.clear_expiration(&owner_id);
+            let removed = self.id_to_version.remove(&owner_id);
+            self.discarded.extend(removed);
+            return Ok(removed);
         } else {
             return Ok(None);
         }
     }
+    /// `prefix`から始まるIDを持つオブジェクト群を削除する.
+    ///
+    /// バージョン管理の設定に関わらず、過去バージョンも含めて全て破棄される.
+    /// 結果には現在のバージョンのみが含まれる.
     pub fn delete_by_prefix(&mut self, object_prefix: &ObjectPrefix) -> Result<Vec<ObjectVersion>> {
         let mut versions = Vec::new();
         for (object_id, version) in self.id_to_version.split_by_prefix(&object_prefix.0) {
             let id = track!(String::from_utf8(object_id).map_err(Error::from))?;
             let _ = self.id_to_data.remove(&id);
+            let _ = self.id_to_user_metadata.remove(&id);
+            self.clear_expiration(&id);
             versions.push(version);
         }
+        self.discarded.extend(versions.iter().cloned());
+
+        let history_ids = self
+            .id_to_history
+            .keys()
+            .filter(|id| id.starts_with(&object_prefix.0))
+            .cloned()
+            .collect::<Vec<_>>();
+        for id in history_ids {
+            self.remove_history(&id, |_| true);
+        }
         Ok(versions)
     }
     pub fn get(&self, object_id: &ObjectId, expect: &Expect) -> Result<Option<Metadata>> {
@@ -126,6 +413,15 @@ impl Machine {
             Metadata { version, data }
         }))
     }
+    /// オブジェクトに付与された利用者定義のメタデータを返す.
+    ///
+    /// オブジェクトが存在しない場合や、メタデータが付与されていない場合には、空のマップが返される.
+    pub fn get_user_metadata(&self, object_id: &ObjectId) -> UserMetadata {
+        self.id_to_user_metadata
+            .get(object_id)
+            .cloned()
+            .unwrap_or_else(UserMetadata::new)
+    }
     pub fn head(&self, object_id: &ObjectId, expect: &Expect) -> Result<Option<ObjectVersion>> {
         track!(self.check_version(object_id, &expect))?;
         Ok(self.id_to_version.get(object_id).cloned())
@@ -137,6 +433,55 @@ impl Machine {
             .map(|(id, &version)| ObjectSummary { id, version })
             .collect()
     }
+    /// `prefix`から始まるIDを持つオブジェクト群を、IDの辞書順に最大`limit`個まで返す.
+    ///
+    /// `start_after`が指定された場合には、それよりも後ろのIDを持つオブジェクトのみが対象となる.
+    pub fn list_page(
+        &self,
+        prefix: &ObjectPrefix,
+        start_after: Option<&ObjectId>,
+        limit: usize,
+    ) -> ObjectPage {
+        let prefix = prefix.0.as_bytes();
+        let wanted = limit + 1;
+        let mut objects = Vec::new();
+        for (subtree, start_after) in subtrees_after(prefix, start_after.map(|s| s.as_bytes())) {
+            if objects.len() >= wanted {
+                break;
+            }
+            let rest = wanted - objects.len();
+            objects.extend(
+                self.id_to_version
+                    .iter_prefix(&subtree)
+                    .filter(|&(ref id, _)| start_after.map_or(true, |s| &id[..] != s))
+                    .take(rest)
+                    .map(|(id, &version)| {
+                        let id = String::from_utf8(id)
+                            .expect("Stringから作ったVec<u8>を復元するので失敗しないはず");
+                        ObjectSummary { id, version }
+                    }),
+            );
+        }
+        let next = if objects.len() > limit {
+            objects.truncate(limit);
+            objects.last().map(|o| o.id.clone())
+        } else {
+            None
+        };
+        let user_metadata = objects
+            .iter()
+            .filter_map(|o| {
+                self.id_to_user_metadata
+                    .get(&o.id)
+                    .map(|m| (o.id.clone(), m.clone()))
+            })
+            .collect();
+        ObjectPage {
+            objects,
+            next,
+            user_metadata,
+        }
+    }
     pub fn latest_version(&self) -> Option<ObjectSummary> {
         self.id_to_version
             .iter()
@@ -148,14 +493,214 @@ impl Machine {
                 version,
             })
     }
+    /// 保持されている全てのバージョン(過去バージョンを含む)を返す.
     pub fn to_versions(&self) -> Vec<ObjectVersion> {
-        self.id_to_version.values().cloned().collect()
+        self.id_to_version
+            .values()
+            .cloned()
+            .chain(self.history_owners.keys().cloned())
+            .collect()
+    }
+    /// 保持されている全てのバージョン(過去バージョンを含む)を、データを保持しているかどうかと共に返す.
+    pub fn to_versions_with_data_flag(&self) -> Vec<(ObjectVersion, bool)> {
+        let with_data = self
+            .id_to_data
+            .keys()
+            .filter_map(|id| self.id_to_version.get(id).cloned())
+            .chain(
+                self.id_to_history
+                    .values()
+                    .flat_map(|history| history.iter())
+                    .filter(|previous| !previous.data.is_empty())
+                    .map(|previous| previous.version),
+            )
+            .collect::<HashSet<_>>();
+        self.to_versions()
+            .into_iter()
+            .map(|version| (version, with_data.contains(&version)))
+            .collect()
+    }
+    /// 過去バージョンを含む、特定のバージョンのオブジェクトを返す.
+    pub fn get_version(
+        &self,
+        object_id: &ObjectId,
+        version: ObjectVersion,
+    ) -> Option<(Metadata, UserMetadata)> {
+        if self.id_to_version.get(object_id) == Some(&version) {
+            let metadata = Metadata {
+                version,
+                data: self.get_data(object_id),
+            };
+            return Some((metadata, self.get_user_metadata(object_id)));
+        }
+        self.id_to_history
+            .get(object_id)
+            .and_then(|history| history.iter().find(|p| p.version == version))
+            .map(|p| {
+                let metadata = Metadata {
+                    version,
+                    data: p.data.clone(),
+                };
+                (metadata, p.user_metadata.clone())
+            })
+    }
+    /// 過去バージョンを含めて、`version`が保持されている場合には`true`を返す.
+    ///
+    /// 現在のバージョンについては全オブジェクトを走査するので、頻繁には呼び出さないこと.
+    pub fn contains_version(&self, version: ObjectVersion) -> bool {
+        self.history_owners.contains_key(&version)
+            || self.id_to_version.values().any(|v| *v == version)
+    }
+    /// オブジェクトの保持されている全バージョンを、新しい順に返す.
+    pub fn list_versions(&self, object_id: &ObjectId) -> Vec<ObjectVersionEntry> {
+        let latest = self
+            .id_to_version
+            .get(object_id)
+            .map(|&version| ObjectVersionEntry {
+                version,
+                is_latest: true,
+                superseded_at: None,
+            });
+        let previous = self
+            .id_to_history
+            .get(object_id)
+            .into_iter()
+            .flat_map(|history| history.iter().rev())
+            .map(|p| ObjectVersionEntry {
+                version: p.version,
+                is_latest: false,
+                superseded_at: Some(p.superseded_at),
+            });
+        latest.into_iter().chain(previous).collect()
+    }
+    /// バージョン管理の設定を返す.
+    pub fn versioning(&self) -> &VersioningPolicy {
+        &self.versioning
+    }
+    /// バージョン管理の設定を変更する.
+    ///
+    /// 新しい設定で保持数を超える過去バージョンは、この時点で破棄される.
+    /// 保持期間については`outdated_versions`を参照.
+    pub fn set_versioning(&mut self, policy: VersioningPolicy) {
+        let max_versions = policy.max_versions as usize;
+        self.versioning = policy;
+        let ids = self
+            .id_to_history
+            .iter()
+            .filter(|(_, history)| history.len() > max_versions)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        for id in ids {
+            self.truncate_history(&id);
+        }
+    }
+    /// 時刻`now`の時点で保持期間を過ぎている過去バージョン群を、古い順に最大`limit`個まで返す.
+    pub fn outdated_versions(&self, now: u64, limit: usize) -> Vec<ObjectVersion> {
+        let max_age = if let Some(max_age) = self.versioning.max_age {
+            max_age
+        } else {
+            return Vec::new();
+        };
+        self.superseded
+            .iter()
+            .take_while(|&&(superseded_at, _)| superseded_at.saturating_add(max_age) <= now)
+            .map(|&(_, version)| version)
+            .take(limit)
+            .collect()
+    }
+    /// 以降に適用されるコマンドの提案時刻を設定する.
+    ///
+    /// この時刻は過去バージョンが上書きないし削除された時刻として記録される.
+    /// 時刻が過去に戻ることはない.
+    pub fn advance_clock(&mut self, now: u64) {
+        self.clock = cmp::max(self.clock, now);
+    }
+    /// 状態機械から完全に取り除かれ、中身を削除しても良くなったバージョン群を取り出す.
+    ///
+    /// バージョン管理が有効な場合には、上書きないし削除されたバージョンは
+    /// 保持数ないし保持期間を超えるまでは、ここには含まれない.
+    pub fn take_discarded_versions(&mut self) -> Vec<ObjectVersion> {
+        mem::replace(&mut self.discarded, Vec::new())
     }
     fn check_version(&self, object_id: &ObjectId, expect: &Expect) -> Result<()> {
         expect
             .validate(self.id_to_version.get(object_id).cloned())
             .map_err(Error::from)
     }
+    // 現在のバージョンを取り除き、バージョン管理が有効であれば過去バージョンとして保持する
+    fn supersede(&mut self, object_id: &ObjectId) -> Option<ObjectVersion> {
+        let data = self.id_to_data.remove(object_id);
+        let user_metadata = self.id_to_user_metadata.remove(object_id);
+        self.clear_expiration(object_id);
+        let version = self.id_to_version.remove(object_id)?;
+        if self.versioning.is_enabled() {
+            let previous = PreviousVersion {
+                version,
+                data: data.unwrap_or_else(Vec::new),
+                user_metadata: user_metadata.unwrap_or_else(UserMetadata::new),
+                superseded_at: self.clock,
+            };
+            self.push_history(object_id.clone(), previous);
+            self.truncate_history(object_id);
+        } else {
+            self.discarded.push(version);
+        }
+        Some(version)
+    }
+    fn push_history(&mut self, object_id: ObjectId, previous: PreviousVersion) {
+        self.history_owners
+            .insert(previous.version, object_id.clone());
+        self.superseded
+            .insert((previous.superseded_at, previous.version));
+        self.id_to_history
+            .entry(object_id)
+            .or_insert_with(Vec::new)
+            .push(previous);
+    }
+    // 保持数を超えた古い過去バージョンを破棄する
+    fn truncate_history(&mut self, object_id: &ObjectId) {
+        let excess = self.id_to_history.get(object_id).map_or(0, |history| {
+            history
+                .len()
+                .saturating_sub(self.versioning.max_versions as usize)
+        });
+        if excess > 0 {
+            let mut count = 0;
+            self.remove_history(object_id, |_| {
+                count += 1;
+                count <= excess
+            });
+        }
+    }
+    fn remove_history<F>(&mut self, object_id: &ObjectId, mut f: F)
+    where
+        F: FnMut(&PreviousVersion) -> bool,
+    {
+        let mut history = if let Some(history) = self.id_to_history.remove(object_id) {
+            history
+        } else {
+            return;
+        };
+        let mut retained = Vec::with_capacity(history.len());
+        for previous in history.drain(..) {
+            if f(&previous) {
+                self.history_owners.remove(&previous.version);
+                self.superseded
+                    .remove(&(previous.superseded_at, previous.version));
+                self.discarded.push(previous.version);
+            } else {
+                retained.push(previous);
+            }
+        }
+        if !retained.is_empty() {
+            self.id_to_history.insert(object_id.clone(), retained);
+        }
+    }
+    fn clear_expiration(&mut self, object_id: &ObjectId) {
+        if let Some(expires_at) = self.id_to_expires_at.remove(object_id) {
+            self.expirations.remove(&(expires_at, object_id.clone()));
+        }
+    }
     fn get_data(&self, object_id: &ObjectId) -> Vec<u8> {
         self.id_to_data
             .get(object_id)
@@ -169,6 +714,7 @@ pub enum Command {
     Put {
         object_id: ObjectId,
         userdata: Vec<u8>,
+        user_metadata: UserMetadata,
         expect: Expect,
 
         // 現在時刻を起点とした秒単位の尺.
@@ -177,10 +723,22 @@ pub enum Command {
         // 絶対時刻だと、複数ノード間の時計が同期していない場合に
         // 微妙な問題があるので、あえて相対時刻にしている.
         put_content_timeout: Seconds,
+
+        // 有効期限(UNIXエポックからの秒数).
+        // 各ノードの時計に依存しないように、提案時にリーダが絶対時刻に変換したものを保持する.
+        expires_at: Option<u64>,
+
+        // 提案時刻(UNIXエポックからの秒数).
+        // バージョン管理が有効な場合に、過去バージョンの保持期間の起点として使われる.
+        proposed_at: Option<u64>,
     },
     Delete {
         object_id: ObjectId,
         expect: Expect,
+
+        // 提案時刻(UNIXエポックからの秒数).
+        // バージョン管理が有効な場合に、過去バージョンの保持期間の起点として使われる.
+        proposed_at: Option<u64>,
     },
     DeleteByVersion {
         object_version: ObjectVersion,
@@ -192,12 +750,125 @@ pub enum Command {
     DeleteByPrefix {
         prefix: ObjectPrefix,
     },
+    SetVersioning {
+        policy: VersioningPolicy,
+    },
+    Rename {
+        from: ObjectId,
+        to: ObjectId,
+        expect: Expect,
+
+        // 提案時刻(UNIXエポックからの秒数).
+        // 変更先の既存オブジェクトが過去バージョンとして保持される場合に、保持期間の起点として使われる.
+        proposed_at: Option<u64>,
+    },
+    Batch {
+        items: Vec<BatchItem>,
+
+        // `Command::Put`のものと同様.
+        put_content_timeout: Seconds,
+
+        // 提案時刻(UNIXエポックからの秒数).
+        // バージョン管理が有効な場合に、過去バージョンの保持期間の起点として使われる.
+        proposed_at: Option<u64>,
+    },
+    Transaction {
+        // 保存ないし削除の操作のみを含む.
+        items: Vec<BatchItem>,
+
+        // `Command::Put`のものと同様.
+        put_content_timeout: Seconds,
+
+        // 提案時刻(UNIXエポックからの秒数).
+        // バージョン管理が有効な場合に、過去バージョンの保持期間の起点として使われる.
+        proposed_at: Option<u64>,
+    },
+}
+
+/// `Command::Batch`に含まれる個々の操作.
+///
+/// 操作は先頭から順に適用され、個々の操作の失敗は後続の操作には影響しない.
+#[derive(Debug, Clone)]
+pub enum BatchItem {
+    Get {
+        object_id: ObjectId,
+        expect: Expect,
+    },
+    Head {
+        object_id: ObjectId,
+        expect: Expect,
+    },
+    Put {
+        object_id: ObjectId,
+        userdata: Vec<u8>,
+        user_metadata: UserMetadata,
+        expect: Expect,
+
+        // 有効期限(UNIXエポックからの秒数).
+        expires_at: Option<u64>,
+    },
+    Delete {
+        object_id: ObjectId,
+        expect: Expect,
+    },
+}
+
+/// 上書きないし削除された後も保持されている、オブジェクトの過去バージョン.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreviousVersion {
+    pub version: ObjectVersion,
+    pub data: Vec<u8>,
+    pub user_metadata: UserMetadata,
+
+    // 上書きないし削除された時刻(UNIXエポックからの秒数)
+    pub superseded_at: u64,
+}
+
+#[derive(Debug)]
+pub struct AssocSnapshot {
+    pub objects: Vec<(ObjectId, Metadata, UserMetadata, Option<u64>)>,
+    pub history: Vec<(ObjectId, PreviousVersion)>,
+    pub versioning: VersioningPolicy,
+    pub version_offset: u64,
+}
+
+#[derive(Debug)]
+pub struct PatriciaSnapshot {
+    pub id_to_version: PatriciaMap<ObjectVersion>,
+    pub versioning: VersioningPolicy,
+    pub version_offset: u64,
 }
 
 #[derive(Debug)]
 pub enum Snapshot {
-    Assoc(Vec<(ObjectId, Metadata)>),
-    Patricia(PatriciaMap<ObjectVersion>),
+    Assoc(AssocSnapshot),
+    Patricia(PatriciaSnapshot),
+}
+
+// `prefix`から始まり、かつ`start_after`よりも後ろのIDのみを含む部分木群を、辞書順に返す.
+//
+// 各要素は、部分木の接頭辞と、その中で除外すべきID(`start_after`自体)の組.
+//
+// NOTE: `PatriciaMap`には指定位置から走査を開始する手段がないので、
+// `start_after`の各位置について、それよりも大きいバイトで分岐する部分木を列挙することで代替している.
+// これにより、探索の回数は(IDの長さには比例するが)オブジェクトの数には依存しなくなる.
+fn subtrees_after<'a>(
+    prefix: &'a [u8],
+    start_after: Option<&'a [u8]>,
+) -> Box<Iterator<Item = (Vec<u8>, Option<&'a [u8]>)> + 'a> {
+    let start_after = match start_after {
+        Some(s) if s.starts_with(prefix) => s,
+        Some(s) if s > prefix => return Box::new(iter::empty()),
+        _ => return Box::new(iter::once((prefix.to_vec(), None))),
+    };
+    let siblings = (prefix.len()..start_after.len()).rev().flat_map(move |i| {
+        (u16::from(start_after[i]) + 1..256).map(move |b| {
+            let mut subtree = start_after[..i].to_vec();
+            subtree.push(b as u8);
+            (subtree, None)
+        })
+    });
+    Box::new(iter::once((start_after.to_vec(), Some(start_after))).chain(siblings))
 }
 
 #[cfg(test)]
@@ -237,7 +908,9 @@ mod tests {
     fn setup_metadata(machine: &mut Machine, metadata_size: usize, kind: MetadataKind) {
         for n in 0..metadata_size {
             let (id, meta) = make_metadata(n, kind);
-            machine.put(id, meta, &Expect::None).unwrap();
+            machine
+                .put(id, meta, UserMetadata::new(), &Expect::None)
+                .unwrap();
         }
     }
 
@@ -249,7 +922,9 @@ mod tests {
                 version,
                 data: vec![0x01, 0x02],
             };
-            machine.put(id, meta, &Expect::None).unwrap();
+            machine
+                .put(id, meta, UserMetadata::new(), &Expect::None)
+                .unwrap();
         });
     }
 
@@ -261,7 +936,9 @@ mod tests {
 
         let (id, meta) = make_metadata(1, MetadataKind::MUSIC);
 
-        assert!(machine.put(id, meta, &Expect::None)?.is_none());
+        assert!(machine
+            .put(id, meta, UserMetadata::new(), &Expect::None)?
+            .is_none());
 
         assert_eq!(machine.len(), 1);
 
@@ -276,11 +953,11 @@ mod tests {
 
         let (id, meta) = make_metadata(1, MetadataKind::MUSIC);
 
-        machine.put(id.clone(), meta.clone(), &Expect::None)?;
+        machine.put(id.clone(), meta.clone(), UserMetadata::new(), &Expect::None)?;
 
         // すでにバージョンが1つ以上ある
         assert!(machine
-            .put(id.clone(), meta.clone(), &Expect::None)
+            .put(id.clone(), meta.clone(), UserMetadata::new(), &Expect::None)
             .is_err());
 
         // バージョンが異なる
@@ -288,6 +965,7 @@ mod tests {
             .put(
                 id.clone(),
                 meta.clone(),
+                UserMetadata::new(),
                 &Expect::IfMatch(vec![UNKNOWN_OBJECT_VERSION])
             )
             .is_err());
@@ -297,6 +975,7 @@ mod tests {
             .put(
                 id.clone(),
                 meta.clone(),
+                UserMetadata::new(),
                 &Expect::IfNoneMatch(vec![DEFAULT_OBJECT_VERSION])
             )
             .is_err());
@@ -407,10 +1086,13 @@ mod tests {
 
         assert_eq!(machine.len(), versions.len());
 
+        assert!(machine.contains_version(deleted_version));
         assert!(machine.delete_version(deleted_version)?.is_some());
+        assert!(!machine.contains_version(deleted_version));
 
         // バージョンが異なるオブジェクトは削除しない
         assert_eq!(machine.len(), versions.len() - 1);
+        assert!(machine.contains_version(DEFAULT_OBJECT_VERSION));
 
         Ok(())
     }
@@ -425,7 +1107,9 @@ mod tests {
 
         let (id, meta) = make_metadata(1, MetadataKind::LYRIC);
 
-        assert!(machine.put(id.clone(), meta, &Expect::None)?.is_none());
+        assert!(machine
+            .put(id.clone(), meta, UserMetadata::new(), &Expect::None)?
+            .is_none());
 
         assert_eq!(machine.len(), music_metadata_size + lyric_metadata_size);
 
@@ -458,4 +1142,477 @@ mod tests {
 
         Ok(())
     }
+
+    fn page_ids(page: &ObjectPage) -> Vec<&str> {
+        page.objects.iter().map(|o| o.id.as_str()).collect()
+    }
+
+    #[test]
+    fn it_lists_objects_by_page() -> TestResult {
+        let mut machine = Machine::new();
+        setup_metadata(&mut machine, 5, MetadataKind::MUSIC);
+        setup_metadata(&mut machine, 2, MetadataKind::LYRIC);
+
+        let music = ObjectPrefix("music".to_owned());
+        let page = machine.list_page(&music, None, 2);
+        assert_eq!(
+            page_ids(&page),
+            vec!["music:metadata:0", "music:metadata:1"]
+        );
+        assert_eq!(page.next, Some("music:metadata:1".to_owned()));
+
+        let page = machine.list_page(&music, page.next.as_ref(), 2);
+        assert_eq!(
+            page_ids(&page),
+            vec!["music:metadata:2", "music:metadata:3"]
+        );
+
+        // 最後のページ
+        let page = machine.list_page(&music, page.next.as_ref(), 2);
+        assert_eq!(page_ids(&page), vec!["music:metadata:4"]);
+        assert_eq!(page.next, None);
+
+        // 接頭辞が空の場合には、全てのオブジェクトが対象となる
+        let all = ObjectPrefix("".to_owned());
+        let page = machine.list_page(&all, None, 10);
+        assert_eq!(page.objects.len(), 7);
+        assert_eq!(page.objects[0].id, "lyric:metadata:0");
+        assert_eq!(page.next, None);
+
+        let page = machine.list_page(&all, Some(&"lyric:metadata:1".to_owned()), 10);
+        assert_eq!(page.objects.len(), 5);
+        assert_eq!(page.objects[0].id, "music:metadata:0");
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_lists_objects_after_the_given_id() -> TestResult {
+        let mut machine = Machine::new();
+        let ids = ["a", "ab", "abc", "abd", "ac", "b", "ba", "b\u{ff}"];
+        for (i, id) in ids.iter().enumerate() {
+            let metadata = Metadata {
+                version: ObjectVersion(i as u64),
+                data: vec![],
+            };
+            track!(machine.put(id.to_string(), metadata, UserMetadata::new(), &Expect::Any))?;
+        }
+
+        let all = ObjectPrefix("".to_owned());
+        let a = ObjectPrefix("a".to_owned());
+        let list = |prefix: &ObjectPrefix, start_after: Option<&str>| {
+            let start_after = start_after.map(|s| s.to_owned());
+            let page = machine.list_page(prefix, start_after.as_ref(), 10);
+            page.objects.into_iter().map(|o| o.id).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            list(&all, Some("ab")),
+            vec!["abc", "abd", "ac", "b", "ba", "b\u{ff}"]
+        );
+        assert_eq!(
+            list(&all, Some("abcc")),
+            vec!["abd", "ac", "b", "ba", "b\u{ff}"]
+        );
+        assert_eq!(list(&all, Some("b")), vec!["ba", "b\u{ff}"]);
+        assert_eq!(list(&all, Some("bb")), vec!["b\u{ff}"]);
+        assert_eq!(list(&all, Some("c")), Vec::<String>::new());
+        assert_eq!(list(&a, Some("abd")), vec!["ac"]);
+
+        // 接頭辞の範囲外の`start_after`
+        assert_eq!(list(&a, Some("")), vec!["a", "ab", "abc", "abd", "ac"]);
+        assert_eq!(list(&a, Some("b")), Vec::<String>::new());
+
+        // ページの区切りをまたいでも、全てのオブジェクトが順番に列挙される
+        let mut listed = Vec::new();
+        let mut next = None;
+        loop {
+            let page = machine.list_page(&all, next.as_ref(), 3);
+            listed.extend(page.objects.into_iter().map(|o| o.id));
+            next = page.next;
+            if next.is_none() {
+                break;
+            }
+        }
+        assert_eq!(
+            listed,
+            ids.iter().map(|id| id.to_string()).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn it_keeps_user_metadata() -> TestResult {
+        let mut machine = Machine::new();
+        let (id, meta) = make_metadata(0, MetadataKind::MUSIC);
+        let mut user_metadata = UserMetadata::new();
+        user_metadata.insert("content-type".to_owned(), "audio/mpeg".to_owned());
+
+        machine.put(
+            id.clone(),
+            meta.clone(),
+            user_metadata.clone(),
+            &Expect::Any,
+        )?;
+        assert_eq!(machine.get_user_metadata(&id), user_metadata);
+
+        // 一覧にも含まれる
+        let page = machine.list_page(&ObjectPrefix(String::new()), None, 10);
+        assert_eq!(page.user_metadata.get(&id), Some(&user_metadata));
+
+        // スナップショットを経由しても保持される
+        let restored = Machine::from_snapshot(machine.to_snapshot());
+        assert_eq!(restored.get_user_metadata(&id), user_metadata);
+
+        // メタデータなしで上書きすると消える
+        machine.put(id.clone(), meta, UserMetadata::new(), &Expect::Any)?;
+        assert!(machine.get_user_metadata(&id).is_empty());
+
+        machine.put(
+            id.clone(),
+            make_metadata(0, MetadataKind::MUSIC).1,
+            user_metadata,
+            &Expect::Any,
+        )?;
+        machine.delete(&id, &Expect::Any)?;
+        assert!(machine.get_user_metadata(&id).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_lists_expired_objects() -> TestResult {
+        let mut machine = Machine::new();
+        setup_metadata(&mut machine, 4, MetadataKind::MUSIC);
+        let ids = (0..4)
+            .map(|n| make_object_id(n, MetadataKind::MUSIC))
+            .collect::<Vec<_>>();
+        machine.set_expiration(&ids[0], 30);
+        machine.set_expiration(&ids[1], 10);
+        machine.set_expiration(&ids[2], 20);
+
+        // 存在しないオブジェクトには設定されない
+        machine.set_expiration(&make_object_id(9, MetadataKind::MUSIC), 10);
+        assert_eq!(
+            machine.expires_at(&make_object_id(9, MetadataKind::MUSIC)),
+            None
+        );
+
+        let expired_ids = |machine: &Machine, now, limit| {
+            machine
+                .expired_objects(now, limit)
+                .into_iter()
+                .map(|o| o.id)
+                .collect::<Vec<_>>()
+        };
+        assert!(expired_ids(&machine, 9, 10).is_empty());
+        assert_eq!(
+            expired_ids(&machine, 20, 10),
+            vec![ids[1].clone(), ids[2].clone()]
+        );
+        assert_eq!(expired_ids(&machine, 100, 1), vec![ids[1].clone()]);
+
+        // スナップショットを経由しても保持される
+        let restored = Machine::from_snapshot(machine.to_snapshot());
+        assert_eq!(restored.expires_at(&ids[0]), Some(30));
+        assert_eq!(
+            expired_ids(&restored, 20, 10),
+            expired_ids(&machine, 20, 10)
+        );
+
+        // 上書きや削除によって有効期限は解除される
+        let (_, meta) = make_metadata(1, MetadataKind::MUSIC);
+        machine.put(ids[1].clone(), meta, UserMetadata::new(), &Expect::Any)?;
+        machine.delete(&ids[2], &Expect::Any)?;
+        assert!(expired_ids(&machine, 20, 10).is_empty());
+        assert_eq!(expired_ids(&machine, 30, 10), vec![ids[0].clone()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_keeps_previous_versions() -> TestResult {
+        let mut machine = Machine::new();
+        machine.set_versioning(VersioningPolicy {
+            max_versions: 2,
+            max_age: Some(100),
+        });
+        let id = make_object_id(0, MetadataKind::MUSIC);
+        for (n, now) in [10, 20, 30, 40].iter().enumerate() {
+            machine.advance_clock(*now);
+            let metadata = Metadata {
+                version: ObjectVersion(n as u64 + 1),
+                data: vec![n as u8],
+            };
+            machine.put(id.clone(), metadata, UserMetadata::new(), &Expect::Any)?;
+        }
+
+        // 保持数を超えた最古のバージョンのみが破棄される
+        assert_eq!(machine.take_discarded_versions(), vec![ObjectVersion(1)]);
+        let versions = |machine: &Machine| {
+            machine
+                .list_versions(&id)
+                .into_iter()
+                .map(|e| e.version.0)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(versions(&machine), vec![4, 3, 2]);
+        assert!(machine.list_versions(&id)[0].is_latest);
+        assert_eq!(machine.list_versions(&id)[1].superseded_at, Some(40));
+        assert_eq!(
+            machine.get_version(&id, ObjectVersion(2)).map(|x| x.0.data),
+            Some(vec![1])
+        );
+        assert!(machine.get_version(&id, ObjectVersion(1)).is_none());
+        assert!(machine.contains_version(ObjectVersion(2)));
+        assert!(!machine.contains_version(ObjectVersion(1)));
+
+        // 削除されたバージョンも過去バージョンとして保持される
+        machine.advance_clock(50);
+        machine.delete(&id, &Expect::Any)?;
+        assert_eq!(machine.take_discarded_versions(), vec![ObjectVersion(2)]);
+        assert_eq!(versions(&machine), vec![4, 3]);
+        assert!(machine.head(&id, &Expect::Any)?.is_none());
+
+        // スナップショットを経由しても保持される
+        let restored = Machine::from_snapshot(machine.to_snapshot());
+        assert_eq!(versions(&restored), vec![4, 3]);
+        assert_eq!(restored.versioning(), machine.versioning());
+
+        // 保持期間を過ぎたバージョンの列挙と削除
+        assert!(machine.outdated_versions(139, 10).is_empty());
+        assert_eq!(machine.outdated_versions(140, 10), vec![ObjectVersion(3)]);
+        assert_eq!(
+            machine.delete_version(ObjectVersion(3))?,
+            Some(ObjectVersion(3))
+        );
+        assert_eq!(machine.take_discarded_versions(), vec![ObjectVersion(3)]);
+
+        // バージョン管理を無効にすると、残りの過去バージョンも破棄される
+        machine.set_versioning(VersioningPolicy::default());
+        assert_eq!(machine.take_discarded_versions(), vec![ObjectVersion(4)]);
+        assert!(versions(&machine).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_keeps_delaying_discards_across_snapshots() -> TestResult {
+        use codec::{decode_machine, encode_machine};
+
+        let mut machine = Machine::new();
+        machine.set_versioning(VersioningPolicy {
+            max_versions: 1,
+            max_age: Some(100),
+        });
+        let id = make_object_id(0, MetadataKind::MUSIC);
+        for &(version, now) in &[(1, 10), (2, 20)] {
+            machine.advance_clock(now);
+            let metadata = Metadata {
+                version: ObjectVersion(version),
+                data: Vec::new(),
+            };
+            machine.put(id.clone(), metadata, UserMetadata::new(), &Expect::Any)?;
+        }
+        assert!(machine.take_discarded_versions().is_empty());
+
+        // 復元後も過去バージョンは保持されており、削除対象(i.e., `Event::Deleted`の対象)とはならない
+        let mut restored = track!(decode_machine(&track!(encode_machine(&machine))?))?;
+        assert_eq!(restored.versioning(), machine.versioning());
+        let mut versions = restored.to_versions_with_data_flag();
+        versions.sort();
+        assert_eq!(
+            versions,
+            vec![(ObjectVersion(1), false), (ObjectVersion(2), false)]
+        );
+        assert!(restored.take_discarded_versions().is_empty());
+
+        // 保持期間は上書きされた時刻から数えられる
+        assert!(restored.outdated_versions(119, 10).is_empty());
+        assert_eq!(restored.outdated_versions(120, 10), vec![ObjectVersion(1)]);
+        assert_eq!(
+            restored.delete_version(ObjectVersion(1))?,
+            Some(ObjectVersion(1))
+        );
+        assert_eq!(restored.take_discarded_versions(), vec![ObjectVersion(1)]);
+
+        // 復元後に上書きされたバージョンも、保持数を超えるまでは破棄されない
+        restored.advance_clock(130);
+        let metadata = Metadata {
+            version: ObjectVersion(3),
+            data: Vec::new(),
+        };
+        restored.put(id.clone(), metadata, UserMetadata::new(), &Expect::Any)?;
+        assert!(restored.take_discarded_versions().is_empty());
+        let mut restored = track!(decode_machine(&track!(encode_machine(&restored))?))?;
+        assert_eq!(restored.outdated_versions(230, 10), vec![ObjectVersion(2)]);
+
+        // バージョン管理を無効にすると、その時点で破棄される
+        restored.set_versioning(VersioningPolicy::default());
+        assert_eq!(restored.take_discarded_versions(), vec![ObjectVersion(2)]);
+        assert_eq!(restored.list_versions(&id).len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn it_renames_object() -> TestResult {
+        let mut machine = Machine::new();
+        let (from, metadata) = make_metadata(0, MetadataKind::MUSIC);
+        let mut user_metadata = UserMetadata::new();
+        user_metadata.insert("content-type".to_owned(), "audio/mpeg".to_owned());
+        machine.put(from.clone(), metadata, user_metadata.clone(), &Expect::Any)?;
+        machine.set_expiration(&from, 100);
+
+        let to = make_object_id(1, MetadataKind::MUSIC);
+        let old = Metadata {
+            version: ObjectVersion(1),
+            data: vec![],
+        };
+        machine.put(to.clone(), old, UserMetadata::new(), &Expect::Any)?;
+
+        // 期待するバージョンが一致しなければ失敗する
+        assert!(machine.rename(&from, to.clone(), &Expect::None).is_err());
+
+        // バージョンやデータ、付加情報はそのまま引き継がれる
+        assert_eq!(
+            machine.rename(&from, to.clone(), &Expect::Any)?,
+            Some((DEFAULT_OBJECT_VERSION, Some(ObjectVersion(1))))
+        );
+        assert_eq!(machine.take_discarded_versions(), vec![ObjectVersion(1)]);
+        assert!(machine.get(&from, &Expect::Any)?.is_none());
+        assert_eq!(
+            machine.get(&to, &Expect::Any)?.map(|m| (m.version, m.data)),
+            Some((DEFAULT_OBJECT_VERSION, vec![0x01, 0x02]))
+        );
+        assert_eq!(machine.get_user_metadata(&to), user_metadata);
+        assert_eq!(machine.expires_at(&to), Some(100));
+        assert_eq!(machine.expires_at(&from), None);
+
+        // 存在しないオブジェクトの名前は変更できない
+        assert_eq!(machine.rename(&from, to, &Expect::Any)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn it_allocates_consecutive_versions() -> TestResult {
+        use codec::{decode_machine, encode_machine};
+
+        let mut machine = Machine::new();
+        assert_eq!(machine.allocate_versions(10, 1), ObjectVersion(10));
+
+        // 複数のバージョンを割り当てると、後続のコミット位置のバージョンがずれる
+        assert_eq!(machine.allocate_versions(11, 3), ObjectVersion(11));
+        assert_eq!(machine.allocate_versions(12, 1), ObjectVersion(14));
+        assert_eq!(machine.allocate_versions(13, 0), ObjectVersion(15));
+        assert_eq!(machine.allocate_versions(14, 1), ObjectVersion(16));
+
+        // ずらし幅はスナップショットを経由しても保持される
+        let mut restored = Machine::from_snapshot(machine.to_snapshot());
+        assert_eq!(restored.allocate_versions(15, 1), ObjectVersion(17));
+
+        // ずらし幅やバージョン管理の設定だけでは、コンパクトな形式は妨げられない
+        machine.set_versioning(VersioningPolicy {
+            max_versions: 2,
+            max_age: None,
+        });
+        if let Snapshot::Assoc(_) = machine.to_snapshot() {
+            panic!("Unexpected snapshot format");
+        }
+        let mut restored = track!(decode_machine(&track!(encode_machine(&machine))?))?;
+        assert_eq!(restored.versioning(), machine.versioning());
+        assert_eq!(restored.allocate_versions(15, 1), ObjectVersion(17));
+        Ok(())
+    }
+
+    #[test]
+    fn it_applies_transaction_atomically() -> TestResult {
+        let mut machine = Machine::new();
+        let (index, metadata) = make_metadata(0, MetadataKind::LYRIC);
+        machine.put(index.clone(), metadata, UserMetadata::new(), &Expect::Any)?;
+        let object = make_object_id(0, MetadataKind::MUSIC);
+        let put = |object_id: &ObjectId, expect: Expect| BatchItem::Put {
+            object_id: object_id.clone(),
+            userdata: vec![0x03],
+            user_metadata: UserMetadata::new(),
+            expect,
+            expires_at: None,
+        };
+
+        // 一つでも条件を満たさなければ、何も適用されない
+        let items = vec![
+            put(&object, Expect::None),
+            put(&index, Expect::IfMatch(vec![UNKNOWN_OBJECT_VERSION])),
+        ];
+        assert!(machine.apply_transaction(10, items).is_err());
+        assert!(machine.head(&object, &Expect::Any)?.is_none());
+        assert_eq!(
+            machine.head(&index, &Expect::Any)?,
+            Some(DEFAULT_OBJECT_VERSION)
+        );
+
+        // 条件は先行する操作の適用後の状態に対して検査される
+        let items = vec![
+            put(&object, Expect::None),
+            put(&index, Expect::IfMatch(vec![DEFAULT_OBJECT_VERSION])),
+            BatchItem::Delete {
+                object_id: object.clone(),
+                expect: Expect::IfMatch(vec![ObjectVersion(11)]),
+            },
+        ];
+        let results = machine.apply_transaction(11, items)?;
+        assert_eq!(results.len(), 3);
+        assert!(machine.head(&object, &Expect::Any)?.is_none());
+        assert_eq!(machine.head(&index, &Expect::Any)?, Some(ObjectVersion(12)));
+        assert_eq!(
+            machine.take_discarded_versions(),
+            vec![DEFAULT_OBJECT_VERSION, ObjectVersion(11)]
+        );
+
+        // 参照系の操作は含められない
+        let items = vec![BatchItem::Head {
+            object_id: index.clone(),
+            expect: Expect::Any,
+        }];
+        assert!(machine.apply_transaction(12, items).is_err());
+
+        // 保存操作の数だけバージョンが消費される
+        assert_eq!(machine.allocate_versions(12, 1), ObjectVersion(13));
+        Ok(())
+    }
+
+    #[test]
+    fn it_reports_versions_with_data() -> TestResult {
+        let mut machine = Machine::new();
+        machine.set_versioning(VersioningPolicy {
+            max_versions: 2,
+            max_age: None,
+        });
+        let put = |machine: &mut Machine, n: usize, version: u64, data: Vec<u8>| {
+            let id = make_object_id(n, MetadataKind::MUSIC);
+            let metadata = Metadata {
+                version: ObjectVersion(version),
+                data,
+            };
+            machine.put(id, metadata, UserMetadata::new(), &Expect::Any)
+        };
+        put(&mut machine, 0, 1, vec![0x01])?;
+        put(&mut machine, 1, 2, Vec::new())?;
+
+        // 過去バージョンについても、データの有無が引き継がれる
+        put(&mut machine, 0, 3, Vec::new())?;
+        put(&mut machine, 1, 4, vec![0x02])?;
+
+        let mut versions = machine.to_versions_with_data_flag();
+        versions.sort();
+        assert_eq!(
+            versions,
+            vec![
+                (ObjectVersion(1), true),
+                (ObjectVersion(2), false),
+                (ObjectVersion(3), false),
+                (ObjectVersion(4), true),
+            ]
+        );
+        Ok(())
+    }
 }
@@ -10,7 +10,11 @@ use libfrugalos::time::Seconds;
 use std::ops::Range;
 
 use super::Request;
-use Error;
+use schema::{
+    BatchOperation, BatchResult, ObjectAttributes, ObjectPage, ObjectVersionEntry, ReadConsistency,
+    UserMetadata,
+};
+use {Error, Result};
 
 macro_rules! future_try {
     ($e:expr) => {
@@ -54,6 +58,19 @@ impl NodeHandle {
         let future = monitor.map_err(|e| track!(Error::from(e)));
         Either::A(future)
     }
+    pub fn list_objects_page(
+        &self,
+        prefix: ObjectPrefix,
+        start_after: Option<ObjectId>,
+        limit: usize,
+        consistency: ReadConsistency,
+    ) -> impl Future<Item = ObjectPage, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::ListPage(prefix, start_after, limit, consistency, monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(future)
+    }
 
     pub fn latest_version(&self) -> impl Future<Item = Option<ObjectSummary>, Error = Error> {
         let (monitored, monitor) = oneshot::monitor();
@@ -78,8 +95,18 @@ impl NodeHandle {
         object_id: ObjectId,
         expect: Expect,
     ) -> impl Future<Item = Option<Metadata>, Error = Error> {
+        self.get_object_with_user_metadata(object_id, expect, ReadConsistency::default())
+            .map(|object| object.map(|(metadata, _)| metadata))
+    }
+
+    pub fn get_object_with_user_metadata(
+        &self,
+        object_id: ObjectId,
+        expect: Expect,
+        consistency: ReadConsistency,
+    ) -> impl Future<Item = Option<(Metadata, UserMetadata)>, Error = Error> {
         let (monitored, monitor) = oneshot::monitor();
-        let request = Request::Get(object_id, expect, monitored);
+        let request = Request::Get(object_id, expect, consistency, monitored);
         future_try!(self.request_tx.send(request));
         let future = monitor.map_err(|e| track!(Error::from(e)));
         Either::A(future)
@@ -90,8 +117,90 @@ impl NodeHandle {
         object_id: ObjectId,
         expect: Expect,
     ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
+        self.head_object_with_user_metadata(object_id, expect, ReadConsistency::default())
+            .map(|object| object.map(|(version, _)| version))
+    }
+
+    pub fn head_object_with_user_metadata(
+        &self,
+        object_id: ObjectId,
+        expect: Expect,
+        consistency: ReadConsistency,
+    ) -> impl Future<Item = Option<(ObjectVersion, UserMetadata)>, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::Head(object_id, expect, consistency, monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(future)
+    }
+
+    pub fn get_object_version(
+        &self,
+        object_id: ObjectId,
+        version: ObjectVersion,
+    ) -> impl Future<Item = Option<(Metadata, UserMetadata)>, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::GetVersion(object_id, version, monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(future)
+    }
+
+    pub fn head_object_version(
+        &self,
+        version: ObjectVersion,
+        consistency: ReadConsistency,
+    ) -> impl Future<Item = bool, Error = Error> {
         let (monitored, monitor) = oneshot::monitor();
-        let request = Request::Head(object_id, expect, monitored);
+        let request = Request::HeadVersion(version, consistency, monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(future)
+    }
+
+    pub fn list_object_versions(
+        &self,
+        object_id: ObjectId,
+    ) -> impl Future<Item = Vec<ObjectVersionEntry>, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::ListVersions(object_id, monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(future)
+    }
+
+    pub fn rename_object(
+        &self,
+        from: ObjectId,
+        to: ObjectId,
+        expect: Expect,
+    ) -> impl Future<Item = Option<(ObjectVersion, Option<ObjectVersion>)>, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::Rename(from, to, expect, monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(future)
+    }
+
+    pub fn batch(
+        &self,
+        operations: Vec<BatchOperation>,
+        put_content_timeout: Seconds,
+    ) -> impl Future<Item = Vec<Result<BatchResult>>, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::Batch(operations, put_content_timeout, monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(future)
+    }
+
+    pub fn transaction(
+        &self,
+        operations: Vec<BatchOperation>,
+        put_content_timeout: Seconds,
+    ) -> impl Future<Item = Vec<BatchResult>, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::Transaction(operations, put_content_timeout, monitored);
         future_try!(self.request_tx.send(request));
         let future = monitor.map_err(|e| track!(Error::from(e)));
         Either::A(future)
@@ -180,11 +289,19 @@ impl NodeHandle {
         &self,
         object_id: ObjectId,
         body: Vec<u8>,
+        attributes: ObjectAttributes,
         expect: Expect,
         put_content_timeout: Seconds,
     ) -> impl Future<Item = (ObjectVersion, Option<ObjectVersion>), Error = Error> {
         let (monitored, monitor) = oneshot::monitor();
-        let request = Request::Put(object_id, body, expect, put_content_timeout, monitored);
+        let request = Request::Put(
+            object_id,
+            body,
+            attributes,
+            expect,
+            put_content_timeout,
+            monitored,
+        );
         future_try!(self.request_tx.send(request));
         let future = monitor.map_err(|e| track!(Error::from(e)));
         Either::A(future)
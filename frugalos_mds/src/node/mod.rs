@@ -6,10 +6,16 @@ use libfrugalos::entity::object::{
 };
 use libfrugalos::expect::Expect;
 use libfrugalos::time::Seconds;
-use raftlog::log::ProposalId;
+use raftlog::log::{LogIndex, ProposalId};
+use raftlog::message::SequenceNumber;
+use std::time::Instant;
 use trackable::error::ErrorKindExt;
 
-use {Error, ErrorKind};
+use schema::{
+    BatchOperation, BatchResult, ObjectAttributes, ObjectPage, ObjectVersionEntry, ReadConsistency,
+    UserMetadata,
+};
+use {Error, ErrorKind, Result};
 
 pub use self::handle::NodeHandle;
 pub use self::node::Node;
@@ -29,6 +35,12 @@ enum Proposal {
         ObjectPrefix,
         Reply<DeleteObjectsByPrefixSummary>,
     ),
+    Rename(
+        ProposalId,
+        Reply<Option<(ObjectVersion, Option<ObjectVersion>)>>,
+    ),
+    Batch(ProposalId, Reply<Vec<Result<BatchResult>>>),
+    Transaction(ProposalId, Reply<Vec<BatchResult>>),
 }
 impl Proposal {
     pub fn id(&self) -> ProposalId {
@@ -36,15 +48,35 @@ impl Proposal {
             Proposal::Put(id, ..) => id,
             Proposal::Delete(id, ..) => id,
             Proposal::DeleteByPrefix(id, ..) => id,
+            Proposal::Rename(id, ..) => id,
+            Proposal::Batch(id, ..) => id,
+            Proposal::Transaction(id, ..) => id,
+        }
+    }
+    pub fn notify_applied(self, applied: Applied) {
+        match (self, applied) {
+            (Proposal::Batch(_, monitored), Applied::Batch(results)) => monitored.exit(Ok(results)),
+            (Proposal::Transaction(_, monitored), Applied::Transaction(results)) => {
+                monitored.exit(Ok(results))
+            }
+            (proposal, Applied::Versions(versions)) => proposal.notify_committed(&versions),
+            (proposal, applied) => proposal.notify_error(
+                ErrorKind::Other
+                    .cause(format!("Unexpected applied results: {:?}", applied))
+                    .into(),
+            ),
         }
     }
     pub fn notify_committed(self, old: &[ObjectVersion]) {
         match self {
-            Proposal::Put(id, monitored) => match old {
-                [] => monitored.exit(Ok((ObjectVersion(id.index.as_u64()), None))),
-                [old] => monitored.exit(Ok((ObjectVersion(id.index.as_u64()), Some(*old)))),
+            Proposal::Put(_, monitored) => match old {
+                [version] => monitored.exit(Ok((*version, None))),
+                [version, old] => monitored.exit(Ok((*version, Some(*old)))),
                 _ => monitored.exit(Err(ErrorKind::InvalidInput
-                    .cause(format!("Expected [] or [ObjectVersion] but got {:?}", old))
+                    .cause(format!(
+                        "Expected [ObjectVersion] or [ObjectVersion, ObjectVersion] but got {:?}",
+                        old
+                    ))
                     .into())),
             },
             Proposal::Delete(_, monitored) => match old {
@@ -59,6 +91,23 @@ impl Proposal {
                     total: old.len() as u64,
                 }));
             }
+            Proposal::Rename(_, monitored) => match old {
+                [] => monitored.exit(Ok(None)),
+                [version] => monitored.exit(Ok(Some((*version, None)))),
+                [version, old] => monitored.exit(Ok(Some((*version, Some(*old))))),
+                _ => monitored.exit(Err(ErrorKind::InvalidInput
+                    .cause(format!(
+                        "Expected [], [ObjectVersion] or [ObjectVersion, ObjectVersion] but got {:?}",
+                        old
+                    ))
+                    .into())),
+            },
+            Proposal::Batch(_, monitored) => monitored.exit(Err(ErrorKind::Other
+                .cause(format!("Expected batch results but got {:?}", old))
+                .into())),
+            Proposal::Transaction(_, monitored) => monitored.exit(Err(ErrorKind::Other
+                .cause(format!("Expected transaction results but got {:?}", old))
+                .into())),
         }
     }
     pub fn notify_rejected(self) {
@@ -76,27 +125,135 @@ impl Proposal {
             Proposal::DeleteByPrefix(_, _, monitored) => {
                 monitored.exit(Err(track!(e)));
             }
+            Proposal::Rename(_, monitored) => {
+                monitored.exit(Err(track!(e)));
+            }
+            Proposal::Batch(_, monitored) => {
+                monitored.exit(Err(track!(e)));
+            }
+            Proposal::Transaction(_, monitored) => {
+                monitored.exit(Err(track!(e)));
+            }
         }
     }
 }
 
+/// ReadIndex方式での線形化可能な読み込みのために、リーダであることの確認を待っている要求群.
+///
+/// リーダはハートビートの送信時点でのコミット済みのログの末尾(ReadIndex)を記録し、
+/// そのハートビートが過半数のノードに受理され、かつReadIndexまでのエントリが適用された時点で、
+/// ログに何も追記することなく、まとめて要求に応答する.
+#[derive(Debug)]
+struct ReadBarrier {
+    seq_no: SequenceNumber,
+    read_index: LogIndex,
+    sent_at: Instant,
+    requests: Vec<Request>,
+}
+impl ReadBarrier {
+    pub fn new(
+        seq_no: SequenceNumber,
+        read_index: LogIndex,
+        sent_at: Instant,
+        requests: Vec<Request>,
+    ) -> Self {
+        ReadBarrier {
+            seq_no,
+            read_index,
+            sent_at,
+            requests,
+        }
+    }
+
+    /// リースの起点となる時刻(i.e., ハートビートの送信時刻)を返す.
+    pub fn sent_at(&self) -> Instant {
+        self.sent_at
+    }
+
+    /// 過半数のノードに受理されたハートビートのシーケンス番号と、次に適用されるログの位置から、
+    /// 要求群に応答可能かどうかを判定する.
+    pub fn is_ready(&self, last_heartbeat_acked: SequenceNumber, next_commit: LogIndex) -> bool {
+        self.seq_no <= last_heartbeat_acked && self.read_index <= next_commit
+    }
+
+    pub fn into_requests(self) -> Vec<Request> {
+        self.requests
+    }
+
+    pub fn notify_error(self, e: Error) {
+        for request in self.requests {
+            request.failed(track!(e.clone()));
+        }
+    }
+}
+
+/// コミットされたコマンドの適用結果.
+#[derive(Debug)]
+enum Applied {
+    /// 追加ないし削除されたバージョン群.
+    Versions(Vec<ObjectVersion>),
+
+    /// バッチ内の各操作の結果.
+    Batch(Vec<Result<BatchResult>>),
+
+    /// トランザクション内の各操作の結果.
+    Transaction(Vec<BatchResult>),
+}
+
 /// `Node`に発行される要求.
 #[derive(Debug)]
 enum Request {
     StartElection,
     GetLeader(Reply<NodeId>),
     List(Reply<Vec<ObjectSummary>>),
+    ListPage(
+        ObjectPrefix,
+        Option<ObjectId>,
+        usize,
+        ReadConsistency,
+        Reply<ObjectPage>,
+    ),
     LatestVersion(Reply<Option<ObjectSummary>>),
     ObjectCount(Reply<u64>),
-    Get(ObjectId, Expect, Reply<Option<Metadata>>),
-    Head(ObjectId, Expect, Reply<Option<ObjectVersion>>),
+    Get(
+        ObjectId,
+        Expect,
+        ReadConsistency,
+        Reply<Option<(Metadata, UserMetadata)>>,
+    ),
+    Head(
+        ObjectId,
+        Expect,
+        ReadConsistency,
+        Reply<Option<(ObjectVersion, UserMetadata)>>,
+    ),
     Put(
         ObjectId,
         Vec<u8>,
+        ObjectAttributes,
         Expect,
         Seconds,
         Reply<(ObjectVersion, Option<ObjectVersion>)>,
     ),
+    GetVersion(
+        ObjectId,
+        ObjectVersion,
+        Reply<Option<(Metadata, UserMetadata)>>,
+    ),
+    ListVersions(ObjectId, Reply<Vec<ObjectVersionEntry>>),
+    HeadVersion(ObjectVersion, ReadConsistency, Reply<bool>),
+    Rename(
+        ObjectId,
+        ObjectId,
+        Expect,
+        Reply<Option<(ObjectVersion, Option<ObjectVersion>)>>,
+    ),
+    Batch(
+        Vec<BatchOperation>,
+        Seconds,
+        Reply<Vec<Result<BatchResult>>>,
+    ),
+    Transaction(Vec<BatchOperation>, Seconds, Reply<Vec<BatchResult>>),
     Delete(ObjectId, Expect, Reply<Option<ObjectVersion>>),
     DeleteByVersion(ObjectVersion, Reply<Option<ObjectVersion>>),
     #[allow(dead_code)]
@@ -106,15 +263,32 @@ enum Request {
     TakeSnapshot,
 }
 impl Request {
+    /// 一貫性レベルの指定が可能な参照系の要求の場合には、そのレベルを返す.
+    pub fn read_consistency(&self) -> Option<ReadConsistency> {
+        match *self {
+            Request::ListPage(_, _, _, consistency, _)
+            | Request::Get(_, _, consistency, _)
+            | Request::Head(_, _, consistency, _)
+            | Request::HeadVersion(_, consistency, _) => Some(consistency),
+            _ => None,
+        }
+    }
     pub fn failed(self, e: Error) {
         match self {
             Request::GetLeader(tx) => tx.exit(Err(track!(e))),
             Request::List(tx) => tx.exit(Err(track!(e))),
+            Request::ListPage(_, _, _, _, tx) => tx.exit(Err(track!(e))),
             Request::LatestVersion(tx) => tx.exit(Err(track!(e))),
             Request::ObjectCount(tx) => tx.exit(Err(track!(e))),
-            Request::Get(_, _, tx) => tx.exit(Err(track!(e))),
-            Request::Head(_, _, tx) => tx.exit(Err(track!(e))),
-            Request::Put(_, _, _, _, tx) => tx.exit(Err(track!(e))),
+            Request::Get(_, _, _, tx) => tx.exit(Err(track!(e))),
+            Request::Head(_, _, _, tx) => tx.exit(Err(track!(e))),
+            Request::Put(_, _, _, _, _, tx) => tx.exit(Err(track!(e))),
+            Request::GetVersion(_, _, tx) => tx.exit(Err(track!(e))),
+            Request::ListVersions(_, tx) => tx.exit(Err(track!(e))),
+            Request::HeadVersion(_, _, tx) => tx.exit(Err(track!(e))),
+            Request::Rename(_, _, _, tx) => tx.exit(Err(track!(e))),
+            Request::Batch(_, _, tx) => tx.exit(Err(track!(e))),
+            Request::Transaction(_, _, tx) => tx.exit(Err(track!(e))),
             Request::Delete(_, _, tx) => tx.exit(Err(track!(e))),
             Request::DeleteByVersion(_, tx) => tx.exit(Err(track!(e))),
             Request::DeleteByRange(_, _, tx) => tx.exit(Err(track!(e))),
@@ -129,9 +303,13 @@ impl Request {
 #[allow(missing_docs)]
 pub enum Event {
     /// メタデータオブジェクトが追加された.
+    ///
+    /// `has_data`は、オブジェクトがデータ(`Metadata.data`)を保持しているかどうか.
+    /// データバケツでは、データを保持しているオブジェクトの中身はストレージには保存されない.
     Putted {
         version: ObjectVersion,
         put_content_timeout: Seconds,
+        has_data: bool,
     },
 
     /// メタデータオブジェクトが削除された.
@@ -173,4 +351,53 @@ mod tests {
         let summary = track!(fibers_global::execute(monitor))?;
         Ok(assert_eq!(summary.total, 1))
     }
+
+    fn make_get_request() -> (Request, Monitor<Option<(Metadata, UserMetadata)>, Error>) {
+        let (monitored, monitor) = make_monitor();
+        let request = Request::Get(
+            ObjectId::from("foo"),
+            Expect::Any,
+            ReadConsistency::Linearizable,
+            monitored,
+        );
+        (request, monitor)
+    }
+
+    #[test]
+    fn read_barrier_waits_for_heartbeat_and_read_index() {
+        let barrier = ReadBarrier::new(
+            SequenceNumber::new(3),
+            LogIndex::new(10),
+            Instant::now(),
+            Vec::new(),
+        );
+        assert!(!barrier.is_ready(SequenceNumber::new(2), LogIndex::new(10)));
+        assert!(!barrier.is_ready(SequenceNumber::new(3), LogIndex::new(9)));
+        assert!(barrier.is_ready(SequenceNumber::new(3), LogIndex::new(10)));
+        assert!(barrier.is_ready(SequenceNumber::new(4), LogIndex::new(11)));
+    }
+
+    #[test]
+    fn it_fails_all_reads_sharing_an_aborted_read_barrier() -> TestResult {
+        let (request0, monitor0) = make_get_request();
+        let (request1, monitor1) = make_get_request();
+        assert_eq!(
+            request0.read_consistency(),
+            Some(ReadConsistency::Linearizable)
+        );
+
+        fibers_global::spawn(futures::lazy(move || {
+            let barrier = ReadBarrier::new(
+                SequenceNumber::new(0),
+                LogIndex::new(0),
+                Instant::now(),
+                vec![request0, request1],
+            );
+            Ok(barrier.notify_error(ErrorKind::NotLeader.error().into()))
+        }));
+
+        assert!(fibers_global::execute(monitor0.map_err(Error::from)).is_err());
+        assert!(fibers_global::execute(monitor1.map_err(Error::from)).is_err());
+        Ok(())
+    }
 }
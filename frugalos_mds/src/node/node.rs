@@ -7,26 +7,36 @@ use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
 use fibers_tasque::{self, AsyncCall, TaskQueueExt};
 use frugalos_raft::{NodeId, RaftIo};
 use futures::{Async, Future, Poll, Stream};
-use libfrugalos::entity::object::{Metadata, ObjectVersion};
+use libfrugalos::entity::object::{Metadata, ObjectId, ObjectVersion};
+use libfrugalos::expect::Expect;
 use prometrics::metrics::{Counter, CounterBuilder, Gauge, GaugeBuilder};
 use raftlog::cluster::{ClusterConfig, ClusterMembers};
-use raftlog::election::Role;
+use raftlog::election::{Role, Term};
 use raftlog::log::{LogEntry, LogIndex, LogPosition};
 use raftlog::{self, ReplicatedLog};
 use slog::Logger;
 use std::collections::VecDeque;
 use std::env;
-use std::time::Duration;
+use std::mem;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use trackable::error::ErrorKindExt;
 
-use super::{Event, NodeHandle, Proposal, Request, Seconds};
+use super::{Applied, Event, NodeHandle, Proposal, ReadBarrier, Request, Seconds};
 use codec;
-use machine::{Command, Machine};
+use machine::{BatchItem, Command, Machine};
 use protobuf;
+use schema::{BatchOperation, BatchResult, ReadConsistency, SharedVersioningPolicy, UserMetadata};
 use {Error, ErrorKind, Result, ServiceHandle};
 
 const DEFAULT_SNAPSHOT_THRESHOLD: usize = 10_000;
 const DEFAULT_REELECTION_THRESHOLD: usize = 10; // 10 * 500ms = 10s
 const DEFAULT_LARGE_QUEUE_THRESHOLD: usize = 1024;
+const MAX_EXPIRATIONS_PER_ROUND: usize = 1000;
+
+// リースは、フォロワーの選挙タイムアウトの最小値よりも短くする必要がある
+// (選挙タイムアウトの値は`frugalos_segment`のデフォルト値と合わせている)
+const DEFAULT_READ_LEASE_MILLIS: u64 = 500;
+const DEFAULT_RAFT_MIN_TIMEOUT_MILLIS: u64 = 1000;
 
 type RaftEvent = raftlog::Event;
 
@@ -92,6 +102,7 @@ pub struct Node {
     node_id: NodeId,
     rlog: ReplicatedLog<RaftIo>,
     leader: Option<NodeId>,
+    leader_term: Option<Term>,
     leader_waitings: Vec<Monitored<NodeId, Error>>,
     leader_waiting_timeout: usize,
     request_rx: mpsc::Receiver<Request>,
@@ -104,7 +115,8 @@ pub struct Node {
     machine: Machine,
     metrics: Metrics,
     ready_snapshot: Option<AsyncCall<Result<(LogIndex, Vec<u8>)>>>,
-    decoding_snapshot: Option<AsyncCall<Result<(LogPosition, Machine, Vec<ObjectVersion>)>>>,
+    decoding_snapshot:
+        Option<AsyncCall<Result<(LogPosition, Machine, Vec<(ObjectVersion, bool)>)>>>,
     polling_timer: timer::Timeout,
     phase: Phase,
     rpc_service: RpcServiceHandle,
@@ -114,6 +126,21 @@ pub struct Node {
     large_queue_threshold: usize,
     reelection_threshold: usize,
     commit_timeout: Option<usize>,
+
+    // 有効期限切れのオブジェクトの削除のために、最後に提案したコマンドのログ位置
+    expiration_proposal: Option<LogIndex>,
+
+    // バケツに設定されているバージョン管理の設定と、その反映のために最後に提案したコマンドのログ位置
+    versioning: SharedVersioningPolicy,
+    versioning_proposal: Option<LogIndex>,
+
+    // リーダのリース: この時刻までは、リーダであることを確認せずに読み込み要求に応答可能
+    read_lease: Option<Instant>,
+    read_lease_duration: Duration,
+
+    // ReadIndex方式の読み込み: 次のハートビートを待っている要求群と、応答待ちのハートビート
+    read_waitings: Vec<Request>,
+    read_barrier: Option<ReadBarrier>,
 }
 impl Node {
     /// 新しい`Node`インスタンスを生成する.
@@ -144,12 +171,28 @@ impl Node {
             .ok()
             .and_then(|v| v.parse().ok())
             .unwrap_or(DEFAULT_LARGE_QUEUE_THRESHOLD);
+        let read_lease_millis = env::var("FRUGALOS_READ_LEASE_MILLIS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_READ_LEASE_MILLIS);
+        let raft_min_timeout_millis = env::var("FRUGALOS_RAFT_MIN_TIMEOUT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_RAFT_MIN_TIMEOUT_MILLIS);
+        track_assert!(
+            read_lease_millis < raft_min_timeout_millis,
+            ErrorKind::InvalidInput,
+            "The read lease must be shorter than the minimum election timeout: read_lease={}ms, raft_min_timeout={}ms",
+            read_lease_millis,
+            raft_min_timeout_millis
+        );
         info!(
             logger,
-            "Thresholds: snapshot={}, reelection={}, queue={}",
+            "Thresholds: snapshot={}, reelection={}, queue={}, read_lease={}ms",
             snapshot_threshold,
             reelection_threshold,
-            large_queue_threshold
+            large_queue_threshold,
+            read_lease_millis
         );
 
         let metrics = track!(Metrics::new(&node_id))?;
@@ -159,6 +202,7 @@ impl Node {
             node_id,
             rlog,
             leader: None,
+            leader_term: None,
             leader_waitings: Vec::new(),
             leader_waiting_timeout: 0,
             request_rx,
@@ -178,6 +222,13 @@ impl Node {
             large_queue_threshold,
             reelection_threshold,
             commit_timeout: None,
+            expiration_proposal: None,
+            versioning: SharedVersioningPolicy::default(),
+            versioning_proposal: None,
+            read_lease: None,
+            read_lease_duration: Duration::from_millis(read_lease_millis),
+            read_waitings: Vec::new(),
+            read_barrier: None,
             rpc_service,
         })
     }
@@ -196,14 +247,55 @@ impl Node {
         self.snapshot_threshold = threshold;
     }
 
+    /// バケツに設定されているバージョン管理の設定を共有するためのハンドルを設定する.
+    ///
+    /// リーダである間は、状態機械の設定がこのハンドルの設定と一致するように`SetVersioning`コマンドが提案される.
+    /// 設定されていない場合には、バージョン管理は無効として扱われる.
+    pub fn set_versioning(&mut self, versioning: SharedVersioningPolicy) {
+        self.versioning = versioning;
+    }
+
+    /// ローカルのマシンが、現在のリーダの任期の開始時点までの状態に追いついている場合には`true`を返す.
+    ///
+    /// スナップショットの読み込みや、起動直後のログの再適用が終わっていない間は`false`となる.
+    /// `versions`等の結果を元に、ストレージ上のデータを削除するような処理は、
+    /// これが`true`になってから開始する必要がある.
+    pub fn is_caught_up(&self) -> bool {
+        self.phase == Phase::Running
+            && self.decoding_snapshot.is_none()
+            && !self.rlog.is_snapshot_installing()
+            && self.leader_term == Some(self.rlog.local_node().ballot.term)
+            && self.next_commit >= self.rlog.local_history().committed_tail().index
+    }
+
+    /// ローカルのマシンが保持している全てのバージョン(過去バージョンを含む)を返す.
+    ///
+    /// リーダかどうかに関わらず、このノードが把握している状態が返される.
+    pub fn versions(&self) -> Vec<ObjectVersion> {
+        self.machine.to_versions()
+    }
+
+    /// ローカルのマシンが保持している、データを保持していない全てのバージョン(過去バージョンを含む)を返す.
+    ///
+    /// データバケツの場合には、中身がストレージに保存されているバージョン群となる.
+    pub fn versions_without_data(&self) -> Vec<ObjectVersion> {
+        self.machine
+            .to_versions_with_data_flag()
+            .into_iter()
+            .filter(|&(_, has_data)| !has_data)
+            .map(|(version, _)| version)
+            .collect()
+    }
+
     fn handle_request(&mut self, request: Request) {
         // NOTE: 整合性を保証したいので、要求を処理できるのはリーダのみとする.
-        // TODO: リースないしハートビートを使って、leaderであることを保証する (READ時)
+        // (ただし、古い状態の読み込みを許容する要求はフォロワーでも処理可能)
         match request {
             Request::GetLeader(_)
             | Request::Stop
             | Request::TakeSnapshot
             | Request::StartElection => {}
+            _ if request.read_consistency() == Some(ReadConsistency::Stale) => {}
             _ => {
                 if let Err(e) = self.check_leader() {
                     request.failed(e);
@@ -239,29 +331,34 @@ impl Node {
                 let list = self.machine.to_summaries();
                 monitored.exit(Ok(list));
             }
+            request @ Request::ListPage(..)
+            | request @ Request::Get(..)
+            | request @ Request::Head(..)
+            | request @ Request::HeadVersion(..) => self.handle_read(request),
             Request::LatestVersion(monitored) => {
                 let latest = self.machine.latest_version();
                 monitored.exit(Ok(latest));
             }
             Request::ObjectCount(monitored) => monitored.exit(Ok(self.machine.len() as u64)),
-            Request::Get(object_id, expect, monitored) => {
-                let result = self
-                    .check_leader()
-                    .and_then(|()| self.machine.get(&object_id, &expect));
-                monitored.exit(result);
+            Request::GetVersion(object_id, version, monitored) => {
+                let object = self.machine.get_version(&object_id, version);
+                monitored.exit(Ok(object));
             }
-            Request::Head(object_id, expect, monitored) => {
-                let result = self
-                    .check_leader()
-                    .and_then(|()| self.machine.head(&object_id, &expect));
-                monitored.exit(result);
+            Request::ListVersions(object_id, monitored) => {
+                let versions = self.machine.list_versions(&object_id);
+                monitored.exit(Ok(versions));
             }
-            Request::Put(object_id, data, expect, put_content_timeout, monitored) => {
+            Request::Put(object_id, data, attributes, expect, put_content_timeout, monitored) => {
+                let now = unix_time_secs();
+                let expires_at = attributes.ttl.map(|ttl| now.saturating_add(ttl));
                 let command = Command::Put {
                     object_id,
                     userdata: data,
+                    user_metadata: attributes.user_metadata,
                     expect,
                     put_content_timeout,
+                    expires_at,
+                    proposed_at: Some(now),
                 };
                 let result = track!(protobuf::command_encoder().encode_into_bytes(command))
                     .map_err(Error::from)
@@ -274,8 +371,91 @@ impl Node {
                     }
                 }
             }
+            Request::Rename(from, to, expect, monitored) => {
+                let command = Command::Rename {
+                    from,
+                    to,
+                    expect,
+                    proposed_at: Some(unix_time_secs()),
+                };
+                let result = track!(protobuf::command_encoder().encode_into_bytes(command))
+                    .map_err(Error::from)
+                    .and_then(|c| track!(self.rlog.propose_command(c)).map_err(Error::from));
+                match result {
+                    Err(e) => monitored.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        let proposal = Proposal::Rename(proposal_id, monitored);
+                        self.push_proposal(proposal);
+                    }
+                }
+            }
+            Request::Batch(operations, put_content_timeout, monitored) => {
+                if operations.iter().all(BatchOperation::is_read_only) {
+                    // 参照系の操作のみであれば、提案は不要
+                    let results = operations
+                        .into_iter()
+                        .map(|operation| self.read_batch_item(to_batch_item(operation, 0)))
+                        .collect();
+                    monitored.exit(Ok(results));
+                    return;
+                }
+
+                let now = unix_time_secs();
+                let command = Command::Batch {
+                    items: operations
+                        .into_iter()
+                        .map(|operation| to_batch_item(operation, now))
+                        .collect(),
+                    put_content_timeout,
+                    proposed_at: Some(now),
+                };
+                let result = track!(protobuf::command_encoder().encode_into_bytes(command))
+                    .map_err(Error::from)
+                    .and_then(|c| track!(self.rlog.propose_command(c)).map_err(Error::from));
+                match result {
+                    Err(e) => monitored.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        let proposal = Proposal::Batch(proposal_id, monitored);
+                        self.push_proposal(proposal);
+                    }
+                }
+            }
+            Request::Transaction(operations, put_content_timeout, monitored) => {
+                if let Some(operation) = operations.iter().find(|o| o.is_read_only()) {
+                    let e = ErrorKind::InvalidInput.cause(format!(
+                        "Read operations cannot be included in a transaction: {:?}",
+                        operation
+                    ));
+                    monitored.exit(Err(track!(Error::from(e))));
+                    return;
+                }
+
+                let now = unix_time_secs();
+                let command = Command::Transaction {
+                    items: operations
+                        .into_iter()
+                        .map(|operation| to_batch_item(operation, now))
+                        .collect(),
+                    put_content_timeout,
+                    proposed_at: Some(now),
+                };
+                let result = track!(protobuf::command_encoder().encode_into_bytes(command))
+                    .map_err(Error::from)
+                    .and_then(|c| track!(self.rlog.propose_command(c)).map_err(Error::from));
+                match result {
+                    Err(e) => monitored.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        let proposal = Proposal::Transaction(proposal_id, monitored);
+                        self.push_proposal(proposal);
+                    }
+                }
+            }
             Request::Delete(object_id, expect, monitored) => {
-                let command = Command::Delete { object_id, expect };
+                let command = Command::Delete {
+                    object_id,
+                    expect,
+                    proposed_at: Some(unix_time_secs()),
+                };
                 let result = track!(protobuf::command_encoder().encode_into_bytes(command))
                     .map_err(Error::from)
                     .and_then(|c| track!(self.rlog.propose_command(c)).map_err(Error::from));
@@ -407,6 +587,107 @@ impl Node {
         }
         Ok(true)
     }
+    /// 一貫性レベルの指定が可能な参照系の要求を処理する.
+    ///
+    /// リーダのリースが有効な場合と、古い状態の読み込みが許容されている場合には、手元の状態から即座に応答する.
+    /// それ以外の場合には、次のハートビートでリーダであることが確認できた時点で応答する(ReadIndex).
+    fn handle_read(&mut self, request: Request) {
+        let local = match request.read_consistency() {
+            Some(ReadConsistency::Stale) => true,
+            Some(ReadConsistency::LeaderLease) => self
+                .read_lease
+                .map_or(false, |expiry| Instant::now() < expiry),
+            Some(ReadConsistency::Linearizable) | None => false,
+        };
+        if local {
+            self.read_locally(request);
+        } else {
+            self.read_waitings.push(request);
+        }
+    }
+
+    /// 待機中の読み込み要求群のために、その時点でのコミット済みのログの末尾を記録して、ハートビートを送信する.
+    ///
+    /// 一度に送信するハートビートは一つだけで、その応答を待っている間に届いた要求群は、次のハートビートでまとめて扱われる.
+    /// また、現在の任期で最初のエントリがコミットされるまでは、コミット済みのログの末尾が確定しないので送信しない.
+    fn start_read_barrier(&mut self) {
+        if self.read_barrier.is_some()
+            || self.check_leader().is_err()
+            || self.leader_term != Some(self.rlog.local_node().ballot.term)
+        {
+            return;
+        }
+
+        let read_index = self.rlog.local_history().committed_tail().index;
+        let sent_at = Instant::now();
+        let requests = mem::replace(&mut self.read_waitings, Vec::new());
+        match track!(self.rlog.heartbeat()).map_err(Error::from) {
+            Err(e) => {
+                for request in requests {
+                    request.failed(track!(e.clone()));
+                }
+            }
+            Ok(seq_no) => {
+                self.read_barrier = Some(ReadBarrier::new(seq_no, read_index, sent_at, requests));
+            }
+        }
+    }
+
+    /// 応答待ちのハートビートが過半数のノードに受理されていれば、リースを延長して、待機中の要求群に応答する.
+    fn poll_read_barrier(&mut self) {
+        let is_ready = self.read_barrier.as_ref().map_or(false, |barrier| {
+            barrier.is_ready(self.rlog.last_heartbeat_acked(), self.next_commit)
+        });
+        if !is_ready {
+            return;
+        }
+
+        let barrier = self.read_barrier.take().expect("Never fails");
+        // 送信時点で過半数のノードがこのリーダを支持していたことが確認できたので、リースを延長する
+        self.read_lease = Some(barrier.sent_at() + self.read_lease_duration);
+        for request in barrier.into_requests() {
+            self.read_locally(request);
+        }
+    }
+
+    /// リーダでなくなった場合に、リースを破棄して、待機中の読み込み要求群を失敗させる.
+    fn abort_reads(&mut self) {
+        self.read_lease = None;
+        let e = Error::from(ErrorKind::NotLeader.cause("Leadership is lost"));
+        for request in self.read_waitings.drain(..) {
+            request.failed(track!(e.clone()));
+        }
+        if let Some(barrier) = self.read_barrier.take() {
+            barrier.notify_error(e);
+        }
+    }
+    fn read_locally(&self, request: Request) {
+        match request {
+            Request::ListPage(prefix, start_after, limit, _, monitored) => {
+                let page = self.machine.list_page(&prefix, start_after.as_ref(), limit);
+                monitored.exit(Ok(page));
+            }
+            Request::Get(object_id, expect, _, monitored) => {
+                let result = self.machine.get(&object_id, &expect).map(|metadata| {
+                    metadata.map(|m| (m, self.machine.get_user_metadata(&object_id)))
+                });
+                monitored.exit(result);
+            }
+            Request::Head(object_id, expect, _, monitored) => {
+                let result = self.machine.head(&object_id, &expect).map(|version| {
+                    version.map(|v| (v, self.machine.get_user_metadata(&object_id)))
+                });
+                monitored.exit(result);
+            }
+            Request::HeadVersion(version, _, monitored) => {
+                monitored.exit(Ok(self.machine.contains_version(version)));
+            }
+            request => {
+                let e = ErrorKind::Other.cause(format!("Not a read request: {:?}", request));
+                request.failed(track!(Error::from(e)));
+            }
+        }
+    }
     fn push_proposal(&mut self, proposal: Proposal) {
         while let Some(last) = self.proposals.pop_back() {
             if last.id().index < proposal.id().index {
@@ -437,6 +718,7 @@ impl Node {
                 info!(self.logger, "New raft role: {:?}", new_role);
                 let role = format!("{:?}", new_role);
                 track!(self.metrics.objects.labels_mut().insert("role", &role))?;
+                self.abort_reads();
             }
             E::TermChanged { new_ballot } => {
                 info!(
@@ -444,6 +726,7 @@ impl Node {
                     "New raft election term: ballot={:?}", new_ballot
                 );
                 self.leader = None;
+                self.abort_reads();
             }
             E::Committed { index, entry } => track!(self.handle_committed(index, entry))?,
             E::SnapshotLoaded { new_head, snapshot } => {
@@ -456,7 +739,7 @@ impl Node {
                 let logger = self.logger.clone();
                 let future = fibers_tasque::DefaultCpuTaskQueue.async_call(move || {
                     let machine = track!(codec::decode_machine(&snapshot))?;
-                    let versions = machine.to_versions();
+                    let versions = machine.to_versions_with_data_flag();
                     info!(logger, "Snapshot decoded: {} bytes", snapshot.len());
                     Ok((new_head, machine, versions))
                 });
@@ -500,7 +783,7 @@ impl Node {
 
         // エントリ毎の処理を実施
         match entry {
-            LogEntry::Noop { .. } => {
+            LogEntry::Noop { term } => {
                 let leader = track!(NodeId::from_raft_node_id(
                     &self.rlog.local_node().ballot.voted_for
                 ))?;
@@ -512,15 +795,16 @@ impl Node {
                     "New leader is elected: {:?} (commit:{:?})", leader, commit
                 );
                 self.leader = Some(leader);
+                self.leader_term = Some(term);
             }
             LogEntry::Command { command, .. } => {
                 self.commit_timeout = None;
                 let command = track!(protobuf::command_decoder().decode_from_bytes(&command))?;
                 let result = track!(self.handle_command(commit, command));
                 if let Some(proposal) = proposal {
-                    match result {
-                        Err(e) => proposal.notify_error(e),
-                        Ok(old) => proposal.notify_committed(&old),
+                    match (proposal, result) {
+                        (proposal, Err(e)) => proposal.notify_error(e),
+                        (proposal, Ok(applied)) => proposal.notify_applied(applied),
                     }
                 }
             }
@@ -535,50 +819,70 @@ impl Node {
         }
         Ok(())
     }
-    fn handle_command(&mut self, commit: LogIndex, command: Command) -> Result<Vec<ObjectVersion>> {
+    fn handle_command(&mut self, commit: LogIndex, command: Command) -> Result<Applied> {
+        let result = self.apply_command(commit, command);
+
+        // 状態機械から取り除かれたバージョン群の中身は、ここでまとめて削除対象とする
+        // (バージョン管理が有効な場合には、上書きないし削除されたバージョンも保持期間中はここに含まれない)
+        for version in self.machine.take_discarded_versions() {
+            self.events.push_back(Event::Deleted { version });
+        }
+        self.metrics.objects.set(self.machine.len() as f64);
+
+        let (applied, events) = track!(result)?;
+        self.events.extend(events);
+        Ok(applied)
+    }
+    fn apply_command(
+        &mut self,
+        commit: LogIndex,
+        command: Command,
+    ) -> Result<(Applied, Vec<Event>)> {
         match command {
             Command::Put {
                 object_id,
                 userdata: data,
+                user_metadata,
                 put_content_timeout,
                 expect,
+                expires_at,
+                proposed_at,
             } => {
-                let version = ObjectVersion(commit.as_u64());
-                let metadata = Metadata { version, data };
-                let old = track!(self.machine.put(object_id, metadata, &expect))?;
-                if let Some(old) = old {
-                    track_assert!(
-                        old < version,
-                        ErrorKind::InvalidInput,
-                        "old={:?}, new={:?}",
-                        old,
-                        version
-                    );
-                    self.events.push_back(Event::Deleted { version: old });
+                if let Some(now) = proposed_at {
+                    self.machine.advance_clock(now);
                 }
-                self.events.push_back(Event::Putted {
+                let version = self.machine.allocate_versions(commit.as_u64(), 1);
+                let has_data = !data.is_empty();
+                let old = track!(self.put_object(
+                    version,
+                    object_id,
+                    data,
+                    user_metadata,
+                    &expect,
+                    expires_at
+                ))?;
+                let putted = Event::Putted {
                     version,
                     put_content_timeout,
-                });
-                self.metrics.objects.set(self.machine.len() as f64);
-
-                Ok(old.into_iter().collect())
+                    has_data,
+                };
+                let versions = Some(version).into_iter().chain(old).collect();
+                Ok((Applied::Versions(versions), vec![putted]))
             }
-            Command::Delete { object_id, expect } => {
-                let old = track!(self.machine.delete(&object_id, &expect))?;
-                if let Some(version) = old {
-                    self.events.push_back(Event::Deleted { version });
+            Command::Delete {
+                object_id,
+                expect,
+                proposed_at,
+            } => {
+                if let Some(now) = proposed_at {
+                    self.machine.advance_clock(now);
                 }
-                self.metrics.objects.set(self.machine.len() as f64);
-                Ok(old.into_iter().collect())
+                let old = track!(self.machine.delete(&object_id, &expect))?;
+                Ok((Applied::Versions(old.into_iter().collect()), Vec::new()))
             }
             Command::DeleteByVersion { object_version } => {
                 let old = track!(self.machine.delete_version(object_version))?;
-                if let Some(version) = old {
-                    self.events.push_back(Event::Deleted { version });
-                }
-                self.metrics.objects.set(self.machine.len() as f64);
-                Ok(old.into_iter().collect())
+                Ok((Applied::Versions(old.into_iter().collect()), Vec::new()))
             }
             // 現時点ではDeleteByRangeに到達することはない。
             // その理由は、Command::DeleteByRangeを発行するべき
@@ -595,14 +899,255 @@ impl Node {
             }
             Command::DeleteByPrefix { prefix } => {
                 let deleted = track!(self.machine.delete_by_prefix(&prefix))?;
+                Ok((Applied::Versions(deleted), Vec::new()))
+            }
+            Command::Rename {
+                from,
+                to,
+                expect,
+                proposed_at,
+            } => {
+                if let Some(now) = proposed_at {
+                    self.machine.advance_clock(now);
+                }
+                // バージョンは変わらないので、中身の保存やイベントの発行は不要
+                // (上書きされたオブジェクトは、破棄されたバージョンとして別途扱われる)
+                let renamed = track!(self.machine.rename(&from, to, &expect))?;
+                let versions = renamed
+                    .into_iter()
+                    .flat_map(|(version, old)| Some(version).into_iter().chain(old))
+                    .collect();
+                Ok((Applied::Versions(versions), Vec::new()))
+            }
+            Command::SetVersioning { policy } => {
+                info!(self.logger, "New versioning policy: {:?}", policy);
+                self.machine.set_versioning(policy);
+                Ok((Applied::Versions(Vec::new()), Vec::new()))
+            }
+            Command::Batch {
+                items,
+                put_content_timeout,
+                proposed_at,
+            } => {
+                if let Some(now) = proposed_at {
+                    self.machine.advance_clock(now);
+                }
 
-                deleted
+                // 保存操作毎に異なるバージョンを割り当てる
+                let puts = items
                     .iter()
-                    .for_each(|&version| self.events.push_back(Event::Deleted { version }));
+                    .filter(|item| match **item {
+                        BatchItem::Put { .. } => true,
+                        _ => false,
+                    })
+                    .count() as u64;
+                let mut next_version = self.machine.allocate_versions(commit.as_u64(), puts);
 
-                self.metrics.objects.set(self.machine.len() as f64);
+                let mut results = Vec::with_capacity(items.len());
+                let mut events = Vec::new();
+                for item in items {
+                    let result = match item {
+                        BatchItem::Put {
+                            object_id,
+                            userdata: data,
+                            user_metadata,
+                            expect,
+                            expires_at,
+                        } => {
+                            let version = next_version;
+                            next_version = ObjectVersion(version.0 + 1);
+                            let has_data = !data.is_empty();
+                            track!(self.put_object(
+                                version,
+                                object_id,
+                                data,
+                                user_metadata,
+                                &expect,
+                                expires_at
+                            ))
+                            .map(|old| {
+                                events.push(Event::Putted {
+                                    version,
+                                    put_content_timeout,
+                                    has_data,
+                                });
+                                BatchResult::Put(version, old)
+                            })
+                        }
+                        BatchItem::Delete { object_id, expect } => {
+                            track!(self.machine.delete(&object_id, &expect))
+                                .map(BatchResult::Delete)
+                        }
+                        item => self.read_batch_item(item),
+                    };
+                    results.push(result);
+                }
+                Ok((Applied::Batch(results), events))
+            }
+            Command::Transaction {
+                items,
+                put_content_timeout,
+                proposed_at,
+            } => {
+                if let Some(now) = proposed_at {
+                    self.machine.advance_clock(now);
+                }
+                let mut has_data = items
+                    .iter()
+                    .filter_map(|item| match *item {
+                        BatchItem::Put { ref userdata, .. } => Some(!userdata.is_empty()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .into_iter();
+                let results = track!(self.machine.apply_transaction(commit.as_u64(), items))?;
+                let events = results
+                    .iter()
+                    .filter_map(|result| match *result {
+                        BatchResult::Put(version, _) => Some(Event::Putted {
+                            version,
+                            put_content_timeout,
+                            has_data: has_data.next().unwrap_or(false),
+                        }),
+                        _ => None,
+                    })
+                    .collect();
+                Ok((Applied::Transaction(results), events))
+            }
+        }
+    }
+    fn put_object(
+        &mut self,
+        version: ObjectVersion,
+        object_id: ObjectId,
+        data: Vec<u8>,
+        user_metadata: UserMetadata,
+        expect: &Expect,
+        expires_at: Option<u64>,
+    ) -> Result<Option<ObjectVersion>> {
+        let metadata = Metadata { version, data };
+        let old = track!(self
+            .machine
+            .put(object_id.clone(), metadata, user_metadata, expect))?;
+        if let Some(expires_at) = expires_at {
+            self.machine.set_expiration(&object_id, expires_at);
+        }
+        if let Some(old) = old {
+            track_assert!(
+                old < version,
+                ErrorKind::InvalidInput,
+                "old={:?}, new={:?}",
+                old,
+                version
+            );
+        }
+        Ok(old)
+    }
+    fn read_batch_item(&self, item: BatchItem) -> Result<BatchResult> {
+        match item {
+            BatchItem::Get { object_id, expect } => {
+                let metadata = track!(self.machine.get(&object_id, &expect))?;
+                let metadata = metadata.map(|m| (m, self.machine.get_user_metadata(&object_id)));
+                Ok(BatchResult::Get(metadata))
+            }
+            BatchItem::Head { object_id, expect } => {
+                let version = track!(self.machine.head(&object_id, &expect))?;
+                let version = version.map(|v| (v, self.machine.get_user_metadata(&object_id)));
+                Ok(BatchResult::Head(version))
+            }
+            _ => track_panic!(ErrorKind::InvalidInput, "Not a read operation: {:?}", item),
+        }
+    }
+    /// 有効期限が切れたオブジェクト群と、保持期間を過ぎた過去バージョン群の削除コマンドを提案する.
+    ///
+    /// 削除は通常の`Delete`ないし`DeleteByVersion`コマンドとして処理されるので、
+    /// `Event::Deleted`も通常通りに発行される.
+    /// 前回提案したコマンド群がコミットされるまでは、新たな提案は行わない.
+    fn expire_objects(&mut self) {
+        if let Some(index) = self.expiration_proposal {
+            if self.last_commit.map_or(true, |commit| commit < index) {
+                return;
+            }
+            self.expiration_proposal = None;
+        }
 
-                Ok(deleted)
+        let now = unix_time_secs();
+        let expired = self
+            .machine
+            .expired_objects(now, MAX_EXPIRATIONS_PER_ROUND)
+            .into_iter()
+            .map(|object| {
+                debug!(self.logger, "Object expired: {:?}", object);
+
+                // 提案後に上書きされた場合に削除してしまわないように、バージョンを指定する
+                Command::Delete {
+                    object_id: object.id,
+                    expect: Expect::IfMatch(vec![object.version]),
+                    proposed_at: Some(now),
+                }
+            });
+        let outdated = self
+            .machine
+            .outdated_versions(now, MAX_EXPIRATIONS_PER_ROUND)
+            .into_iter()
+            .map(|object_version| {
+                debug!(
+                    self.logger,
+                    "Previous version outdated: {:?}", object_version
+                );
+                Command::DeleteByVersion { object_version }
+            });
+        let commands = expired
+            .chain(outdated)
+            .take(MAX_EXPIRATIONS_PER_ROUND)
+            .collect::<Vec<_>>();
+        for command in commands {
+            let result = track!(protobuf::command_encoder().encode_into_bytes(command))
+                .map_err(Error::from)
+                .and_then(|c| track!(self.rlog.propose_command(c)).map_err(Error::from));
+            match result {
+                Err(e) => {
+                    warn!(
+                        self.logger,
+                        "Cannot propose the deletion of expired objects or versions: {}", e
+                    );
+                    break;
+                }
+                Ok(proposal_id) => {
+                    self.expiration_proposal = Some(proposal_id.index);
+                }
+            }
+        }
+    }
+    /// バケツに設定されているバージョン管理の設定が状態機械に反映されていなければ、その変更を提案する.
+    ///
+    /// 設定の変更はRaftログを経由して全てのノードに同じ位置で適用されるので、
+    /// 過去バージョンの保持や破棄の判断がノード間で食い違うことはない.
+    /// 前回提案したコマンドがコミットされるまでは、新たな提案は行わない.
+    fn sync_versioning(&mut self) {
+        if let Some(index) = self.versioning_proposal {
+            if self.last_commit.map_or(true, |commit| commit < index) {
+                return;
+            }
+            self.versioning_proposal = None;
+        }
+
+        let policy = self.versioning.get();
+        if policy == *self.machine.versioning() {
+            return;
+        }
+        info!(
+            self.logger,
+            "Proposes a new versioning policy: {:?}", policy
+        );
+        let command = Command::SetVersioning { policy };
+        let result = track!(protobuf::command_encoder().encode_into_bytes(command))
+            .map_err(Error::from)
+            .and_then(|c| track!(self.rlog.propose_command(c)).map_err(Error::from));
+        match result {
+            Err(e) => warn!(self.logger, "Cannot propose the versioning policy: {}", e),
+            Ok(proposal_id) => {
+                self.versioning_proposal = Some(proposal_id.index);
             }
         }
     }
@@ -683,6 +1228,15 @@ impl Stream for Node {
                 warn!(self.logger, "Leader waiting timeout (cleared)");
                 self.leader_waitings.clear();
             }
+
+            // 読み込み要求が無い間も、定期的にハートビートを確認してリースを延長しておく
+            self.start_read_barrier();
+
+            // バージョン管理の設定および有効期限チェック
+            if self.check_leader().is_ok() && self.phase == Phase::Running {
+                self.sync_versioning();
+                self.expire_objects();
+            }
         }
 
         match track!(self.decoding_snapshot.poll().map_err(Error::from))? {
@@ -696,11 +1250,15 @@ impl Stream for Node {
                     .and_then(|v| v.parse().ok())
                     .unwrap_or(10);
                 self.events.reserve_exact(machine.len());
-                self.events
-                    .extend(versions.into_iter().map(|version| Event::Putted {
-                        version,
-                        put_content_timeout: Seconds(delay),
-                    }));
+                self.events.extend(
+                    versions
+                        .into_iter()
+                        .map(|(version, has_data)| Event::Putted {
+                            version,
+                            put_content_timeout: Seconds(delay),
+                            has_data,
+                        }),
+                );
                 self.next_commit = new_head.index;
                 self.machine = machine;
                 self.metrics.objects.set(self.machine.len() as f64);
@@ -750,6 +1308,11 @@ impl Stream for Node {
             }
         }
 
+        self.poll_read_barrier();
+        if !self.read_waitings.is_empty() {
+            self.start_read_barrier();
+        }
+
         // FIXME: もっと適切な場所に移動
         if self.phase == Phase::Stopped {
             info!(self.logger, "Stopped");
@@ -765,3 +1328,33 @@ impl Stream for Node {
         Ok(Async::NotReady)
     }
 }
+
+// RPC経由で受け取った操作を、状態機械に適用可能な形式に変換する
+// (有効期限は`now`を起点とした絶対時刻に変換される)
+fn to_batch_item(operation: BatchOperation, now: u64) -> BatchItem {
+    match operation {
+        BatchOperation::Get { object_id, expect } => BatchItem::Get { object_id, expect },
+        BatchOperation::Head { object_id, expect } => BatchItem::Head { object_id, expect },
+        BatchOperation::Put {
+            object_id,
+            data,
+            attributes,
+            expect,
+        } => BatchItem::Put {
+            object_id,
+            userdata: data,
+            user_metadata: attributes.user_metadata,
+            expect,
+            expires_at: attributes.ttl.map(|ttl| now.saturating_add(ttl)),
+        },
+        BatchOperation::Delete { object_id, expect } => BatchItem::Delete { object_id, expect },
+    }
+}
+
+// 現在時刻をUNIXエポックからの秒数で返す
+fn unix_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -6,15 +6,16 @@ use libfrugalos::entity::object::{Metadata, ObjectPrefix, ObjectVersion};
 use libfrugalos::expect::Expect;
 use libfrugalos::time::Seconds;
 use patricia_tree::node::{NodeDecoder, NodeEncoder};
-use protobuf_codec::field::branch::{Branch2, Branch3, Branch5};
-use protobuf_codec::field::num::{F1, F2, F3, F4, F5};
+use protobuf_codec::field::branch::{Branch2, Branch3, Branch4, Branch8};
+use protobuf_codec::field::num::{F1, F2, F3, F4, F5, F6, F7, F8};
 use protobuf_codec::message::{MessageDecode, MessageEncode};
 use protobuf_codec::scalar::{
-    BytesDecoder, BytesEncoder, CustomBytesDecoder, CustomBytesEncoder, StringDecoder,
-    StringEncoder, Uint64Decoder, Uint64Encoder,
+    BoolDecoder, BoolEncoder, BytesDecoder, BytesEncoder, CustomBytesDecoder, CustomBytesEncoder,
+    StringDecoder, StringEncoder, Uint32Decoder, Uint32Encoder, Uint64Decoder, Uint64Encoder,
 };
 
-use machine::{Command, Snapshot};
+use machine::{AssocSnapshot, BatchItem, Command, PatriciaSnapshot, PreviousVersion, Snapshot};
+use schema::{UserMetadata, VersioningPolicy};
 
 pub fn command_decoder() -> impl MessageDecode<Item = Command> {
     let base = protobuf_message_decoder![(
@@ -23,29 +24,58 @@ pub fn command_decoder() -> impl MessageDecode<Item = Command> {
         (F2, delete_command_decoder(), message),
         (F3, delete_version_command_decoder(), message),
         (F4, delete_by_range_command_decoder(), message),
-        (F5, delete_by_prefix_command_decoder(), message)
+        (F5, delete_by_prefix_command_decoder(), message),
+        (F6, versioning_policy_decoder(), message),
+        (F7, rename_command_decoder(), message),
+        (F8, batch_command_decoder(), message)
     )];
     base.map(|x| match x {
-        Branch5::A(x) => Command::Put {
+        Branch8::A(x) => Command::Put {
             object_id: x.0,
             userdata: x.1,
+            user_metadata: x.4,
             expect: x.2,
             put_content_timeout: Seconds(x.3),
+            expires_at: x.5,
+            proposed_at: x.6,
         },
-        Branch5::B(x) => Command::Delete {
+        Branch8::B(x) => Command::Delete {
             object_id: x.0,
             expect: x.1,
+            proposed_at: x.2,
         },
-        Branch5::C(x) => Command::DeleteByVersion {
+        Branch8::C(x) => Command::DeleteByVersion {
             object_version: ObjectVersion(x),
         },
-        Branch5::D(x) => Command::DeleteByRange {
+        Branch8::D(x) => Command::DeleteByRange {
             version_from: ObjectVersion(x.0),
             version_to: ObjectVersion(x.1),
         },
-        Branch5::E(x) => Command::DeleteByPrefix {
+        Branch8::E(x) => Command::DeleteByPrefix {
             prefix: ObjectPrefix(x),
         },
+        Branch8::F(x) => Command::SetVersioning { policy: x },
+        Branch8::G(x) => Command::Rename {
+            from: x.0,
+            to: x.1,
+            expect: x.2,
+            proposed_at: x.3,
+        },
+        Branch8::H(x) => {
+            if x.3 {
+                Command::Transaction {
+                    items: x.0,
+                    put_content_timeout: Seconds(x.1),
+                    proposed_at: x.2,
+                }
+            } else {
+                Command::Batch {
+                    items: x.0,
+                    put_content_timeout: Seconds(x.1),
+                    proposed_at: x.2,
+                }
+            }
+        }
     })
 }
 
@@ -56,30 +86,73 @@ pub fn command_encoder() -> impl SizedEncode<Item = Command> + MessageEncode<Ite
         (F2, delete_command_encoder(), message),
         (F3, delete_version_command_encoder(), message),
         (F4, delete_by_range_command_encoder(), message),
-        (F5, delete_by_prefix_command_encoder(), message)
+        (F5, delete_by_prefix_command_encoder(), message),
+        (F6, versioning_policy_encoder(), message),
+        (F7, rename_command_encoder(), message),
+        (F8, batch_command_encoder(), unsized_message)
     )];
     base.map_from(|x: Command| match x {
         Command::Put {
             object_id,
             userdata,
+            user_metadata,
             expect,
             put_content_timeout,
-        } => Branch5::A((object_id, userdata, expect, put_content_timeout.0)),
-        Command::Delete { object_id, expect } => Branch5::B((object_id, expect)),
-        Command::DeleteByVersion { object_version } => Branch5::C(object_version.0),
+            expires_at,
+            proposed_at,
+        } => Branch8::A((
+            object_id,
+            userdata,
+            expect,
+            put_content_timeout.0,
+            user_metadata,
+            expires_at,
+            proposed_at,
+        )),
+        Command::Delete {
+            object_id,
+            expect,
+            proposed_at,
+        } => Branch8::B((object_id, expect, proposed_at)),
+        Command::DeleteByVersion { object_version } => Branch8::C(object_version.0),
         Command::DeleteByRange {
             version_from,
             version_to,
-        } => Branch5::D((version_from.0, version_to.0)),
-        Command::DeleteByPrefix { prefix } => Branch5::E(prefix.0),
+        } => Branch8::D((version_from.0, version_to.0)),
+        Command::DeleteByPrefix { prefix } => Branch8::E(prefix.0),
+        Command::SetVersioning { policy } => Branch8::F(policy),
+        Command::Rename {
+            from,
+            to,
+            expect,
+            proposed_at,
+        } => Branch8::G((from, to, expect, proposed_at)),
+        Command::Batch {
+            items,
+            put_content_timeout,
+            proposed_at,
+        } => Branch8::H((items, put_content_timeout.0, proposed_at, false)),
+        Command::Transaction {
+            items,
+            put_content_timeout,
+            proposed_at,
+        } => Branch8::H((items, put_content_timeout.0, proposed_at, true)),
     })
 }
 
 #[allow(dead_code)]
-pub type PutCommand = (String, Vec<u8>, Expect, u64);
+pub type PutCommand = (
+    String,
+    Vec<u8>,
+    Expect,
+    u64,
+    UserMetadata,
+    Option<u64>,
+    Option<u64>,
+);
 
 #[allow(dead_code)]
-pub type DeleteCommand = (String, Expect);
+pub type DeleteCommand = (String, Expect, Option<u64>);
 
 #[allow(dead_code)]
 pub type DeleteVersionCommand = u64;
@@ -90,40 +163,214 @@ pub type DeleteByRangeCommand = (u64, u64);
 #[allow(dead_code)]
 pub type DeleteByPrefixCommand = String;
 
+#[allow(dead_code)]
+pub type RenameCommand = (String, String, Expect, Option<u64>);
+
+#[allow(dead_code)]
+pub type BatchCommand = (Vec<BatchItem>, u64, Option<u64>, bool);
+
 pub fn put_command_decoder() -> impl MessageDecode<Item = PutCommand> {
     let base = protobuf_message_decoder![
         (F1, StringDecoder::new()),
         (F2, BytesDecoder::new()),
         (F3, expect_decoder(), message),
-        (F4, Uint64Decoder::new())
+        (F4, Uint64Decoder::new()),
+        (F5, user_metadata_decoder(), message),
+        (F6, Uint64Decoder::new()),
+        (F7, Uint64Decoder::new())
     ];
-    base.map(|x| (x.0, x.1, x.2.unwrap_or(Expect::Any), x.3))
+    base.map(|x| {
+        (
+            x.0,
+            x.1,
+            x.2.unwrap_or(Expect::Any),
+            x.3,
+            x.4.unwrap_or_default(),
+            from_unix_time(x.5),
+            from_unix_time(x.6),
+        )
+    })
 }
 
 pub fn put_command_encoder(
 ) -> impl SizedEncode<Item = PutCommand> + MessageEncode<Item = PutCommand> {
-    protobuf_message_encoder![
+    let base = protobuf_message_encoder![
         (F1, StringEncoder::new()),
         (F2, BytesEncoder::new()),
         (F3, expect_encoder(), required_unsized_message),
-        (F4, Uint64Encoder::new())
-    ]
+        (F4, Uint64Encoder::new()),
+        (F5, user_metadata_encoder(), unsized_message),
+        (F6, Uint64Encoder::new()),
+        (F7, Uint64Encoder::new())
+    ];
+    base.map_from(|x: PutCommand| {
+        (
+            x.0,
+            x.1,
+            x.2,
+            x.3,
+            non_empty(x.4),
+            into_unix_time(x.5),
+            into_unix_time(x.6),
+        )
+    })
+}
+
+// 時刻(有効期限や提案時刻)および期間は、未指定を`0`で表現する
+fn from_unix_time(time: u64) -> Option<u64> {
+    if time == 0 {
+        None
+    } else {
+        Some(time)
+    }
+}
+
+fn into_unix_time(time: Option<u64>) -> u64 {
+    time.unwrap_or(0)
 }
 
 pub fn delete_command_decoder() -> impl MessageDecode<Item = DeleteCommand> {
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, expect_decoder(), message),
+        (F3, Uint64Decoder::new())
+    ];
+    base.map(|x| (x.0, x.1.unwrap_or(Expect::Any), from_unix_time(x.2)))
+}
+
+pub fn delete_command_encoder(
+) -> impl SizedEncode<Item = DeleteCommand> + MessageEncode<Item = DeleteCommand> {
+    let base = protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, expect_encoder(), required_unsized_message),
+        (F3, Uint64Encoder::new())
+    ];
+    base.map_from(|x: DeleteCommand| (x.0, x.1, into_unix_time(x.2)))
+}
+
+pub fn rename_command_decoder() -> impl MessageDecode<Item = RenameCommand> {
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, StringDecoder::new()),
+        (F3, expect_decoder(), message),
+        (F4, Uint64Decoder::new())
+    ];
+    base.map(|x| (x.0, x.1, x.2.unwrap_or(Expect::Any), from_unix_time(x.3)))
+}
+
+pub fn rename_command_encoder(
+) -> impl SizedEncode<Item = RenameCommand> + MessageEncode<Item = RenameCommand> {
+    let base = protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, StringEncoder::new()),
+        (F3, expect_encoder(), required_unsized_message),
+        (F4, Uint64Encoder::new())
+    ];
+    base.map_from(|x: RenameCommand| (x.0, x.1, x.2, into_unix_time(x.3)))
+}
+
+pub fn batch_command_decoder() -> impl MessageDecode<Item = BatchCommand> {
+    let base = protobuf_message_decoder![
+        (F1, batch_item_decoder(), repeated_message),
+        (F2, Uint64Decoder::new()),
+        (F3, Uint64Decoder::new()),
+        (F4, BoolDecoder::new())
+    ];
+    base.map(|x| (x.0, x.1, from_unix_time(x.2), x.3))
+}
+
+pub fn batch_command_encoder() -> impl MessageEncode<Item = BatchCommand> {
+    let base = protobuf_message_encoder![
+        (F1, batch_item_encoder(), repeated_message),
+        (F2, Uint64Encoder::new()),
+        (F3, Uint64Encoder::new()),
+        (F4, BoolEncoder::new())
+    ];
+    base.map_from(|x: BatchCommand| (x.0, x.1, into_unix_time(x.2), x.3))
+}
+
+pub fn batch_item_decoder() -> impl MessageDecode<Item = BatchItem> {
+    let base = protobuf_message_decoder![(
+        required_oneof,
+        (F1, object_request_decoder(), message),
+        (F2, object_request_decoder(), message),
+        (F3, put_command_decoder(), message),
+        (F4, object_request_decoder(), message)
+    )];
+    base.map(|x| match x {
+        Branch4::A((object_id, expect)) => BatchItem::Get { object_id, expect },
+        Branch4::B((object_id, expect)) => BatchItem::Head { object_id, expect },
+        Branch4::C(x) => BatchItem::Put {
+            object_id: x.0,
+            userdata: x.1,
+            expect: x.2,
+            user_metadata: x.4,
+            expires_at: x.5,
+        },
+        Branch4::D((object_id, expect)) => BatchItem::Delete { object_id, expect },
+    })
+}
+
+pub fn batch_item_encoder() -> impl SizedEncode<Item = BatchItem> + MessageEncode<Item = BatchItem>
+{
+    let base = protobuf_message_encoder![(
+        required_oneof,
+        (F1, object_request_encoder(), message),
+        (F2, object_request_encoder(), message),
+        (F3, put_command_encoder(), message),
+        (F4, object_request_encoder(), message)
+    )];
+    base.map_from(|x: BatchItem| match x {
+        BatchItem::Get { object_id, expect } => Branch4::A((object_id, expect)),
+        BatchItem::Head { object_id, expect } => Branch4::B((object_id, expect)),
+        BatchItem::Put {
+            object_id,
+            userdata,
+            user_metadata,
+            expect,
+            expires_at,
+        } => Branch4::C((
+            object_id,
+            userdata,
+            expect,
+            0,
+            user_metadata,
+            expires_at,
+            None,
+        )),
+        BatchItem::Delete { object_id, expect } => Branch4::D((object_id, expect)),
+    })
+}
+
+// バッチ内の参照および削除操作は、対象のIDと期待するバージョンの組で表現される
+pub fn object_request_decoder() -> impl MessageDecode<Item = (String, Expect)> {
     let base =
         protobuf_message_decoder![(F1, StringDecoder::new()), (F2, expect_decoder(), message)];
     base.map(|x| (x.0, x.1.unwrap_or(Expect::Any)))
 }
 
-pub fn delete_command_encoder(
-) -> impl SizedEncode<Item = DeleteCommand> + MessageEncode<Item = DeleteCommand> {
+pub fn object_request_encoder(
+) -> impl SizedEncode<Item = (String, Expect)> + MessageEncode<Item = (String, Expect)> {
     protobuf_message_encoder![
         (F1, StringEncoder::new()),
         (F2, expect_encoder(), required_unsized_message)
     ]
 }
 
+pub fn versioning_policy_decoder() -> impl MessageDecode<Item = VersioningPolicy> {
+    let base = protobuf_message_decoder![(F1, Uint32Decoder::new()), (F2, Uint64Decoder::new())];
+    base.map(|x| VersioningPolicy {
+        max_versions: x.0,
+        max_age: from_unix_time(x.1),
+    })
+}
+
+pub fn versioning_policy_encoder(
+) -> impl SizedEncode<Item = VersioningPolicy> + MessageEncode<Item = VersioningPolicy> {
+    let base = protobuf_message_encoder![(F1, Uint32Encoder::new()), (F2, Uint64Encoder::new())];
+    base.map_from(|x: VersioningPolicy| (x.max_versions, into_unix_time(x.max_age)))
+}
+
 pub fn delete_version_command_decoder() -> impl MessageDecode<Item = DeleteVersionCommand> {
     protobuf_message_decoder![(F1, Uint64Decoder::new())]
 }
@@ -204,14 +451,22 @@ pub fn empty_encoder() -> impl SizedEncode<Item = ()> + MessageEncode<Item = ()>
 pub fn snapshot_decoder() -> impl MessageDecode<Item = Snapshot> {
     let patricia =
         CustomBytesDecoder::new(NodeDecoder::new(U64beDecoder::new().map(ObjectVersion)));
-    let base = protobuf_message_decoder![(
-        required_oneof,
-        (F1, objects_decoder(), message),
-        (F2, patricia)
-    )];
-    base.map(|x| match x {
+    let base = protobuf_message_decoder![
+        (
+            required_oneof,
+            (F1, objects_decoder(), message),
+            (F2, patricia)
+        ),
+        (F3, versioning_policy_decoder(), message),
+        (F4, Uint64Decoder::new())
+    ];
+    base.map(|x| match x.0 {
         Branch2::A(x) => Snapshot::Assoc(x),
-        Branch2::B(x) => Snapshot::Patricia(x.into()),
+        Branch2::B(id_to_version) => Snapshot::Patricia(PatriciaSnapshot {
+            id_to_version: id_to_version.into(),
+            versioning: x.1.unwrap_or_default(),
+            version_offset: x.2,
+        }),
     })
 }
 
@@ -219,31 +474,133 @@ pub fn snapshot_encoder() -> impl MessageEncode<Item = Snapshot> {
     let patricia = CustomBytesEncoder::new(
         NodeEncoder::new(U64beEncoder::new().map_from(|v: ObjectVersion| v.0)).pre_encode(),
     );
-    let base = protobuf_message_encoder![(
-        required_oneof,
-        (F1, objects_encoder(), unsized_message),
-        (F2, patricia)
-    )];
+    let base = protobuf_message_encoder![
+        (
+            required_oneof,
+            (F1, objects_encoder(), unsized_message),
+            (F2, patricia)
+        ),
+        (F3, versioning_policy_encoder(), message),
+        (F4, Uint64Encoder::new())
+    ];
+    // NOTE: `Patricia`形式のバージョン管理の設定とバージョンのずらし幅は、
+    // 既定値の場合には省略される(以前の形式と同じバイト列になるように)
     base.map_from(|x: Snapshot| match x {
-        Snapshot::Assoc(x) => Branch2::A(x),
-        Snapshot::Patricia(x) => Branch2::B(x.into()),
+        Snapshot::Assoc(x) => (Branch2::A(x), None, 0),
+        Snapshot::Patricia(x) => {
+            let versioning = if x.versioning == VersioningPolicy::default() {
+                None
+            } else {
+                Some(x.versioning)
+            };
+            (
+                Branch2::B(x.id_to_version.into()),
+                versioning,
+                x.version_offset,
+            )
+        }
     })
 }
 
-pub fn objects_decoder() -> impl MessageDecode<Item = Vec<(String, Metadata)>> {
+pub type SnapshotObject = (String, Metadata, UserMetadata, Option<u64>);
+
+pub fn objects_decoder() -> impl MessageDecode<Item = AssocSnapshot> {
     let map = protobuf_message_decoder![
         (F1, StringDecoder::new()),
-        (F2, metadata_decoder(), required_message)
+        (F2, metadata_decoder(), required_message),
+        (F3, user_metadata_decoder(), message),
+        (F4, Uint64Decoder::new())
     ];
-    protobuf_message_decoder![(F1, map, repeated_message)]
+    let map = map.map(|x| (x.0, x.1, x.2.unwrap_or_default(), from_unix_time(x.3)));
+    let base = protobuf_message_decoder![
+        (F1, map, repeated_message),
+        (F2, previous_version_decoder(), repeated_message),
+        (F3, versioning_policy_decoder(), message),
+        (F4, Uint64Decoder::new())
+    ];
+    base.map(|x| AssocSnapshot {
+        objects: x.0,
+        history: x.1,
+        versioning: x.2.unwrap_or_default(),
+        version_offset: x.3,
+    })
 }
 
-pub fn objects_encoder() -> impl MessageEncode<Item = Vec<(String, Metadata)>> {
+pub fn objects_encoder() -> impl MessageEncode<Item = AssocSnapshot> {
     let map = protobuf_message_encoder![
         (F1, StringEncoder::new()),
-        (F2, metadata_encoder(), required_message)
+        (F2, metadata_encoder(), required_message),
+        (F3, user_metadata_encoder(), unsized_message),
+        (F4, Uint64Encoder::new())
     ];
-    protobuf_message_encoder![(F1, map, repeated_message)]
+    let map = map.map_from(|x: SnapshotObject| (x.0, x.1, non_empty(x.2), into_unix_time(x.3)));
+    let base = protobuf_message_encoder![
+        (F1, map, repeated_message),
+        (F2, previous_version_encoder(), repeated_message),
+        (F3, versioning_policy_encoder(), required_message),
+        (F4, Uint64Encoder::new())
+    ];
+    base.map_from(|x: AssocSnapshot| (x.objects, x.history, x.versioning, x.version_offset))
+}
+
+pub fn previous_version_decoder() -> impl MessageDecode<Item = (String, PreviousVersion)> {
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, Uint64Decoder::new()),
+        (F3, BytesDecoder::new()),
+        (F4, user_metadata_decoder(), message),
+        (F5, Uint64Decoder::new())
+    ];
+    base.map(|x| {
+        let previous = PreviousVersion {
+            version: ObjectVersion(x.1),
+            data: x.2,
+            user_metadata: x.3.unwrap_or_default(),
+            superseded_at: x.4,
+        };
+        (x.0, previous)
+    })
+}
+
+pub fn previous_version_encoder() -> impl MessageEncode<Item = (String, PreviousVersion)> {
+    let base = protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, Uint64Encoder::new()),
+        (F3, BytesEncoder::new()),
+        (F4, user_metadata_encoder(), unsized_message),
+        (F5, Uint64Encoder::new())
+    ];
+    base.map_from(|(id, p): (String, PreviousVersion)| {
+        (
+            id,
+            p.version.0,
+            p.data,
+            non_empty(p.user_metadata),
+            p.superseded_at,
+        )
+    })
+}
+
+// 利用者定義のメタデータが空の場合には、フィールド自体を省略する
+// (メタデータを持たないコマンドやスナップショットが、以前のバージョンと同じバイト列になるように)
+fn non_empty(x: UserMetadata) -> Option<UserMetadata> {
+    if x.is_empty() {
+        None
+    } else {
+        Some(x)
+    }
+}
+
+pub fn user_metadata_decoder() -> impl MessageDecode<Item = UserMetadata> {
+    let entry = protobuf_message_decoder![(F1, StringDecoder::new()), (F2, StringDecoder::new())];
+    let base = protobuf_message_decoder![(F1, entry, repeated_message)];
+    base.map(|entries: Vec<(String, String)>| entries.into_iter().collect())
+}
+
+pub fn user_metadata_encoder() -> impl MessageEncode<Item = UserMetadata> {
+    let entry = protobuf_message_encoder![(F1, StringEncoder::new()), (F2, StringEncoder::new())];
+    let base = protobuf_message_encoder![(F1, entry, repeated_message)];
+    base.map_from(|x: UserMetadata| x.into_iter().collect::<Vec<_>>())
 }
 
 pub fn metadata_decoder() -> impl MessageDecode<Item = Metadata> {
@@ -258,3 +615,99 @@ pub fn metadata_encoder() -> impl SizedEncode<Item = Metadata> + MessageEncode<I
     let base = protobuf_message_encoder![(F1, Uint64Encoder::new()), (F2, BytesEncoder::new())];
     base.map_from(|x: Metadata| (x.version.0, x.data))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use patricia_tree::PatriciaMap;
+    use trackable::result::TestResult;
+
+    #[test]
+    fn put_command_without_user_metadata_is_compatible() -> TestResult {
+        // 利用者定義のメタデータ導入前の形式
+        let old_encoder = protobuf_message_encoder![
+            (F1, StringEncoder::new()),
+            (F2, BytesEncoder::new()),
+            (F3, expect_encoder(), required_unsized_message),
+            (F4, Uint64Encoder::new())
+        ];
+        let old = track!(old_encoder.encode_into_bytes((
+            "foo".to_owned(),
+            vec![1, 2, 3],
+            Expect::Any,
+            10
+        )))?;
+
+        let command = (
+            "foo".to_owned(),
+            vec![1, 2, 3],
+            Expect::Any,
+            10,
+            UserMetadata::new(),
+            None,
+            None,
+        );
+        let new = track!(put_command_encoder().encode_into_bytes(command.clone()))?;
+        assert_eq!(new, old);
+
+        // メタデータを持つ場合
+        let mut user_metadata = UserMetadata::new();
+        user_metadata.insert("content-type".to_owned(), "text/plain".to_owned());
+        let command = (
+            command.0,
+            command.1,
+            command.2,
+            command.3,
+            user_metadata,
+            None,
+            None,
+        );
+        let bytes = track!(put_command_encoder().encode_into_bytes(command.clone()))?;
+        assert_ne!(bytes, old);
+        let decoded = track!(put_command_decoder().decode_from_bytes(&bytes))?;
+        assert_eq!(decoded, command);
+        Ok(())
+    }
+
+    #[test]
+    fn patricia_snapshot_with_default_values_is_compatible() -> TestResult {
+        let mut id_to_version = PatriciaMap::new();
+        id_to_version.insert("foo", ObjectVersion(3));
+
+        // バージョン管理の設定やずらし幅の導入前の形式
+        let patricia = CustomBytesEncoder::new(
+            NodeEncoder::new(U64beEncoder::new().map_from(|v: ObjectVersion| v.0)).pre_encode(),
+        );
+        let old_encoder = protobuf_message_encoder![(F2, patricia)];
+        let old = track!(old_encoder.encode_into_bytes(id_to_version.clone().into()))?;
+
+        let snapshot = Snapshot::Patricia(PatriciaSnapshot {
+            id_to_version: id_to_version.clone(),
+            versioning: VersioningPolicy::default(),
+            version_offset: 0,
+        });
+        let new = track!(snapshot_encoder().encode_into_bytes(snapshot))?;
+        assert_eq!(new, old);
+
+        // 既定値以外の場合
+        let snapshot = Snapshot::Patricia(PatriciaSnapshot {
+            id_to_version,
+            versioning: VersioningPolicy {
+                max_versions: 3,
+                max_age: Some(60),
+            },
+            version_offset: 5,
+        });
+        let bytes = track!(snapshot_encoder().encode_into_bytes(snapshot))?;
+        match track!(snapshot_decoder().decode_from_bytes(&bytes))? {
+            Snapshot::Patricia(x) => {
+                assert_eq!(x.id_to_version.get("foo"), Some(&ObjectVersion(3)));
+                assert_eq!(x.versioning.max_versions, 3);
+                assert_eq!(x.versioning.max_age, Some(60));
+                assert_eq!(x.version_offset, 5);
+            }
+            Snapshot::Assoc(_) => panic!(),
+        }
+        Ok(())
+    }
+}
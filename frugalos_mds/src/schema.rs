@@ -0,0 +1,495 @@
+//! `libfrugalos`には含まれない、MDS固有のRPCのスキーマ定義.
+use bytecodec::bincode_codec::{BincodeDecoder, BincodeEncoder};
+use fibers_rpc::{Call, ProcedureId};
+use libfrugalos;
+use libfrugalos::entity::object::{Metadata, ObjectId, ObjectPrefix, ObjectSummary, ObjectVersion};
+use libfrugalos::expect::Expect;
+use libfrugalos::schema::mds::ObjectRequest;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// オブジェクトに付与される、利用者定義のメタデータ.
+///
+/// キーはHTTPヘッダ名(小文字)、値はその値.
+pub type UserMetadata = BTreeMap<String, String>;
+
+/// オブジェクトの保存時に指定される、中身以外の付加情報.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectAttributes {
+    /// 利用者定義のメタデータ.
+    pub user_metadata: UserMetadata,
+
+    /// オブジェクトの有効期限(保存時点からの秒数).
+    ///
+    /// 期限が切れたオブジェクトは、MDSのリーダによって自動的に削除される.
+    pub ttl: Option<u64>,
+}
+impl ObjectAttributes {
+    /// 付加情報が何も指定されていない場合には`true`を返す.
+    pub fn is_empty(&self) -> bool {
+        self.user_metadata.is_empty() && self.ttl.is_none()
+    }
+}
+
+/// オブジェクト一覧をページ単位で取得するためのRPC.
+#[derive(Debug)]
+pub struct ListObjectsPageRpc;
+impl Call for ListObjectsPageRpc {
+    const ID: ProcedureId = ProcedureId(0x0101_0000);
+    const NAME: &'static str = "frugalos.mds.object.list_page";
+
+    type Req = ListObjectsPageRequest;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+
+    type Res = libfrugalos::Result<ObjectPage>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+}
+
+/// `ListObjectsPageRpc`の要求.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListObjectsPageRequest {
+    /// 対象ノードのID.
+    pub node_id: String,
+
+    /// 取得対象のオブジェクトIDの接頭辞.
+    pub prefix: ObjectPrefix,
+
+    /// 指定された場合には、このIDよりも(辞書順で)後ろのオブジェクトのみが対象となる.
+    pub start_after: Option<ObjectId>,
+
+    /// 一度に取得するオブジェクトの最大数.
+    pub limit: u32,
+
+    /// 読み込みの一貫性レベル.
+    pub consistency: ReadConsistency,
+}
+
+/// オブジェクト一覧のページ.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectPage {
+    /// IDの辞書順に並んだオブジェクト一覧.
+    pub objects: Vec<ObjectSummary>,
+
+    /// 続きが存在する場合には、次のページの取得時に`start_after`として指定すべきID.
+    ///
+    /// 利用者側でのフィルタリング等により`objects`の中身が除外される場合があるので、
+    /// この値は`objects`の最後の要素のIDと一致するとは限らない.
+    pub next: Option<ObjectId>,
+
+    /// `objects`の内、利用者定義のメタデータを持つオブジェクトのメタデータ.
+    pub user_metadata: BTreeMap<ObjectId, UserMetadata>,
+}
+
+/// 付加情報(利用者定義のメタデータや有効期限)付きでオブジェクトを保存するためのRPC.
+#[derive(Debug)]
+pub struct PutObjectWithAttributesRpc;
+impl Call for PutObjectWithAttributesRpc {
+    const ID: ProcedureId = ProcedureId(0x0101_0001);
+    const NAME: &'static str = "frugalos.mds.object.put_with_attributes";
+
+    type Req = PutObjectWithAttributesRequest;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+
+    type Res = libfrugalos::Result<(ObjectVersion, Option<ObjectVersion>)>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+}
+
+/// `PutObjectWithAttributesRpc`の要求.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PutObjectWithAttributesRequest {
+    /// 対象ノードのID.
+    pub node_id: String,
+
+    /// オブジェクトのID.
+    pub object_id: ObjectId,
+
+    /// MDSに保存されるデータ.
+    pub data: Vec<u8>,
+
+    /// オブジェクトの付加情報.
+    pub attributes: ObjectAttributes,
+
+    /// 期待するバージョン.
+    pub expect: Expect,
+
+    /// 中身の保存完了までのタイムアウト(秒単位).
+    pub put_content_timeout: u64,
+}
+
+/// 利用者定義のメタデータ付きでオブジェクトを取得するためのRPC.
+#[derive(Debug)]
+pub struct GetObjectWithUserMetadataRpc;
+impl Call for GetObjectWithUserMetadataRpc {
+    const ID: ProcedureId = ProcedureId(0x0101_0002);
+    const NAME: &'static str = "frugalos.mds.object.get_with_user_metadata";
+
+    type Req = ReadObjectRequest;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+
+    type Res = libfrugalos::Result<Option<(Metadata, UserMetadata)>>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+}
+
+/// 利用者定義のメタデータ付きでオブジェクトの存在確認を行うためのRPC.
+#[derive(Debug)]
+pub struct HeadObjectWithUserMetadataRpc;
+impl Call for HeadObjectWithUserMetadataRpc {
+    const ID: ProcedureId = ProcedureId(0x0101_0003);
+    const NAME: &'static str = "frugalos.mds.object.head_with_user_metadata";
+
+    type Req = ReadObjectRequest;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+
+    type Res = libfrugalos::Result<Option<(ObjectVersion, UserMetadata)>>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+}
+
+/// `GetObjectWithUserMetadataRpc`および`HeadObjectWithUserMetadataRpc`の要求.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadObjectRequest {
+    /// 対象ノードのID.
+    pub node_id: String,
+
+    /// オブジェクトのID.
+    pub object_id: ObjectId,
+
+    /// 期待するバージョン.
+    pub expect: Expect,
+
+    /// 読み込みの一貫性レベル.
+    pub consistency: ReadConsistency,
+}
+
+/// 参照系の要求に対する一貫性のレベル.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReadConsistency {
+    /// 線形化可能な読み込み.
+    ///
+    /// リーダは、要求の受理時点のコミット済みインデックスを記録した上でハートビートを送信し、
+    /// それが過半数に応答され(i.e., 自身がまだリーダであることが確認され)、
+    /// かつ記録したインデックスまでが適用された時点の状態を返す(ReadIndex).
+    /// 同時期に届いた読み込みは、一つのハートビートを共有する.
+    Linearizable,
+
+    /// リーダのリースに基づく読み込み.
+    ///
+    /// 過半数に応答された直近のハートビートの送信時点から一定期間(リース)内であれば、
+    /// リーダはハートビートを待たずに手元の状態を返す.
+    /// リースが切れている場合には`Linearizable`と同様に扱われる.
+    /// リースの期間は選挙タイムアウトの最小値よりも短いので、時計のずれが十分に小さければ線形化可能となる.
+    LeaderLease,
+
+    /// 古い状態の読み込みを許容する.
+    ///
+    /// リーダに限らず、要求を受けたノード(フォロワーを含む)が手元の状態をそのまま返す.
+    Stale,
+}
+impl Default for ReadConsistency {
+    fn default() -> Self {
+        ReadConsistency::LeaderLease
+    }
+}
+
+/// バケツのバージョン管理の設定.
+///
+/// デフォルトではバージョン管理は無効であり、上書きないし削除されたバージョンは即座に破棄される.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersioningPolicy {
+    /// オブジェクト毎に保持する過去バージョンの最大数.
+    ///
+    /// `0`の場合にはバージョン管理が無効となる.
+    pub max_versions: u32,
+
+    /// 過去バージョンを保持する最大期間(上書きないし削除されてからの秒数).
+    ///
+    /// 指定された場合には、`max_versions`の範囲内であっても、この期間を過ぎたバージョンは破棄される.
+    pub max_age: Option<u64>,
+}
+impl VersioningPolicy {
+    /// バージョン管理が有効な場合には`true`を返す.
+    pub fn is_enabled(&self) -> bool {
+        self.max_versions > 0
+    }
+}
+
+/// バケツに設定されている`VersioningPolicy`を、クライアントやノード間で共有するためのハンドル.
+///
+/// `set`による変更は、同じハンドルを共有している全ての箇所に即座に反映される.
+/// MDSのノードは、リーダである間はこの設定と状態機械の設定を比較し、
+/// 異なっていれば`SetVersioning`コマンドを提案することで、Raftログ経由で設定を反映する.
+#[derive(Debug, Clone, Default)]
+pub struct SharedVersioningPolicy(Arc<Mutex<VersioningPolicy>>);
+impl SharedVersioningPolicy {
+    /// 新しい`SharedVersioningPolicy`インスタンスを生成する.
+    pub fn new(policy: VersioningPolicy) -> Self {
+        SharedVersioningPolicy(Arc::new(Mutex::new(policy)))
+    }
+
+    /// 現在の設定を返す.
+    pub fn get(&self) -> VersioningPolicy {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// 設定を変更する.
+    pub fn set(&self, policy: VersioningPolicy) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = policy;
+    }
+}
+
+/// オブジェクトの個々のバージョンの情報.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectVersionEntry {
+    /// バージョン.
+    pub version: ObjectVersion,
+
+    /// 現在のバージョンの場合には`true`.
+    pub is_latest: bool,
+
+    /// 過去バージョンの場合には、上書きないし削除された時刻(UNIXエポックからの秒数).
+    pub superseded_at: Option<u64>,
+}
+
+/// 過去バージョンを含む、特定のバージョンのオブジェクトを取得するためのRPC.
+#[derive(Debug)]
+pub struct GetObjectVersionRpc;
+impl Call for GetObjectVersionRpc {
+    const ID: ProcedureId = ProcedureId(0x0101_0004);
+    const NAME: &'static str = "frugalos.mds.object.get_version";
+
+    type Req = ObjectVersionRequest;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+
+    type Res = libfrugalos::Result<Option<(Metadata, UserMetadata)>>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+}
+
+/// `GetObjectVersionRpc`の要求.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectVersionRequest {
+    /// 対象ノードのID.
+    pub node_id: String,
+
+    /// オブジェクトのID.
+    pub object_id: ObjectId,
+
+    /// 取得対象のバージョン.
+    pub version: ObjectVersion,
+}
+
+/// 過去バージョンを含めて、特定のバージョンが保持されているかどうかを確認するためのRPC.
+///
+/// オブジェクトのIDが分からない場合(e.g., 複製やフラグメントの修復時)に使用される.
+#[derive(Debug)]
+pub struct HeadObjectVersionRpc;
+impl Call for HeadObjectVersionRpc {
+    const ID: ProcedureId = ProcedureId(0x0101_000B);
+    const NAME: &'static str = "frugalos.mds.object.head_version";
+
+    type Req = HeadObjectVersionRequest;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+
+    type Res = libfrugalos::Result<bool>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+}
+
+/// `HeadObjectVersionRpc`の要求.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeadObjectVersionRequest {
+    /// 対象ノードのID.
+    pub node_id: String,
+
+    /// 確認対象のバージョン.
+    pub version: ObjectVersion,
+
+    /// 読み込みの一貫性レベル.
+    pub consistency: ReadConsistency,
+}
+
+/// オブジェクトの保持されている全バージョンを取得するためのRPC.
+///
+/// 結果は新しいバージョン順に並ぶ.
+#[derive(Debug)]
+pub struct ListObjectVersionsRpc;
+impl Call for ListObjectVersionsRpc {
+    const ID: ProcedureId = ProcedureId(0x0101_0005);
+    const NAME: &'static str = "frugalos.mds.object.list_versions";
+
+    type Req = ObjectRequest;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+
+    type Res = libfrugalos::Result<Vec<ObjectVersionEntry>>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+}
+
+/// 同一セグメント内でオブジェクトのIDを(アトミックに)変更するためのRPC.
+///
+/// バージョンは変更前のものがそのまま引き継がれる.
+/// 変更元が存在しない場合には`None`が、それ以外の場合には変更後のオブジェクトのバージョンと、
+/// 上書きされたオブジェクトのバージョンの組が返される.
+#[derive(Debug)]
+pub struct RenameObjectRpc;
+impl Call for RenameObjectRpc {
+    const ID: ProcedureId = ProcedureId(0x0101_0008);
+    const NAME: &'static str = "frugalos.mds.object.rename";
+
+    type Req = RenameObjectRequest;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+
+    type Res = libfrugalos::Result<Option<(ObjectVersion, Option<ObjectVersion>)>>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+}
+
+/// `RenameObjectRpc`の要求.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RenameObjectRequest {
+    /// 対象ノードのID.
+    pub node_id: String,
+
+    /// 変更元のオブジェクトのID.
+    pub from: ObjectId,
+
+    /// 変更先のオブジェクトのID.
+    pub to: ObjectId,
+
+    /// 変更先のオブジェクトに期待するバージョン.
+    pub expect: Expect,
+}
+
+/// 同一セグメント内の複数の操作を、まとめて(一つのRaftの提案として)実行するためのRPC.
+///
+/// 結果は操作毎に、要求内の順番通りに返される.
+/// 参照系の操作のみからなる場合には、提案は行われずにリーダが直接応答する.
+#[derive(Debug)]
+pub struct BatchRpc;
+impl Call for BatchRpc {
+    const ID: ProcedureId = ProcedureId(0x0101_0009);
+    const NAME: &'static str = "frugalos.mds.object.batch";
+
+    type Req = BatchRequest;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+
+    type Res = libfrugalos::Result<Vec<libfrugalos::Result<BatchResult>>>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+}
+
+/// `BatchRpc`の要求.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchRequest {
+    /// 対象ノードのID.
+    pub node_id: String,
+
+    /// 実行する操作群(先頭から順に適用される).
+    pub operations: Vec<BatchOperation>,
+
+    /// 中身の保存完了までのタイムアウト(秒単位).
+    pub put_content_timeout: u64,
+}
+
+/// 同一セグメント内の複数オブジェクトをアトミックに更新するためのRPC.
+///
+/// 全ての操作の事前条件(`Expect`)が満たされた場合にのみ全体が適用され、
+/// 一つでも満たされない場合には何も適用されずにエラーとなる.
+#[derive(Debug)]
+pub struct TransactionRpc;
+impl Call for TransactionRpc {
+    const ID: ProcedureId = ProcedureId(0x0101_000A);
+    const NAME: &'static str = "frugalos.mds.object.transaction";
+
+    type Req = TransactionRequest;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+
+    type Res = libfrugalos::Result<Vec<BatchResult>>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+}
+
+/// `TransactionRpc`の要求.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionRequest {
+    /// 対象ノードのID.
+    pub node_id: String,
+
+    /// 実行する操作群(`Put`ないし`Delete`のみ).
+    pub operations: Vec<BatchOperation>,
+
+    /// 中身の保存完了までのタイムアウト(秒単位).
+    pub put_content_timeout: u64,
+}
+
+/// バッチ内の個々の操作.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(missing_docs)]
+pub enum BatchOperation {
+    /// オブジェクトを取得する.
+    Get { object_id: ObjectId, expect: Expect },
+
+    /// オブジェクトの存在確認を行う.
+    Head { object_id: ObjectId, expect: Expect },
+
+    /// オブジェクトを保存する.
+    Put {
+        object_id: ObjectId,
+        data: Vec<u8>,
+        attributes: ObjectAttributes,
+        expect: Expect,
+    },
+
+    /// オブジェクトを削除する.
+    Delete { object_id: ObjectId, expect: Expect },
+}
+impl BatchOperation {
+    /// 対象のオブジェクトのIDを返す.
+    pub fn object_id(&self) -> &ObjectId {
+        match *self {
+            BatchOperation::Get { ref object_id, .. }
+            | BatchOperation::Head { ref object_id, .. }
+            | BatchOperation::Put { ref object_id, .. }
+            | BatchOperation::Delete { ref object_id, .. } => object_id,
+        }
+    }
+
+    /// 参照系の操作の場合には`true`を返す.
+    pub fn is_read_only(&self) -> bool {
+        match *self {
+            BatchOperation::Get { .. } | BatchOperation::Head { .. } => true,
+            BatchOperation::Put { .. } | BatchOperation::Delete { .. } => false,
+        }
+    }
+}
+
+/// バッチ内の個々の操作の結果.
+///
+/// 各要素の中身は、対応する単独の操作用のRPCの結果と同様.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BatchResult {
+    /// `BatchOperation::Get`の結果.
+    Get(Option<(Metadata, UserMetadata)>),
+
+    /// `BatchOperation::Head`の結果.
+    Head(Option<(ObjectVersion, UserMetadata)>),
+
+    /// `BatchOperation::Put`の結果(保存されたバージョンと、上書きされたバージョン).
+    Put(ObjectVersion, Option<ObjectVersion>),
+
+    /// `BatchOperation::Delete`の結果.
+    Delete(Option<ObjectVersion>),
+}
@@ -4,10 +4,12 @@ use fibers_rpc::server::{
 use frugalos_raft::LocalNodeId;
 use futures::Future;
 use libfrugalos::schema::mds as rpc;
+use libfrugalos::time::Seconds;
 use trackable::error::ErrorKindExt;
 
 use error::to_rpc_error;
 use node::NodeHandle;
+use schema::{self, ObjectAttributes};
 use {Error, ErrorKind, Result, ServiceHandle};
 
 macro_rules! rpc_try {
@@ -38,9 +40,19 @@ impl Server {
         builder.add_cast_handler::<rpc::RecommendToLeaderRpc, _>(this.clone());
         builder.add_call_handler::<rpc::GetLeaderRpc, _>(this.clone());
         builder.add_call_handler::<rpc::ListObjectsRpc, _>(this.clone());
+        builder.add_call_handler::<schema::ListObjectsPageRpc, _>(this.clone());
         builder.add_call_handler::<rpc::GetObjectRpc, _>(this.clone());
         builder.add_call_handler::<rpc::HeadObjectRpc, _>(this.clone());
         builder.add_call_handler::<rpc::PutObjectRpc, _>(this.clone());
+        builder.add_call_handler::<schema::GetObjectWithUserMetadataRpc, _>(this.clone());
+        builder.add_call_handler::<schema::HeadObjectWithUserMetadataRpc, _>(this.clone());
+        builder.add_call_handler::<schema::PutObjectWithAttributesRpc, _>(this.clone());
+        builder.add_call_handler::<schema::GetObjectVersionRpc, _>(this.clone());
+        builder.add_call_handler::<schema::HeadObjectVersionRpc, _>(this.clone());
+        builder.add_call_handler::<schema::ListObjectVersionsRpc, _>(this.clone());
+        builder.add_call_handler::<schema::RenameObjectRpc, _>(this.clone());
+        builder.add_call_handler::<schema::BatchRpc, _>(this.clone());
+        builder.add_call_handler::<schema::TransactionRpc, _>(this.clone());
         builder.add_call_handler::<rpc::DeleteObjectRpc, _>(this.clone());
         builder.add_call_handler::<rpc::GetLatestVersionRpc, _>(this.clone());
         builder.add_call_handler::<rpc::GetObjectCountRpc, _>(this.clone());
@@ -82,6 +94,25 @@ impl HandleCall<rpc::ListObjectsRpc> for Server {
         Reply::future(node.list_objects().map_err(to_rpc_error).then(Ok))
     }
 }
+impl HandleCall<schema::ListObjectsPageRpc> for Server {
+    fn handle_call(
+        &self,
+        request: schema::ListObjectsPageRequest,
+    ) -> Reply<schema::ListObjectsPageRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.list_objects_page(
+                request.prefix,
+                request.start_after,
+                request.limit as usize,
+                request.consistency,
+            )
+            .map_err(to_rpc_error)
+            .then(Ok),
+        )
+    }
+}
 
 impl HandleCall<rpc::GetLatestVersionRpc> for Server {
     fn handle_call(&self, node_id: String) -> Reply<rpc::GetLatestVersionRpc> {
@@ -129,6 +160,7 @@ impl HandleCall<rpc::PutObjectRpc> for Server {
             node.put_object(
                 request.object_id,
                 request.metadata,
+                ObjectAttributes::default(),
                 request.expect,
                 request.put_content_timeout.into(),
             )
@@ -137,6 +169,140 @@ impl HandleCall<rpc::PutObjectRpc> for Server {
         )
     }
 }
+impl HandleCall<schema::GetObjectWithUserMetadataRpc> for Server {
+    fn handle_call(
+        &self,
+        request: schema::ReadObjectRequest,
+    ) -> Reply<schema::GetObjectWithUserMetadataRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.get_object_with_user_metadata(
+                request.object_id,
+                request.expect,
+                request.consistency,
+            )
+            .map_err(to_rpc_error)
+            .then(Ok),
+        )
+    }
+}
+impl HandleCall<schema::HeadObjectWithUserMetadataRpc> for Server {
+    fn handle_call(
+        &self,
+        request: schema::ReadObjectRequest,
+    ) -> Reply<schema::HeadObjectWithUserMetadataRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.head_object_with_user_metadata(
+                request.object_id,
+                request.expect,
+                request.consistency,
+            )
+            .map_err(to_rpc_error)
+            .then(Ok),
+        )
+    }
+}
+impl HandleCall<schema::PutObjectWithAttributesRpc> for Server {
+    fn handle_call(
+        &self,
+        request: schema::PutObjectWithAttributesRequest,
+    ) -> Reply<schema::PutObjectWithAttributesRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.put_object(
+                request.object_id,
+                request.data,
+                request.attributes,
+                request.expect,
+                Seconds(request.put_content_timeout),
+            )
+            .map_err(to_rpc_error)
+            .then(Ok),
+        )
+    }
+}
+impl HandleCall<schema::GetObjectVersionRpc> for Server {
+    fn handle_call(
+        &self,
+        request: schema::ObjectVersionRequest,
+    ) -> Reply<schema::GetObjectVersionRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.get_object_version(request.object_id, request.version)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<schema::HeadObjectVersionRpc> for Server {
+    fn handle_call(
+        &self,
+        request: schema::HeadObjectVersionRequest,
+    ) -> Reply<schema::HeadObjectVersionRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.head_object_version(request.version, request.consistency)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<schema::ListObjectVersionsRpc> for Server {
+    fn handle_call(&self, request: rpc::ObjectRequest) -> Reply<schema::ListObjectVersionsRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.list_object_versions(request.object_id)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<schema::RenameObjectRpc> for Server {
+    fn handle_call(&self, request: schema::RenameObjectRequest) -> Reply<schema::RenameObjectRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.rename_object(request.from, request.to, request.expect)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<schema::BatchRpc> for Server {
+    fn handle_call(&self, request: schema::BatchRequest) -> Reply<schema::BatchRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.batch(request.operations, Seconds(request.put_content_timeout))
+                .map(|results| {
+                    results
+                        .into_iter()
+                        .map(|result| result.map_err(to_rpc_error))
+                        .collect()
+                })
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<schema::TransactionRpc> for Server {
+    fn handle_call(&self, request: schema::TransactionRequest) -> Reply<schema::TransactionRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.transaction(request.operations, Seconds(request.put_content_timeout))
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
 impl HandleCall<rpc::DeleteObjectRpc> for Server {
     fn handle_call(&self, request: rpc::ObjectRequest) -> Reply<rpc::DeleteObjectRpc> {
         let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
@@ -0,0 +1,123 @@
+//! オブジェクトの中身を、固定サイズのチャンク単位で扱うための構成要素。
+//!
+//! `CHUNK_SIZE`を超えるオブジェクトは、チャンク毎に個別のオブジェクト(パート)として保存され、
+//! 論理的なオブジェクトのIDにはパート一覧(マニフェスト)が保存される。
+//! これにより、保存・取得時にメモリ上に保持する必要があるのは、高々一チャンク分のデータとなる。
+use futures::{Async, Poll, Stream};
+use libfrugalos::entity::object::ObjectVersion;
+use std::fmt;
+use std::mem;
+
+use {Error, UserMetadata};
+
+/// チャンクのサイズ(バイト単位)。
+pub const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// オブジェクトの中身を表すストリーム。
+pub type ContentStream = Box<Stream<Item = Vec<u8>, Error = Error> + Send + 'static>;
+
+/// ストリームとして取得されるオブジェクト。
+pub struct ObjectStream {
+    /// バージョン番号。
+    pub version: ObjectVersion,
+
+    /// 利用者定義のメタデータ。
+    pub user_metadata: UserMetadata,
+
+    /// 中身。
+    pub content: ContentStream,
+}
+impl fmt::Debug for ObjectStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ObjectStream {{ version: {:?}, user_metadata: {:?}, .. }}",
+            self.version, self.user_metadata
+        )
+    }
+}
+
+/// 任意の長さのバイト列のストリームを、固定サイズのチャンクのストリームに変換する。
+///
+/// 最後のチャンクのみは`chunk_size`よりも短くなり得る。
+#[derive(Debug)]
+pub(crate) struct Chunks<S> {
+    inner: S,
+    chunk_size: usize,
+    buf: Vec<u8>,
+    eos: bool,
+}
+impl<S> Chunks<S>
+where
+    S: Stream<Item = Vec<u8>, Error = Error>,
+{
+    pub fn new(inner: S, chunk_size: usize) -> Self {
+        Chunks {
+            inner,
+            chunk_size,
+            buf: Vec::new(),
+            eos: false,
+        }
+    }
+}
+impl<S> Stream for Chunks<S>
+where
+    S: Stream<Item = Vec<u8>, Error = Error>,
+{
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if self.buf.len() >= self.chunk_size {
+                let rest = self.buf.split_off(self.chunk_size);
+                let chunk = mem::replace(&mut self.buf, rest);
+                return Ok(Async::Ready(Some(chunk)));
+            }
+            if self.eos {
+                if self.buf.is_empty() {
+                    return Ok(Async::Ready(None));
+                }
+                let chunk = mem::replace(&mut self.buf, Vec::new());
+                return Ok(Async::Ready(Some(chunk)));
+            }
+            match track!(self.inner.poll())? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(None) => self.eos = true,
+                Async::Ready(Some(bytes)) => {
+                    if self.buf.is_empty() {
+                        self.buf = bytes;
+                    } else {
+                        self.buf.extend_from_slice(&bytes);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{stream, Future};
+
+    use super::*;
+
+    #[test]
+    fn chunks_works() {
+        let input = vec![vec![0, 1], vec![2, 3, 4, 5, 6], vec![], vec![7]];
+        let chunks = Chunks::new(stream::iter_ok(input), 3).collect().wait().ok();
+        assert_eq!(chunks, Some(vec![vec![0, 1, 2], vec![3, 4, 5], vec![6, 7]]));
+
+        let chunks = Chunks::new(stream::iter_ok(vec![vec![0, 1, 2]]), 3)
+            .collect()
+            .wait()
+            .ok();
+        assert_eq!(chunks, Some(vec![vec![0, 1, 2]]));
+
+        let chunks = Chunks::new(stream::iter_ok(Vec::<Vec<u8>>::new()), 3)
+            .collect()
+            .wait()
+            .ok();
+        assert_eq!(chunks, Some(vec![]));
+    }
+}
@@ -0,0 +1,325 @@
+//! lumpに保存されるデータに付与される、チェックサム等のトレイラの処理。
+//!
+//! トレイラはデータの末尾に付与され、その最後の1バイトがトレイラのバージョンを表す。
+//! 各バージョンのレイアウトは以下の通り(整数は全てビッグエンディアン):
+//!
+//! - バージョン`0`: `<<Payload, Adler32:32, 0:8>>`
+//! - バージョン`1`: `<<Payload, Checksum:N, Algorithm:8, 1:8>>`
+//!   - `Algorithm`が`0`ならAdler-32(`N=32`)、`1`ならCRC-32C(`N=32`)、`2`ならxxHash64(`N=64`)
+//! - バージョン`2`: `<<Payload, Checksum:N, Algorithm:8, Compression:8, 2:8>>`
+//!   - `Compression`は中身全体の圧縮方式で、`1`ならZstandard、`2`ならLZ4
+//! - バージョン`3`: `<<Payload, Checksum:N, Algorithm:8, Compression:8, KeyId:32, 3:8>>`
+//!   - `KeyId`は`Payload`の暗号化に使用した鍵のID(`Compression`は`0`なら非圧縮)
+//!
+//! いずれのバージョンでも、チェックサムは`Payload`部分のみを対象に計算される。
+//! なお圧縮は符号化の前に行われるので、`Payload`は圧縮後の中身(ないしそのフラグメント)となる。
+//! 一方で暗号化は符号化の後に行われるので、暗号化されている場合の`Payload`は暗号文となり、
+//! チェックサムの検証自体には鍵は不要である。
+//!
+//! 古いバージョンのノードでも読み込めるように、トレイラは必要最小限のバージョンで書き込まれる
+//! (i.e., 暗号化されていない場合にはバージョン`2`、さらに圧縮されていない場合にはバージョン`1`、
+//! さらにAdler-32が選択されている場合にはバージョン`0`)。
+use adler32;
+use byteorder::{BigEndian, ByteOrder};
+use crc::crc32;
+use std::hash::Hasher;
+use twox_hash::XxHash64;
+
+use client::encryption::KeyId;
+use config::{ChecksumAlgorithm, Compression};
+use {ErrorKind, Result};
+
+const TRAILER_VERSION_LEGACY: u8 = 0;
+const TRAILER_VERSION_TAGGED: u8 = 1;
+const TRAILER_VERSION_COMPRESSED: u8 = 2;
+const TRAILER_VERSION_ENCRYPTED: u8 = 3;
+
+const ALGORITHM_ADLER32: u8 = 0;
+const ALGORITHM_CRC32C: u8 = 1;
+const ALGORITHM_XXHASH64: u8 = 2;
+
+const COMPRESSION_NONE: u8 = 0;
+const COMPRESSION_ZSTD: u8 = 1;
+const COMPRESSION_LZ4: u8 = 2;
+
+/// `bytes`の末尾に、`algorithm`によるチェックサムと、中身の圧縮方式および暗号鍵のIDを記録したトレイラを付与する。
+pub(crate) fn append_checksum(
+    bytes: &mut Vec<u8>,
+    algorithm: ChecksumAlgorithm,
+    compression: Compression,
+    key_id: Option<KeyId>,
+) {
+    let checksum = calculate(algorithm, &bytes[..]);
+    let size = checksum_size(algorithm);
+    let mut trailer = [0; 8];
+    BigEndian::write_uint(&mut trailer[..size], checksum, size);
+    bytes.extend_from_slice(&trailer[..size]);
+    match (algorithm, compression, key_id) {
+        (ChecksumAlgorithm::Adler32, Compression::None, None) => {
+            bytes.push(TRAILER_VERSION_LEGACY);
+        }
+        (_, Compression::None, None) => {
+            bytes.push(algorithm_code(algorithm));
+            bytes.push(TRAILER_VERSION_TAGGED);
+        }
+        (_, _, None) => {
+            bytes.push(algorithm_code(algorithm));
+            bytes.push(compression_code(compression));
+            bytes.push(TRAILER_VERSION_COMPRESSED);
+        }
+        (_, _, Some(key_id)) => {
+            bytes.push(algorithm_code(algorithm));
+            bytes.push(compression_code(compression));
+            let mut key_id_bytes = [0; 4];
+            BigEndian::write_u32(&mut key_id_bytes, key_id);
+            bytes.extend_from_slice(&key_id_bytes);
+            bytes.push(TRAILER_VERSION_ENCRYPTED);
+        }
+    }
+}
+
+/// チェックサムを検証した上で、`bytes`からトレイラを取り除く。
+///
+/// 結果は、トレイラに記録されていた中身の圧縮方式と、暗号化に使用された鍵のID。
+pub(crate) fn verify_and_remove_checksum(
+    bytes: &mut Vec<u8>,
+) -> Result<(Compression, Option<KeyId>)> {
+    let (payload_len, compression, key_id) = track!(verify(bytes))?;
+    bytes.truncate(payload_len);
+    Ok((compression, key_id))
+}
+
+/// `append_checksum`で付与されたチェックサムを検証する。
+pub(crate) fn verify_checksum(bytes: &[u8]) -> Result<()> {
+    track!(verify(bytes))?;
+    Ok(())
+}
+
+/// チェックサムを検証して、トレイラを除いたデータのサイズと、中身の圧縮方式および暗号鍵のIDを返す。
+fn verify(bytes: &[u8]) -> Result<(usize, Compression, Option<KeyId>)> {
+    track_assert!(!bytes.is_empty(), ErrorKind::Invalid);
+    let version = bytes[bytes.len() - 1];
+    let (algorithm, compression, key_id, trailer_size) = match version {
+        TRAILER_VERSION_LEGACY => (ChecksumAlgorithm::Adler32, Compression::None, None, 5),
+        TRAILER_VERSION_TAGGED => {
+            track_assert!(bytes.len() >= 2, ErrorKind::Invalid);
+            let algorithm = track!(algorithm_from_code(bytes[bytes.len() - 2]))?;
+            (
+                algorithm,
+                Compression::None,
+                None,
+                checksum_size(algorithm) + 2,
+            )
+        }
+        TRAILER_VERSION_COMPRESSED => {
+            track_assert!(bytes.len() >= 3, ErrorKind::Invalid);
+            let algorithm = track!(algorithm_from_code(bytes[bytes.len() - 3]))?;
+            let compression = track!(compression_from_code(bytes[bytes.len() - 2]))?;
+            (algorithm, compression, None, checksum_size(algorithm) + 3)
+        }
+        TRAILER_VERSION_ENCRYPTED => {
+            track_assert!(bytes.len() >= 7, ErrorKind::Invalid);
+            let algorithm = track!(algorithm_from_code(bytes[bytes.len() - 7]))?;
+            let compression = track!(compression_from_code(bytes[bytes.len() - 6]))?;
+            let key_id = BigEndian::read_u32(&bytes[bytes.len() - 5..]);
+            track_assert_ne!(key_id, 0, ErrorKind::Invalid);
+            (
+                algorithm,
+                compression,
+                Some(key_id),
+                checksum_size(algorithm) + 7,
+            )
+        }
+        _ => track_panic!(
+            ErrorKind::Invalid,
+            "Unknown checksum trailer version: {}",
+            version
+        ),
+    };
+    track_assert!(bytes.len() >= trailer_size, ErrorKind::Invalid);
+
+    let payload_len = bytes.len() - trailer_size;
+    let size = checksum_size(algorithm);
+    let checksum = calculate(algorithm, &bytes[..payload_len]);
+    let expected = BigEndian::read_uint(&bytes[payload_len..][..size], size);
+    track_assert_eq!(checksum, expected, ErrorKind::Invalid);
+    Ok((payload_len, compression, key_id))
+}
+
+fn calculate(algorithm: ChecksumAlgorithm, bytes: &[u8]) -> u64 {
+    match algorithm {
+        ChecksumAlgorithm::Adler32 => u64::from(adler32::adler32(bytes).expect("Never fails")),
+        ChecksumAlgorithm::Crc32c => u64::from(crc32::checksum_castagnoli(bytes)),
+        ChecksumAlgorithm::XxHash64 => {
+            let mut hasher = XxHash64::with_seed(0);
+            hasher.write(bytes);
+            hasher.finish()
+        }
+    }
+}
+
+fn checksum_size(algorithm: ChecksumAlgorithm) -> usize {
+    match algorithm {
+        ChecksumAlgorithm::Adler32 | ChecksumAlgorithm::Crc32c => 4,
+        ChecksumAlgorithm::XxHash64 => 8,
+    }
+}
+
+fn algorithm_code(algorithm: ChecksumAlgorithm) -> u8 {
+    match algorithm {
+        ChecksumAlgorithm::Adler32 => ALGORITHM_ADLER32,
+        ChecksumAlgorithm::Crc32c => ALGORITHM_CRC32C,
+        ChecksumAlgorithm::XxHash64 => ALGORITHM_XXHASH64,
+    }
+}
+
+fn algorithm_from_code(code: u8) -> Result<ChecksumAlgorithm> {
+    Ok(match code {
+        ALGORITHM_ADLER32 => ChecksumAlgorithm::Adler32,
+        ALGORITHM_CRC32C => ChecksumAlgorithm::Crc32c,
+        ALGORITHM_XXHASH64 => ChecksumAlgorithm::XxHash64,
+        _ => track_panic!(ErrorKind::Invalid, "Unknown checksum algorithm: {}", code),
+    })
+}
+
+fn compression_code(compression: Compression) -> u8 {
+    match compression {
+        Compression::None => COMPRESSION_NONE,
+        Compression::Zstd => COMPRESSION_ZSTD,
+        Compression::Lz4 => COMPRESSION_LZ4,
+    }
+}
+
+fn compression_from_code(code: u8) -> Result<Compression> {
+    Ok(match code {
+        COMPRESSION_NONE => Compression::None,
+        COMPRESSION_ZSTD => Compression::Zstd,
+        COMPRESSION_LZ4 => Compression::Lz4,
+        _ => track_panic!(ErrorKind::Invalid, "Unknown compression: {}", code),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trackable::result::TestResult;
+
+    const ALGORITHMS: [ChecksumAlgorithm; 3] = [
+        ChecksumAlgorithm::Adler32,
+        ChecksumAlgorithm::Crc32c,
+        ChecksumAlgorithm::XxHash64,
+    ];
+
+    #[test]
+    fn verify_checksum_works() -> TestResult {
+        for &algorithm in &ALGORITHMS {
+            let mut bytes = b"foo".to_vec();
+            append_checksum(&mut bytes, algorithm, Compression::None, None);
+            track!(verify_checksum(&bytes))?;
+
+            let mut content = bytes.clone();
+            let trailer = track!(verify_and_remove_checksum(&mut content))?;
+            assert_eq!(content, b"foo");
+            assert_eq!(trailer, (Compression::None, None));
+
+            bytes[0] = b'b';
+            assert!(verify_checksum(&bytes).is_err());
+            assert!(verify_checksum(&bytes[..4]).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_trailer_is_readable() -> TestResult {
+        // 以前のバージョンで書き込まれたデータ
+        let mut bytes = b"foo".to_vec();
+        let mut trailer = [0; 5];
+        BigEndian::write_u32(&mut trailer[..], adler32::adler32(&b"foo"[..]).unwrap());
+        bytes.extend_from_slice(&trailer[..]);
+
+        track!(verify_checksum(&bytes))?;
+        let trailer = track!(verify_and_remove_checksum(&mut bytes))?;
+        assert_eq!(bytes, b"foo");
+        assert_eq!(trailer, (Compression::None, None));
+
+        // Adler-32が選択されている場合には、以前と同じ形式で書き込まれる
+        let mut legacy = b"foo".to_vec();
+        legacy.extend_from_slice(&trailer[..]);
+        let mut bytes = b"foo".to_vec();
+        append_checksum(
+            &mut bytes,
+            ChecksumAlgorithm::Adler32,
+            Compression::None,
+            None,
+        );
+        assert_eq!(bytes, legacy);
+        Ok(())
+    }
+
+    #[test]
+    fn compression_is_recorded() -> TestResult {
+        for &algorithm in &ALGORITHMS {
+            for &compression in &[Compression::Zstd, Compression::Lz4] {
+                let mut bytes = b"foo".to_vec();
+                append_checksum(&mut bytes, algorithm, compression, None);
+                track!(verify_checksum(&bytes))?;
+
+                let mut content = bytes.clone();
+                assert_eq!(
+                    track!(verify_and_remove_checksum(&mut content))?,
+                    (compression, None)
+                );
+                assert_eq!(content, b"foo");
+
+                bytes[0] = b'b';
+                assert!(verify_checksum(&bytes).is_err());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn key_id_is_recorded() -> TestResult {
+        for &algorithm in &ALGORITHMS {
+            for &compression in &[Compression::None, Compression::Zstd] {
+                let mut bytes = b"foo".to_vec();
+                append_checksum(&mut bytes, algorithm, compression, Some(42));
+                assert_eq!(bytes.last(), Some(&TRAILER_VERSION_ENCRYPTED));
+                track!(verify_checksum(&bytes))?;
+
+                let mut content = bytes.clone();
+                assert_eq!(
+                    track!(verify_and_remove_checksum(&mut content))?,
+                    (compression, Some(42))
+                );
+                assert_eq!(content, b"foo");
+
+                bytes[0] = b'b';
+                assert!(verify_checksum(&bytes).is_err());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_trailer_is_rejected() {
+        let mut bytes = b"foo".to_vec();
+        append_checksum(
+            &mut bytes,
+            ChecksumAlgorithm::Crc32c,
+            Compression::None,
+            None,
+        );
+
+        let mut unknown_version = bytes.clone();
+        *unknown_version.last_mut().unwrap() = 4;
+        assert!(verify_checksum(&unknown_version).is_err());
+
+        let i = bytes.len() - 2;
+        bytes[i] = 100;
+        assert!(verify_checksum(&bytes).is_err());
+
+        assert!(verify_checksum(&[]).is_err());
+        assert!(verify_checksum(&[TRAILER_VERSION_TAGGED]).is_err());
+    }
+}
@@ -0,0 +1,151 @@
+//! オブジェクトの中身の圧縮関連の処理。
+use lz4;
+use prometrics::metrics::{Counter, Gauge, MetricBuilder};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use trackable::error::ErrorKindExt;
+use zstd;
+
+use config::Compression;
+use {Error, ErrorKind, Result};
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// バケツで選択されている`Compression`と、圧縮に関するメトリクス。
+///
+/// バケツ単位で作成され、そのバケツに属する全セグメントのクライアントとノードで共有される。
+/// `set`による変更は、共有している全ての箇所に即座に反映される。
+#[derive(Debug, Clone)]
+pub struct Compressor {
+    compression: Arc<AtomicUsize>,
+    input_bytes: Counter,
+    output_bytes: Counter,
+    skipped: Counter,
+    ratio: Gauge,
+}
+impl Compressor {
+    /// 新しい`Compressor`インスタンスを生成する。
+    pub fn new(bucket_id: &str, compression: Compression) -> Self {
+        let metric_builder = MetricBuilder::new()
+            .namespace("frugalos")
+            .subsystem("compression")
+            .label("bucket", bucket_id)
+            .clone();
+        Compressor {
+            compression: Arc::new(AtomicUsize::new(compression.to_usize())),
+            input_bytes: metric_builder
+                .counter("input_bytes_total")
+                .finish()
+                .expect("Never fails"),
+            output_bytes: metric_builder
+                .counter("output_bytes_total")
+                .finish()
+                .expect("Never fails"),
+            skipped: metric_builder
+                .counter("skipped_total")
+                .finish()
+                .expect("Never fails"),
+            ratio: metric_builder.gauge("ratio").finish().expect("Never fails"),
+        }
+    }
+
+    /// 現在選択されている圧縮方式を返す。
+    pub fn get(&self) -> Compression {
+        Compression::from_usize(self.compression.load(Ordering::SeqCst))
+    }
+
+    /// 圧縮方式を変更する。
+    pub fn set(&self, compression: Compression) {
+        self.compression
+            .store(compression.to_usize(), Ordering::SeqCst);
+    }
+
+    /// 現在の圧縮方式で`content`を圧縮して、実際に適用された方式と共に返す。
+    ///
+    /// 圧縮してもサイズが小さくならない場合には、`content`がそのまま返される。
+    pub(crate) fn compress(&self, content: Vec<u8>) -> (Vec<u8>, Compression) {
+        let compression = self.get();
+        if compression == Compression::None {
+            return (content, Compression::None);
+        }
+
+        // NOTE: 圧縮に失敗した場合も、縮まなかった場合と同様に非圧縮で保存する
+        let input_bytes = content.len() as u64;
+        let compressed = compress(compression, &content)
+            .ok()
+            .filter(|compressed| compressed.len() < content.len());
+        let (content, compression) = if let Some(compressed) = compressed {
+            (compressed, compression)
+        } else {
+            self.skipped.increment();
+            (content, Compression::None)
+        };
+        self.input_bytes.add_u64(input_bytes);
+        self.output_bytes.add_u64(content.len() as u64);
+        if self.output_bytes.value() > 0.0 {
+            self.ratio
+                .set(self.input_bytes.value() / self.output_bytes.value());
+        }
+        (content, compression)
+    }
+}
+
+fn compress(compression: Compression, content: &[u8]) -> Result<Vec<u8>> {
+    let compressed = match compression {
+        Compression::None => Ok(content.to_vec()),
+        Compression::Zstd => zstd::stream::encode_all(content, ZSTD_LEVEL),
+        Compression::Lz4 => lz4::block::compress(content, None, true),
+    };
+    compressed.map_err(|e| track!(Error::from(e)))
+}
+
+/// `compression`で圧縮された`content`を伸長する。
+pub(crate) fn decompress(compression: Compression, content: Vec<u8>) -> Result<Vec<u8>> {
+    let decompressed = match compression {
+        Compression::None => return Ok(content),
+        Compression::Zstd => zstd::stream::decode_all(&content[..]),
+        Compression::Lz4 => lz4::block::decompress(&content[..], None),
+    };
+    decompressed.map_err(|e| track!(Error::from(ErrorKind::Corrupted.cause(e))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trackable::result::TestResult;
+
+    #[test]
+    fn compress_works() -> TestResult {
+        let content = b"foo"
+            .iter()
+            .cycle()
+            .take(1024)
+            .cloned()
+            .collect::<Vec<_>>();
+        for &compression in &[Compression::Zstd, Compression::Lz4] {
+            let compressor = Compressor::new("compress_works", compression);
+            let (compressed, applied) = compressor.compress(content.clone());
+            assert_eq!(applied, compression);
+            assert!(compressed.len() < content.len());
+            assert_eq!(track!(decompress(applied, compressed))?, content);
+        }
+
+        let compressor = Compressor::new("compress_works", Compression::None);
+        assert_eq!(
+            compressor.compress(content.clone()),
+            (content.clone(), Compression::None)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn incompressible_content_is_stored_as_is() {
+        let compressor =
+            Compressor::new("incompressible_content_is_stored_as_is", Compression::Zstd);
+        let content = vec![0x42];
+        assert_eq!(
+            compressor.compress(content.clone()),
+            (content, Compression::None)
+        );
+    }
+}
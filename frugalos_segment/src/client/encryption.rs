@@ -0,0 +1,391 @@
+//! lumpに保存されるデータの暗号化関連の処理。
+//!
+//! 暗号化は複製ないしフラグメント(i.e., lump)単位で行われ、暗号化されたデータのレイアウトは以下の通り:
+//!
+//! - `<<Nonce:96, Ciphertext, Tag:128>>`
+//!
+//! 使用した鍵のIDはトレイラに記録される(`checksum`モジュールを参照)。
+//! `Nonce`はlumpの書き込み毎にランダムに生成される。
+//!
+//! また、保存先のlumpのID(ノードとオブジェクトのバージョンから決まる)を追加認証データ(AAD)として使用するので、
+//! 暗号化されたデータを別のlump(i.e., 別のオブジェクトや別のフラグメント)に移し替えても復号できない。
+//! そのため、lumpを複製ないし修復する際には、常に書き込み先のlumpのIDで暗号化し直す必要がある。
+use byteorder::{BigEndian, ByteOrder};
+use cannyls::lump::LumpId;
+use ring::aead::{self, Aad, Nonce, OpeningKey, SealingKey, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use trackable::error::ErrorKindExt;
+
+use {Error, ErrorKind, Result};
+
+/// 暗号鍵のID。
+///
+/// `0`は「暗号化しない」ことを表すために予約されているので、鍵のIDは`1`以上である必要がある。
+pub type KeyId = u32;
+
+/// 鍵のサイズ(バイト単位)。
+const KEY_LEN: usize = 32;
+
+/// 暗号化のアルゴリズム。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    /// AES-256-GCM.
+    Aes256Gcm,
+
+    /// ChaCha20-Poly1305.
+    ChaCha20Poly1305,
+}
+impl Cipher {
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            Cipher::Aes256Gcm => &aead::AES_256_GCM,
+            Cipher::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+}
+impl FromStr for Cipher {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "aes256gcm" => Ok(Cipher::Aes256Gcm),
+            "chacha20poly1305" => Ok(Cipher::ChaCha20Poly1305),
+            _ => track_panic!(ErrorKind::Invalid, "Unknown cipher: {:?}", s),
+        }
+    }
+}
+
+/// 暗号鍵。
+#[derive(Clone)]
+pub struct EncryptionKey {
+    id: KeyId,
+    cipher: Cipher,
+    bytes: Vec<u8>,
+}
+impl EncryptionKey {
+    /// 新しい`EncryptionKey`インスタンスを生成する。
+    ///
+    /// `bytes`のサイズは32バイトである必要がある。
+    pub fn new(id: KeyId, cipher: Cipher, bytes: Vec<u8>) -> Result<Self> {
+        track_assert_ne!(id, 0, ErrorKind::Invalid, "Key ID 0 is reserved");
+        track_assert_eq!(bytes.len(), KEY_LEN, ErrorKind::Invalid, "id={}", id);
+        Ok(EncryptionKey { id, cipher, bytes })
+    }
+
+    /// 鍵のIDを返す。
+    pub fn id(&self) -> KeyId {
+        self.id
+    }
+
+    /// 暗号化のアルゴリズムを返す。
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    fn seal(&self, mut payload: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>> {
+        let algorithm = self.cipher.algorithm();
+        let key = track!(SealingKey::new(algorithm, &self.bytes)
+            .map_err(|e| Error::from(ErrorKind::Other.cause(e))))?;
+        let mut nonce = [0; NONCE_LEN];
+        track!(SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|e| Error::from(ErrorKind::Other.cause(e))))?;
+
+        let mut sealed = Vec::with_capacity(NONCE_LEN + payload.len() + algorithm.tag_len());
+        sealed.extend_from_slice(&nonce);
+        sealed.append(&mut payload);
+        sealed.resize(sealed.len() + algorithm.tag_len(), 0);
+        let sealed_len = track!(aead::seal_in_place(
+            &key,
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad),
+            &mut sealed[NONCE_LEN..],
+            algorithm.tag_len(),
+        )
+        .map_err(|e| Error::from(ErrorKind::Other.cause(e))))?;
+        sealed.truncate(NONCE_LEN + sealed_len);
+        Ok(sealed)
+    }
+
+    fn open(&self, mut sealed: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>> {
+        let algorithm = self.cipher.algorithm();
+        track_assert!(
+            sealed.len() >= NONCE_LEN + algorithm.tag_len(),
+            ErrorKind::Corrupted
+        );
+        let key = track!(OpeningKey::new(algorithm, &self.bytes)
+            .map_err(|e| Error::from(ErrorKind::Other.cause(e))))?;
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&sealed[..NONCE_LEN]);
+        let payload_len = track!(aead::open_in_place(
+            &key,
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad),
+            NONCE_LEN,
+            &mut sealed,
+        )
+        .map(|payload| payload.len())
+        .map_err(|e| Error::from(
+            ErrorKind::Corrupted.cause(format!("Cannot decrypt (key_id={}): {}", self.id, e))
+        )))?;
+        sealed.truncate(payload_len);
+        Ok(sealed)
+    }
+}
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // NOTE: 鍵の中身はログ等に出力しない
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .field("cipher", &self.cipher)
+            .finish()
+    }
+}
+
+/// 暗号鍵の提供者。
+///
+/// lumpの読み書きの度に同期的に呼び出されるので、
+/// 外部のサービス等から鍵を取得する実装では、事前に取得した鍵をキャッシュしておく必要がある。
+pub trait KeyProvider: Send + Sync + 'static {
+    /// `id`に対応する鍵を返す。
+    ///
+    /// 鍵が存在しない場合には`None`が返される。
+    fn get_key(&self, id: KeyId) -> Result<Option<EncryptionKey>>;
+}
+
+/// ローカルの鍵ファイルから読み込まれた鍵群を提供する`KeyProvider`の実装。
+///
+/// 鍵ファイルは、一行に一つの鍵を`${ID} ${CIPHER} ${KEY}`の形式で記述したテキストファイル:
+///
+/// - `ID`: 鍵のID(`1`以上の整数)
+/// - `CIPHER`: `aes256gcm`ないし`chacha20poly1305`
+/// - `KEY`: 16進数で表記された32バイトの鍵
+///
+/// 空行と`#`で始まる行は無視される。
+///
+/// 一度使用した鍵のIDに対応するアルゴリズムや鍵を変更してはならない。
+/// また、鍵を削除するのは、その鍵で暗号化されたlumpが全て再暗号化されてからにする必要がある。
+#[derive(Debug, Default, Clone)]
+pub struct KeyFile {
+    keys: HashMap<KeyId, EncryptionKey>,
+}
+impl KeyFile {
+    /// `path`の鍵ファイルを読み込む。
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let text = track!(fs::read_to_string(path.as_ref()).map_err(Error::from))?;
+        track!(text.parse::<KeyFile>(), "path={:?}", path.as_ref())
+    }
+}
+impl FromStr for KeyFile {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let mut keys = HashMap::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            track_assert_eq!(fields.len(), 3, ErrorKind::Invalid, "line={}", i + 1);
+            let id = track!(
+                fields[0]
+                    .parse::<KeyId>()
+                    .map_err(|e| Error::from(ErrorKind::Invalid.cause(e))),
+                "line={}",
+                i + 1
+            )?;
+            let cipher = track!(fields[1].parse::<Cipher>(), "line={}", i + 1)?;
+            let bytes = track!(decode_hex(fields[2]), "line={}", i + 1)?;
+            let key = track!(EncryptionKey::new(id, cipher, bytes), "line={}", i + 1)?;
+            track_assert!(
+                !keys.contains_key(&id),
+                ErrorKind::Invalid,
+                "Duplicate key ID: {}",
+                id
+            );
+            keys.insert(id, key);
+        }
+        Ok(KeyFile { keys })
+    }
+}
+impl KeyProvider for KeyFile {
+    fn get_key(&self, id: KeyId) -> Result<Option<EncryptionKey>> {
+        Ok(self.keys.get(&id).cloned())
+    }
+}
+
+fn make_aad(lump_id: LumpId) -> [u8; 16] {
+    let mut aad = [0; 16];
+    BigEndian::write_u128(&mut aad, lump_id.as_u128());
+    aad
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    track_assert_eq!(s.len() % 2, 0, ErrorKind::Invalid);
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            let byte = s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok());
+            let byte = track_assert_some!(byte, ErrorKind::Invalid, "Not a hex string");
+            Ok(byte)
+        })
+        .collect()
+}
+
+/// バケツで選択されている暗号鍵と、鍵の提供者。
+///
+/// バケツ単位で作成され、そのバケツに属する全セグメントのクライアントとノードで共有される。
+/// `set`による変更は、共有している全ての箇所に即座に反映される。
+#[derive(Clone)]
+pub struct Encryptor {
+    key_id: Arc<AtomicUsize>,
+    provider: Arc<KeyProvider>,
+}
+impl Encryptor {
+    /// 新しい`Encryptor`インスタンスを生成する。
+    ///
+    /// `key_id`が`None`の場合には、新たに書き込むlumpは暗号化されない。
+    pub fn new(provider: Arc<KeyProvider>, key_id: Option<KeyId>) -> Self {
+        Encryptor {
+            key_id: Arc::new(AtomicUsize::new(key_id.unwrap_or(0) as usize)),
+            provider,
+        }
+    }
+
+    /// 現在選択されている暗号鍵のIDを返す。
+    pub fn get(&self) -> Option<KeyId> {
+        match self.key_id.load(Ordering::SeqCst) {
+            0 => None,
+            id => Some(id as KeyId),
+        }
+    }
+
+    /// 暗号鍵を変更する。
+    pub fn set(&self, key_id: Option<KeyId>) {
+        self.key_id
+            .store(key_id.unwrap_or(0) as usize, Ordering::SeqCst);
+    }
+
+    /// 現在選択されている鍵で、`lump_id`のlumpに保存する`payload`を暗号化して、使用した鍵のIDと共に返す。
+    ///
+    /// 鍵が選択されていない場合には、`payload`がそのまま返される。
+    pub(crate) fn encrypt(
+        &self,
+        payload: Vec<u8>,
+        lump_id: LumpId,
+    ) -> Result<(Vec<u8>, Option<KeyId>)> {
+        if let Some(id) = self.get() {
+            let key = track!(self.key(id))?;
+            let sealed = track!(key.seal(payload, &make_aad(lump_id)))?;
+            Ok((sealed, Some(id)))
+        } else {
+            Ok((payload, None))
+        }
+    }
+
+    /// `lump_id`のlumpから読み込んだ、`key_id`の鍵で暗号化された`payload`を復号する。
+    pub(crate) fn decrypt(
+        &self,
+        payload: Vec<u8>,
+        key_id: Option<KeyId>,
+        lump_id: LumpId,
+    ) -> Result<Vec<u8>> {
+        if let Some(id) = key_id {
+            let key = track!(self.key(id))?;
+            track!(
+                key.open(payload, &make_aad(lump_id)),
+                "lump_id={:?}",
+                lump_id
+            )
+        } else {
+            Ok(payload)
+        }
+    }
+
+    fn key(&self, id: KeyId) -> Result<EncryptionKey> {
+        let key = track!(self.provider.get_key(id))?;
+        let key = track_assert_some!(key, ErrorKind::Other, "Unknown encryption key: id={}", id);
+        Ok(key)
+    }
+}
+impl Default for Encryptor {
+    fn default() -> Self {
+        Encryptor::new(Arc::new(KeyFile::default()), None)
+    }
+}
+impl fmt::Debug for Encryptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Encryptor")
+            .field("key_id", &self.get())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trackable::result::TestResult;
+
+    const KEY_FILE: &str = "
+# comment
+1 aes256gcm 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f
+2 chacha20poly1305 1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100
+";
+
+    #[test]
+    fn encrypt_works() -> TestResult {
+        let keys: KeyFile = track!(KEY_FILE.parse())?;
+        let encryptor = Encryptor::new(Arc::new(keys), None);
+        let lump_id = LumpId::new(10);
+        assert_eq!(
+            track!(encryptor.encrypt(b"foo".to_vec(), lump_id))?,
+            (b"foo".to_vec(), None)
+        );
+
+        for &id in &[1, 2] {
+            encryptor.set(Some(id));
+            let (sealed, key_id) = track!(encryptor.encrypt(b"foo".to_vec(), lump_id))?;
+            assert_eq!(key_id, Some(id));
+            assert_ne!(&sealed[NONCE_LEN..][..3], b"foo");
+            assert_eq!(
+                track!(encryptor.decrypt(sealed.clone(), key_id, lump_id))?,
+                b"foo"
+            );
+
+            // 改竄されたデータや、異なる鍵では復号できない
+            let mut tampered = sealed.clone();
+            tampered[NONCE_LEN] ^= 1;
+            assert!(encryptor.decrypt(tampered, key_id, lump_id).is_err());
+            assert!(encryptor
+                .decrypt(sealed.clone(), Some(3 - id), lump_id)
+                .is_err());
+            assert!(encryptor.decrypt(sealed.clone(), Some(3), lump_id).is_err());
+
+            // 別のlumpに移し替えられたデータも復号できない
+            assert!(encryptor.decrypt(sealed, key_id, LumpId::new(11)).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_key_file_is_rejected() {
+        let key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        assert!(format!("0 aes256gcm {}", key).parse::<KeyFile>().is_err());
+        assert!(format!("1 aes128gcm {}", key).parse::<KeyFile>().is_err());
+        assert!(format!("1 aes256gcm {}", &key[2..])
+            .parse::<KeyFile>()
+            .is_err());
+        assert!(format!("1 aes256gcm {}ZZ", &key[2..])
+            .parse::<KeyFile>()
+            .is_err());
+        assert!(format!("1 aes256gcm {}\n1 aes256gcm {}", key, key)
+            .parse::<KeyFile>()
+            .is_err());
+    }
+}
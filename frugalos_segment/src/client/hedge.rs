@@ -0,0 +1,126 @@
+//! ErasureCodingのGET時の、フラグメント取得要求のヘッジ関連の処理。
+use prometrics::metrics::{Counter, MetricBuilder};
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use config::HedgePolicy;
+
+/// 保持するフラグメント取得の遅延のサンプル数の上限。
+const MAX_LATENCY_SAMPLES: usize = 256;
+
+/// パーセンタイル値を待ち時間として使うために必要な最小のサンプル数。
+const MIN_LATENCY_SAMPLES: usize = 32;
+
+/// フラグメント取得要求のヘッジに関する統計情報とメトリクス。
+///
+/// バケツ単位で作成され、そのバケツに属する全セグメントのクライアントで共有される。
+#[derive(Debug, Clone)]
+pub struct HedgeMonitor {
+    latencies: Arc<Mutex<VecDeque<Duration>>>,
+    primary_requests: Counter,
+    hedged_requests: Counter,
+    cancelled_requests: Counter,
+}
+impl HedgeMonitor {
+    /// 新しい`HedgeMonitor`インスタンスを生成する。
+    pub fn new(bucket_id: &str) -> Self {
+        let metric_builder = MetricBuilder::new()
+            .namespace("frugalos")
+            .subsystem("dispersed_get")
+            .label("bucket", bucket_id)
+            .clone();
+        HedgeMonitor {
+            latencies: Arc::new(Mutex::new(VecDeque::new())),
+            primary_requests: metric_builder
+                .counter("fragment_requests_total")
+                .label("type", "primary")
+                .finish()
+                .expect("Never fails"),
+            hedged_requests: metric_builder
+                .counter("fragment_requests_total")
+                .label("type", "hedged")
+                .finish()
+                .expect("Never fails"),
+            cancelled_requests: metric_builder
+                .counter("cancelled_fragment_requests_total")
+                .finish()
+                .expect("Never fails"),
+        }
+    }
+
+    /// ヘッジ方針に従って、取得候補を増やすまでの待ち時間を返す。
+    pub(crate) fn hedge_delay(&self, policy: &HedgePolicy) -> Duration {
+        let max = Duration::from_millis(policy.delay_millis);
+        policy
+            .delay_percentile
+            .and_then(|p| self.latency_percentile(p))
+            .map_or(max, |d| cmp::min(d, max))
+    }
+
+    pub(crate) fn record_latency(&self, latency: Duration) {
+        if let Ok(mut latencies) = self.latencies.lock() {
+            if latencies.len() == MAX_LATENCY_SAMPLES {
+                latencies.pop_front();
+            }
+            latencies.push_back(latency);
+        }
+    }
+
+    pub(crate) fn record_request(&self, hedged: bool) {
+        if hedged {
+            self.hedged_requests.increment();
+        } else {
+            self.primary_requests.increment();
+        }
+    }
+
+    pub(crate) fn record_cancelled_requests(&self, count: usize) {
+        self.cancelled_requests.add_u64(count as u64);
+    }
+
+    fn latency_percentile(&self, percentile: u8) -> Option<Duration> {
+        let mut latencies = self
+            .latencies
+            .lock()
+            .ok()?
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        if latencies.len() < MIN_LATENCY_SAMPLES {
+            return None;
+        }
+        latencies.sort();
+        let percentile = cmp::min(percentile, 100) as usize;
+        let i = (latencies.len() * percentile / 100).saturating_sub(1);
+        Some(latencies[i])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hedge_delay_works() {
+        let monitor = HedgeMonitor::new("hedge_delay_works");
+        let mut policy = HedgePolicy::default();
+        policy.delay_millis = 50;
+        policy.delay_percentile = Some(90);
+
+        // 統計が不十分な間は、固定の待ち時間が使われる
+        assert_eq!(monitor.hedge_delay(&policy), Duration::from_millis(50));
+
+        for i in 1..=100 {
+            monitor.record_latency(Duration::from_millis(i));
+        }
+        assert_eq!(monitor.hedge_delay(&policy), Duration::from_millis(50));
+
+        policy.delay_millis = 1000;
+        assert_eq!(monitor.hedge_delay(&policy), Duration::from_millis(90));
+
+        policy.delay_percentile = None;
+        assert_eq!(monitor.hedge_delay(&policy), Duration::from_millis(1000));
+    }
+}
@@ -1,5 +1,14 @@
 use cannyls::deadline::Deadline;
 use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
+use fibers_rpc::Call;
+use frugalos_mds::schema::{
+    BatchOperation, BatchRequest, BatchResult, BatchRpc, GetObjectVersionRpc,
+    GetObjectWithUserMetadataRpc, HeadObjectVersionRequest, HeadObjectVersionRpc,
+    HeadObjectWithUserMetadataRpc, ListObjectVersionsRpc, ListObjectsPageRequest,
+    ListObjectsPageRpc, ObjectAttributes, ObjectPage, ObjectVersionEntry, ObjectVersionRequest,
+    PutObjectWithAttributesRequest, PutObjectWithAttributesRpc, ReadConsistency, ReadObjectRequest,
+    RenameObjectRequest, RenameObjectRpc, TransactionRequest, TransactionRpc, UserMetadata,
+};
 use frugalos_mds::{Error as MdsError, ErrorKind as MdsErrorKind};
 use frugalos_raft::{LocalNodeId, NodeId};
 use futures::{Async, Future, Poll};
@@ -9,6 +18,7 @@ use libfrugalos::entity::object::{
     DeleteObjectsByPrefixSummary, ObjectId, ObjectPrefix, ObjectSummary, ObjectVersion,
 };
 use libfrugalos::expect::Expect;
+use libfrugalos::schema::mds::ObjectRequest;
 use libfrugalos::time::Seconds;
 use rand::{self, Rng};
 use rustracing::tag::{StdTag, Tag};
@@ -47,7 +57,7 @@ impl MdsClient {
 
     pub fn latest(&self) -> impl Future<Item = Option<ObjectSummary>, Error = Error> {
         let parent = Span::inactive().handle();
-        Request::new(self.clone(), parent, move |client| {
+        Request::new(self.clone(), parent, move |client, _| {
             Box::new(client.latest_version().map_err(MdsError::from))
         })
     }
@@ -55,18 +65,55 @@ impl MdsClient {
     pub fn list(&self) -> impl Future<Item = Vec<ObjectSummary>, Error = Error> {
         debug!(self.logger, "Starts LIST");
         let parent = Span::inactive().handle();
-        Request::new(self.clone(), parent, move |client| {
+        Request::new(self.clone(), parent, move |client, _| {
             Box::new(client.list_objects().map_err(MdsError::from))
         })
     }
 
+    pub fn list_page(
+        &self,
+        prefix: ObjectPrefix,
+        start_after: Option<ObjectId>,
+        limit: usize,
+        consistency: ReadConsistency,
+    ) -> impl Future<Item = ObjectPage, Error = Error> {
+        debug!(
+            self.logger,
+            "Starts LIST: prefix={:?}, start_after={:?}, limit={}, consistency={:?}",
+            prefix,
+            start_after,
+            limit,
+            consistency
+        );
+        let parent = Span::inactive().handle();
+        let rpc_service = self.rpc_service.clone();
+        Request::new(self.clone(), parent, move |_, (addr, node_id)| {
+            let request = ListObjectsPageRequest {
+                node_id,
+                prefix: prefix.clone(),
+                start_after: start_after.clone(),
+                limit: limit as u32,
+                consistency,
+            };
+            let future = ListObjectsPageRpc::client(&rpc_service)
+                .call(addr, request)
+                .then(|result| match result {
+                    Err(e) => Err(MdsErrorKind::Other.cause(e).into()),
+                    Ok(Err(e)) => Err(MdsError::from(e)),
+                    Ok(Ok(page)) => Ok((None, page)),
+                });
+            Box::new(future)
+        })
+        .consistency(consistency)
+    }
+
     pub fn get(
         &self,
         id: ObjectId,
         parent: SpanHandle,
     ) -> impl Future<Item = Option<ObjectValue>, Error = Error> {
         debug!(self.logger, "Starts GET: id={:?}", id);
-        Request::new(self.clone(), parent, move |client| {
+        Request::new(self.clone(), parent, move |client, _| {
             let future = client
                 .get_object(id.clone(), Expect::Any)
                 .map(|(leader, v)| {
@@ -82,13 +129,115 @@ impl MdsClient {
         })
     }
 
+    pub fn get_with_user_metadata(
+        &self,
+        id: ObjectId,
+        consistency: ReadConsistency,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<(ObjectValue, UserMetadata)>, Error = Error> {
+        debug!(
+            self.logger,
+            "Starts GET: id={:?}, consistency={:?}", id, consistency
+        );
+        let rpc_service = self.rpc_service.clone();
+        Request::new(self.clone(), parent, move |_, (addr, node_id)| {
+            let request = ReadObjectRequest {
+                node_id,
+                object_id: id.clone(),
+                expect: Expect::Any,
+                consistency,
+            };
+            let future = GetObjectWithUserMetadataRpc::client(&rpc_service)
+                .call(addr, request)
+                .then(|result| match result {
+                    Err(e) => Err(MdsErrorKind::Other.cause(e).into()),
+                    Ok(Err(e)) => Err(MdsError::from(e)),
+                    Ok(Ok(v)) => {
+                        let v = v.map(|(metadata, user_metadata)| {
+                            let value = ObjectValue {
+                                version: metadata.version,
+                                content: metadata.data,
+                            };
+                            (value, user_metadata)
+                        });
+                        Ok((None, v))
+                    }
+                });
+            Box::new(future)
+        })
+        .consistency(consistency)
+    }
+
+    /// 過去バージョンを含む、特定のバージョンのオブジェクトを取得する.
+    pub fn get_version(
+        &self,
+        id: ObjectId,
+        version: ObjectVersion,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<(ObjectValue, UserMetadata)>, Error = Error> {
+        debug!(
+            self.logger,
+            "Starts GET: id={:?}, version={:?}", id, version
+        );
+        let rpc_service = self.rpc_service.clone();
+        Request::new(self.clone(), parent, move |_, (addr, node_id)| {
+            let request = ObjectVersionRequest {
+                node_id,
+                object_id: id.clone(),
+                version,
+            };
+            let future = GetObjectVersionRpc::client(&rpc_service)
+                .call(addr, request)
+                .then(|result| match result {
+                    Err(e) => Err(MdsErrorKind::Other.cause(e).into()),
+                    Ok(Err(e)) => Err(MdsError::from(e)),
+                    Ok(Ok(v)) => {
+                        let v = v.map(|(metadata, user_metadata)| {
+                            let value = ObjectValue {
+                                version: metadata.version,
+                                content: metadata.data,
+                            };
+                            (value, user_metadata)
+                        });
+                        Ok((None, v))
+                    }
+                });
+            Box::new(future)
+        })
+    }
+
+    /// オブジェクトの保持されている全バージョンを、新しい順に取得する.
+    pub fn list_versions(
+        &self,
+        id: ObjectId,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Vec<ObjectVersionEntry>, Error = Error> {
+        debug!(self.logger, "Starts LIST VERSIONS: id={:?}", id);
+        let rpc_service = self.rpc_service.clone();
+        Request::new(self.clone(), parent, move |_, (addr, node_id)| {
+            let request = ObjectRequest {
+                node_id,
+                object_id: id.clone(),
+                expect: Expect::Any,
+            };
+            let future = ListObjectVersionsRpc::client(&rpc_service)
+                .call(addr, request)
+                .then(|result| match result {
+                    Err(e) => Err(MdsErrorKind::Other.cause(e).into()),
+                    Ok(Err(e)) => Err(MdsError::from(e)),
+                    Ok(Ok(v)) => Ok((None, v)),
+                });
+            Box::new(future)
+        })
+    }
+
     pub fn head(
         &self,
         id: ObjectId,
         parent: SpanHandle,
     ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
         debug!(self.logger, "Starts HEAD: id={:?}", id);
-        Request::new(self.clone(), parent, move |client| {
+        Request::new(self.clone(), parent, move |client, _| {
             Box::new(
                 client
                     .head_object(id.clone(), Expect::Any)
@@ -97,6 +246,65 @@ impl MdsClient {
         })
     }
 
+    pub fn head_with_user_metadata(
+        &self,
+        id: ObjectId,
+        consistency: ReadConsistency,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<(ObjectVersion, UserMetadata)>, Error = Error> {
+        debug!(
+            self.logger,
+            "Starts HEAD: id={:?}, consistency={:?}", id, consistency
+        );
+        let rpc_service = self.rpc_service.clone();
+        Request::new(self.clone(), parent, move |_, (addr, node_id)| {
+            let request = ReadObjectRequest {
+                node_id,
+                object_id: id.clone(),
+                expect: Expect::Any,
+                consistency,
+            };
+            let future = HeadObjectWithUserMetadataRpc::client(&rpc_service)
+                .call(addr, request)
+                .then(|result| match result {
+                    Err(e) => Err(MdsErrorKind::Other.cause(e).into()),
+                    Ok(Err(e)) => Err(MdsError::from(e)),
+                    Ok(Ok(v)) => Ok((None, v)),
+                });
+            Box::new(future)
+        })
+        .consistency(consistency)
+    }
+
+    /// 過去バージョンを含めて、`version`がMDSに保持されているかどうかを確認する.
+    ///
+    /// 削除との競合を検出するために使われるので、常に線形化可能な読み込みとなる.
+    pub fn head_version(
+        &self,
+        version: ObjectVersion,
+        parent: SpanHandle,
+    ) -> impl Future<Item = bool, Error = Error> {
+        debug!(self.logger, "Starts HEAD: version={:?}", version);
+        let rpc_service = self.rpc_service.clone();
+        let consistency = ReadConsistency::Linearizable;
+        Request::new(self.clone(), parent, move |_, (addr, node_id)| {
+            let request = HeadObjectVersionRequest {
+                node_id,
+                version,
+                consistency,
+            };
+            let future = HeadObjectVersionRpc::client(&rpc_service)
+                .call(addr, request)
+                .then(|result| match result {
+                    Err(e) => Err(MdsErrorKind::Other.cause(e).into()),
+                    Ok(Err(e)) => Err(MdsError::from(e)),
+                    Ok(Ok(v)) => Ok((None, v)),
+                });
+            Box::new(future)
+        })
+        .consistency(consistency)
+    }
+
     pub fn delete(
         &self,
         id: ObjectId,
@@ -104,7 +312,7 @@ impl MdsClient {
         parent: SpanHandle,
     ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
         debug!(self.logger, "Starts DELETE: id={:?}", id);
-        Request::new(self.clone(), parent, move |client| {
+        Request::new(self.clone(), parent, move |client, _| {
             Box::new(
                 client
                     .delete_object(id.clone(), expect.clone())
@@ -113,13 +321,44 @@ impl MdsClient {
         })
     }
 
+    /// 同一セグメント内でオブジェクトのIDを変更する.
+    ///
+    /// 変更元が存在しない場合には`None`が、それ以外の場合には(変更前と同じ)バージョンと、
+    /// 変更先に既に存在していたオブジェクトのバージョンの組が返される.
+    pub fn rename(
+        &self,
+        from: ObjectId,
+        to: ObjectId,
+        expect: Expect,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<(ObjectVersion, Option<ObjectVersion>)>, Error = Error> {
+        debug!(self.logger, "Starts RENAME: from={:?}, to={:?}", from, to);
+        let rpc_service = self.rpc_service.clone();
+        Request::new(self.clone(), parent, move |_, (addr, node_id)| {
+            let request = RenameObjectRequest {
+                node_id,
+                from: from.clone(),
+                to: to.clone(),
+                expect: expect.clone(),
+            };
+            let future = RenameObjectRpc::client(&rpc_service)
+                .call(addr, request)
+                .then(|result| match result {
+                    Err(e) => Err(MdsErrorKind::Other.cause(e).into()),
+                    Ok(Err(e)) => Err(MdsError::from(e)),
+                    Ok(Ok(v)) => Ok((None, v)),
+                });
+            Box::new(future)
+        })
+    }
+
     pub fn delete_by_version(
         &self,
         version: ObjectVersion,
         parent: SpanHandle,
     ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
         debug!(self.logger, "Starts DELETE: version={:?}", version);
-        Request::new(self.clone(), parent, move |client| {
+        Request::new(self.clone(), parent, move |client, _| {
             Box::new(
                 client
                     .delete_object_by_version(version)
@@ -137,7 +376,7 @@ impl MdsClient {
             self.logger,
             "Starts DELETE: versions if {:?} <= it < {:?}", targets.start, targets.end
         );
-        Request::new(self.clone(), parent, move |client| {
+        Request::new(self.clone(), parent, move |client, _| {
             Box::new(
                 client
                     .delete_by_range(targets.clone())
@@ -152,7 +391,7 @@ impl MdsClient {
         parent: SpanHandle,
     ) -> impl Future<Item = DeleteObjectsByPrefixSummary, Error = Error> {
         debug!(self.logger, "Starts DELETE: prefix={:?}", prefix);
-        Request::new(self.clone(), parent, move |client| {
+        Request::new(self.clone(), parent, move |client, _| {
             Box::new(
                 client
                     .delete_by_prefix(prefix.clone())
@@ -170,12 +409,8 @@ impl MdsClient {
         parent: SpanHandle,
     ) -> impl Future<Item = (ObjectVersion, bool), Error = Error> {
         debug!(self.logger, "Starts PUT: id={:?}", id);
-        let put_content_timeout = Seconds(if let Deadline::Within(d) = deadline {
-            d.as_secs() + self.client_config.put_content_timeout.0
-        } else {
-            self.client_config.put_content_timeout.0
-        });
-        Request::new(self.clone(), parent, move |client| {
+        let put_content_timeout = self.put_content_timeout(deadline);
+        Request::new(self.clone(), parent, move |client, _| {
             Box::new(
                 client
                     .put_object(
@@ -190,14 +425,126 @@ impl MdsClient {
         })
     }
 
+    /// 付加情報(利用者定義のメタデータや有効期限)付きでオブジェクトを保存する.
+    ///
+    /// 付加情報が空の場合には、`put`と同様に`libfrugalos`のRPCが使用される.
+    pub fn put_with_attributes(
+        &self,
+        id: ObjectId,
+        content: Vec<u8>,
+        attributes: ObjectAttributes,
+        expect: Expect,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> Box<Future<Item = (ObjectVersion, bool), Error = Error> + Send + 'static> {
+        if attributes.is_empty() {
+            return Box::new(self.put(id, content, expect, deadline, parent));
+        }
+        debug!(self.logger, "Starts PUT: id={:?}", id);
+        let put_content_timeout = self.put_content_timeout(deadline);
+        let rpc_service = self.rpc_service.clone();
+        let future = Request::new(self.clone(), parent, move |_, (addr, node_id)| {
+            let request = PutObjectWithAttributesRequest {
+                node_id,
+                object_id: id.clone(),
+                data: content.clone(),
+                attributes: attributes.clone(),
+                expect: expect.clone(),
+                put_content_timeout: put_content_timeout.0,
+            };
+            let future = PutObjectWithAttributesRpc::client(&rpc_service)
+                .call(addr, request)
+                .then(|result| match result {
+                    Err(e) => Err(MdsErrorKind::Other.cause(e).into()),
+                    Ok(Err(e)) => Err(MdsError::from(e)),
+                    Ok(Ok((version, old))) => Ok((None, (version, old.is_none()))),
+                });
+            Box::new(future)
+        });
+        Box::new(future)
+    }
+
+    /// 複数の操作をまとめて実行する.
+    ///
+    /// 結果は操作毎に、指定された順番通りに返される.
+    pub fn batch(
+        &self,
+        operations: Vec<BatchOperation>,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Vec<Result<BatchResult>>, Error = Error> {
+        debug!(self.logger, "Starts BATCH: operations={}", operations.len());
+        let put_content_timeout = self.put_content_timeout(deadline);
+        let rpc_service = self.rpc_service.clone();
+        Request::new(self.clone(), parent, move |_, (addr, node_id)| {
+            let request = BatchRequest {
+                node_id,
+                operations: operations.clone(),
+                put_content_timeout: put_content_timeout.0,
+            };
+            let future = BatchRpc::client(&rpc_service)
+                .call(addr, request)
+                .then(|result| match result {
+                    Err(e) => Err(MdsErrorKind::Other.cause(e).into()),
+                    Ok(Err(e)) => Err(MdsError::from(e)),
+                    Ok(Ok(results)) => {
+                        let results = results
+                            .into_iter()
+                            .map(|result| result.map_err(|e| track!(Error::from(e))))
+                            .collect();
+                        Ok((None, results))
+                    }
+                });
+            Box::new(future)
+        })
+    }
+
+    pub fn transaction(
+        &self,
+        operations: Vec<BatchOperation>,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Vec<BatchResult>, Error = Error> {
+        debug!(
+            self.logger,
+            "Starts TRANSACTION: operations={}",
+            operations.len()
+        );
+        let put_content_timeout = self.put_content_timeout(deadline);
+        let rpc_service = self.rpc_service.clone();
+        Request::new(self.clone(), parent, move |_, (addr, node_id)| {
+            let request = TransactionRequest {
+                node_id,
+                operations: operations.clone(),
+                put_content_timeout: put_content_timeout.0,
+            };
+            let future = TransactionRpc::client(&rpc_service)
+                .call(addr, request)
+                .then(|result| match result {
+                    Err(e) => Err(MdsErrorKind::Other.cause(e).into()),
+                    Ok(Err(e)) => Err(MdsError::from(e)),
+                    Ok(Ok(results)) => Ok((None, results)),
+                });
+            Box::new(future)
+        })
+    }
+
     /// セグメント内に保持されているオブジェクトの数を返す.
     pub fn object_count(&self) -> impl Future<Item = u64, Error = Error> {
         let parent = Span::inactive().handle();
-        Request::new(self.clone(), parent, |client| {
+        Request::new(self.clone(), parent, |client, _| {
             Box::new(client.object_count().map_err(MdsError::from))
         })
     }
 
+    fn put_content_timeout(&self, deadline: Deadline) -> Seconds {
+        Seconds(if let Deadline::Within(d) = deadline {
+            d.as_secs() + self.client_config.put_content_timeout.0
+        } else {
+            self.client_config.put_content_timeout.0
+        })
+    }
+
     fn max_retry(&self) -> usize {
         self.inner.lock().expect("TODO").config.members.len()
     }
@@ -230,6 +577,13 @@ impl MdsClient {
         }
         inner.leader.expect("Never fails")
     }
+    fn random_member(&self) -> NodeId {
+        let inner = self.inner.lock().expect("TODO");
+        rand::thread_rng()
+            .choose(&inner.config.members)
+            .map(|m| m.node)
+            .expect("Never fails")
+    }
 }
 
 #[derive(Debug)]
@@ -255,6 +609,9 @@ pub struct Request<F, V> {
     max_retry: usize,
     request: F,
     parent: SpanHandle,
+
+    // `true`の場合には、リーダに限らず任意のノードに要求を送信する
+    any_node: bool,
     future:
         Option<Box<Future<Item = (Option<RemoteNodeId>, V), Error = MdsError> + Send + 'static>>,
 }
@@ -263,6 +620,7 @@ where
     V: Send + 'static,
     F: Fn(
         RaftMdsClient,
+        RemoteNodeId,
     ) -> Box<Future<Item = (Option<RemoteNodeId>, V), Error = MdsError> + Send + 'static>,
 {
     pub fn new(client: MdsClient, parent: SpanHandle, request: F) -> Self {
@@ -272,14 +630,27 @@ where
             max_retry,
             request,
             parent,
+            any_node: false,
             future: None,
         }
     }
+
+    /// 読み込みの一貫性レベルを指定する.
+    ///
+    /// `ReadConsistency::Stale`の場合には、リーダ以外のノードにも要求が送信されるようになる.
+    pub fn consistency(mut self, consistency: ReadConsistency) -> Self {
+        self.any_node = consistency == ReadConsistency::Stale;
+        self
+    }
     fn request_once(&mut self) -> Result<()> {
         track_assert_ne!(self.max_retry, 0, ErrorKind::Busy);
         self.max_retry -= 1;
 
-        let leader = self.client.leader2();
+        let leader = if self.any_node {
+            self.client.random_member()
+        } else {
+            self.client.leader2()
+        };
         let mut span = self.parent.child("mds_request", |span| {
             span.tag(StdTag::component(module_path!()))
                 .tag(StdTag::span_kind("client"))
@@ -288,11 +659,9 @@ where
                 .tag(Tag::new("peer.node", leader.local_id.to_string()))
                 .start()
         });
-        let client = RaftMdsClient::new(
-            (leader.addr, leader.local_id.to_string()),
-            self.client.rpc_service.clone(),
-        );
-        let future = (self.request)(client);
+        let node = (leader.addr, leader.local_id.to_string());
+        let client = RaftMdsClient::new(node.clone(), self.client.rpc_service.clone());
+        let future = (self.request)(client, node);
         let future = future.then(move |result| {
             if let Err(ref e) = result {
                 span.set_tag(StdTag::error); // NOTE: NotLeaderの場合はエラーではない
@@ -311,6 +680,7 @@ impl<F, V> Future for Request<F, V>
 where
     F: Fn(
         RaftMdsClient,
+        RemoteNodeId,
     ) -> Box<Future<Item = (Option<RemoteNodeId>, V), Error = MdsError> + Send + 'static>,
     V: Send + 'static,
 {
@@ -329,7 +699,7 @@ where
                     return Err(
                         track!(ErrorKind::UnexpectedVersion { current }.takes_over(e)).into(),
                     );
-                } else {
+                } else if !self.any_node {
                     self.client.clear_leader();
                 }
                 if self.max_retry == 0 {
@@ -1,22 +1,53 @@
 use cannyls::deadline::Deadline;
 use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
+use frugalos_mds::schema::{
+    BatchResult as MdsBatchResult, ObjectPage, ObjectVersionEntry, SharedVersioningPolicy,
+};
 use futures::future::Either;
-use futures::{self, Future};
+use futures::{self, Future, Stream};
 use libfrugalos::entity::object::{
     DeleteObjectsByPrefixSummary, ObjectId, ObjectPrefix, ObjectSummary, ObjectVersion,
 };
 use libfrugalos::expect::Expect;
-use rustracing_jaeger::span::SpanHandle;
+use rand;
+use rustracing_jaeger::span::{Span, SpanHandle};
 use slog::Logger;
+use std::cmp;
 use std::mem;
 use std::ops::Range;
+use std::sync::Arc;
+use trackable::error::ErrorKindExt;
 
+use self::hedge::HedgeMonitor;
 use self::mds::MdsClient;
+use self::repair::ReadRepairer;
 use self::storage::{ErasureCoder, StorageClient};
+use chunk::{Chunks, ContentStream, ObjectStream, CHUNK_SIZE};
 use config::ClientConfig;
-use {Error, ObjectValue};
+use inline::{self, MAX_INLINE_THRESHOLD};
+use multipart::{self, is_internal_object_id, Manifest, UploadId, UploadRecord, MAX_PART_NUMBER};
+use range::{assemble_parts, extract_parts, part_ranges, resolve_ranges};
+use {
+    BatchOperation, BatchResult, ByteRange, Error, ErrorKind, ObjectAttributes, ObjectValue,
+    PartialObjectValue, ReadConsistency, UserMetadata,
+};
+
+type BoxFuture<T> = Box<Future<Item = T, Error = Error> + Send + 'static>;
+
+/// バッチ操作およびトランザクションにおいて、一つの`put`で保存可能な中身の最大サイズ(バイト)。
+pub const MAX_BATCH_CONTENT_SIZE: usize = 1024 * 1024;
 
+/// バッチ操作およびトランザクションにおいて、一回の呼び出しで保存可能な中身の合計の最大サイズ(バイト)。
+///
+/// 操作群はMDSの一つの提案となるため、インライン化される中身を含めて提案が肥大化しないように制限している。
+pub const MAX_BATCH_TOTAL_CONTENT_SIZE: usize = 8 * 1024 * 1024;
+
+pub(crate) mod checksum;
+pub mod compression;
+pub mod encryption;
+pub mod hedge;
 mod mds;
+pub mod repair;
 pub mod storage; // TODO: private
 
 /// セグメントにアクセスるために使用するクライアント。
@@ -24,6 +55,8 @@ pub mod storage; // TODO: private
 pub struct Client {
     mds: MdsClient,
     pub(crate) storage: StorageClient, // TODO: private
+    pub(crate) versioning: SharedVersioningPolicy,
+    inline_threshold: usize,
 }
 impl Client {
     /// 新しい`Client`インスタンスを生成する。
@@ -32,6 +65,8 @@ impl Client {
         rpc_service: RpcServiceHandle,
         config: ClientConfig,
         ec: Option<ErasureCoder>,
+        hedge_monitor: Option<HedgeMonitor>,
+        read_repairer: Option<ReadRepairer>,
     ) -> Self {
         let mds = MdsClient::new(
             logger.clone(),
@@ -39,8 +74,32 @@ impl Client {
             config.cluster.clone(),
             config.mds.clone(),
         );
-        let storage = StorageClient::new(logger, config, rpc_service, ec);
-        Client { mds, storage }
+        let inline_threshold = cmp::min(config.inline_threshold, MAX_INLINE_THRESHOLD);
+        let versioning = config.versioning.clone();
+
+        // 削除と競合した修復によってlumpが書き戻されないように、書き込み前にMDSでバージョンの存在を確認する
+        let read_repairer = read_repairer.map(|repairer| {
+            let mds = mds.clone();
+            repairer.with_version_checker(Arc::new(move |version| {
+                let future: BoxFuture<_> =
+                    Box::new(mds.head_version(version, Span::inactive().handle()));
+                future
+            }))
+        });
+        let storage = StorageClient::new(
+            logger,
+            config,
+            rpc_service,
+            ec,
+            hedge_monitor,
+            read_repairer,
+        );
+        Client {
+            mds,
+            storage,
+            versioning,
+            inline_threshold,
+        }
     }
 
     /// オブジェクトを取得する。
@@ -54,8 +113,7 @@ impl Client {
         self.mds.get(id, parent.clone()).and_then(move |object| {
             if let Some(object) = object {
                 let version = object.version;
-                let future = storage
-                    .get(object, deadline, parent)
+                let future = get_content(storage, object, deadline, parent)
                     .map(move |content| ObjectValue { version, content })
                     .map(Some);
                 Either::A(future)
@@ -65,6 +123,102 @@ impl Client {
         })
     }
 
+    /// オブジェクトをストリームとして取得する。
+    ///
+    /// 複数のチャンクから構成されるオブジェクトの場合には、各チャンクは必要になった時点で取得される。
+    /// MDSからのメタデータの読み込みは、`consistency`で指定された一貫性レベルで行われる。
+    pub fn get_stream(
+        &self,
+        id: ObjectId,
+        consistency: ReadConsistency,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<ObjectStream>, Error = Error> {
+        let storage = self.storage.clone();
+        self.mds
+            .get_with_user_metadata(id, consistency, parent.clone())
+            .map(move |object| {
+                object.map(|(object, user_metadata)| ObjectStream {
+                    version: object.version,
+                    user_metadata,
+                    content: content_stream(storage, object, deadline, parent),
+                })
+            })
+    }
+
+    /// 過去バージョンを含む、特定のバージョンのオブジェクトをストリームとして取得する。
+    ///
+    /// 過去バージョンが保持されるのは、バージョン管理が有効な場合のみ。
+    pub fn get_version_stream(
+        &self,
+        id: ObjectId,
+        version: ObjectVersion,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<ObjectStream>, Error = Error> {
+        let storage = self.storage.clone();
+        self.mds
+            .get_version(id, version, parent.clone())
+            .map(move |object| {
+                object.map(|(object, user_metadata)| ObjectStream {
+                    version: object.version,
+                    user_metadata,
+                    content: content_stream(storage, object, deadline, parent),
+                })
+            })
+    }
+
+    /// オブジェクトの保持されている全バージョンを、新しい順に取得する。
+    pub fn list_versions(
+        &self,
+        id: ObjectId,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Vec<ObjectVersionEntry>, Error = Error> {
+        self.mds.list_versions(id, parent)
+    }
+
+    /// オブジェクトの一部分を取得する。
+    ///
+    /// `ranges`のうち充足可能なもののみが結果に含まれる。
+    pub fn get_range(
+        &self,
+        id: ObjectId,
+        ranges: Vec<ByteRange>,
+        consistency: ReadConsistency,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<PartialObjectValue>, Error = Error> {
+        let storage = self.storage.clone();
+        self.mds
+            .get_with_user_metadata(id, consistency, parent.clone())
+            .and_then(move |object| {
+                if let Some((object, user_metadata)) = object {
+                    let version = object.version;
+                    let future: BoxFuture<_> = if is_manifest(&storage, &object) {
+                        get_manifest_range(storage, object, ranges, deadline, parent)
+                    } else if is_inline(&storage, &object) {
+                        Box::new(
+                            get_content(storage, object, deadline, parent)
+                                .map(move |content| extract_parts(&content, &ranges)),
+                        )
+                    } else {
+                        storage.get_range(object, ranges, deadline, parent)
+                    };
+                    let future = future
+                        .map(move |(size, parts)| PartialObjectValue {
+                            version,
+                            size,
+                            user_metadata,
+                            parts,
+                        })
+                        .map(Some);
+                    Either::A(future)
+                } else {
+                    Either::B(futures::finished(None))
+                }
+            })
+    }
+
     /// オブジェクトの存在確認を行う。
     pub fn head(
         &self,
@@ -74,11 +228,25 @@ impl Client {
         self.mds.head(id, parent)
     }
 
+    /// オブジェクトの存在確認を行い、バージョンと利用者定義のメタデータを返す。
+    pub fn head_with_user_metadata(
+        &self,
+        id: ObjectId,
+        consistency: ReadConsistency,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<(ObjectVersion, UserMetadata)>, Error = Error> {
+        self.mds.head_with_user_metadata(id, consistency, parent)
+    }
+
     /// オブジェクトを保存する。
+    ///
+    /// `attributes`(利用者定義のメタデータや有効期限)は、オブジェクトのバージョンと共にMDSに保存される。
+    /// 中身のサイズがバケツのインライン化の閾値未満の場合には、中身もMDSに保存され、ストレージには何も書き込まれない。
     pub fn put(
         &self,
         id: ObjectId,
         mut content: Vec<u8>,
+        attributes: ObjectAttributes,
         deadline: Deadline,
         expect: Expect,
         parent: SpanHandle,
@@ -86,20 +254,694 @@ impl Client {
         // TODO: mdsにdeadlineを渡せるようにする
         // (repairのトリガー時間の判断用)
         let storage = self.storage.clone();
+        let inlined = self.is_inline_target(&id, &content);
         let metadata = if self.storage.is_metadata() {
             mem::replace(&mut content, Vec::new())
+        } else if inlined {
+            inline::encode(&mem::replace(&mut content, Vec::new()))
         } else {
             Vec::new()
         };
         self.mds
-            .put(id, metadata, expect, deadline, parent.clone())
+            .put_with_attributes(id, metadata, attributes, expect, deadline, parent.clone())
             .and_then(move |(version, created)| {
-                storage
+                if inlined {
+                    return Either::A(futures::finished((version, created)));
+                }
+                let future = storage
                     .put(version, content, deadline, parent)
-                    .map(move |()| (version, created))
+                    .map(move |()| (version, created));
+                Either::B(future)
             })
     }
 
+    /// ストリームとして与えられた中身を持つオブジェクトを保存する。
+    ///
+    /// 中身が`CHUNK_SIZE`を超える場合には、チャンク毎に到着次第パートとして保存(符号化)され、
+    /// 最後にパート一覧がマニフェストとして`id`に保存される。
+    /// そうではない場合には`put`と同様に保存される。
+    pub fn put_stream(
+        &self,
+        id: ObjectId,
+        content: ContentStream,
+        attributes: ObjectAttributes,
+        deadline: Deadline,
+        expect: Expect,
+        parent: SpanHandle,
+    ) -> impl Future<Item = (ObjectVersion, bool), Error = Error> {
+        let this = self.clone();
+        if self.storage.is_metadata() {
+            let future = content.concat2().and_then(move |content| {
+                this.put(id, content, attributes, deadline, expect, parent)
+            });
+            return Either::A(future);
+        }
+        let future = Chunks::new(content, CHUNK_SIZE)
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(first, rest)| {
+                rest.into_future()
+                    .map_err(|(e, _)| e)
+                    .map(move |(second, rest)| (first, second, rest))
+            })
+            .and_then(move |(first, second, rest)| {
+                let first = first.unwrap_or_default();
+                if let Some(second) = second {
+                    let chunks = futures::stream::iter_ok(vec![first, second]).chain(rest);
+                    Either::A(this.put_chunks(id, chunks, attributes, deadline, expect, parent))
+                } else {
+                    Either::B(this.put(id, first, attributes, deadline, expect, parent))
+                }
+            });
+        Either::B(future)
+    }
+
+    fn put_chunks<S>(
+        &self,
+        id: ObjectId,
+        chunks: S,
+        attributes: ObjectAttributes,
+        deadline: Deadline,
+        expect: Expect,
+        parent: SpanHandle,
+    ) -> BoxFuture<(ObjectVersion, bool)>
+    where
+        S: Stream<Item = Vec<u8>, Error = Error> + Send + 'static,
+    {
+        let this = self.clone();
+        let upload_id = UploadId(rand::random());
+        let record = UploadRecord::new(id.clone());
+        let future = self
+            .mds
+            .put(
+                upload_id.record_id(),
+                record.to_bytes(),
+                Expect::None,
+                deadline,
+                parent.clone(),
+            )
+            .and_then(move |_| {
+                let parts_this = this.clone();
+                let parts_parent = parent.clone();
+                chunks
+                    .zip(futures::stream::iter_ok(1..))
+                    .and_then(move |(chunk, number)| {
+                        if number > MAX_PART_NUMBER {
+                            let e = ErrorKind::Invalid.cause(format!(
+                                "Too many chunks (the maximum object size is {} bytes)",
+                                u64::from(MAX_PART_NUMBER) * CHUNK_SIZE as u64
+                            ));
+                            return Either::A(futures::failed(track!(Error::from(e))));
+                        }
+                        let size = chunk.len() as u64;
+                        let part_attributes = multipart::part_attributes(chunk.len());
+                        let future = parts_this
+                            .put(
+                                upload_id.part_id(number),
+                                chunk,
+                                part_attributes,
+                                deadline,
+                                Expect::Any,
+                                parts_parent.clone(),
+                            )
+                            .map(move |(version, _)| ((number, version), size));
+                        Either::B(future)
+                    })
+                    .collect()
+                    .map(move |parts| (this, record, parts, parent))
+            })
+            .and_then(move |(this, record, parts, parent)| {
+                let (parts, part_sizes): (Vec<_>, Vec<_>) = parts.into_iter().unzip();
+                let manifest = Manifest {
+                    upload_id,
+                    parts,
+                    part_sizes: Some(part_sizes),
+                };
+                this.finish_upload(id, manifest, record, attributes, deadline, expect, parent)
+            });
+        Box::new(future)
+    }
+
+    /// オブジェクトを複製する。
+    ///
+    /// `source`は複製元のオブジェクトを保持するセグメントのクライアント(`self`と同じでも良い)。
+    /// 双方のストレージの保存形式が同一であれば、中身は復号や再符号化を経ずにlump単位でそのまま複製される。
+    /// そうではない場合や、複数チャンクから構成されるオブジェクトの場合には、
+    /// 複製元から取得した中身を`put_stream`と同様に改めて保存する。
+    ///
+    /// `attributes`の利用者定義のメタデータが空の場合には、複製元のものが引き継がれる
+    /// (有効期限は引き継がれないので、必要であれば`attributes`で指定する)。
+    /// 複製元が存在しない場合には`None`が返される。
+    #[allow(clippy::too_many_arguments)]
+    pub fn copy(
+        &self,
+        source: &Client,
+        source_id: ObjectId,
+        id: ObjectId,
+        mut attributes: ObjectAttributes,
+        deadline: Deadline,
+        expect: Expect,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<(ObjectVersion, bool)>, Error = Error> {
+        let this = self.clone();
+        let source_storage = source.storage.clone();
+        source
+            .mds
+            .get_with_user_metadata(source_id, ReadConsistency::default(), parent.clone())
+            .and_then(move |object| {
+                let (object, user_metadata) = if let Some(object) = object {
+                    object
+                } else {
+                    return Either::A(futures::finished(None));
+                };
+                if attributes.user_metadata.is_empty() {
+                    attributes.user_metadata = user_metadata;
+                }
+                let future: BoxFuture<_> = if is_manifest(&source_storage, &object)
+                    || is_inline(&source_storage, &object)
+                    || !this.storage.can_copy_lumps_from(&source_storage)
+                {
+                    let content = content_stream(source_storage, object, deadline, parent.clone());
+                    Box::new(this.put_stream(id, content, attributes, deadline, expect, parent))
+                } else {
+                    let storage = this.storage.clone();
+                    let source_version = object.version;
+                    Box::new(
+                        this.mds
+                            .put_with_attributes(
+                                id,
+                                Vec::new(),
+                                attributes,
+                                expect,
+                                deadline,
+                                parent.clone(),
+                            )
+                            .and_then(move |(version, created)| {
+                                storage
+                                    .copy_lumps_from(
+                                        source_storage,
+                                        source_version,
+                                        version,
+                                        deadline,
+                                        parent,
+                                    )
+                                    .map(move |()| (version, created))
+                            }),
+                    )
+                };
+                Either::B(future.map(Some))
+            })
+    }
+
+    /// セグメント内でオブジェクトのIDを`from`から`to`に(アトミックに)変更する。
+    ///
+    /// バージョンおよび中身はそのまま引き継がれるので、データの移動は発生しない。
+    /// `expect`は変更先のオブジェクトに対して検査され、変更先に既存のオブジェクトがあれば上書きされる。
+    ///
+    /// 結果として、変更後のバージョンと、変更先に新規にオブジェクトが作成されたかどうかの組を返す。
+    /// 変更元が存在しない場合には`None`が返される。
+    pub fn rename(
+        &self,
+        from: ObjectId,
+        to: ObjectId,
+        deadline: Deadline,
+        expect: Expect,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<(ObjectVersion, bool)>, Error = Error> {
+        let this = self.clone();
+        self.mds
+            .get(from.clone(), parent.clone())
+            .and_then(move |object| {
+                let object = if let Some(object) = object {
+                    object
+                } else {
+                    return Either::A(futures::finished(None));
+                };
+
+                // マルチパートアップロードで作成されたオブジェクトの場合には、
+                // パート群がガベージコレクションされないように、変更後のIDをアップロード記録に反映しておく
+                let prepare: BoxFuture<_> = if is_manifest(&this.storage, &object) {
+                    let manifest = match track!(Manifest::from_bytes(&object.content)) {
+                        Ok(manifest) => manifest,
+                        Err(e) => return Either::B(Either::A(futures::failed(e))),
+                    };
+                    Box::new(this.rename_upload_record(
+                        manifest.upload_id,
+                        from.clone(),
+                        to.clone(),
+                        deadline,
+                        parent.clone(),
+                    ))
+                } else {
+                    Box::new(futures::finished(()))
+                };
+                let mds = this.mds.clone();
+                let future = prepare
+                    .and_then(move |()| mds.rename(from, to, expect, parent))
+                    .map(|renamed| renamed.map(|(version, old)| (version, old.is_none())));
+                Either::B(Either::B(future))
+            })
+    }
+
+    // アップロード記録の対象オブジェクトのIDを変更する (変更前のIDも参照確認用に保持される)
+    fn rename_upload_record(
+        &self,
+        upload_id: UploadId,
+        from: ObjectId,
+        to: ObjectId,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> impl Future<Item = (), Error = Error> {
+        let mds = self.mds.clone();
+        self.mds
+            .get(upload_id.record_id(), parent.clone())
+            .and_then(move |object| {
+                let object = track!(object.ok_or_else(
+                    || ErrorKind::Corrupted.cause(format!("No such upload: {}", upload_id))
+                ))?;
+                let mut record = track!(UploadRecord::from_bytes(&object.content))?;
+                if !record.former_object_ids.contains(&from) {
+                    record.former_object_ids.push(from);
+                }
+                record.object_id = to;
+                Ok((record, object.version))
+            })
+            .and_then(move |(record, version)| {
+                mds.put(
+                    upload_id.record_id(),
+                    record.to_bytes(),
+                    Expect::IfMatch(vec![version]),
+                    deadline,
+                    parent,
+                )
+                .map(|_| ())
+            })
+    }
+
+    /// マルチパートアップロードを開始する。
+    pub fn initiate_upload(
+        &self,
+        id: ObjectId,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> impl Future<Item = UploadId, Error = Error> {
+        let upload_id = UploadId(rand::random());
+        let record = UploadRecord::new(id);
+        self.mds
+            .put(
+                upload_id.record_id(),
+                record.to_bytes(),
+                Expect::None,
+                deadline,
+                parent,
+            )
+            .map(move |_| upload_id)
+    }
+
+    /// マルチパートアップロードのパートを保存する。
+    ///
+    /// パートは、内部用のIDを持つ通常のオブジェクトとして保存される。
+    /// 対象のアップロードが存在しない場合には`None`が返される。
+    pub fn upload_part(
+        &self,
+        id: ObjectId,
+        upload_id: UploadId,
+        part_number: u32,
+        content: Vec<u8>,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
+        let this = self.clone();
+        let valid = if part_number == 0 || part_number > MAX_PART_NUMBER {
+            Err(track!(ErrorKind::Invalid.cause(format!(
+                "Part number must be between 1 and {}: {}",
+                MAX_PART_NUMBER, part_number
+            )))
+            .into())
+        } else {
+            Ok(())
+        };
+        futures::future::result(valid)
+            .and_then(move |()| {
+                this.get_upload_record(id, upload_id, parent.clone())
+                    .map(|r| (this, r, parent))
+            })
+            .and_then(move |(this, record, parent)| {
+                if record.is_none() {
+                    return Either::A(futures::finished(None));
+                }
+                let attributes = multipart::part_attributes(content.len());
+                let future = this
+                    .put(
+                        upload_id.part_id(part_number),
+                        content,
+                        attributes,
+                        deadline,
+                        Expect::Any,
+                        parent,
+                    )
+                    .map(|(version, _)| Some(version));
+                Either::B(future)
+            })
+    }
+
+    /// マルチパートアップロードを完了する。
+    ///
+    /// `part_numbers`で指定された順番にパートを連結したものが、`id`に対応するオブジェクトの中身となる。
+    /// 対象のアップロードが存在しない場合には`None`が返される。
+    #[allow(clippy::too_many_arguments)]
+    pub fn complete_upload(
+        &self,
+        id: ObjectId,
+        upload_id: UploadId,
+        part_numbers: Vec<u32>,
+        attributes: ObjectAttributes,
+        deadline: Deadline,
+        expect: Expect,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<(ObjectVersion, bool)>, Error = Error> {
+        let this = self.clone();
+        let mds = self.mds.clone();
+        let parts_parent = parent.clone();
+        self.get_upload_record(id.clone(), upload_id, parent.clone())
+            .and_then(move |record| {
+                let record = if let Some(record) = record {
+                    record
+                } else {
+                    return Either::A(futures::finished(None));
+                };
+                if part_numbers.is_empty() {
+                    let e = ErrorKind::Invalid.cause("At least one part is required");
+                    return Either::B(Either::A(futures::failed(track!(Error::from(e)))));
+                }
+                let future = futures::stream::iter_ok(part_numbers)
+                    .and_then(move |number| {
+                        mds.head_with_user_metadata(
+                            upload_id.part_id(number),
+                            ReadConsistency::default(),
+                            parts_parent.clone(),
+                        )
+                        .and_then(move |part| {
+                            let (version, user_metadata) = track!(part.ok_or_else(|| {
+                                ErrorKind::Invalid.cause(format!("No such part: {}", number))
+                            }))?;
+                            Ok(((number, version), multipart::part_size(&user_metadata)))
+                        })
+                    })
+                    .collect()
+                    .and_then(move |parts: Vec<_>| {
+                        let (parts, part_sizes): (Vec<_>, Vec<_>) = parts.into_iter().unzip();
+                        let manifest = Manifest {
+                            upload_id,
+                            parts,
+                            part_sizes: part_sizes.into_iter().collect(),
+                        };
+                        this.finish_upload(
+                            id, manifest, record, attributes, deadline, expect, parent,
+                        )
+                    })
+                    .map(Some);
+                Either::B(Either::B(future))
+            })
+    }
+
+    /// マルチパートアップロードを中止する。
+    ///
+    /// アップロード記録とアップロード済みのパート群が削除される。
+    /// 対象のアップロードが存在しない場合には`false`が返される。
+    pub fn abort_upload(
+        &self,
+        id: ObjectId,
+        upload_id: UploadId,
+        parent: SpanHandle,
+    ) -> impl Future<Item = bool, Error = Error> {
+        let mds = self.mds.clone();
+        self.get_upload_record(id, upload_id, parent.clone())
+            .and_then(move |record| {
+                if record.is_none() {
+                    return Either::A(futures::finished(false));
+                }
+                let future = mds
+                    .delete_by_prefix(upload_id.prefix(), parent)
+                    .map(|_| true);
+                Either::B(future)
+            })
+    }
+
+    /// 不要になったマルチパートアップロードのパート群を削除する。
+    ///
+    /// 以下のいずれかに該当するアップロードが削除対象となる:
+    ///
+    /// - 完了済みだが、対応するオブジェクトが削除あるいは上書きされている
+    ///   (バージョン管理が有効な場合には、保持されている過去バージョンからも参照されていない)
+    /// - 開始から`expiration_secs`秒以上経過しているが、完了していない
+    ///
+    /// 結果として、削除されたアップロードの数を返す。
+    pub fn collect_garbage_uploads(
+        &self,
+        expiration_secs: u64,
+        parent: SpanHandle,
+    ) -> impl Future<Item = u64, Error = Error> {
+        let mds = self.mds.clone();
+        self.mds
+            .list()
+            .map(|summaries| {
+                summaries
+                    .into_iter()
+                    .filter_map(|s| UploadId::from_record_id(&s.id))
+                    .collect::<Vec<_>>()
+            })
+            .and_then(move |upload_ids| {
+                futures::stream::iter_ok(upload_ids)
+                    .and_then(move |upload_id| {
+                        let mds0 = mds.clone();
+                        let mds1 = mds.clone();
+                        let parent0 = parent.clone();
+                        let parent1 = parent.clone();
+                        mds.get(upload_id.record_id(), parent.clone())
+                            .and_then(move |record| {
+                                let record =
+                                    match record.map(|r| UploadRecord::from_bytes(&r.content)) {
+                                        Some(Ok(record)) => record,
+                                        _ => return Either::A(futures::finished(None)),
+                                    };
+                                let object_ids = record.object_ids();
+                                let future = is_upload_in_use(mds0, object_ids, upload_id, parent0)
+                                    .map(move |in_use| {
+                                        if !in_use
+                                            && (record.completed
+                                                || record.is_expired(expiration_secs))
+                                        {
+                                            Some(upload_id)
+                                        } else {
+                                            None
+                                        }
+                                    });
+                                Either::B(future)
+                            })
+                            .and_then(move |garbage| {
+                                if let Some(upload_id) = garbage {
+                                    let future = mds1
+                                        .delete_by_prefix(upload_id.prefix(), parent1)
+                                        .map(|_| 1);
+                                    Either::A(future)
+                                } else {
+                                    Either::B(futures::finished(0))
+                                }
+                            })
+                    })
+                    .fold(0, |total, n| -> Result<_, Error> { Ok(total + n) })
+            })
+    }
+
+    // マニフェストを保存し、アップロードを完了済みにする
+    #[allow(clippy::too_many_arguments)]
+    fn finish_upload(
+        &self,
+        id: ObjectId,
+        manifest: Manifest,
+        mut record: UploadRecord,
+        attributes: ObjectAttributes,
+        deadline: Deadline,
+        expect: Expect,
+        parent: SpanHandle,
+    ) -> impl Future<Item = (ObjectVersion, bool), Error = Error> {
+        let mds = self.mds.clone();
+        let upload_id = manifest.upload_id;
+        self.mds
+            .put_with_attributes(
+                id,
+                manifest.to_bytes(),
+                attributes,
+                expect,
+                deadline,
+                parent.clone(),
+            )
+            .and_then(move |result| {
+                record.completed = true;
+                mds.put(
+                    upload_id.record_id(),
+                    record.to_bytes(),
+                    Expect::Any,
+                    deadline,
+                    parent,
+                )
+                .map(move |_| result)
+            })
+    }
+
+    fn get_upload_record(
+        &self,
+        id: ObjectId,
+        upload_id: UploadId,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<UploadRecord>, Error = Error> {
+        self.mds
+            .get(upload_id.record_id(), parent)
+            .and_then(move |object| {
+                let object = if let Some(object) = object {
+                    object
+                } else {
+                    return Ok(None);
+                };
+                let record = track!(UploadRecord::from_bytes(&object.content))?;
+                track_assert_eq!(
+                    record.object_id,
+                    id,
+                    ErrorKind::Invalid,
+                    "Upload {} belongs to another object",
+                    upload_id
+                );
+                Ok(Some(record))
+            })
+    }
+
+    /// 複数の操作をまとめて実行する。
+    ///
+    /// MDSに対する操作群は一つのRaftの提案としてコミットされる(参照系の操作のみの場合には提案は行われない)。
+    /// 保存対象の中身は`MAX_BATCH_CONTENT_SIZE`以下である必要があり、それを超える操作のみが失敗となる。
+    /// 中身の合計が`MAX_BATCH_TOTAL_CONTENT_SIZE`を超える場合には、全体が失敗となる。
+    /// 結果は操作毎に、指定された順番通りに返される。
+    pub fn batch(
+        &self,
+        operations: Vec<BatchOperation>,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Vec<Result<BatchResult, Error>>, Error = Error> {
+        if let Err(e) = track!(check_batch_total_content_size(&operations)) {
+            return Either::A(futures::failed(e));
+        }
+
+        // MDSに送らずに失敗とした操作は`Err`、それ以外はストレージに保存すべき中身(があればそれ)を保持する
+        let mut slots = Vec::with_capacity(operations.len());
+        let mut mds_operations = Vec::with_capacity(operations.len());
+        for mut operation in operations {
+            let mut content = None;
+            if let BatchOperation::Put {
+                ref object_id,
+                ref mut data,
+                ..
+            } = operation
+            {
+                if data.len() > MAX_BATCH_CONTENT_SIZE {
+                    let e = ErrorKind::Invalid.cause(format!(
+                        "Too large content for a batch: {} bytes (the maximum is {} bytes)",
+                        data.len(),
+                        MAX_BATCH_CONTENT_SIZE
+                    ));
+                    slots.push(Err(track!(Error::from(e))));
+                    continue;
+                }
+                content = self.take_content(object_id, data);
+            }
+            slots.push(Ok(content));
+            mds_operations.push(operation);
+        }
+
+        let storage = self.storage.clone();
+        let future = self
+            .mds
+            .batch(mds_operations, deadline, parent.clone())
+            .and_then(move |results| {
+                let mut results = results.into_iter();
+                let items = slots.into_iter().map(|slot| {
+                    let result = slot.and_then(|content| {
+                        let result = track_assert_some!(
+                            results.next(),
+                            ErrorKind::Other,
+                            "Too few results from MDS"
+                        );
+                        result.map(|result| (result, content))
+                    });
+                    let future: BoxFuture<_> = match result {
+                        Err(e) => Box::new(futures::failed(e)),
+                        Ok((result, content)) => {
+                            complete_batch_result(&storage, result, content, deadline, &parent)
+                        }
+                    };
+                    future.then(|result| -> Result<_, Error> { Ok(result) })
+                });
+                futures::future::join_all(items.collect::<Vec<_>>())
+            });
+        Either::B(future)
+    }
+
+    /// 同一セグメント内の複数のオブジェクトに対する保存ないし削除をアトミックに実行する。
+    ///
+    /// 全ての操作の`Expect`が満たされた場合にのみMDSに適用され、一つでも満たされない場合には何も変更されない。
+    /// 参照系の操作を含めることはできず、保存対象の中身はバッチ操作と同じ制限(`MAX_BATCH_CONTENT_SIZE`等)に従う必要がある。
+    /// 中身のストレージへの保存はMDSへのコミット後に行われる(`put`と同様)。
+    pub fn transaction(
+        &self,
+        operations: Vec<BatchOperation>,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Vec<BatchResult>, Error = Error> {
+        if let Err(e) = track!(check_batch_total_content_size(&operations)) {
+            return Either::A(futures::failed(e));
+        }
+
+        let mut contents = Vec::with_capacity(operations.len());
+        let mut mds_operations = Vec::with_capacity(operations.len());
+        for mut operation in operations {
+            let mut content = None;
+            if let BatchOperation::Put {
+                ref object_id,
+                ref mut data,
+                ..
+            } = operation
+            {
+                if data.len() > MAX_BATCH_CONTENT_SIZE {
+                    let e = ErrorKind::Invalid.cause(format!(
+                        "Too large content for a transaction: {} bytes (the maximum is {} bytes)",
+                        data.len(),
+                        MAX_BATCH_CONTENT_SIZE
+                    ));
+                    return Either::A(futures::failed(track!(Error::from(e))));
+                }
+                content = self.take_content(object_id, data);
+            }
+            contents.push(content);
+            mds_operations.push(operation);
+        }
+
+        let storage = self.storage.clone();
+        let future = self
+            .mds
+            .transaction(mds_operations, deadline, parent.clone())
+            .and_then(move |results| {
+                let items = results
+                    .into_iter()
+                    .zip(contents)
+                    .map(|(result, content)| {
+                        complete_batch_result(&storage, result, content, deadline, &parent)
+                    })
+                    .collect::<Vec<_>>();
+                futures::future::join_all(items)
+            });
+        Either::B(future)
+    }
+
     /// オブジェクトを削除する。
     pub fn delete(
         &self,
@@ -148,6 +990,19 @@ impl Client {
         self.mds.list()
     }
 
+    /// セグメント内のオブジェクト一覧を、IDの辞書順にページ単位で取得する。
+    ///
+    /// 対象となるのは、IDが`prefix`から始まり、かつ`start_after`よりも後ろのオブジェクトのみ。
+    pub fn list_page(
+        &self,
+        prefix: ObjectPrefix,
+        start_after: Option<ObjectId>,
+        limit: usize,
+        consistency: ReadConsistency,
+    ) -> impl Future<Item = ObjectPage, Error = Error> {
+        self.mds.list_page(prefix, start_after, limit, consistency)
+    }
+
     /// セグメント内の最新オブジェクトのバージョンを取得する。
     pub fn latest(&self) -> impl Future<Item = Option<ObjectSummary>, Error = Error> {
         self.mds.latest()
@@ -157,4 +1012,328 @@ impl Client {
     pub fn object_count(&self) -> impl Future<Item = u64, Error = Error> {
         self.mds.object_count()
     }
+
+    // 中身をインライン化してMDSに保存すべきかどうかを判定する
+    //
+    // NOTE:
+    // - マルチパートアップロードのパートは、マニフェストからバージョンのみで参照されるのでインライン化しない
+    // - MDSに保存されるデータは暗号化されないので、暗号化が有効なバケツではインライン化しない
+    fn is_inline_target(&self, id: &ObjectId, content: &[u8]) -> bool {
+        !self.storage.is_metadata()
+            && content.len() < self.inline_threshold
+            && !is_internal_object_id(id)
+            && self.storage.encryption_key_id().is_none()
+    }
+
+    // バッチ内の保存操作の中身を、MDSに送るデータとストレージに保存すべき中身に振り分ける
+    // (後者は、中身がMDSに保存される場合には`None`となる)
+    fn take_content(&self, id: &ObjectId, data: &mut Vec<u8>) -> Option<Vec<u8>> {
+        if self.storage.is_metadata() {
+            None
+        } else if self.is_inline_target(id, data) {
+            let encoded = inline::encode(data);
+            *data = encoded;
+            None
+        } else {
+            Some(mem::replace(data, Vec::new()))
+        }
+    }
+}
+
+// いずれかのオブジェクトの保持されているいずれかのバージョン(過去バージョンを含む)が、
+// 指定のアップロードをマニフェストとして参照している場合には`true`を返す
+fn is_upload_in_use(
+    mds: MdsClient,
+    ids: Vec<ObjectId>,
+    upload_id: UploadId,
+    parent: SpanHandle,
+) -> impl Future<Item = bool, Error = Error> {
+    futures::stream::iter_ok(ids)
+        .and_then(move |id| {
+            let mds = mds.clone();
+            let parent = parent.clone();
+            mds.list_versions(id.clone(), parent.clone())
+                .map(move |versions| (mds, id, versions, parent))
+        })
+        .map(|(mds, id, versions, parent)| {
+            futures::stream::iter_ok(versions)
+                .and_then(move |entry| mds.get_version(id.clone(), entry.version, parent.clone()))
+        })
+        .flatten()
+        .fold(false, move |in_use, object| -> Result<_, Error> {
+            let refers = object.map_or(false, |(o, _)| {
+                Manifest::from_bytes(&o.content)
+                    .map(|m| m.upload_id == upload_id)
+                    .unwrap_or(false)
+            });
+            Ok(in_use || refers)
+        })
+}
+
+// バッチ操作の中身の合計サイズが上限以下であることを確認する
+fn check_batch_total_content_size(operations: &[BatchOperation]) -> Result<(), Error> {
+    let total = operations
+        .iter()
+        .map(|operation| match *operation {
+            BatchOperation::Put { ref data, .. } => data.len(),
+            _ => 0,
+        })
+        .sum::<usize>();
+    track_assert!(
+        total <= MAX_BATCH_TOTAL_CONTENT_SIZE,
+        ErrorKind::Invalid,
+        "Too large contents for a batch: {} bytes in total (the maximum is {} bytes)",
+        total,
+        MAX_BATCH_TOTAL_CONTENT_SIZE
+    );
+    Ok(())
+}
+
+// MDSでの操作結果を受けて、中身の取得ないし保存を行う
+fn complete_batch_result(
+    storage: &StorageClient,
+    result: MdsBatchResult,
+    content: Option<Vec<u8>>,
+    deadline: Deadline,
+    parent: &SpanHandle,
+) -> BoxFuture<BatchResult> {
+    match result {
+        MdsBatchResult::Get(Some((metadata, user_metadata))) => {
+            let version = metadata.version;
+            let object = ObjectValue {
+                version,
+                content: metadata.data,
+            };
+            let future = get_content(storage.clone(), object, deadline, parent.clone()).map(
+                move |content| {
+                    BatchResult::Get(Some((ObjectValue { version, content }, user_metadata)))
+                },
+            );
+            Box::new(future)
+        }
+        MdsBatchResult::Get(None) => Box::new(futures::finished(BatchResult::Get(None))),
+        MdsBatchResult::Head(object) => Box::new(futures::finished(BatchResult::Head(object))),
+        MdsBatchResult::Put(version, old) => {
+            let result = BatchResult::Put(version, old.is_none());
+            if let Some(content) = content {
+                let future = storage
+                    .clone()
+                    .put(version, content, deadline, parent.clone())
+                    .map(move |()| result);
+                Box::new(future)
+            } else {
+                Box::new(futures::finished(result))
+            }
+        }
+        MdsBatchResult::Delete(old) => Box::new(futures::finished(BatchResult::Delete(old))),
+    }
+}
+
+fn is_manifest(storage: &StorageClient, object: &ObjectValue) -> bool {
+    !storage.is_metadata() && Manifest::is_manifest(&object.content)
+}
+
+fn is_inline(storage: &StorageClient, object: &ObjectValue) -> bool {
+    !storage.is_metadata() && inline::is_inline(&object.content)
+}
+
+// マニフェストの場合には、各パートを順番に取得して連結する
+// (インライン化されている場合には、ストレージにはアクセスせずにMDSのデータから取り出す)
+fn get_content(
+    storage: StorageClient,
+    object: ObjectValue,
+    deadline: Deadline,
+    parent: SpanHandle,
+) -> BoxFuture<Vec<u8>> {
+    if is_inline(&storage, &object) {
+        return Box::new(futures::future::result(track!(inline::decode(
+            object.content
+        ))));
+    }
+    if !is_manifest(&storage, &object) {
+        return storage.get(object, deadline, parent);
+    }
+    let future = content_stream(storage, object, deadline, parent).fold(
+        Vec::new(),
+        |mut content, part| -> Result<_, Error> {
+            content.extend_from_slice(&part);
+            Ok(content)
+        },
+    );
+    Box::new(future)
+}
+
+// マニフェストの範囲指定での取得では、要求された範囲と重なるパートのみを順番に取得して、範囲の部分を組み立てる
+// (パートのサイズが記録されていない古いマニフェストの場合には、全てのパートを取得する)
+fn get_manifest_range(
+    storage: StorageClient,
+    object: ObjectValue,
+    ranges: Vec<ByteRange>,
+    deadline: Deadline,
+    parent: SpanHandle,
+) -> BoxFuture<(u64, Vec<(Range<u64>, Vec<u8>)>)> {
+    let manifest = match track!(Manifest::from_bytes(&object.content)) {
+        Ok(manifest) => manifest,
+        Err(e) => return Box::new(futures::failed(e)),
+    };
+    let sizes = if let Some(sizes) = manifest.part_sizes {
+        sizes
+    } else {
+        let future = get_content(storage, object, deadline, parent)
+            .map(move |content| extract_parts(&content, &ranges));
+        return Box::new(future);
+    };
+
+    let part_ranges = part_ranges(&sizes);
+    let size = part_ranges.last().map_or(0, |r| r.end);
+    let ranges = resolve_ranges(&ranges, size);
+    let needed = manifest
+        .parts
+        .into_iter()
+        .zip(part_ranges)
+        .filter(|&(_, ref p)| ranges.iter().any(|r| r.start < p.end && p.start < r.end))
+        .map(|((_, version), p)| (version, p))
+        .collect::<Vec<_>>();
+    let future = futures::stream::iter_ok(needed)
+        .and_then(move |(version, range)| {
+            let part = ObjectValue {
+                version,
+                content: Vec::new(),
+            };
+            storage
+                .clone()
+                .get(part, deadline, parent.clone())
+                .and_then(move |content| {
+                    track_assert_eq!(
+                        content.len() as u64,
+                        range.end - range.start,
+                        ErrorKind::Corrupted,
+                        "Unexpected part size: version={:?}",
+                        version
+                    );
+                    Ok((range, content))
+                })
+        })
+        .collect()
+        .map(move |parts| (size, assemble_parts(&parts, ranges)));
+    Box::new(future)
+}
+
+// マニフェストの場合には、各パートを(前のパートが消費された後に)順番に取得するストリームを返す
+fn content_stream(
+    storage: StorageClient,
+    object: ObjectValue,
+    deadline: Deadline,
+    parent: SpanHandle,
+) -> ContentStream {
+    if is_inline(&storage, &object) {
+        return Box::new(futures::stream::once(track!(inline::decode(
+            object.content
+        ))));
+    }
+    if !is_manifest(&storage, &object) {
+        return Box::new(storage.get(object, deadline, parent).into_stream());
+    }
+    let manifest = match track!(Manifest::from_bytes(&object.content)) {
+        Ok(manifest) => manifest,
+        Err(e) => return Box::new(futures::stream::once(Err(e))),
+    };
+    let stream = futures::stream::iter_ok(manifest.parts).and_then(move |(_, version)| {
+        let part = ObjectValue {
+            version,
+            content: Vec::new(),
+        };
+        storage.clone().get(part, deadline, parent.clone())
+    });
+    Box::new(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use rustracing_jaeger::span::Span;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use trackable::result::TestResult;
+
+    use super::*;
+    use test_util::tests::{wait, System};
+
+    // MDSのリーダが選出されて、要求を処理可能になるまで待つ
+    fn wait_for_mds(client: &Client) -> Result<(), Error> {
+        let started_at = Instant::now();
+        loop {
+            let result = wait(client.head("dummy".to_owned(), Span::inactive().handle()));
+            if result.is_ok() {
+                return Ok(());
+            }
+            track_assert!(
+                started_at.elapsed() < Duration::from_secs(60),
+                ErrorKind::Other,
+                "MDS is unavailable: {:?}",
+                result
+            );
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn ranged_read_of_streamed_object_works() -> TestResult {
+        let mut system = track!(System::new(5))?;
+        let client = track!(system.boot_segment(5))?;
+        track!(wait_for_mds(&client))?;
+
+        // 到着する断片の大きさに関わらず、`CHUNK_SIZE`毎のパートとして保存される
+        let id = "foo".to_owned();
+        let content = (0..CHUNK_SIZE * 2 + 10)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let pieces = content
+            .chunks(1024 * 1024)
+            .map(|c| c.to_vec())
+            .collect::<Vec<_>>();
+        let stream: ContentStream = Box::new(futures::stream::iter_ok(pieces));
+        track!(wait(client.put_stream(
+            id.clone(),
+            stream,
+            ObjectAttributes::default(),
+            Deadline::Infinity,
+            Expect::Any,
+            Span::inactive().handle(),
+        )))?;
+
+        let object = track!(wait(client.mds.get(id.clone(), Span::inactive().handle())))?;
+        let object = track_assert_some!(object, ErrorKind::Other);
+        assert!(is_manifest(&client.storage, &object));
+        let manifest = track!(Manifest::from_bytes(&object.content))?;
+        let chunk_size = CHUNK_SIZE as u64;
+        assert_eq!(manifest.parts.len(), 3);
+        assert_eq!(manifest.part_sizes, Some(vec![chunk_size, chunk_size, 10]));
+
+        // パートの境界を跨ぐ範囲と、最後のパートのみに含まれる範囲
+        let ranges = vec![
+            ByteRange::FromTo(chunk_size - 5, chunk_size + 4),
+            ByteRange::Suffix(3),
+        ];
+        let value = track!(wait(client.get_range(
+            id,
+            ranges,
+            ReadConsistency::default(),
+            Deadline::Infinity,
+            Span::inactive().handle(),
+        )))?;
+        let value = track_assert_some!(value, ErrorKind::Other);
+        let size = content.len() as u64;
+        assert_eq!(value.size, size);
+        assert_eq!(
+            value.parts,
+            vec![
+                (
+                    chunk_size - 5..chunk_size + 5,
+                    content[CHUNK_SIZE - 5..CHUNK_SIZE + 5].to_vec(),
+                ),
+                (size - 3..size, content[content.len() - 3..].to_vec()),
+            ]
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,372 @@
+//! GET時に欠損や破損が見つかった複製・フラグメントを、非同期に修復(read-repair)するための処理。
+use cannyls::lump::LumpId;
+use fibers::sync::mpsc;
+use futures::future::Either;
+use futures::{self, Future};
+use libfrugalos::entity::object::ObjectVersion;
+use prometrics::metrics::{Counter, MetricBuilder};
+use slog::Logger;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use config::ClusterMember;
+use Error;
+
+/// 同時に実行される修復の数の上限。
+const MAX_REPAIRS_IN_FLIGHT: usize = 32;
+
+/// 一つのデバイスに対して同時に実行される修復の数の上限。
+const MAX_REPAIRS_PER_DEVICE: usize = 2;
+
+/// 修復に失敗したデバイスに対して、修復を再開するまでの待ち時間(秒)。
+const FAILED_DEVICE_BACKOFF_SECS: u64 = 60;
+
+/// `ReadRepairer`が発行し、セグメントサービスが実行する修復処理。
+pub(crate) type RepairTask = Box<Future<Item = (), Error = ()> + Send + 'static>;
+
+/// 修復対象のバージョンが、まだMDS上に存在するかどうかを確認するための関数。
+pub(crate) type VersionChecker = Arc<
+    Fn(ObjectVersion) -> Box<Future<Item = bool, Error = Error> + Send + 'static> + Send + Sync,
+>;
+
+/// GET時に欠損や破損が見つかった複製・フラグメントの修復を管理する。
+///
+/// 同じlumpに対する修復の重複や、特定のデバイスへの修復の集中(e.g., ディスク故障時)を避けるために、
+/// 修復要求の一部は実行されずに破棄される。
+/// 破棄された欠損・破損は、各ノードのスクラブや同期処理によって修復されることになる。
+///
+/// 削除されたオブジェクトのlumpを書き戻してしまわないように、
+/// 書き込みの直前に、対象のバージョンがMDS上にまだ存在することを確認する(線形化可能な読み込み)。
+/// ただし、その確認から書き込みまでの間に削除が行われた場合には、lumpが書き戻される可能性が残る。
+/// そのようなlumpは孤児GCが有効な場合(`FRUGALOS_ORPHAN_GC_DRY_RUN=0`)にのみ回収される。
+#[derive(Clone)]
+pub struct ReadRepairer {
+    logger: Logger,
+    state: Arc<Mutex<RepairState>>,
+    task_tx: mpsc::Sender<RepairTask>,
+    metrics: RepairMetrics,
+    version_checker: Option<VersionChecker>,
+}
+impl ReadRepairer {
+    /// 新しい`ReadRepairer`インスタンスと、修復処理を受け取るための`Receiver`を生成する。
+    pub(crate) fn new(logger: Logger) -> (Self, mpsc::Receiver<RepairTask>) {
+        let (task_tx, task_rx) = mpsc::channel();
+        let repairer = ReadRepairer {
+            logger,
+            state: Arc::new(Mutex::new(RepairState::default())),
+            task_tx,
+            metrics: RepairMetrics::new(),
+            version_checker: None,
+        };
+        (repairer, task_rx)
+    }
+
+    /// 修復の直前に、対象のバージョンがまだ存在するかどうかを`checker`で確認するようにする。
+    ///
+    /// 確認に失敗した場合には、修復も失敗扱いとなる。
+    pub(crate) fn with_version_checker(mut self, checker: VersionChecker) -> Self {
+        self.version_checker = Some(checker);
+        self
+    }
+
+    /// `member`に保存されている`version`の複製ないしフラグメントの修復を要求する。
+    ///
+    /// 修復が受け付けられた場合にのみ`make_future`が呼び出され、
+    /// その結果の`Future`がバックグラウンドで実行される。
+    /// バージョンの存在確認は、その`Future`のポーリング前に行われるので、
+    /// 書き込みはポーリングされた時点で開始されるようにする必要がある。
+    pub(crate) fn repair<F>(&self, member: &ClusterMember, version: ObjectVersion, make_future: F)
+    where
+        F: FnOnce() -> Box<Future<Item = (), Error = Error> + Send + 'static>,
+    {
+        let lump_id = member.make_lump_id(version);
+        let key = (member.device.clone(), lump_id);
+        if let Err(reason) = self.lock_state().start(&key, Instant::now()) {
+            debug!(
+                self.logger,
+                "Read-repair is skipped: device={}, lump_id={:?}, reason={}",
+                member.device,
+                lump_id,
+                reason
+            );
+            self.metrics.skipped(reason);
+            return;
+        }
+        info!(
+            self.logger,
+            "Starts read-repair: device={}, lump_id={:?}", member.device, lump_id
+        );
+        self.metrics.started_repairs.increment();
+
+        let write = make_future();
+        let future = if let Some(ref checker) = self.version_checker {
+            let logger = self.logger.clone();
+            let metrics = self.metrics.clone();
+            let device = member.device.clone();
+            Either::A(checker(version).and_then(move |exists| {
+                if exists {
+                    Either::A(write)
+                } else {
+                    info!(
+                        logger,
+                        "Read-repair is skipped: device={}, lump_id={:?}, reason={}",
+                        device,
+                        lump_id,
+                        SkipReason::Deleted
+                    );
+                    metrics.skipped(SkipReason::Deleted);
+                    Either::B(futures::finished(()))
+                }
+            }))
+        } else {
+            Either::B(write)
+        };
+
+        let logger = self.logger.clone();
+        let state = self.state.clone();
+        let metrics = self.metrics.clone();
+        let task = future.then(move |result| {
+            let succeeded = result.is_ok();
+            if let Err(e) = result {
+                warn!(
+                    logger,
+                    "Read-repair failed: device={}, lump_id={:?}, error={}", key.0, key.1, e
+                );
+                metrics.failed_repairs.increment();
+            } else {
+                metrics.completed_repairs.increment();
+            }
+            if let Ok(mut state) = state.lock() {
+                state.finish(&key, succeeded, Instant::now());
+            }
+            Ok(())
+        });
+        if self.task_tx.send(Box::new(task)).is_err() {
+            // サービスが停止済み
+            // (実行されなかった修復処理は破棄されるが、その際に状態を戻すことはしない)
+            warn!(self.logger, "Read-repair service has been stopped");
+        }
+    }
+
+    fn lock_state(&self) -> MutexGuard<RepairState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+impl fmt::Debug for ReadRepairer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let in_flight = self.lock_state().in_flight.len();
+        f.debug_struct("ReadRepairer")
+            .field("in_flight", &in_flight)
+            .finish()
+    }
+}
+
+type RepairKey = (String, LumpId);
+
+#[derive(Debug, Default)]
+struct RepairState {
+    in_flight: HashSet<RepairKey>,
+    device_repairs: HashMap<String, usize>,
+    suspended_devices: HashMap<String, Instant>,
+}
+impl RepairState {
+    fn start(&mut self, key: &RepairKey, now: Instant) -> Result<(), SkipReason> {
+        let device = &key.0;
+        if self
+            .suspended_devices
+            .get(device)
+            .map_or(false, |t| now < *t)
+        {
+            return Err(SkipReason::Suspended);
+        }
+        self.suspended_devices.remove(device);
+        if self.in_flight.contains(key) {
+            return Err(SkipReason::Duplicated);
+        }
+        let device_repairs = self.device_repairs.get(device).cloned().unwrap_or(0);
+        if self.in_flight.len() >= MAX_REPAIRS_IN_FLIGHT || device_repairs >= MAX_REPAIRS_PER_DEVICE
+        {
+            return Err(SkipReason::Throttled);
+        }
+        self.in_flight.insert(key.clone());
+        self.device_repairs
+            .insert(device.clone(), device_repairs + 1);
+        Ok(())
+    }
+
+    fn finish(&mut self, key: &RepairKey, succeeded: bool, now: Instant) {
+        if !self.in_flight.remove(key) {
+            return;
+        }
+        let device = &key.0;
+        let remaining = self.device_repairs.get(device).map_or(0, |n| n - 1);
+        if remaining == 0 {
+            self.device_repairs.remove(device);
+        } else {
+            self.device_repairs.insert(device.clone(), remaining);
+        }
+        if !succeeded {
+            self.suspended_devices.insert(
+                device.clone(),
+                now + Duration::from_secs(FAILED_DEVICE_BACKOFF_SECS),
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SkipReason {
+    Duplicated,
+    Throttled,
+    Suspended,
+
+    // 修復対象のバージョンが既に削除されていた
+    Deleted,
+}
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SkipReason::Duplicated => write!(f, "duplicated"),
+            SkipReason::Throttled => write!(f, "throttled"),
+            SkipReason::Suspended => write!(f, "suspended"),
+            SkipReason::Deleted => write!(f, "deleted"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RepairMetrics {
+    started_repairs: Counter,
+    completed_repairs: Counter,
+    failed_repairs: Counter,
+    duplicated_repairs: Counter,
+    throttled_repairs: Counter,
+    suspended_repairs: Counter,
+    deleted_repairs: Counter,
+}
+impl RepairMetrics {
+    fn new() -> Self {
+        let metric_builder = MetricBuilder::new()
+            .namespace("frugalos")
+            .subsystem("read_repair")
+            .clone();
+        let skipped = |reason: &str| {
+            metric_builder
+                .counter("skipped_repairs_total")
+                .label("reason", reason)
+                .finish()
+                .expect("Never fails")
+        };
+        RepairMetrics {
+            started_repairs: metric_builder
+                .counter("started_repairs_total")
+                .finish()
+                .expect("Never fails"),
+            completed_repairs: metric_builder
+                .counter("completed_repairs_total")
+                .finish()
+                .expect("Never fails"),
+            failed_repairs: metric_builder
+                .counter("failed_repairs_total")
+                .finish()
+                .expect("Never fails"),
+            duplicated_repairs: skipped("duplicated"),
+            throttled_repairs: skipped("throttled"),
+            suspended_repairs: skipped("suspended"),
+            deleted_repairs: skipped("deleted"),
+        }
+    }
+
+    fn skipped(&self, reason: SkipReason) {
+        match reason {
+            SkipReason::Duplicated => self.duplicated_repairs.increment(),
+            SkipReason::Throttled => self.throttled_repairs.increment(),
+            SkipReason::Suspended => self.suspended_repairs.increment(),
+            SkipReason::Deleted => self.deleted_repairs.increment(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Stream;
+    use slog::{Discard, Logger};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use test_util::tests::make_node_id;
+
+    fn key(device: &str, lump_id: u128) -> RepairKey {
+        (device.to_owned(), LumpId::new(lump_id))
+    }
+
+    #[test]
+    fn repair_state_deduplicates_and_throttles_repairs() {
+        let now = Instant::now();
+        let mut state = RepairState::default();
+
+        assert_eq!(state.start(&key("a", 0), now), Ok(()));
+        assert_eq!(state.start(&key("a", 0), now), Err(SkipReason::Duplicated));
+        assert_eq!(state.start(&key("a", 1), now), Ok(()));
+        assert_eq!(state.start(&key("a", 2), now), Err(SkipReason::Throttled));
+        assert_eq!(state.start(&key("b", 0), now), Ok(()));
+
+        state.finish(&key("a", 0), true, now);
+        assert_eq!(state.start(&key("a", 2), now), Ok(()));
+
+        // 全体の上限
+        let mut state = RepairState::default();
+        for i in 0..MAX_REPAIRS_IN_FLIGHT {
+            assert_eq!(state.start(&key(&i.to_string(), 0), now), Ok(()));
+        }
+        assert_eq!(state.start(&key("x", 0), now), Err(SkipReason::Throttled));
+    }
+
+    #[test]
+    fn repair_state_suspends_failed_devices() {
+        let now = Instant::now();
+        let mut state = RepairState::default();
+
+        assert_eq!(state.start(&key("a", 0), now), Ok(()));
+        state.finish(&key("a", 0), false, now);
+        assert_eq!(state.start(&key("a", 1), now), Err(SkipReason::Suspended));
+        assert_eq!(state.start(&key("b", 1), now), Ok(()));
+
+        let later = now + Duration::from_secs(FAILED_DEVICE_BACKOFF_SECS);
+        assert_eq!(state.start(&key("a", 1), later), Ok(()));
+    }
+
+    #[test]
+    fn repair_is_skipped_if_version_has_been_deleted() {
+        let member = ClusterMember {
+            node: make_node_id(1),
+            device: "a".to_owned(),
+        };
+        for &exists in &[false, true] {
+            let (repairer, task_rx) = ReadRepairer::new(Logger::root(Discard, o!()));
+            let repairer = repairer.with_version_checker(Arc::new(move |_| {
+                Box::new(futures::finished::<_, Error>(exists))
+            }));
+
+            let written = Arc::new(AtomicBool::new(false));
+            let flag = written.clone();
+            repairer.repair(&member, ObjectVersion(10), move || {
+                Box::new(futures::lazy(move || {
+                    flag.store(true, Ordering::SeqCst);
+                    Ok::<_, Error>(())
+                }))
+            });
+            // 書き込みは修復処理の実行時まで行われない
+            assert!(!written.load(Ordering::SeqCst));
+
+            let (task, _) = task_rx.into_future().wait().ok().expect("Never fails");
+            assert!(task.expect("Never fails").wait().is_ok());
+            assert_eq!(written.load(Ordering::SeqCst), exists);
+        }
+    }
+}
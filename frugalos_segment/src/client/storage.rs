@@ -1,8 +1,7 @@
 #![allow(clippy::needless_pass_by_value)]
-use adler32;
-use byteorder::{BigEndian, ByteOrder};
+use byteorder::{ByteOrder, LittleEndian};
 use cannyls::deadline::Deadline;
-use cannyls::lump::LumpData;
+use cannyls::lump::{LumpData, LumpId};
 use cannyls_rpc::Client as CannyLsClient;
 use cannyls_rpc::DeviceId;
 use ecpool::liberasurecode::LibErasureCoderBuilder;
@@ -10,23 +9,33 @@ use ecpool::ErasureCoderPool;
 use fibers::time::timer;
 use fibers_rpc::client::{ClientServiceHandle as RpcServiceHandle, Options as RpcOptions};
 use frugalos_raft::NodeId;
-use futures::future;
+use futures::future::{self, Either};
 use futures::{self, Async, Future, Poll};
 use libfrugalos::entity::object::ObjectVersion;
 use rustracing::tag::{StdTag, Tag};
 use rustracing_jaeger::span::{Span, SpanHandle};
 use slog::Logger;
+use std::cmp;
+use std::collections::BTreeMap;
 use std::mem;
 use std::num::NonZeroUsize;
+use std::ops::Range;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use trackable::error::ErrorKindExt;
 
+use client::checksum::{append_checksum, verify_and_remove_checksum};
+use client::compression::{decompress, Compressor};
+use client::encryption::{Encryptor, KeyId};
+use client::hedge::HedgeMonitor;
+use client::repair::ReadRepairer;
 use config::{
-    ClientConfig, ClusterConfig, ClusterMember, DispersedConfig, Participants, ReplicatedConfig,
+    ChecksumAlgorithm, ChecksumSelector, ClientConfig, ClusterConfig, ClusterMember, Compression,
+    DispersedConfig, Participants, ReplicatedConfig,
 };
-use util::Phase;
-use {Error, ErrorKind, ObjectValue, Result};
+use range::{extract_parts, resolve_ranges};
+use util::{Phase, Phase3};
+use {ByteRange, Error, ErrorKind, ObjectValue, Result};
 
 type BoxFuture<T> = Box<Future<Item = T, Error = Error> + Send + 'static>;
 
@@ -34,6 +43,12 @@ type BoxFuture<T> = Box<Future<Item = T, Error = Error> + Send + 'static>;
 const CANNYLS_MAX_QUEUE_LEN: usize = 4096;
 const RPC_MAX_QUEUE_LEN: u64 = 512;
 
+// liberasurecodeが各フラグメントの先頭に付与するヘッダのレイアウト
+// (`fragment_header_t`を参照のこと; 値はネイティブエンディアンで格納される)
+const FRAGMENT_HEADER_SIZE: usize = 80;
+const FRAGMENT_HEADER_MAGIC: u32 = 0x0b0c_5ecc;
+const FRAGMENT_HEADER_MAGIC_OFFSET: usize = 59;
+
 #[derive(Clone)]
 pub enum StorageClient {
     Metadata,
@@ -46,19 +61,32 @@ impl StorageClient {
         config: ClientConfig,
         rpc_service: RpcServiceHandle,
         ec: Option<ErasureCoder>,
+        hedge_monitor: Option<HedgeMonitor>,
+        read_repairer: Option<ReadRepairer>,
     ) -> Self {
         use config::Storage;
         match config.storage {
             Storage::Metadata => StorageClient::Metadata,
-            Storage::Replicated(c) => {
-                StorageClient::Replicated(ReplicatedClient::new(config.cluster, c, rpc_service))
-            }
+            Storage::Replicated(c) => StorageClient::Replicated(ReplicatedClient::new(
+                config.cluster,
+                c,
+                rpc_service,
+                read_repairer,
+                config.checksum,
+                config.compression,
+                config.encryption,
+            )),
             Storage::Dispersed(c) => StorageClient::Dispersed(DispersedClient::new(
                 logger,
                 config.cluster,
                 c,
                 rpc_service,
                 ec,
+                hedge_monitor,
+                read_repairer,
+                config.checksum,
+                config.compression,
+                config.encryption,
             )),
         }
     }
@@ -69,6 +97,61 @@ impl StorageClient {
             false
         }
     }
+
+    /// 新たに書き込むlumpの暗号化に使用する鍵のIDを返す。
+    ///
+    /// 暗号化が無効な場合には`None`が返される。
+    pub fn encryption_key_id(&self) -> Option<KeyId> {
+        match *self {
+            StorageClient::Metadata => None,
+            StorageClient::Replicated(ref c) => c.encryption.get(),
+            StorageClient::Dispersed(ref c) => c.encryption.get(),
+        }
+    }
+
+    /// 同期処理等で復元した複製ないしフラグメントを、現在の設定で`lump_id`のlumpに保存する形式に変換する。
+    pub(crate) fn encode_lump(
+        &self,
+        fragment: Vec<u8>,
+        compression: Compression,
+        lump_id: LumpId,
+    ) -> Result<Vec<u8>> {
+        match *self {
+            StorageClient::Metadata => track_panic!(ErrorKind::Other, "unreachable"),
+            StorageClient::Replicated(ref c) => track!(encode_lump(
+                fragment,
+                c.checksum.get(),
+                compression,
+                &c.encryption,
+                lump_id
+            )),
+            StorageClient::Dispersed(ref c) => track!(encode_lump(
+                fragment,
+                c.checksum.get(),
+                compression,
+                &c.encryption,
+                lump_id
+            )),
+        }
+    }
+
+    /// `lump_id`のlumpから読み込んだ、`key_id`の鍵で暗号化された複製ないしフラグメントを復号する。
+    pub(crate) fn decrypt(
+        &self,
+        payload: Vec<u8>,
+        key_id: Option<KeyId>,
+        lump_id: LumpId,
+    ) -> Result<Vec<u8>> {
+        match *self {
+            StorageClient::Metadata => track_panic!(ErrorKind::Other, "unreachable"),
+            StorageClient::Replicated(ref c) => {
+                track!(c.encryption.decrypt(payload, key_id, lump_id))
+            }
+            StorageClient::Dispersed(ref c) => {
+                track!(c.encryption.decrypt(payload, key_id, lump_id))
+            }
+        }
+    }
     pub fn get_fragment(self, local_node: NodeId, version: ObjectVersion) -> GetFragment {
         match self {
             StorageClient::Metadata => GetFragment::Failed(futures::failed(
@@ -94,6 +177,24 @@ impl StorageClient {
             StorageClient::Dispersed(c) => c.get(object.version, deadline, parent),
         }
     }
+    pub fn get_range(
+        self,
+        object: ObjectValue,
+        ranges: Vec<ByteRange>,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> BoxFuture<(u64, Vec<(Range<u64>, Vec<u8>)>)> {
+        match self {
+            StorageClient::Metadata => {
+                Box::new(futures::finished(extract_parts(&object.content, &ranges)))
+            }
+            StorageClient::Replicated(c) => Box::new(
+                c.get(object.version, deadline)
+                    .map(move |content| extract_parts(&content, &ranges)),
+            ),
+            StorageClient::Dispersed(c) => c.get_range(object.version, ranges, deadline, parent),
+        }
+    }
     pub fn put(
         self,
         version: ObjectVersion,
@@ -107,6 +208,109 @@ impl StorageClient {
             StorageClient::Dispersed(c) => c.put(version, content, deadline, parent),
         }
     }
+
+    /// `source`に保存されている中身を、lumpのまま複製可能かどうかを判定する。
+    ///
+    /// 冗長化の方式(ErasureCodingの場合にはフラグメントの構成も)と、
+    /// lumpの形式に関わる設定(暗号鍵、チェックサム、圧縮方式)が全て一致する場合には、
+    /// 中身の復号や再符号化を行わずに、lump単位での複製が可能となる。
+    ///
+    /// 複製不可能な場合には、中身を取得して通常通りに保存し直す必要がある。
+    pub fn can_copy_lumps_from(&self, source: &StorageClient) -> bool {
+        let same_layout = match (self, source) {
+            (&StorageClient::Replicated(_), &StorageClient::Replicated(_)) => true,
+            (&StorageClient::Dispersed(ref dst), &StorageClient::Dispersed(ref src)) => {
+                dst.config.fragments == src.config.fragments
+                    && dst.config.tolerable_faults == src.config.tolerable_faults
+            }
+            _ => false,
+        };
+        same_layout && self.lump_settings() == source.lump_settings()
+    }
+
+    // lumpの形式に関わる設定(暗号鍵、チェックサム、圧縮方式)を返す
+    fn lump_settings(&self) -> Option<(Option<KeyId>, ChecksumAlgorithm, Compression)> {
+        match *self {
+            StorageClient::Metadata => None,
+            StorageClient::Replicated(ref c) => {
+                Some((c.encryption.get(), c.checksum.get(), c.compression.get()))
+            }
+            StorageClient::Dispersed(ref c) => {
+                Some((c.encryption.get(), c.checksum.get(), c.compression.get()))
+            }
+        }
+    }
+
+    /// `source`に`source_version`として保存されている中身を、`version`の中身としてlump単位で複製する。
+    ///
+    /// 事前に`can_copy_lumps_from`で複製可能なことを確認しておく必要がある。
+    pub fn copy_lumps_from(
+        self,
+        source: StorageClient,
+        source_version: ObjectVersion,
+        version: ObjectVersion,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> BoxFuture<()> {
+        match (self, source) {
+            (StorageClient::Replicated(dst), StorageClient::Replicated(src)) => {
+                dst.copy_from(src, source_version, version, deadline)
+            }
+            (StorageClient::Dispersed(dst), StorageClient::Dispersed(src)) => {
+                dst.copy_from(src, source_version, version, deadline, parent)
+            }
+            _ => Box::new(futures::failed(
+                ErrorKind::Invalid
+                    .cause("Incompatible storage layouts")
+                    .into(),
+            )),
+        }
+    }
+
+    /// `local_node`以外の参加ノード群が保持している`version`の複製ないしフラグメントの数から、
+    /// あと幾つの欠損までなら中身の復元が可能か(残りの冗長度)を返す。
+    ///
+    /// 既に復元が不可能な場合には`0`が、`local_node`が中身を保持する必要がない場合には`None`が返される。
+    /// なお、lumpの有無のみを確認しているので、破損の可能性は考慮されない。
+    pub fn remaining_redundancy(
+        self,
+        local_node: NodeId,
+        version: ObjectVersion,
+    ) -> BoxFuture<Option<usize>> {
+        match self {
+            StorageClient::Metadata => Box::new(futures::failed(
+                ErrorKind::Other.cause("unreachable").into(),
+            )),
+            StorageClient::Replicated(c) => c.remaining_redundancy(local_node, version),
+            StorageClient::Dispersed(c) => c.remaining_redundancy(local_node, version),
+        }
+    }
+}
+
+/// `members`の内で、`version`のlumpを保持しているものの数を返す。
+///
+/// 応答がないメンバーは、lumpを保持していないものとして扱われる。
+fn count_existing_lumps(
+    rpc_service: RpcServiceHandle,
+    members: Vec<ClusterMember>,
+    version: ObjectVersion,
+) -> BoxFuture<usize> {
+    let futures = members.into_iter().map(move |m| {
+        let client = CannyLsClient::new(m.node.addr, rpc_service.clone());
+        let mut request = client.request();
+        request.rpc_options(RpcOptions {
+            max_queue_len: Some(RPC_MAX_QUEUE_LEN),
+            ..Default::default()
+        });
+        let lump_id = m.make_lump_id(version);
+        request
+            .deadline(Deadline::Infinity)
+            .head_lump(DeviceId::new(m.device.clone()), lump_id)
+            .then(|result| Ok::<_, Error>(result.ok().map_or(false, |header| header.is_some())))
+    });
+    let future = future::join_all(futures)
+        .map(|exists: Vec<bool>| exists.into_iter().filter(|&b| b).count());
+    Box::new(future)
 }
 
 #[derive(Debug, Clone)]
@@ -114,60 +318,97 @@ pub struct ReplicatedClient {
     cluster: Arc<ClusterConfig>,
     config: ReplicatedConfig,
     rpc_service: RpcServiceHandle,
+    read_repairer: Option<ReadRepairer>,
+    checksum: ChecksumSelector,
+    compression: Compressor,
+    encryption: Encryptor,
 }
 impl ReplicatedClient {
     pub fn new(
         cluster: ClusterConfig,
         config: ReplicatedConfig,
         rpc_service: RpcServiceHandle,
+        read_repairer: Option<ReadRepairer>,
+        checksum: ChecksumSelector,
+        compression: Compressor,
+        encryption: Encryptor,
     ) -> Self {
         ReplicatedClient {
             cluster: Arc::new(cluster),
             config,
             rpc_service,
+            read_repairer,
+            checksum,
+            compression,
+            encryption,
         }
     }
     pub fn get_fragment(
-        self,
+        mut self,
         _local_node: NodeId,
         version: ObjectVersion,
     ) -> GetReplicatedFragment {
         // TODO: `_local_node`は問い合わせ候補から外す(必ず失敗するので)
-        let future = self.get(version, Deadline::Infinity);
-        GetReplicatedFragment(future)
+
+        // NOTE: 欠損している複製の修復は、呼び出し側(同期処理)の責務
+        self.read_repairer = None;
+        let future = self.get_compressed(version, Deadline::Infinity);
+        GetReplicatedFragment(Box::new(future))
     }
-    pub fn get(self, version: ObjectVersion, deadline: Deadline) -> BoxFuture<Vec<u8>> {
+    pub fn remaining_redundancy(
+        self,
+        local_node: NodeId,
+        version: ObjectVersion,
+    ) -> BoxFuture<Option<usize>> {
         let replica = self.config.tolerable_faults as usize + 1;
-        let mut candidates = self
+        let members = self
             .cluster
             .candidates(version)
             .take(replica)
+            .filter(|m| m.node != local_node)
             .cloned()
-            .collect::<Vec<_>>();
+            .collect();
+        let future = count_existing_lumps(self.rpc_service, members, version)
+            .map(|replicas| Some(replicas.saturating_sub(1)));
+        Box::new(future)
+    }
+    pub fn get(self, version: ObjectVersion, deadline: Deadline) -> BoxFuture<Vec<u8>> {
+        let future = self
+            .get_compressed(version, deadline)
+            .and_then(|(content, compression)| track!(decompress(compression, content)));
+        Box::new(future)
+    }
+
+    /// 複製を、伸長せずに圧縮方式と共に取得する。
+    fn get_compressed(self, version: ObjectVersion, deadline: Deadline) -> ReplicatedGet {
+        let replica = self.config.tolerable_faults as usize + 1;
+        let mut candidates = self.cluster.read_candidates(version, replica);
         candidates.reverse();
-        let future = ReplicatedGet {
+        ReplicatedGet {
             version,
             deadline,
             candidates,
             rpc_service: self.rpc_service,
             future: Box::new(futures::finished(None)),
-        };
-        Box::new(future)
+            current: None,
+            broken: Vec::new(),
+            read_repairer: self.read_repairer,
+            checksum: self.checksum.get(),
+            encryption: self.encryption,
+        }
     }
     pub fn put(
         self,
         version: ObjectVersion,
-        mut content: Vec<u8>,
+        content: Vec<u8>,
         deadline: Deadline,
     ) -> BoxFuture<()> {
         let rpc_service = self.rpc_service;
         let replica = self.config.tolerable_faults as usize + 1;
-        append_checksum(&mut content);
-
-        let data = match track!(LumpData::new(content)) {
-            Ok(data) => data,
-            Err(error) => return Box::new(futures::failed(Error::from(error))),
-        };
+        let required_put_count = self.config.required_put_count();
+        let (content, compression) = self.compression.compress(content);
+        let checksum = self.checksum.get();
+        let encryption = self.encryption;
 
         let futures = self
             .cluster
@@ -181,19 +422,49 @@ impl ReplicatedClient {
                     ..Default::default()
                 });
 
+                // NOTE: 暗号化されたデータは保存先のlumpに束縛されるので、複製毎に変換する
                 let device_id = DeviceId::new(m.device.clone());
                 let lump_id = m.make_lump_id(version);
+                let data = match track!(encode_lump(
+                    content.clone(),
+                    checksum,
+                    compression,
+                    &encryption,
+                    lump_id
+                ))
+                .and_then(|lump| track!(LumpData::new(lump).map_err(Error::from)))
+                {
+                    Ok(data) => data,
+                    Err(error) => {
+                        let future: BoxFuture<_> = Box::new(futures::failed(error));
+                        return future;
+                    }
+                };
                 let future: BoxFuture<_> = Box::new(
                     request
                         .deadline(deadline)
                         .max_queue_len(CANNYLS_MAX_QUEUE_LEN)
-                        .put_lump(device_id, lump_id, data.clone())
+                        .put_lump(device_id, lump_id, data)
                         .map(|_is_new| ())
                         .map_err(|e| track!(Error::from(e))),
                 );
                 future
             });
-        Box::new(PutAll::new(futures, 1))
+        Box::new(PutAll::new(futures, required_put_count))
+    }
+
+    /// `source`から取得した複製を、そのまま`version`の複製として保存する。
+    pub fn copy_from(
+        self,
+        source: ReplicatedClient,
+        source_version: ObjectVersion,
+        version: ObjectVersion,
+        deadline: Deadline,
+    ) -> BoxFuture<()> {
+        let future = source
+            .get(source_version, deadline)
+            .and_then(move |content| self.put(version, content, deadline));
+        Box::new(future)
     }
 }
 
@@ -203,20 +474,74 @@ pub struct ReplicatedGet {
     candidates: Vec<ClusterMember>,
     future: BoxFuture<Option<Vec<u8>>>,
     rpc_service: RpcServiceHandle,
+
+    // `future`で問い合わせ中の候補
+    current: Option<ClusterMember>,
+
+    // 複製が欠損ないし破損していた候補群
+    broken: Vec<ClusterMember>,
+    read_repairer: Option<ReadRepairer>,
+
+    // 修復時に書き込むlumpに付与するチェックサムのアルゴリズム
+    checksum: ChecksumAlgorithm,
+
+    // 複製の復号と、修復時に書き込むlumpの暗号化に使用される
+    encryption: Encryptor,
+}
+impl ReplicatedGet {
+    fn repair_broken_replicas(&mut self, content: &[u8], compression: Compression) {
+        let repairer = if let Some(ref repairer) = self.read_repairer {
+            repairer
+        } else {
+            return;
+        };
+        let checksum = self.checksum;
+        for m in self.broken.drain(..) {
+            let version = self.version;
+            let rpc_service = &self.rpc_service;
+            let encryption = &self.encryption;
+            repairer.repair(&m, version, || {
+                // NOTE: 書き込みは、バージョンの存在確認の後に開始されるようにする
+                let member = m.clone();
+                let content = content.to_vec();
+                let encryption = encryption.clone();
+                let rpc_service = rpc_service.clone();
+                let future: BoxFuture<_> = Box::new(futures::lazy(move || {
+                    put_fragment_lump(
+                        &member,
+                        version,
+                        content,
+                        checksum,
+                        compression,
+                        &encryption,
+                        Deadline::Infinity,
+                        &rpc_service,
+                        &Span::inactive().handle(),
+                    )
+                }));
+                future
+            });
+        }
+    }
 }
 impl Future for ReplicatedGet {
-    type Item = Vec<u8>;
+    type Item = (Vec<u8>, Compression);
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         loop {
             match self.future.poll() {
                 Err(e) => {
+                    // NOTE: ノードの停止等も考えられるので、修復の対象とはしない
+                    self.current = None;
                     if self.candidates.is_empty() {
                         return Err(track!(e));
                     }
                     self.future = Box::new(futures::finished(None))
                 }
                 Ok(Async::Ready(None)) => {
+                    if let Some(m) = self.current.take() {
+                        self.broken.push(m);
+                    }
                     let m = track!(self
                         .candidates
                         .pop()
@@ -231,17 +556,40 @@ impl Future for ReplicatedGet {
                     let lump_id = m.make_lump_id(self.version);
                     let future = request
                         .deadline(self.deadline)
-                        .get_lump(DeviceId::new(m.device), lump_id);
+                        .get_lump(DeviceId::new(m.device.clone()), lump_id);
                     self.future = Box::new(future.map_err(|e| track!(Error::from(e))));
+                    self.current = Some(m);
                 }
                 Ok(Async::Ready(Some(mut content))) => {
-                    if let Err(e) = track!(verify_and_remove_checksum(&mut content)) {
-                        if self.candidates.is_empty() {
-                            return Err(track!(e));
+                    match track!(verify_and_remove_checksum(&mut content)) {
+                        Err(e) => {
+                            if let Some(m) = self.current.take() {
+                                self.broken.push(m);
+                            }
+                            if self.candidates.is_empty() {
+                                return Err(track!(e));
+                            }
+                            self.future = Box::new(futures::finished(None));
+                        }
+                        Ok((compression, key_id)) => {
+                            let lump_id =
+                                track_assert_some!(self.current.as_ref(), ErrorKind::Other)
+                                    .make_lump_id(self.version);
+                            match track!(self.encryption.decrypt(content, key_id, lump_id)) {
+                                Err(e) => {
+                                    // NOTE: 鍵の設定の問題である可能性が高いので、修復の対象とはしない
+                                    self.current = None;
+                                    if self.candidates.is_empty() {
+                                        return Err(track!(e));
+                                    }
+                                    self.future = Box::new(futures::finished(None));
+                                }
+                                Ok(content) => {
+                                    self.repair_broken_replicas(&content, compression);
+                                    return Ok(Async::Ready((content, compression)));
+                                }
+                            }
                         }
-                        self.future = Box::new(futures::finished(None));
-                    } else {
-                        return Ok(Async::Ready(content));
                     }
                 }
                 Ok(Async::NotReady) => break,
@@ -276,7 +624,8 @@ impl Future for PutAll {
         loop {
             let remainings = match self.future.poll() {
                 Err((e, _, remainings)) => {
-                    if remainings.is_empty() && self.ok_count < self.required_ok_count {
+                    // NOTE: 残りが全て成功しても必要数に届かない場合には、その時点で失敗とする
+                    if self.ok_count + remainings.len() < self.required_ok_count {
                         return Err(track!(e));
                     }
                     remainings
@@ -284,7 +633,6 @@ impl Future for PutAll {
                 Ok(Async::Ready(((), _, remainings))) => {
                     self.ok_count += 1;
                     if self.ok_count >= self.required_ok_count {
-                        // TODO: パラメータ化
                         return Ok(Async::Ready(()));
                     }
                     remainings
@@ -292,7 +640,12 @@ impl Future for PutAll {
                 Ok(Async::NotReady) => break,
             };
             if remainings.is_empty() {
-                return Ok(Async::Ready(()));
+                track_panic!(
+                    ErrorKind::Other,
+                    "Too few puts succeeded: ok_count={}, required_ok_count={}",
+                    self.ok_count,
+                    self.required_ok_count
+                );
             }
             self.future = future::select_all(remainings);
         }
@@ -319,14 +672,25 @@ pub struct DispersedClient {
     data_fragments: usize,
     ec: ErasureCoder,
     rpc_service: RpcServiceHandle,
+    hedge_monitor: Option<HedgeMonitor>,
+    read_repairer: Option<ReadRepairer>,
+    checksum: ChecksumSelector,
+    compression: Compressor,
+    encryption: Encryptor,
 }
 impl DispersedClient {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         logger: Logger,
         cluster: ClusterConfig,
         config: DispersedConfig,
         rpc_service: RpcServiceHandle,
         ec: Option<ErasureCoder>,
+        hedge_monitor: Option<HedgeMonitor>,
+        read_repairer: Option<ReadRepairer>,
+        checksum: ChecksumSelector,
+        compression: Compressor,
+        encryption: Encryptor,
     ) -> Self {
         let parity_fragments = config.tolerable_faults as usize;
         let data_fragments = config.fragments as usize - parity_fragments;
@@ -338,6 +702,11 @@ impl DispersedClient {
             ec,
             data_fragments,
             rpc_service,
+            hedge_monitor,
+            read_repairer,
+            checksum,
+            compression,
+            encryption,
         }
     }
     pub fn get_fragment(self, local_node: NodeId, version: ObjectVersion) -> GetDispersedFragment {
@@ -369,7 +738,7 @@ impl DispersedClient {
         let dummy: BoxFuture<_> = Box::new(futures::finished(None));
         let future = CollectFragments {
             logger: self.logger.clone(),
-            futures: vec![dummy],
+            futures: vec![(None, dummy)],
             fragments: Vec::new(),
             data_fragments: self.data_fragments,
             spares,
@@ -378,12 +747,44 @@ impl DispersedClient {
             rpc_service: self.rpc_service,
             parent: Span::inactive().handle(), // TODO
             timeout: None,
+            extra_requests: 0,
+            hedge_delay: Duration::default(),
+            hedge_monitor: None,
+            participants: Vec::new(),
+            broken: Vec::new(),
+
+            // NOTE: 欠損しているフラグメントの修復は、呼び出し側(同期処理)の責務
+            read_repair: None,
+            checksum: self.checksum.get(),
+            encryption: self.encryption.clone(),
+            compression: Compression::None,
         };
         GetDispersedFragment {
             phase: Phase::A(future),
             ec: self.ec.clone(),
             missing_index,
+            compression: Compression::None,
+        }
+    }
+    pub fn remaining_redundancy(
+        self,
+        local_node: NodeId,
+        version: ObjectVersion,
+    ) -> BoxFuture<Option<usize>> {
+        let candidates = self
+            .cluster
+            .candidates(version)
+            .cloned()
+            .collect::<Vec<_>>();
+        let participants = Participants::dispersed(&candidates, self.config.fragments());
+        if participants.fragment_index(&local_node).is_none() {
+            return Box::new(futures::finished(None));
         }
+        let members = participants.spares(&local_node);
+        let data_fragments = self.data_fragments;
+        let future = count_existing_lumps(self.rpc_service, members, version)
+            .map(move |fragments| Some(fragments.saturating_sub(data_fragments)));
+        Box::new(future)
     }
     pub fn get(
         self,
@@ -393,9 +794,7 @@ impl DispersedClient {
     ) -> BoxFuture<Vec<u8>> {
         let mut spares = self
             .cluster
-            .candidates(version)
-            .cloned()
-            .collect::<Vec<_>>();
+            .read_candidates(version, self.cluster.members.len());
         spares.reverse();
         // rand::thread_rng().shuffle(&mut spares);
         let dummy: BoxFuture<_> = Box::new(futures::finished(None));
@@ -406,9 +805,18 @@ impl DispersedClient {
                 .tag(Tag::new("storage.type", "dispersed"))
                 .start()
         });
+        let hedge_delay = self.hedge_delay();
+        let participants = self
+            .cluster
+            .candidates(version)
+            .take(self.config.fragments as usize)
+            .cloned()
+            .collect();
+        let ec = self.ec.clone();
+        let read_repair = self.read_repairer.map(|repairer| (repairer, ec));
         let future = CollectFragments {
             logger: self.logger.clone(),
-            futures: vec![dummy],
+            futures: vec![(None, dummy)],
             fragments: Vec::new(),
             data_fragments: self.data_fragments,
             spares,
@@ -416,11 +824,80 @@ impl DispersedClient {
             deadline,
             rpc_service: self.rpc_service,
             parent: span.handle(),
-            timeout: Some(timer::timeout(Duration::from_secs(2))), // TODO: ハードコーディングは止める
+            timeout: Some(timer::timeout(hedge_delay)),
+            extra_requests: self.config.hedge.extra_requests as usize,
+            hedge_delay,
+            hedge_monitor: self.hedge_monitor,
+            participants,
+            broken: Vec::new(),
+            read_repair,
+            checksum: self.checksum.get(),
+            encryption: self.encryption.clone(),
+            compression: Compression::None,
         };
         Box::new(DispersedGet {
             phase: Phase::A(future),
             ec: self.ec.clone(),
+            compression: Compression::None,
+            span,
+        })
+    }
+
+    /// フラグメント群の取得時に、取得候補を増やすまでの待ち時間を返す。
+    fn hedge_delay(&self) -> Duration {
+        if let Some(ref monitor) = self.hedge_monitor {
+            monitor.hedge_delay(&self.config.hedge)
+        } else {
+            Duration::from_millis(self.config.hedge.delay_millis)
+        }
+    }
+
+    /// オブジェクトの一部分を取得する。
+    ///
+    /// いずれかのデータフラグメントのヘッダからブロックサイズを求めた上で、
+    /// 要求範囲を含むデータフラグメントのみを取得する。
+    /// いずれかのデータフラグメントが取得できなかった場合や、中身が圧縮されている場合には、
+    /// 通常の`get`と同様に全体を復元した上で切り出す。
+    pub fn get_range(
+        self,
+        version: ObjectVersion,
+        ranges: Vec<ByteRange>,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> BoxFuture<(u64, Vec<(Range<u64>, Vec<u8>)>)> {
+        let span = parent.child("get_content_range", |span| {
+            span.tag(StdTag::component(module_path!()))
+                .tag(Tag::new("object.version", version.0 as i64))
+                .tag(Tag::new("storage.type", "dispersed"))
+                .start()
+        });
+        let candidates = self
+            .cluster
+            .candidates(version)
+            .take(self.data_fragments)
+            .cloned()
+            .collect::<Vec<_>>();
+        let probe_index =
+            select_probe_fragment(&self.cluster, version, self.data_fragments, &ranges);
+        let future = get_fragment_lump(
+            &candidates[probe_index],
+            version,
+            deadline,
+            &self.rpc_service,
+            &self.encryption,
+            &span.handle(),
+        );
+        Box::new(DispersedGetRange {
+            client: self,
+            version,
+            deadline,
+            ranges,
+            candidates,
+            probe_index,
+            payloads: BTreeMap::new(),
+            size: 0,
+            block_size: 0,
+            phase: Phase3::A(future),
             span,
         })
     }
@@ -438,6 +915,7 @@ impl DispersedClient {
                 .start()
         });
 
+        let (content, compression) = self.compression.compress(content);
         let mut child = span.child("ec_encode", |span| {
             span.tag(StdTag::component(module_path!())).start()
         });
@@ -458,20 +936,120 @@ impl DispersedClient {
             cluster: self.cluster.clone(),
             version,
             deadline,
-            data_fragments: self.data_fragments,
+            required_put_count: self.config.required_put_count(),
             rpc_service: self.rpc_service,
+            checksum: self.checksum.get(),
+            compression,
+            encryption: self.encryption.clone(),
             phase: Phase::A(Box::new(future)),
             parent: span,
         })
     }
+
+    /// `source`の各フラグメントを、同じ番号のフラグメントとして`version`の配置先にそのまま保存する。
+    ///
+    /// フラグメントの中身はバージョンに依存しないので、復号や再符号化は不要である。
+    /// データフラグメント数以上の複製に成功しなかった場合には、
+    /// 通常の取得および保存(復号と再符号化)による複製にフォールバックする。
+    pub fn copy_from(
+        self,
+        source: DispersedClient,
+        source_version: ObjectVersion,
+        version: ObjectVersion,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> BoxFuture<()> {
+        let mut span = parent.child("copy_content", |span| {
+            span.tag(StdTag::component(module_path!()))
+                .tag(Tag::new("object.version", version.0 as i64))
+                .tag(Tag::new("source.version", source_version.0 as i64))
+                .tag(Tag::new("storage.type", "dispersed"))
+                .start()
+        });
+        let handle = span.handle();
+        let checksum = self.checksum.get();
+        let copies = source
+            .cluster
+            .candidates(source_version)
+            .zip(self.cluster.candidates(version))
+            .take(self.config.fragments as usize)
+            .map(|(src, dst)| {
+                let dst = dst.clone();
+                let rpc_service = self.rpc_service.clone();
+                let encryption = self.encryption.clone();
+                let parent = handle.clone();
+                get_fragment_lump(
+                    src,
+                    source_version,
+                    deadline,
+                    &rpc_service,
+                    &source.encryption,
+                    &handle,
+                )
+                .and_then(move |fragment| {
+                    if let Some((fragment, compression)) = fragment {
+                        let future = put_fragment_lump(
+                            &dst,
+                            version,
+                            fragment,
+                            checksum,
+                            compression,
+                            &encryption,
+                            deadline,
+                            &rpc_service,
+                            &parent,
+                        );
+                        Either::A(future)
+                    } else {
+                        let e = ErrorKind::Corrupted.cause("Missing fragment");
+                        Either::B(futures::failed(track!(Error::from(e))))
+                    }
+                })
+                .then(|result| -> Result<bool> { Ok(result.is_ok()) })
+            })
+            .collect::<Vec<_>>();
+
+        let future = future::join_all(copies)
+            .and_then(move |copied: Vec<bool>| {
+                let copied = copied.into_iter().filter(|&ok| ok).count();
+                if copied >= self.data_fragments {
+                    return Either::A(futures::finished(()));
+                }
+                warn!(
+                    self.logger,
+                    "Too few fragments are copied (falls back to re-encoding): \
+                     source_version={:?}, version={:?}, copied={}",
+                    source_version,
+                    version,
+                    copied
+                );
+                let future = source
+                    .get(source_version, deadline, handle.clone())
+                    .and_then(move |content| self.put(version, content, deadline, handle));
+                Either::B(future)
+            })
+            .then(move |result| {
+                if let Err(ref e) = result {
+                    span.set_tag(StdTag::error);
+                    span.log(|log| {
+                        log.error().message(e.to_string());
+                    });
+                }
+                result
+            });
+        Box::new(future)
+    }
 }
 
 pub struct DispersedPut {
     cluster: Arc<ClusterConfig>,
     version: ObjectVersion,
     deadline: Deadline,
-    data_fragments: usize,
+    required_put_count: usize,
     rpc_service: RpcServiceHandle,
+    checksum: ChecksumAlgorithm,
+    compression: Compression,
+    encryption: Encryptor,
     phase: Phase<BoxFuture<Vec<Vec<u8>>>, PutAll>,
     parent: Span,
 }
@@ -486,12 +1064,28 @@ impl Future for DispersedPut {
                     let version = self.version;
                     let deadline = self.deadline;
                     let rpc_service = self.rpc_service.clone();
+                    let checksum = self.checksum;
+                    let compression = self.compression;
+                    let encryption = self.encryption.clone();
                     let futures = self
                         .cluster
                         .candidates(self.version)
                         .zip(fragments.into_iter())
-                        .map(move |(m, mut content)| {
-                            append_checksum(&mut content);
+                        .map(move |(m, content)| {
+                            let lump_id = m.make_lump_id(version);
+                            let content = match track!(encode_lump(
+                                content,
+                                checksum,
+                                compression,
+                                &encryption,
+                                lump_id
+                            )) {
+                                Ok(content) => content,
+                                Err(error) => {
+                                    let future: BoxFuture<_> = Box::new(futures::failed(error));
+                                    return future;
+                                }
+                            };
                             let client = CannyLsClient::new(m.node.addr, rpc_service.clone());
                             let mut request = client.request();
                             request.rpc_options(RpcOptions {
@@ -500,7 +1094,6 @@ impl Future for DispersedPut {
                             });
 
                             let device_id = m.device.clone();
-                            let lump_id = m.make_lump_id(version);
                             let data = match track!(LumpData::new(content)) {
                                 Ok(data) => data,
                                 Err(error) => {
@@ -540,7 +1133,7 @@ impl Future for DispersedPut {
                             );
                             future
                         });
-                    Phase::B(PutAll::new(futures, self.data_fragments))
+                    Phase::B(PutAll::new(futures, self.required_put_count))
                 }
                 Phase::B(()) => {
                     return Ok(Async::Ready(()));
@@ -555,6 +1148,9 @@ impl Future for DispersedPut {
 pub struct DispersedGet {
     phase: Phase<CollectFragments, BoxFuture<Vec<u8>>>,
     ec: ErasureCoderPool<LibErasureCoderBuilder>,
+
+    // 復号後の中身の圧縮方式
+    compression: Compression,
     span: Span,
 }
 impl Future for DispersedGet {
@@ -563,7 +1159,8 @@ impl Future for DispersedGet {
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         while let Async::Ready(phase) = track!(self.phase.poll().map_err(Error::from))? {
             let next = match phase {
-                Phase::A(fragments) => {
+                Phase::A((fragments, compression)) => {
+                    self.compression = compression;
                     let mut child = self.span.child("ec_decode", |span| {
                         span.tag(StdTag::component(module_path!()))
                             .tag(Tag::new(
@@ -588,7 +1185,10 @@ impl Future for DispersedGet {
                     );
                     Phase::B(future)
                 }
-                Phase::B(content) => return Ok(Async::Ready(content)),
+                Phase::B(content) => {
+                    let content = track!(decompress(self.compression, content))?;
+                    return Ok(Async::Ready(content));
+                }
             };
             self.phase = next;
         }
@@ -596,9 +1196,361 @@ impl Future for DispersedGet {
     }
 }
 
+type RangePhase = Phase3<
+    BoxFuture<Option<(Vec<u8>, Compression)>>,
+    BoxFuture<Vec<(usize, Option<(Vec<u8>, Compression)>)>>,
+    BoxFuture<Vec<u8>>,
+>;
+
+/// 分散保存されたオブジェクトの一部分を取得するための`Future`。
+///
+/// liberasurecodeの組織符号(systematic code)では、
+/// `i`番目のデータフラグメントのペイロードが元データの`[i * block_size, (i + 1) * block_size)`に対応するので、
+/// 要求範囲を含むデータフラグメントのみを取得すれば、復号処理なしで部分を得ることができる。
+///
+/// ただし中身が圧縮されている場合には、部分の位置を求められないので、全体の取得にフォールバックする。
+pub struct DispersedGetRange {
+    client: DispersedClient,
+    version: ObjectVersion,
+    deadline: Deadline,
+    ranges: Vec<ByteRange>,
+    candidates: Vec<ClusterMember>,
+
+    // ブロックサイズを求めるために、最初に取得するデータフラグメントのインデックス
+    probe_index: usize,
+
+    // フラグメントのインデックスとペイロードの組
+    payloads: BTreeMap<usize, Vec<u8>>,
+    size: u64,
+    block_size: u64,
+    phase: RangePhase,
+    span: Span,
+}
+impl DispersedGetRange {
+    fn fallback(&mut self) -> RangePhase {
+        debug!(
+            self.client.logger,
+            "Falls back to the whole content decoding: version={:?}", self.version
+        );
+        self.span.set_tag(|| Tag::new("fallback", true));
+        let client = self.client.clone();
+        Phase3::C(client.get(self.version, self.deadline, self.span.handle()))
+    }
+    fn handle_fragment(&mut self, index: usize, fragment: Option<(Vec<u8>, Compression)>) -> bool {
+        let fragment = match fragment {
+            Some((fragment, Compression::None)) => fragment,
+            _ => return false,
+        };
+        match FragmentHeader::parse(&fragment) {
+            Some(ref header) if header.index == index && header.block_size > 0 => {
+                if index == self.probe_index {
+                    self.size = header.data_size;
+                    self.block_size = header.block_size as u64;
+                }
+                if header.block_size as u64 != self.block_size {
+                    return false;
+                }
+                let payload = fragment
+                    [FRAGMENT_HEADER_SIZE..FRAGMENT_HEADER_SIZE + header.block_size]
+                    .to_vec();
+                self.payloads.insert(index, payload);
+                true
+            }
+            _ => false,
+        }
+    }
+    fn assemble(&self, ranges: &[Range<u64>]) -> Vec<(Range<u64>, Vec<u8>)> {
+        ranges
+            .iter()
+            .map(|r| {
+                let mut bytes = Vec::with_capacity((r.end - r.start) as usize);
+                let mut offset = r.start;
+                while offset < r.end {
+                    let index = (offset / self.block_size) as usize;
+                    let payload = &self.payloads[&index];
+                    let block_start = index as u64 * self.block_size;
+                    let end = cmp::min(r.end, block_start + self.block_size);
+                    bytes.extend_from_slice(
+                        &payload[(offset - block_start) as usize..(end - block_start) as usize],
+                    );
+                    offset = end;
+                }
+                (r.clone(), bytes)
+            })
+            .collect()
+    }
+}
+impl Future for DispersedGetRange {
+    type Item = (u64, Vec<(Range<u64>, Vec<u8>)>);
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let phase = match self.phase.poll() {
+                Err(e) => {
+                    if let Phase3::C(_) = self.phase {
+                        return Err(track!(e));
+                    }
+                    debug!(self.client.logger, "[DispersedGetRange] Error: {}", e);
+                    self.phase = self.fallback();
+                    continue;
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(phase)) => phase,
+            };
+            let next = match phase {
+                Phase3::A(fragment) => {
+                    let probe_index = self.probe_index;
+                    if !self.handle_fragment(probe_index, fragment) {
+                        self.fallback()
+                    } else {
+                        let ranges = resolve_ranges(&self.ranges, self.size);
+                        let mut indices = Vec::new();
+                        for r in &ranges {
+                            let first = (r.start / self.block_size) as usize;
+                            let last = ((r.end - 1) / self.block_size) as usize;
+                            indices.extend((first..=last).filter(|&i| i != probe_index));
+                        }
+                        indices.sort();
+                        indices.dedup();
+                        if indices.iter().any(|&i| i >= self.candidates.len()) {
+                            self.fallback()
+                        } else {
+                            let futures = indices
+                                .into_iter()
+                                .map(|i| {
+                                    get_fragment_lump(
+                                        &self.candidates[i],
+                                        self.version,
+                                        self.deadline,
+                                        &self.client.rpc_service,
+                                        &self.client.encryption,
+                                        &self.span.handle(),
+                                    )
+                                    .map(move |fragment| (i, fragment))
+                                })
+                                .collect::<Vec<_>>();
+                            let future = future::join_all(futures);
+                            Phase3::B(Box::new(future))
+                        }
+                    }
+                }
+                Phase3::B(fragments) => {
+                    let mut ok = true;
+                    for (index, fragment) in fragments {
+                        ok &= self.handle_fragment(index, fragment);
+                    }
+                    let ranges = resolve_ranges(&self.ranges, self.size);
+                    let covered = ranges.iter().all(|r| {
+                        let first = r.start / self.block_size;
+                        let last = (r.end - 1) / self.block_size;
+                        (first..=last).all(|i| self.payloads.contains_key(&(i as usize)))
+                    });
+                    if ok && covered {
+                        let parts = self.assemble(&ranges);
+                        return Ok(Async::Ready((self.size, parts)));
+                    }
+                    self.fallback()
+                }
+                Phase3::C(content) => {
+                    return Ok(Async::Ready(extract_parts(&content, &self.ranges)));
+                }
+            };
+            self.phase = next;
+        }
+    }
+}
+
+/// 部分取得の際に、ブロックサイズを求めるために最初に取得するデータフラグメントのインデックスを返す。
+///
+/// ブロックサイズはヘッダを取得するまで分からないので、
+/// 先頭の要求範囲を含むことが明らかなフラグメント(e.g., 先頭からの範囲なら最初のデータフラグメント)を優先する。
+/// そのようなフラグメントがない場合には、データフラグメントのうち、クライアントに最も近いものを選択する。
+fn select_probe_fragment(
+    cluster: &ClusterConfig,
+    version: ObjectVersion,
+    data_fragments: usize,
+    ranges: &[ByteRange],
+) -> usize {
+    match ranges.first() {
+        Some(&ByteRange::FromTo(0, _)) | Some(&ByteRange::From(0)) => 0,
+        Some(&ByteRange::Suffix(_)) => data_fragments - 1,
+        _ => cluster
+            .read_candidates(version, data_fragments)
+            .first()
+            .and_then(|nearest| cluster.candidates(version).position(|m| m == nearest))
+            .unwrap_or(0),
+    }
+}
+
+/// liberasurecodeのフラグメントヘッダのうち、部分取得に必要な情報。
+///
+/// liberasurecodeはヘッダをホストのバイトオーダーで書き込むので、
+/// リトルエンディアンのホストで書き込まれたヘッダのみを扱う。
+/// それ以外の場合には`parse`が`None`を返し、全体の取得にフォールバックする。
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FragmentHeader {
+    index: usize,
+    block_size: usize,
+    data_size: u64,
+}
+impl FragmentHeader {
+    fn parse(fragment: &[u8]) -> Option<Self> {
+        if fragment.len() < FRAGMENT_HEADER_SIZE {
+            return None;
+        }
+        if cfg!(target_endian = "big") {
+            return None;
+        }
+        let magic = LittleEndian::read_u32(&fragment[FRAGMENT_HEADER_MAGIC_OFFSET..]);
+        if magic != FRAGMENT_HEADER_MAGIC {
+            return None;
+        }
+        let header = FragmentHeader {
+            index: LittleEndian::read_u32(&fragment[0..]) as usize,
+            block_size: LittleEndian::read_u32(&fragment[4..]) as usize,
+            data_size: LittleEndian::read_u64(&fragment[12..]),
+        };
+        if fragment.len() < FRAGMENT_HEADER_SIZE + header.block_size {
+            return None;
+        }
+        Some(header)
+    }
+}
+
+fn get_fragment_lump(
+    m: &ClusterMember,
+    version: ObjectVersion,
+    deadline: Deadline,
+    rpc_service: &RpcServiceHandle,
+    encryption: &Encryptor,
+    parent: &SpanHandle,
+) -> BoxFuture<Option<(Vec<u8>, Compression)>> {
+    let client = CannyLsClient::new(m.node.addr, rpc_service.clone());
+    let lump_id = m.make_lump_id(version);
+    let mut span = parent.child("get_fragment", |span| {
+        span.tag(StdTag::component(module_path!()))
+            .tag(StdTag::span_kind("client"))
+            .tag(StdTag::peer_ip(m.node.addr.ip()))
+            .tag(StdTag::peer_port(m.node.addr.port()))
+            .tag(Tag::new("device", m.device.clone()))
+            .tag(Tag::new("lump", format!("{:?}", lump_id)))
+            .start()
+    });
+
+    let mut request = client.request();
+    request.rpc_options(RpcOptions {
+        max_queue_len: Some(RPC_MAX_QUEUE_LEN),
+        ..Default::default()
+    });
+    let encryption = encryption.clone();
+    let future = request
+        .deadline(deadline)
+        .get_lump(DeviceId::new(m.device.clone()), lump_id)
+        .map_err(|e| track!(Error::from(e)))
+        .and_then(move |fragment| {
+            if let Some(mut fragment) = fragment {
+                let (compression, key_id) = track!(verify_and_remove_checksum(&mut fragment))?;
+                let fragment = track!(encryption.decrypt(fragment, key_id, lump_id))?;
+                Ok(Some((fragment, compression)))
+            } else {
+                Ok(None)
+            }
+        })
+        .then(move |result| {
+            if let Err(ref e) = result {
+                span.set_tag(StdTag::error);
+                span.log(|log| {
+                    log.error().message(e.to_string());
+                });
+            }
+            result
+        });
+    Box::new(future)
+}
+
+/// 複製ないしフラグメントを、`lump_id`のlumpに保存する形式に変換する。
+///
+/// `encryption`で鍵が選択されている場合には暗号化した上で、チェックサム等のトレイラを付与する。
+fn encode_lump(
+    fragment: Vec<u8>,
+    checksum: ChecksumAlgorithm,
+    compression: Compression,
+    encryption: &Encryptor,
+    lump_id: LumpId,
+) -> Result<Vec<u8>> {
+    let (mut lump, key_id) = track!(encryption.encrypt(fragment, lump_id))?;
+    append_checksum(&mut lump, checksum, compression, key_id);
+    Ok(lump)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn put_fragment_lump(
+    m: &ClusterMember,
+    version: ObjectVersion,
+    fragment: Vec<u8>,
+    checksum: ChecksumAlgorithm,
+    compression: Compression,
+    encryption: &Encryptor,
+    deadline: Deadline,
+    rpc_service: &RpcServiceHandle,
+    parent: &SpanHandle,
+) -> BoxFuture<()> {
+    let lump_id = m.make_lump_id(version);
+    let lump = match track!(encode_lump(
+        fragment,
+        checksum,
+        compression,
+        encryption,
+        lump_id
+    )) {
+        Ok(lump) => lump,
+        Err(error) => return Box::new(futures::failed(error)),
+    };
+    let data = match track!(LumpData::new(lump)) {
+        Ok(data) => data,
+        Err(error) => return Box::new(futures::failed(Error::from(error))),
+    };
+    let client = CannyLsClient::new(m.node.addr, rpc_service.clone());
+    let mut span = parent.child("put_fragment", |span| {
+        span.tag(StdTag::component(module_path!()))
+            .tag(StdTag::span_kind("client"))
+            .tag(StdTag::peer_ip(m.node.addr.ip()))
+            .tag(StdTag::peer_port(m.node.addr.port()))
+            .tag(Tag::new("node", m.node.local_id.to_string()))
+            .tag(Tag::new("device.id", m.device.clone()))
+            .tag(Tag::new("lump.id", lump_id.to_string()))
+            .tag(Tag::new("lump.bytes", data.as_bytes().len() as i64))
+            .start()
+    });
+
+    let mut request = client.request();
+    request.rpc_options(RpcOptions {
+        max_queue_len: Some(RPC_MAX_QUEUE_LEN),
+        ..Default::default()
+    });
+    let future = request
+        .deadline(deadline)
+        .max_queue_len(CANNYLS_MAX_QUEUE_LEN)
+        .put_lump(DeviceId::new(m.device.clone()), lump_id, data)
+        .map(|_is_new| ())
+        .map_err(|e| track!(Error::from(e)))
+        .then(move |result| {
+            if let Err(ref e) = result {
+                span.set_tag(StdTag::error);
+                span.log(|log| {
+                    log.error().message(e.to_string());
+                });
+            }
+            result
+        });
+    Box::new(future)
+}
+
 pub struct CollectFragments {
     logger: Logger,
-    futures: Vec<BoxFuture<Option<Vec<u8>>>>,
+
+    // 取得要求とその対象の候補の組
+    futures: Vec<(Option<ClusterMember>, BoxFuture<Option<Vec<u8>>>)>,
     fragments: Vec<Vec<u8>>,
     data_fragments: usize,
     spares: Vec<ClusterMember>,
@@ -619,10 +1571,85 @@ pub struct CollectFragments {
     //
     // TODO: `deadline`を考慮した値を使うようにする
     timeout: Option<timer::Timeout>,
+
+    // 最初から必要数(`data_fragments`)に加えて発行しておく取得要求の数
+    extra_requests: usize,
+
+    // タイムアウトの度に再設定される待ち時間
+    hedge_delay: Duration,
+
+    hedge_monitor: Option<HedgeMonitor>,
+
+    // フラグメントの保存先(`i`番目の要素が`i`番目のフラグメントを保持する)
+    participants: Vec<ClusterMember>,
+
+    // フラグメントが欠損ないし破損していた候補群
+    broken: Vec<ClusterMember>,
+
+    read_repair: Option<(ReadRepairer, ErasureCoder)>,
+
+    // 修復時に書き込むlumpに付与するチェックサムのアルゴリズム
+    checksum: ChecksumAlgorithm,
+
+    // フラグメントの復号と、修復時に書き込むlumpの暗号化に使用される
+    encryption: Encryptor,
+
+    // 取得したフラグメントのトレイラに記録されていた、中身の圧縮方式
+    compression: Compression,
 }
 impl CollectFragments {
+    fn repair_broken_fragments(&mut self) {
+        let (repairer, ec) = if let Some((ref repairer, ref ec)) = self.read_repair {
+            (repairer, ec)
+        } else {
+            return;
+        };
+        for m in self.broken.drain(..) {
+            let index = if let Some(index) = self.participants.iter().position(|p| *p == m) {
+                index
+            } else {
+                // フラグメントの保存先ではない
+                continue;
+            };
+            let version = self.version;
+            let checksum = self.checksum;
+            let compression = self.compression;
+            let encryption = self.encryption.clone();
+            let rpc_service = self.rpc_service.clone();
+            let fragments = &self.fragments;
+            repairer.repair(&m, version, || {
+                let member = m.clone();
+                let future: BoxFuture<_> = Box::new(
+                    ec.reconstruct(index, fragments.clone())
+                        .map_err(|e| track!(Error::from(e)))
+                        .and_then(move |fragment| {
+                            put_fragment_lump(
+                                &member,
+                                version,
+                                fragment,
+                                checksum,
+                                compression,
+                                &encryption,
+                                Deadline::Infinity,
+                                &rpc_service,
+                                &Span::inactive().handle(),
+                            )
+                        }),
+                );
+                future
+            });
+        }
+    }
+
     fn fill_shortage_from_spare(&mut self, mut force: bool) -> Result<()> {
-        while force || self.futures.len() + self.fragments.len() < self.data_fragments {
+        while force
+            || self.futures.len() + self.fragments.len() < self.data_fragments + self.extra_requests
+        {
+            let hedged = force || self.futures.len() + self.fragments.len() >= self.data_fragments;
+            if hedged && self.spares.is_empty() {
+                // NOTE: 必要数は満たしているので、ヘッジ用の候補が無くなってもエラーにはしない
+                break;
+            }
             force = false;
 
             let m = track!(self
@@ -666,33 +1693,46 @@ impl CollectFragments {
                 ..Default::default()
             });
 
+            if let Some(ref monitor) = self.hedge_monitor {
+                monitor.record_request(hedged);
+            }
+            let monitor = self.hedge_monitor.clone();
+            let started_at = Instant::now();
             let future = request
                 .deadline(self.deadline)
-                .get_lump(DeviceId::new(m.device), lump_id)
+                .get_lump(DeviceId::new(m.device.clone()), lump_id)
                 .then(move |result| {
-                    if let Err(ref e) = result {
-                        span.set_tag(StdTag::error);
-                        span.log(|log| {
-                            let kind = format!("{:?}", e.kind());
-                            log.error().kind(kind).message(e.to_string());
-                        });
+                    match result {
+                        Err(ref e) => {
+                            span.set_tag(StdTag::error);
+                            span.log(|log| {
+                                let kind = format!("{:?}", e.kind());
+                                log.error().kind(kind).message(e.to_string());
+                            });
+                        }
+                        Ok(Some(_)) => {
+                            if let Some(monitor) = monitor {
+                                monitor.record_latency(started_at.elapsed());
+                            }
+                        }
+                        Ok(None) => {}
                     }
                     result
                 });
             let future: BoxFuture<_> = Box::new(future.map_err(|e| track!(Error::from(e))));
-            self.futures.push(future);
+            self.futures.push((Some(m), future));
         }
         Ok(())
     }
 }
 impl Future for CollectFragments {
-    type Item = Vec<Vec<u8>>;
+    type Item = (Vec<Vec<u8>>, Compression);
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         loop {
             let mut i = 0;
             while i < self.futures.len() {
-                match track!(self.futures[i].poll()) {
+                match track!(self.futures[i].1.poll()) {
                     Err(e) => {
                         self.futures.swap_remove(i);
                         debug!(self.logger, "[CollectFragments] Error: {}", e);
@@ -702,24 +1742,56 @@ impl Future for CollectFragments {
                         i += 1;
                     }
                     Ok(Async::Ready(fragment)) => {
-                        self.futures.swap_remove(i);
+                        let (member, _) = self.futures.swap_remove(i);
                         if let Some(mut fragment) = fragment {
-                            if let Err(e) = track!(verify_and_remove_checksum(&mut fragment)) {
-                                // TODO: Add protection for log overflow
-                                warn!(self.logger, "[CollectFragments] Corrupted fragment: {}", e);
-                                track!(self.fill_shortage_from_spare(false))?;
-                            } else {
-                                self.fragments.push(fragment);
+                            match track!(verify_and_remove_checksum(&mut fragment)) {
+                                Err(e) => {
+                                    // TODO: Add protection for log overflow
+                                    warn!(
+                                        self.logger,
+                                        "[CollectFragments] Corrupted fragment: {}", e
+                                    );
+                                    self.broken.extend(member);
+                                    track!(self.fill_shortage_from_spare(false))?;
+                                }
+                                Ok((compression, key_id)) => {
+                                    let lump_id =
+                                        member.as_ref().map(|m| m.make_lump_id(self.version));
+                                    let lump_id = track_assert_some!(lump_id, ErrorKind::Other);
+                                    match track!(self.encryption.decrypt(fragment, key_id, lump_id))
+                                    {
+                                        Err(e) => {
+                                            // NOTE: 鍵の設定の問題である可能性が高いので、修復の対象とはしない
+                                            warn!(
+                                                self.logger,
+                                                "[CollectFragments] Cannot decrypt fragment: {}", e
+                                            );
+                                            track!(self.fill_shortage_from_spare(false))?;
+                                        }
+                                        Ok(fragment) => {
+                                            self.compression = compression;
+                                            self.fragments.push(fragment);
+                                        }
+                                    }
+                                }
                             }
                         } else {
                             debug!(self.logger, "[CollectFragments] NotFound");
+                            self.broken.extend(member);
                             track!(self.fill_shortage_from_spare(false))?;
                         }
                     }
                 }
             }
             if self.fragments.len() == self.data_fragments {
-                return Ok(Async::Ready(mem::replace(&mut self.fragments, Vec::new())));
+                // 必要数が揃ったので、残りの(遅延している)取得要求は破棄する
+                if let Some(ref monitor) = self.hedge_monitor {
+                    monitor.record_cancelled_requests(self.futures.len());
+                }
+                self.futures.clear();
+                self.repair_broken_fragments();
+                let fragments = mem::replace(&mut self.fragments, Vec::new());
+                return Ok(Async::Ready((fragments, self.compression)));
             }
             if let Ok(Async::Ready(Some(()))) = self.timeout.poll() {
                 // TODO: ログは出さなくする(かわりにprometheusを使う)
@@ -732,7 +1804,7 @@ impl Future for CollectFragments {
                     if let Err(e) = track!(self.fill_shortage_from_spare(true)) {
                         warn!(self.logger, "{}", e);
                     } else {
-                        self.timeout = Some(timer::timeout(Duration::from_secs(2)));
+                        self.timeout = Some(timer::timeout(self.hedge_delay));
                         continue;
                     }
                 }
@@ -746,8 +1818,8 @@ impl Future for CollectFragments {
 #[derive(Debug, PartialEq, Eq)]
 /// This enum represents the result of `GetFragment`.
 pub enum MaybeFragment {
-    /// Successfully get a content.
-    Fragment(Vec<u8>),
+    /// Successfully get a content, and the compression of the whole content recorded in its trailer.
+    Fragment(Vec<u8>, Compression),
 
     /// It's not responsible for storing a fragment.
     NotParticipant,
@@ -755,7 +1827,7 @@ pub enum MaybeFragment {
 
 #[allow(clippy::large_enum_variant)]
 pub enum GetFragment {
-    Failed(future::Failed<Vec<u8>, Error>),
+    Failed(future::Failed<MaybeFragment, Error>),
     Replicated(GetReplicatedFragment),
     Dispersed(GetDispersedFragment),
 }
@@ -764,20 +1836,18 @@ impl Future for GetFragment {
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         match *self {
-            GetFragment::Failed(ref mut f) => {
-                track!(f.poll().map(|content| content.map(MaybeFragment::Fragment)))
-            }
-            GetFragment::Replicated(ref mut f) => {
-                track!(f.poll().map(|content| content.map(MaybeFragment::Fragment)))
-            }
+            GetFragment::Failed(ref mut f) => track!(f.poll()),
+            GetFragment::Replicated(ref mut f) => track!(f.poll().map(|content| {
+                content.map(|(content, compression)| MaybeFragment::Fragment(content, compression))
+            })),
             GetFragment::Dispersed(ref mut f) => track!(f.poll()),
         }
     }
 }
 
-pub struct GetReplicatedFragment(BoxFuture<Vec<u8>>);
+pub struct GetReplicatedFragment(BoxFuture<(Vec<u8>, Compression)>);
 impl Future for GetReplicatedFragment {
-    type Item = Vec<u8>;
+    type Item = (Vec<u8>, Compression);
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         track!(self.0.poll())
@@ -796,6 +1866,9 @@ pub struct GetDispersedFragment {
     /// The index of a focusing node.
     /// None represents that there is no missing index.
     missing_index: Option<usize>,
+
+    /// The compression of the whole content, which is recorded in the collected fragments.
+    compression: Compression,
 }
 impl Future for GetDispersedFragment {
     type Item = MaybeFragment;
@@ -809,12 +1882,18 @@ impl Future for GetDispersedFragment {
 
         while let Async::Ready(phase) = track!(self.phase.poll().map_err(Error::from))? {
             let next = match phase {
-                Phase::A(fragments) => {
+                Phase::A((fragments, compression)) => {
+                    self.compression = compression;
                     let future = self.ec.reconstruct(missing_index, fragments);
                     let future: BoxFuture<_> = Box::new(future.map_err(|e| track!(Error::from(e))));
                     Phase::B(future)
                 }
-                Phase::B(fragment) => return Ok(Async::Ready(MaybeFragment::Fragment(fragment))),
+                Phase::B(fragment) => {
+                    return Ok(Async::Ready(MaybeFragment::Fragment(
+                        fragment,
+                        self.compression,
+                    )));
+                }
             };
             self.phase = next;
         }
@@ -822,29 +1901,13 @@ impl Future for GetDispersedFragment {
     }
 }
 
-pub(crate) fn append_checksum(bytes: &mut Vec<u8>) {
-    let checksum = adler32::adler32(&bytes[..]).expect("Never fails");
-    let mut trailer = [0; 5]; // TODO: フォーマットを文書化
-    BigEndian::write_u32(&mut trailer[..], checksum);
-    bytes.extend_from_slice(&trailer[..]);
-}
-
-fn verify_and_remove_checksum(bytes: &mut Vec<u8>) -> Result<()> {
-    track_assert!(bytes.len() >= 5, ErrorKind::Invalid);
-    let split_pos = bytes.len() - 5;
-
-    let checksum = adler32::adler32(&bytes[..split_pos]).expect("Never fails");
-    let expected = BigEndian::read_u32(&bytes[split_pos..]);
-    track_assert_eq!(checksum, expected, ErrorKind::Invalid);
-
-    bytes.truncate(split_pos);
-    Ok(())
-}
-
 #[cfg(test)]
 mod tests {
+    use byteorder::BigEndian;
+
     use super::*;
-    use test_util::tests::{setup_system, wait, System};
+    use config::Locality;
+    use test_util::tests::{make_node_id, setup_system, wait, System};
     use trackable::result::TestResult;
 
     #[test]
@@ -876,6 +1939,132 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn it_gets_range_correctly() -> TestResult {
+        // fragments = 5 (data_fragments = 4, parity_fragments = 1)
+        let fragments = 5;
+        let cluster_size = 5;
+        let mut system = System::new(fragments)?;
+        let (_, _, storage_client) = setup_system(&mut system, cluster_size)?;
+        let version = ObjectVersion(2);
+        let content = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+
+        let _ = wait(storage_client.clone().put(
+            version.clone(),
+            content.clone(),
+            Deadline::Infinity,
+            Span::inactive().handle(),
+        ))?;
+        let (size, parts) = wait(storage_client.clone().get_range(
+            ObjectValue {
+                version,
+                content: Vec::new(),
+            },
+            vec![
+                ByteRange::FromTo(10, 19),
+                ByteRange::Suffix(300),
+                ByteRange::From(1000),
+            ],
+            Deadline::Infinity,
+            Span::inactive().handle(),
+        ))?;
+
+        assert_eq!(size, 1000);
+        assert_eq!(
+            parts,
+            vec![
+                (10..20, content[10..20].to_vec()),
+                (700..1000, content[700..1000].to_vec()),
+            ]
+        );
+
+        // 先頭以外のデータフラグメントからブロックサイズを求める場合
+        for ranges in vec![
+            vec![ByteRange::Suffix(300), ByteRange::FromTo(10, 19)],
+            vec![ByteRange::From(500)],
+        ] {
+            let (size, parts) = wait(storage_client.clone().get_range(
+                ObjectValue {
+                    version,
+                    content: Vec::new(),
+                },
+                ranges.clone(),
+                Deadline::Infinity,
+                Span::inactive().handle(),
+            ))?;
+            assert_eq!(size, 1000);
+            assert_eq!(parts, extract_parts(&content, &ranges).1);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn select_probe_fragment_works() {
+        let mut cluster = ClusterConfig {
+            members: (0..5)
+                .map(|n| ClusterMember {
+                    node: make_node_id(n),
+                    device: n.to_string(),
+                })
+                .collect(),
+            locality: Locality::default(),
+        };
+        let version = ObjectVersion(1);
+        let data_fragments = 3;
+        let select = |cluster: &ClusterConfig, range| {
+            select_probe_fragment(cluster, version, data_fragments, &[range])
+        };
+
+        assert_eq!(select(&cluster, ByteRange::FromTo(0, 10)), 0);
+        assert_eq!(select(&cluster, ByteRange::From(0)), 0);
+        assert_eq!(select(&cluster, ByteRange::Suffix(10)), 2);
+        assert_eq!(select(&cluster, ByteRange::From(10)), 0);
+
+        // データフラグメントのうち、最も近いもの
+        let devices = cluster
+            .candidates(version)
+            .map(|m| m.device.clone())
+            .collect::<Vec<_>>();
+        cluster.locality.set_distance(devices[1].clone(), 0);
+        cluster.locality.set_distance(devices[3].clone(), 0);
+        assert_eq!(select(&cluster, ByteRange::From(10)), 1);
+        assert_eq!(select(&cluster, ByteRange::FromTo(0, 10)), 0);
+    }
+
+    #[test]
+    fn fragment_header_parse_works() {
+        let mut fragment = vec![0; FRAGMENT_HEADER_SIZE + 8];
+        LittleEndian::write_u32(&mut fragment[0..], 1);
+        LittleEndian::write_u32(&mut fragment[4..], 8);
+        LittleEndian::write_u64(&mut fragment[12..], 20);
+        LittleEndian::write_u32(
+            &mut fragment[FRAGMENT_HEADER_MAGIC_OFFSET..],
+            FRAGMENT_HEADER_MAGIC,
+        );
+        assert_eq!(
+            FragmentHeader::parse(&fragment),
+            Some(FragmentHeader {
+                index: 1,
+                block_size: 8,
+                data_size: 20,
+            })
+        );
+
+        // ペイロードが足りない
+        assert_eq!(
+            FragmentHeader::parse(&fragment[..FRAGMENT_HEADER_SIZE]),
+            None
+        );
+
+        // バイトオーダーの異なるホストで書き込まれたヘッダ
+        BigEndian::write_u32(
+            &mut fragment[FRAGMENT_HEADER_MAGIC_OFFSET..],
+            FRAGMENT_HEADER_MAGIC,
+        );
+        assert_eq!(FragmentHeader::parse(&fragment), None);
+    }
+
     #[test]
     fn get_fragment_works() -> TestResult {
         // fragments = 5 (data_fragments = 4, parity_fragments = 1)
@@ -899,7 +2088,7 @@ mod tests {
                 .get_fragment(node_id.clone(), version.clone()),
         )?;
 
-        if let MaybeFragment::Fragment(content) = result {
+        if let MaybeFragment::Fragment(content, _) = result {
             assert!(content.len() > 0);
             return Ok(());
         }
@@ -936,4 +2125,29 @@ mod tests {
 
         Ok(())
     }
+
+    fn put_results(results: Vec<bool>) -> impl Iterator<Item = BoxFuture<()>> {
+        results.into_iter().map(|ok| {
+            let future: BoxFuture<()> = if ok {
+                Box::new(futures::finished(()))
+            } else {
+                Box::new(futures::failed(ErrorKind::Other.error().into()))
+            };
+            future
+        })
+    }
+
+    #[test]
+    fn put_all_works() -> TestResult {
+        wait(PutAll::new(put_results(vec![false, true, true]), 2))?;
+        wait(PutAll::new(put_results(vec![true, false, true]), 1))?;
+        Ok(())
+    }
+
+    #[test]
+    fn put_all_fails_if_too_few_puts_succeed() {
+        assert!(wait(PutAll::new(put_results(vec![false, true]), 2)).is_err());
+        assert!(wait(PutAll::new(put_results(vec![true, false, false]), 2)).is_err());
+        assert!(wait(PutAll::new(put_results(vec![true, true]), 3)).is_err());
+    }
 }
@@ -1,15 +1,26 @@
 //! セグメント構成に関係する構造体等。
 use byteorder::{BigEndian, ByteOrder};
 use cannyls::lump::LumpId;
+use frugalos_mds::schema::SharedVersioningPolicy;
 use frugalos_raft::NodeId;
 use libfrugalos::entity::object::ObjectVersion;
 use libfrugalos::time::Seconds;
 use raftlog::cluster::ClusterMembers;
 use siphasher::sip::SipHasher;
+use std::cmp;
+use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use client::compression::Compressor;
+use client::encryption::Encryptor;
 
 // TODO: LumpIdの名前空間の使い方に関してWikiに記載する
 pub(crate) const LUMP_NAMESPACE_CONTENT: u8 = 1;
+pub(crate) const LUMP_NAMESPACE_REBUILD: u8 = 2;
+pub(crate) const LUMP_NAMESPACE_SYNC_QUEUE: u8 = 3;
+pub(crate) const LUMP_NAMESPACE_REENCRYPTION: u8 = 4;
 
 /// Raftクラスタ(i.e., セグメント)内のメンバ情報。
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -38,6 +49,31 @@ pub(crate) fn make_lump_id(node: &NodeId, version: ObjectVersion) -> LumpId {
     LumpId::new(BigEndian::read_u128(&id[..]))
 }
 
+/// 対象ノードの同期処理の未処理の削除TODOを保存する際に使用する`LumpId`を返す。
+pub(crate) fn make_sync_queue_lump_id(node: &NodeId, version: ObjectVersion) -> LumpId {
+    let mut id = [0; 16];
+    (&mut id[0..7]).copy_from_slice(node.local_id.as_slice());
+    id[0] = LUMP_NAMESPACE_SYNC_QUEUE;
+    BigEndian::write_u64(&mut id[8..], version.0);
+    LumpId::new(BigEndian::read_u128(&id[..]))
+}
+
+/// 対象ノードの再構築の進捗を保存する際に使用する`LumpId`を返す。
+pub(crate) fn make_rebuild_lump_id(node: &NodeId) -> LumpId {
+    let mut id = [0; 16];
+    (&mut id[0..7]).copy_from_slice(node.local_id.as_slice());
+    id[0] = LUMP_NAMESPACE_REBUILD;
+    LumpId::new(BigEndian::read_u128(&id[..]))
+}
+
+/// 対象ノードの再暗号化が完了した鍵を保存する際に使用する`LumpId`を返す。
+pub(crate) fn make_reencryption_lump_id(node: &NodeId) -> LumpId {
+    let mut id = [0; 16];
+    (&mut id[0..7]).copy_from_slice(node.local_id.as_slice());
+    id[0] = LUMP_NAMESPACE_REENCRYPTION;
+    LumpId::new(BigEndian::read_u128(&id[..]))
+}
+
 /// Configuration for `MdsClient`.
 #[derive(Debug, Clone)]
 pub struct MdsClientConfig {
@@ -62,6 +98,23 @@ pub struct ClientConfig {
     pub cluster: ClusterConfig,
     pub storage: Storage,
     pub mds: MdsClientConfig,
+
+    /// 新たに書き込むlumpに付与するチェックサムのアルゴリズム。
+    pub checksum: ChecksumSelector,
+
+    /// 新たに書き込む中身の圧縮方式。
+    pub compression: Compressor,
+
+    /// 新たに書き込むlumpの暗号化に使用する鍵と、読み込み時の復号に使用する鍵の提供者。
+    pub encryption: Encryptor,
+
+    /// このサイズ(バイト)未満の中身は、ストレージではなくMDSに保存される(`0`の場合はインライン化しない)。
+    ///
+    /// `MAX_INLINE_THRESHOLD`を超える値は、その値に切り詰められる。
+    pub inline_threshold: usize,
+
+    /// バケツのバージョン管理の設定(セグメントのMDSノードに反映される)。
+    pub versioning: SharedVersioningPolicy,
 }
 impl ClientConfig {
     /// 対象のセグメントに属しているメンバ一覧を返す。
@@ -79,6 +132,10 @@ impl ClientConfig {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClusterConfig {
     pub members: Vec<ClusterMember>,
+
+    /// 読み込み時の取得先候補の順序付けに使われる、クライアントから見た各メンバの近さ。
+    #[serde(skip)]
+    pub locality: Locality,
 }
 impl ClusterConfig {
     /// オブジェクトデータの取得先候補を優先順位が高い順に返す。
@@ -88,6 +145,48 @@ impl ClusterConfig {
         let i = hasher.finish() as usize % self.members.len();
         Candidates::new(&self.members, i)
     }
+
+    /// `candidates`の先頭`n`個を、クライアントに近い順に並べ替えて返す。
+    ///
+    /// 近さが等しいメンバ同士の順序は`candidates`のものが維持される。
+    pub fn read_candidates(&self, version: ObjectVersion, n: usize) -> Vec<ClusterMember> {
+        let mut candidates = self
+            .candidates(version)
+            .take(n)
+            .cloned()
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|m| self.locality.distance(&m.device));
+        candidates
+    }
+}
+
+/// クライアントから見た、各デバイスの近さの情報。
+#[derive(Debug, Clone, Default)]
+pub struct Locality {
+    distances: HashMap<String, usize>,
+}
+impl Locality {
+    /// 新しい`Locality`インスタンスを生成する。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// デバイスの距離を設定する。
+    ///
+    /// `0`はクライアントと同じサーバ上のデバイスを意味し、値が小さいほど近いことを表す。
+    pub fn set_distance(&mut self, device: String, distance: usize) {
+        self.distances.insert(device, distance);
+    }
+
+    /// デバイスの距離を返す。
+    ///
+    /// 距離が設定されていないデバイスは、最も遠いものとして扱われる。
+    pub fn distance(&self, device: &str) -> usize {
+        self.distances
+            .get(device)
+            .cloned()
+            .unwrap_or(usize::max_value())
+    }
 }
 
 /// A set of `ClusterMember`s which MAY have a replica of original data.
@@ -191,6 +290,21 @@ pub struct ReplicatedConfig {
     ///
     /// `tolerable_faults + 1`が複製数となる。
     pub tolerable_faults: u8,
+
+    /// PUT時の書き込みクォーラム。
+    #[serde(default)]
+    pub write_quorum: WriteQuorum,
+}
+impl ReplicatedConfig {
+    /// PUTを成功とみなすために、保存が完了している必要がある複製の数を返す。
+    pub fn required_put_count(&self) -> usize {
+        let replica = self.tolerable_faults as usize + 1;
+        match self.write_quorum {
+            WriteQuorum::One => 1,
+            WriteQuorum::Majority => replica / 2 + 1,
+            WriteQuorum::All => replica,
+        }
+    }
 }
 
 /// ErasureCodingによる冗長化を行うストレージの構成情報。
@@ -203,6 +317,14 @@ pub struct DispersedConfig {
 
     /// データおよびパリティを合わせたフラグメントの合計数。
     pub fragments: u8,
+
+    /// PUT時の書き込みクォーラム。
+    #[serde(default)]
+    pub write_quorum: WriteQuorum,
+
+    /// GET時のフラグメント取得要求のヘッジ方針。
+    #[serde(default)]
+    pub hedge: HedgePolicy,
 }
 
 impl DispersedConfig {
@@ -211,6 +333,185 @@ impl DispersedConfig {
     pub fn fragments(&self) -> u8 {
         self.fragments
     }
+
+    /// PUTを成功とみなすために、保存が完了している必要があるフラグメントの数を返す。
+    ///
+    /// 復元にはデータフラグメント数分が必要なので、この値がそれを下回ることはない。
+    pub fn required_put_count(&self) -> usize {
+        let fragments = self.fragments as usize;
+        let data_fragments = fragments - self.tolerable_faults as usize;
+        match self.write_quorum {
+            WriteQuorum::One => data_fragments,
+            WriteQuorum::Majority => cmp::max(data_fragments, fragments / 2 + 1),
+            WriteQuorum::All => fragments,
+        }
+    }
+}
+
+/// PUT時に、成功を返すまでに保存の完了を待つ配置先の数の指定。
+///
+/// 待たなかった配置先への保存が失敗した場合でも、PUT自体は成功として扱われる
+/// (欠損分は、後続の同期処理によって補われることを期待する)。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WriteQuorum {
+    /// 最小限の配置先への保存を待つ。
+    ///
+    /// 複製の場合には一つ、ErasureCodingの場合にはデータフラグメント数分となる。
+    #[serde(rename = "one")]
+    One,
+
+    /// 過半数の配置先への保存を待つ。
+    #[serde(rename = "majority")]
+    Majority,
+
+    /// 全ての配置先への保存を待つ。
+    #[serde(rename = "all")]
+    All,
+}
+impl Default for WriteQuorum {
+    fn default() -> Self {
+        WriteQuorum::One
+    }
+}
+
+/// ErasureCodingのGET時に、フラグメントの取得要求をヘッジする方針。
+///
+/// 一部のディスクの遅延がそのままGETの遅延とならないように、
+/// 必要数(データフラグメント数)を超える取得要求を発行する。
+/// 必要数のフラグメントが揃った時点で、残りの要求は破棄される。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HedgePolicy {
+    /// 最初から必要数に加えて発行しておく取得要求の数。
+    #[serde(default)]
+    pub extra_requests: u8,
+
+    /// 必要数のフラグメントが揃わない場合に、取得候補を一つ増やすまでの待ち時間(ミリ秒)。
+    #[serde(default = "default_hedge_delay_millis")]
+    pub delay_millis: u64,
+
+    /// 指定された場合には、直近のフラグメント取得の遅延のこのパーセンタイル値(`1..=100`)を待ち時間とする。
+    ///
+    /// ただし`delay_millis`を上限とし、統計が十分に集まるまでは`delay_millis`が使われる。
+    #[serde(default)]
+    pub delay_percentile: Option<u8>,
+}
+impl Default for HedgePolicy {
+    fn default() -> Self {
+        HedgePolicy {
+            extra_requests: 0,
+            delay_millis: default_hedge_delay_millis(),
+            delay_percentile: None,
+        }
+    }
+}
+
+fn default_hedge_delay_millis() -> u64 {
+    2000
+}
+
+/// lumpに保存されるデータに付与するチェックサムのアルゴリズム。
+///
+/// 読み込み時には、書き込み時に使われたアルゴリズムで検証されるので、
+/// 設定を変更しても既存のlumpは引き続き読み込み可能。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChecksumAlgorithm {
+    /// Adler-32.
+    #[serde(rename = "adler32")]
+    Adler32,
+
+    /// CRC-32C (Castagnoli).
+    #[serde(rename = "crc32c")]
+    Crc32c,
+
+    /// xxHash (64bit).
+    #[serde(rename = "xxhash64")]
+    XxHash64,
+}
+impl ChecksumAlgorithm {
+    fn to_usize(self) -> usize {
+        match self {
+            ChecksumAlgorithm::Adler32 => 0,
+            ChecksumAlgorithm::Crc32c => 1,
+            ChecksumAlgorithm::XxHash64 => 2,
+        }
+    }
+
+    fn from_usize(n: usize) -> Self {
+        match n {
+            1 => ChecksumAlgorithm::Crc32c,
+            2 => ChecksumAlgorithm::XxHash64,
+            _ => ChecksumAlgorithm::Adler32,
+        }
+    }
+}
+impl Default for ChecksumAlgorithm {
+    fn default() -> Self {
+        // NOTE: 古いバージョンのノードでも読み込めるように、デフォルトは従来のアルゴリズムとする
+        ChecksumAlgorithm::Adler32
+    }
+}
+
+/// バケツで選択されている`ChecksumAlgorithm`を、クライアントやノード間で共有するためのハンドル。
+///
+/// `set`による変更は、同じハンドルを共有している全ての箇所に即座に反映される。
+#[derive(Debug, Clone, Default)]
+pub struct ChecksumSelector(Arc<AtomicUsize>);
+impl ChecksumSelector {
+    /// 新しい`ChecksumSelector`インスタンスを生成する。
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        ChecksumSelector(Arc::new(AtomicUsize::new(algorithm.to_usize())))
+    }
+
+    /// 現在選択されているアルゴリズムを返す。
+    pub fn get(&self) -> ChecksumAlgorithm {
+        ChecksumAlgorithm::from_usize(self.0.load(Ordering::SeqCst))
+    }
+
+    /// アルゴリズムを変更する。
+    pub fn set(&self, algorithm: ChecksumAlgorithm) {
+        self.0.store(algorithm.to_usize(), Ordering::SeqCst);
+    }
+}
+
+/// オブジェクトの中身の圧縮方式。
+///
+/// 圧縮は、複製ないしErasureCodingによる符号化の前に、中身全体に対して行われる。
+/// 使用された方式は各lumpのトレイラに記録されるので、読み込み時には自動的に伸長される。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    /// 圧縮しない。
+    #[serde(rename = "none")]
+    None,
+
+    /// Zstandard.
+    #[serde(rename = "zstd")]
+    Zstd,
+
+    /// LZ4.
+    #[serde(rename = "lz4")]
+    Lz4,
+}
+impl Compression {
+    pub(crate) fn to_usize(self) -> usize {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+
+    pub(crate) fn from_usize(n: usize) -> Self {
+        match n {
+            1 => Compression::Zstd,
+            2 => Compression::Lz4,
+            _ => Compression::None,
+        }
+    }
+}
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
 }
 
 #[cfg(test)]
@@ -244,7 +545,10 @@ mod tests {
             members.push(make_member(n));
         }
 
-        ClusterConfig { members }
+        ClusterConfig {
+            members,
+            locality: Locality::default(),
+        }
     }
 
     /// Collects all device names from `ClusterConfig`.
@@ -258,6 +562,45 @@ mod tests {
             .collect::<Vec<_>>()
     }
 
+    #[test]
+    fn replicated_required_put_count_works() {
+        let mut config = ReplicatedConfig {
+            tolerable_faults: 2,
+            write_quorum: WriteQuorum::default(),
+        };
+        assert_eq!(config.required_put_count(), 1);
+
+        config.write_quorum = WriteQuorum::Majority;
+        assert_eq!(config.required_put_count(), 2);
+
+        config.write_quorum = WriteQuorum::All;
+        assert_eq!(config.required_put_count(), 3);
+    }
+
+    #[test]
+    fn dispersed_required_put_count_works() {
+        // data_fragments = 4, parity_fragments = 2
+        let mut config = DispersedConfig {
+            tolerable_faults: 2,
+            fragments: 6,
+            write_quorum: WriteQuorum::default(),
+            hedge: HedgePolicy::default(),
+        };
+        assert_eq!(config.required_put_count(), 4);
+
+        config.write_quorum = WriteQuorum::Majority;
+        assert_eq!(config.required_put_count(), 4);
+
+        config.write_quorum = WriteQuorum::All;
+        assert_eq!(config.required_put_count(), 6);
+
+        // data_fragments = 2, parity_fragments = 3
+        config.tolerable_faults = 3;
+        config.fragments = 5;
+        config.write_quorum = WriteQuorum::Majority;
+        assert_eq!(config.required_put_count(), 3);
+    }
+
     #[test]
     fn cluster_config_works() {
         let cluster = make_cluster(5);
@@ -271,6 +614,29 @@ mod tests {
         assert_eq!(candidates[4], "2");
     }
 
+    #[test]
+    fn read_candidates_works() {
+        let mut cluster = make_cluster(5);
+        let collect = |cluster: &ClusterConfig, n| {
+            cluster
+                .read_candidates(ObjectVersion(1), n)
+                .into_iter()
+                .map(|m| m.device)
+                .collect::<Vec<_>>()
+        };
+
+        // 近さの情報がない場合には、ハッシュ順のまま
+        assert_eq!(collect(&cluster, 5), vec!["3", "4", "0", "1", "2"]);
+
+        cluster.locality.set_distance("0".to_owned(), 0);
+        cluster.locality.set_distance("4".to_owned(), 1);
+        cluster.locality.set_distance("2".to_owned(), 1);
+        assert_eq!(collect(&cluster, 5), vec!["0", "4", "2", "3", "1"]);
+
+        // 並べ替えの対象は、ハッシュ順で先頭の`n`個のみ
+        assert_eq!(collect(&cluster, 2), vec!["4", "3"]);
+    }
+
     #[test]
     fn participants_works() -> TestResult {
         let cluster_size = 5;
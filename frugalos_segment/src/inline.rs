@@ -0,0 +1,63 @@
+//! データ(`metadata`以外)のバケツで、小さなオブジェクトの中身をMDSに直接保存(インライン化)するための処理。
+//!
+//! インライン化された中身は、先頭一バイトのタグに続けてMDSのデータとして保存され、
+//! ストレージ(lump)には保存されない。
+//! そのため読み込み時には`StorageClient`を経由せず、同期処理による修復や再構築の対象にもならない。
+//!
+//! タグはマルチパートアップロードのマニフェスト等と共通の空間に属する(`multipart`モジュールを参照)。
+use {ErrorKind, Result};
+
+/// インライン化の閾値として指定可能な最大値(バイト)。
+///
+/// これよりも大きな閾値が指定された場合には、この値に切り詰められる。
+pub const MAX_INLINE_THRESHOLD: usize = 64 * 1024;
+
+const DATA_KIND_INLINE: u8 = 3;
+
+/// MDSに保存されているデータが、インライン化された中身かどうかを判定する。
+pub(crate) fn is_inline(data: &[u8]) -> bool {
+    data.first() == Some(&DATA_KIND_INLINE)
+}
+
+/// 中身を、インライン化してMDSに保存するためのデータに変換する。
+pub(crate) fn encode(content: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(1 + content.len());
+    data.push(DATA_KIND_INLINE);
+    data.extend_from_slice(content);
+    data
+}
+
+/// MDSに保存されているデータから、インライン化された中身を取り出す。
+pub(crate) fn decode(mut data: Vec<u8>) -> Result<Vec<u8>> {
+    track_assert!(is_inline(&data), ErrorKind::Corrupted);
+    data.remove(0);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use multipart::{Manifest, UploadId};
+    use trackable::result::TestResult;
+
+    #[test]
+    fn inline_data_works() -> TestResult {
+        for content in &[&b""[..], &b"foo"[..]] {
+            let data = encode(content);
+            assert!(is_inline(&data));
+            assert!(!Manifest::is_manifest(&data));
+            assert_eq!(track!(decode(data))?, content.to_vec());
+        }
+
+        // 外部に保存されたオブジェクトやマニフェストは、インライン化されたものとはみなされない
+        assert!(!is_inline(&[]));
+        let manifest = Manifest {
+            upload_id: UploadId(1),
+            parts: Vec::new(),
+            part_sizes: None,
+        };
+        assert!(!is_inline(&manifest.to_bytes()));
+        assert!(decode(manifest.to_bytes()).is_err());
+        Ok(())
+    }
+}
@@ -7,6 +7,7 @@ extern crate adler32;
 extern crate byteorder;
 extern crate cannyls;
 extern crate cannyls_rpc;
+extern crate crc;
 extern crate ecpool;
 extern crate fibers;
 #[cfg(test)]
@@ -16,9 +17,11 @@ extern crate frugalos_mds;
 extern crate frugalos_raft;
 extern crate futures;
 extern crate libfrugalos;
+extern crate lz4;
 extern crate prometrics;
 extern crate raftlog;
 extern crate rand;
+extern crate ring;
 extern crate rustracing;
 extern crate rustracing_jaeger;
 extern crate serde;
@@ -29,17 +32,43 @@ extern crate siphasher;
 extern crate slog;
 #[macro_use]
 extern crate trackable;
+extern crate twox_hash;
+extern crate zstd;
 
+pub use chunk::{ContentStream, ObjectStream, CHUNK_SIZE};
+pub use client::compression::Compressor;
+pub use client::encryption::{Cipher, EncryptionKey, Encryptor, KeyFile, KeyId, KeyProvider};
+pub use client::hedge::HedgeMonitor;
+pub use client::repair::ReadRepairer;
 pub use client::storage::{build_ec, ErasureCoder};
-pub use client::Client;
+pub use client::{Client, MAX_BATCH_CONTENT_SIZE, MAX_BATCH_TOTAL_CONTENT_SIZE};
 pub use error::{Error, ErrorKind};
+pub use frugalos_mds::schema::{
+    BatchOperation, ObjectAttributes, ObjectVersionEntry, ReadConsistency, SharedVersioningPolicy,
+    UserMetadata, VersioningPolicy,
+};
+pub use inline::MAX_INLINE_THRESHOLD;
+pub use multipart::{is_internal_object_id, UploadId, MAX_PART_NUMBER};
+pub use range::{ByteRange, PartialObjectValue};
+pub use repair_control::{RepairController, RepairSettings};
+pub use scrubber::{ScrubStatus, ScrubberRegistry};
 pub use service::{Service, ServiceHandle};
 
 pub mod config;
 
+mod chunk;
 mod client;
 mod error;
+mod inline;
+mod multipart;
+mod orphan_gc;
+mod range;
+mod rebuilder;
+mod reencryptor;
+mod repair_control;
+mod scrubber;
 mod service;
+mod sync_queue;
 mod synchronizer;
 mod test_util;
 mod util;
@@ -56,3 +85,19 @@ pub struct ObjectValue {
     /// 中身。
     pub content: Vec<u8>,
 }
+
+/// `Client::batch`で実行された個々の操作の結果。
+#[derive(Debug, Clone)]
+pub enum BatchResult {
+    /// `BatchOperation::Get`の結果。
+    Get(Option<(ObjectValue, UserMetadata)>),
+
+    /// `BatchOperation::Head`の結果。
+    Head(Option<(libfrugalos::entity::object::ObjectVersion, UserMetadata)>),
+
+    /// `BatchOperation::Put`の結果(保存されたバージョンと、新規作成の場合には`true`)。
+    Put(libfrugalos::entity::object::ObjectVersion, bool),
+
+    /// `BatchOperation::Delete`の結果。
+    Delete(Option<libfrugalos::entity::object::ObjectVersion>),
+}
@@ -0,0 +1,367 @@
+//! マルチパートアップロード関連の構成要素。
+//!
+//! マルチパートアップロードでは、以下の三種類のオブジェクトがセグメント内に保存される:
+//!
+//! - アップロード記録: アップロード開始時に作成される、MDSのみに保存されるオブジェクト
+//! - パート: 通常のオブジェクトと同様に、それぞれが個別のlumpとして保存されるオブジェクト
+//! - マニフェスト: アップロード完了時に、論理的なオブジェクトのIDで保存されるパート一覧
+//!
+//! アップロード記録とパートのIDは`\0`から始まる内部用のIDとなる。
+//! これはHTTP経由で指定されることはないので、利用者のオブジェクトと衝突することはない。
+use byteorder::{BigEndian, ByteOrder};
+use libfrugalos::entity::object::{ObjectId, ObjectPrefix, ObjectVersion};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use {Error, ErrorKind, ObjectAttributes, Result, UserMetadata};
+
+/// パート番号の最大値。
+pub const MAX_PART_NUMBER: u32 = 10_000;
+
+const INTERNAL_OBJECT_ID_PREFIX: char = '\u{0}';
+const MULTIPART_OBJECT_ID_PREFIX: &str = "\u{0}multipart/";
+
+// MDSに保存されるデータの種別を示すタグ (先頭一バイトに格納される)
+//
+// データ(`metadata`以外)のバケツでは、これまでMDSにデータが保存されることはなかったので、
+// 空ではないデータは、これらのいずれかとなる。
+// (`3`はインライン化された中身を示すタグとして`inline`モジュールで使用されている)
+const DATA_KIND_MANIFEST: u8 = 1;
+const DATA_KIND_UPLOAD_RECORD: u8 = 2;
+
+// パートのサイズを保持する、パートの(利用者定義の)メタデータのキー
+//
+// パートは内部用のオブジェクトなので、利用者が指定したメタデータと衝突することはない。
+const PART_SIZE_METADATA_KEY: &str = "size";
+
+/// 内部用のオブジェクトIDかどうかを判定する。
+///
+/// 内部用のオブジェクトは、オブジェクト一覧等の利用者向けの結果からは除外されるべきである。
+pub fn is_internal_object_id(id: &str) -> bool {
+    id.starts_with(INTERNAL_OBJECT_ID_PREFIX)
+}
+
+/// パートの保存時に指定する付加情報を返す。
+///
+/// パートのサイズがメタデータとして保存され、アップロードの完了時にマニフェストに記録される。
+pub(crate) fn part_attributes(size: usize) -> ObjectAttributes {
+    let mut attributes = ObjectAttributes::default();
+    attributes
+        .user_metadata
+        .insert(PART_SIZE_METADATA_KEY.to_owned(), size.to_string());
+    attributes
+}
+
+/// `part_attributes`で保存されたパートのメタデータから、そのサイズを取り出す。
+///
+/// サイズを保持していない(古い)パートの場合には`None`が返される。
+pub(crate) fn part_size(user_metadata: &UserMetadata) -> Option<u64> {
+    user_metadata
+        .get(PART_SIZE_METADATA_KEY)
+        .and_then(|v| v.parse().ok())
+}
+
+/// マルチパートアップロードのID。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UploadId(pub u64);
+impl UploadId {
+    pub(crate) fn record_id(self) -> ObjectId {
+        format!("{}{}", MULTIPART_OBJECT_ID_PREFIX, self)
+    }
+    pub(crate) fn part_id(self, part_number: u32) -> ObjectId {
+        format!("{}{}/{:05}", MULTIPART_OBJECT_ID_PREFIX, self, part_number)
+    }
+    pub(crate) fn prefix(self) -> ObjectPrefix {
+        ObjectPrefix(self.record_id())
+    }
+    pub(crate) fn from_record_id(id: &str) -> Option<Self> {
+        if !id.starts_with(MULTIPART_OBJECT_ID_PREFIX) {
+            return None;
+        }
+        let rest = &id[MULTIPART_OBJECT_ID_PREFIX.len()..];
+        if rest.len() != 16 {
+            return None;
+        }
+        rest.parse().ok()
+    }
+}
+impl fmt::Display for UploadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+impl FromStr for UploadId {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let n = track!(u64::from_str_radix(s, 16).map_err(|e| ErrorKind::Invalid.cause(e)))?;
+        Ok(UploadId(n))
+    }
+}
+
+/// マルチパートアップロードの記録。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct UploadRecord {
+    pub object_id: ObjectId,
+
+    /// 完了後にオブジェクトの名前が変更された場合の、変更前のID群。
+    ///
+    /// パート群が利用中かどうかは、`object_id`に加えてこれらのIDに対しても確認される。
+    pub former_object_ids: Vec<ObjectId>,
+
+    /// アップロードの開始時刻 (UNIXエポックからの秒数)。
+    pub initiated_at: u64,
+
+    /// アップロードが完了したかどうか。
+    pub completed: bool,
+}
+impl UploadRecord {
+    pub fn new(object_id: ObjectId) -> Self {
+        UploadRecord {
+            object_id,
+            former_object_ids: Vec::new(),
+            initiated_at: unix_time_secs(),
+            completed: false,
+        }
+    }
+    pub fn is_expired(&self, expiration_secs: u64) -> bool {
+        self.initiated_at.saturating_add(expiration_secs) < unix_time_secs()
+    }
+    /// パート群を参照している可能性のある、オブジェクトのID群を返す。
+    pub fn object_ids(&self) -> Vec<ObjectId> {
+        let mut ids = vec![self.object_id.clone()];
+        ids.extend(self.former_object_ids.iter().cloned());
+        ids
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let id = self.object_id.as_bytes();
+        let mut bytes = vec![0; 1 + 4 + id.len() + 8 + 1];
+        bytes[0] = DATA_KIND_UPLOAD_RECORD;
+        BigEndian::write_u32(&mut bytes[1..], id.len() as u32);
+        bytes[5..5 + id.len()].copy_from_slice(id);
+        BigEndian::write_u64(&mut bytes[5 + id.len()..], self.initiated_at);
+        bytes[5 + id.len() + 8] = self.completed as u8;
+
+        // 変更前のID群は、存在する場合にのみ末尾に追加する (以前の形式との互換性のため)
+        if !self.former_object_ids.is_empty() {
+            let mut buf = [0; 4];
+            BigEndian::write_u32(&mut buf, self.former_object_ids.len() as u32);
+            bytes.extend_from_slice(&buf);
+            for id in &self.former_object_ids {
+                BigEndian::write_u32(&mut buf, id.len() as u32);
+                bytes.extend_from_slice(&buf);
+                bytes.extend_from_slice(id.as_bytes());
+            }
+        }
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        track_assert!(bytes.len() >= 5, ErrorKind::Corrupted);
+        track_assert_eq!(bytes[0], DATA_KIND_UPLOAD_RECORD, ErrorKind::Corrupted);
+        let id_len = BigEndian::read_u32(&bytes[1..]) as usize;
+        let base_len = 1 + 4 + id_len + 8 + 1;
+        track_assert!(bytes.len() >= base_len, ErrorKind::Corrupted);
+        let object_id = track!(String::from_utf8(bytes[5..5 + id_len].to_vec())
+            .map_err(|e| ErrorKind::Corrupted.cause(e)))?;
+        let initiated_at = BigEndian::read_u64(&bytes[5 + id_len..]);
+        let completed = bytes[5 + id_len + 8] != 0;
+
+        let mut former_object_ids = Vec::new();
+        let mut rest = &bytes[base_len..];
+        if !rest.is_empty() {
+            track_assert!(rest.len() >= 4, ErrorKind::Corrupted);
+            let count = BigEndian::read_u32(rest);
+            rest = &rest[4..];
+            for _ in 0..count {
+                track_assert!(rest.len() >= 4, ErrorKind::Corrupted);
+                let len = BigEndian::read_u32(rest) as usize;
+                track_assert!(rest.len() >= 4 + len, ErrorKind::Corrupted);
+                let id = track!(String::from_utf8(rest[4..4 + len].to_vec())
+                    .map_err(|e| ErrorKind::Corrupted.cause(e)))?;
+                former_object_ids.push(id);
+                rest = &rest[4 + len..];
+            }
+            track_assert!(rest.is_empty(), ErrorKind::Corrupted);
+        }
+        Ok(UploadRecord {
+            object_id,
+            former_object_ids,
+            initiated_at,
+            completed,
+        })
+    }
+}
+
+/// マルチパートアップロードで作成されたオブジェクトを構成するパート一覧。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    pub upload_id: UploadId,
+
+    /// パート番号とそのバージョンの組 (オブジェクト内での順番に並ぶ)。
+    pub parts: Vec<(u32, ObjectVersion)>,
+
+    /// 各パートのサイズ (`parts`と同じ順番に並ぶ)。
+    ///
+    /// 範囲指定での取得時に、必要なパートのみを読み込むために使用される。
+    /// サイズを保持していないパートを含む場合には`None`となる。
+    pub part_sizes: Option<Vec<u64>>,
+}
+impl Manifest {
+    /// MDSに保存されているデータがマニフェストかどうかを判定する。
+    pub fn is_manifest(data: &[u8]) -> bool {
+        data.first() == Some(&DATA_KIND_MANIFEST)
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; 1 + 8 + 4 + self.parts.len() * 12];
+        bytes[0] = DATA_KIND_MANIFEST;
+        BigEndian::write_u64(&mut bytes[1..], self.upload_id.0);
+        BigEndian::write_u32(&mut bytes[9..], self.parts.len() as u32);
+        for (i, &(number, version)) in self.parts.iter().enumerate() {
+            let offset = 13 + i * 12;
+            BigEndian::write_u32(&mut bytes[offset..], number);
+            BigEndian::write_u64(&mut bytes[offset + 4..], version.0);
+        }
+
+        // パートのサイズ群は、存在する場合にのみ末尾に追加する (以前の形式との互換性のため)
+        if let Some(ref sizes) = self.part_sizes {
+            let mut buf = [0; 8];
+            for &size in sizes {
+                BigEndian::write_u64(&mut buf, size);
+                bytes.extend_from_slice(&buf);
+            }
+        }
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        track_assert!(bytes.len() >= 13, ErrorKind::Corrupted);
+        track_assert_eq!(bytes[0], DATA_KIND_MANIFEST, ErrorKind::Corrupted);
+        let upload_id = UploadId(BigEndian::read_u64(&bytes[1..]));
+        let count = BigEndian::read_u32(&bytes[9..]) as usize;
+        let base_len = 13 + count * 12;
+        track_assert!(
+            bytes.len() == base_len || bytes.len() == base_len + count * 8,
+            ErrorKind::Corrupted
+        );
+        let parts = (0..count)
+            .map(|i| {
+                let offset = 13 + i * 12;
+                let number = BigEndian::read_u32(&bytes[offset..]);
+                let version = ObjectVersion(BigEndian::read_u64(&bytes[offset + 4..]));
+                (number, version)
+            })
+            .collect();
+        let part_sizes = if bytes.len() == base_len {
+            None
+        } else {
+            Some(
+                bytes[base_len..]
+                    .chunks(8)
+                    .map(BigEndian::read_u64)
+                    .collect(),
+            )
+        };
+        Ok(Manifest {
+            upload_id,
+            parts,
+            part_sizes,
+        })
+    }
+}
+
+fn unix_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upload_id_works() {
+        let upload_id = UploadId(0x1234);
+        assert_eq!(upload_id.to_string(), "0000000000001234");
+        assert_eq!(upload_id.record_id(), "\u{0}multipart/0000000000001234");
+        assert_eq!(
+            upload_id.part_id(3),
+            "\u{0}multipart/0000000000001234/00003"
+        );
+        assert!(upload_id.part_id(3).starts_with(&upload_id.prefix().0));
+        assert!(is_internal_object_id(&upload_id.part_id(3)));
+        assert!(!is_internal_object_id("foo"));
+
+        assert_eq!(
+            UploadId::from_record_id(&upload_id.record_id()),
+            Some(upload_id)
+        );
+        assert_eq!(UploadId::from_record_id(&upload_id.part_id(1)), None);
+        assert_eq!("0000000000001234".parse::<UploadId>().ok(), Some(upload_id));
+    }
+
+    #[test]
+    fn upload_record_encoding_works() {
+        let mut record = UploadRecord::new("foo".to_owned());
+        assert_eq!(
+            UploadRecord::from_bytes(&record.to_bytes()).ok(),
+            Some(record.clone())
+        );
+        assert!(!record.is_expired(60));
+
+        record.completed = true;
+        record.initiated_at = 0;
+        assert_eq!(
+            UploadRecord::from_bytes(&record.to_bytes()).ok(),
+            Some(record.clone())
+        );
+        assert!(record.is_expired(60));
+        assert!(!Manifest::is_manifest(&record.to_bytes()));
+
+        // 変更前のIDを持たない場合には、以前の形式と同じになる
+        assert_eq!(record.to_bytes().len(), 1 + 4 + 3 + 8 + 1);
+        record.object_id = "bar".to_owned();
+        record.former_object_ids.push("foo".to_owned());
+        assert_eq!(
+            UploadRecord::from_bytes(&record.to_bytes()).ok(),
+            Some(record.clone())
+        );
+        assert_eq!(
+            record.object_ids(),
+            vec!["bar".to_owned(), "foo".to_owned()]
+        );
+        let bytes = record.to_bytes();
+        assert!(UploadRecord::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn manifest_encoding_works() {
+        let manifest = Manifest {
+            upload_id: UploadId(10),
+            parts: vec![(1, ObjectVersion(30)), (2, ObjectVersion(31))],
+            part_sizes: None,
+        };
+        let bytes = manifest.to_bytes();
+        assert!(Manifest::is_manifest(&bytes));
+        assert_eq!(Manifest::from_bytes(&bytes).ok(), Some(manifest.clone()));
+        assert!(Manifest::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(!Manifest::is_manifest(&[]));
+
+        // パートのサイズ付き
+        let manifest = Manifest {
+            part_sizes: Some(vec![100, 5]),
+            ..manifest
+        };
+        let sized = manifest.to_bytes();
+        assert_eq!(&sized[..bytes.len()], &bytes[..]);
+        assert_eq!(Manifest::from_bytes(&sized).ok(), Some(manifest));
+        assert!(Manifest::from_bytes(&sized[..sized.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn part_size_works() {
+        let attributes = part_attributes(123);
+        assert_eq!(part_size(&attributes.user_metadata), Some(123));
+        assert_eq!(part_size(&UserMetadata::new()), None);
+    }
+}
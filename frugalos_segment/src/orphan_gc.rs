@@ -0,0 +1,326 @@
+//! どのオブジェクトからも参照されていない中身(lump)を削除するための処理。
+use cannyls::deadline::Deadline;
+use cannyls::device::DeviceHandle;
+use cannyls::lump::LumpId;
+use frugalos_mds::Event;
+use frugalos_raft::NodeId;
+use futures::future::Either;
+use futures::{self, stream, Async, Future, Poll, Stream};
+use libfrugalos::entity::object::ObjectVersion;
+use prometrics::metrics::{Counter, MetricBuilder};
+use slog::Logger;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use config;
+use Error;
+
+const DELETE_CONCURRENCY: usize = 16;
+
+type BoxFuture<T> = Box<Future<Item = T, Error = Error> + Send + 'static>;
+
+/// 参照されていないlump(孤児)を検出して削除する。
+///
+/// ノードがクラッシュしたり、`Event::Deleted`を取りこぼした場合に残ったlumpが対象となる。
+///
+/// 書き込み途中のlumpを削除してしまわないように、孤児と判定されたlumpは猶予期間を置いて再確認し、
+/// その時点でも参照されていないものだけを削除する。
+/// 猶予期間は、少なくとも`put_content_timeout`の二倍以上が確保される。
+///
+/// 参照中のバージョン群はMDSの状態機械から取得されるので、
+/// 呼び出し側はMDSのノードの状態が追いついてから(`Node::is_caught_up`)回収処理を開始する必要がある。
+pub(crate) struct OrphanCollector {
+    logger: Logger,
+    node_id: NodeId,
+    device: DeviceHandle,
+    dry_run: bool,
+    interval: Option<Duration>,
+    grace_period: Duration,
+    max_put_content_timeout: Duration,
+    next_run: Option<Instant>,
+    referenced: Option<HashSet<ObjectVersion>>,
+    suspects: HashMap<ObjectVersion, Instant>,
+    task: Task,
+    orphan_lumps: Counter,
+    orphan_bytes: Counter,
+    deleted_lumps: Counter,
+    reclaimed_bytes: Counter,
+}
+impl OrphanCollector {
+    /// 新しい`OrphanCollector`インスタンスを生成する。
+    ///
+    /// `interval`が`None`の場合には、孤児の削除は行われない。
+    /// `dry_run`が`true`の場合には、孤児の検出と報告のみが行われ、実際の削除は行われない。
+    pub fn new(
+        logger: Logger,
+        node_id: NodeId,
+        device: DeviceHandle,
+        interval: Option<Duration>,
+        grace_period: Duration,
+        dry_run: bool,
+    ) -> Self {
+        let metric_builder = MetricBuilder::new()
+            .namespace("frugalos")
+            .subsystem("orphan_gc")
+            .label("node", &node_id.to_string())
+            .clone();
+        OrphanCollector {
+            logger,
+            node_id,
+            device,
+            dry_run,
+            interval,
+            grace_period,
+            max_put_content_timeout: Duration::from_secs(0),
+            next_run: interval.map(|d| Instant::now() + d),
+            referenced: None,
+            suspects: HashMap::new(),
+            task: Task::Idle,
+            orphan_lumps: metric_builder
+                .counter("orphan_lumps_total")
+                .finish()
+                .unwrap(),
+            orphan_bytes: metric_builder
+                .counter("orphan_bytes_total")
+                .finish()
+                .unwrap(),
+            deleted_lumps: metric_builder
+                .counter("deleted_lumps_total")
+                .finish()
+                .unwrap(),
+            reclaimed_bytes: metric_builder
+                .counter("reclaimed_bytes_total")
+                .finish()
+                .unwrap(),
+        }
+    }
+
+    /// 新しい回収処理を開始すべきかどうかを判定する。
+    ///
+    /// `true`が返された場合には、呼び出し側は`start`メソッドを呼び出す必要がある。
+    pub fn needs_versions(&self) -> bool {
+        self.referenced.is_none() && self.next_run.map_or(false, |t| t <= Instant::now())
+    }
+
+    /// 指定のバージョン群を参照中のものとして、回収処理を開始する。
+    pub fn start(&mut self, versions: Vec<ObjectVersion>) {
+        debug!(
+            self.logger,
+            "Starts collecting orphan lumps: referenced_versions={}",
+            versions.len()
+        );
+        self.referenced = Some(versions.into_iter().collect());
+        let future = self
+            .device
+            .request()
+            .deadline(Deadline::Infinity)
+            .list()
+            .map_err(Error::from);
+        self.task = Task::List(Box::new(future));
+    }
+
+    pub fn handle_event(&mut self, event: &Event) {
+        if let Event::Putted {
+            version,
+            put_content_timeout,
+            ..
+        } = *event
+        {
+            let timeout = Duration::from_secs(put_content_timeout.0);
+            self.max_put_content_timeout = cmp::max(self.max_put_content_timeout, timeout);
+            if let Some(ref mut referenced) = self.referenced {
+                referenced.insert(version);
+            }
+        }
+    }
+
+    fn grace_period(&self) -> Duration {
+        cmp::max(self.grace_period, self.max_put_content_timeout * 2)
+    }
+
+    fn handle_listed(&mut self, lumps: Vec<LumpId>) -> Task {
+        let now = Instant::now();
+        let grace_period = self.grace_period();
+        let mut suspects = HashMap::new();
+        let mut orphans = Vec::new();
+        if let Some(ref referenced) = self.referenced {
+            for lump_id in lumps {
+                let version = match content_version(&self.node_id, lump_id) {
+                    Some(version) => version,
+                    None => continue,
+                };
+                if referenced.contains(&version) {
+                    continue;
+                }
+                let detected_at = self.suspects.get(&version).cloned().unwrap_or(now);
+                if now.duration_since(detected_at) >= grace_period {
+                    orphans.push(lump_id);
+                } else {
+                    suspects.insert(version, detected_at);
+                }
+            }
+        }
+        self.suspects = suspects;
+        debug!(
+            self.logger,
+            "Orphan lumps are listed: orphans={}, suspects={}",
+            orphans.len(),
+            self.suspects.len()
+        );
+
+        if orphans.is_empty() {
+            self.finish();
+            Task::Idle
+        } else {
+            Task::Delete(self.delete_orphans(orphans))
+        }
+    }
+
+    fn delete_orphans(&self, orphans: Vec<LumpId>) -> BoxFuture<(u64, u64)> {
+        let device = self.device.clone();
+        let dry_run = self.dry_run;
+        let logger = self.logger.clone();
+        let future = stream::iter_ok(orphans)
+            .map(move |lump_id| {
+                let device = device.clone();
+                let logger = logger.clone();
+                let head = device.request().deadline(Deadline::Infinity).head(lump_id);
+                head.map_err(Error::from).and_then(move |header| {
+                    let size = if let Some(header) = header {
+                        u64::from(header.approximate_data_size)
+                    } else {
+                        // 既に削除済み
+                        return Either::A(futures::finished::<_, Error>(None));
+                    };
+                    info!(
+                        logger,
+                        "Orphan lump is detected: lump_id={:?}, size={}, dry_run={}",
+                        lump_id,
+                        size,
+                        dry_run
+                    );
+                    if dry_run {
+                        return Either::A(futures::finished::<_, Error>(Some(size)));
+                    }
+                    let future = device
+                        .request()
+                        .deadline(Deadline::Infinity)
+                        .delete(lump_id)
+                        .map(move |_| Some(size))
+                        .map_err(Error::from);
+                    Either::B(future)
+                })
+            })
+            .buffer_unordered(DELETE_CONCURRENCY)
+            .fold((0, 0), |(count, bytes), size| {
+                let acc = size.map_or((count, bytes), |size| (count + 1, bytes + size));
+                futures::finished::<_, Error>(acc)
+            });
+        Box::new(future)
+    }
+
+    fn handle_deleted(&mut self, count: u64, bytes: u64) {
+        self.orphan_lumps.add_u64(count);
+        self.orphan_bytes.add_u64(bytes);
+        if self.dry_run {
+            info!(
+                self.logger,
+                "Orphan lumps are detected (dry-run): lumps={}, bytes={}", count, bytes
+            );
+        } else {
+            self.deleted_lumps.add_u64(count);
+            self.reclaimed_bytes.add_u64(bytes);
+            info!(
+                self.logger,
+                "Orphan lumps are deleted: lumps={}, reclaimed_bytes={}", count, bytes
+            );
+        }
+        self.finish();
+    }
+
+    fn finish(&mut self) {
+        self.referenced = None;
+        self.next_run = self.interval.map(|d| Instant::now() + d);
+    }
+}
+impl Future for OrphanCollector {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let next = match self.task.poll() {
+                Err(e) => {
+                    // 回収処理のエラーは致命的ではないので、ログを出すだけに留める
+                    warn!(self.logger, "Orphan lump collection failure: {}", e);
+                    self.finish();
+                    Task::Idle
+                }
+                Ok(Async::NotReady) | Ok(Async::Ready(Done::Idle)) => break,
+                Ok(Async::Ready(Done::Listed(lumps))) => self.handle_listed(lumps),
+                Ok(Async::Ready(Done::Deleted(count, bytes))) => {
+                    self.handle_deleted(count, bytes);
+                    Task::Idle
+                }
+            };
+            self.task = next;
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+enum Done {
+    Idle,
+    Listed(Vec<LumpId>),
+    Deleted(u64, u64),
+}
+
+enum Task {
+    Idle,
+    List(BoxFuture<Vec<LumpId>>),
+    Delete(BoxFuture<(u64, u64)>),
+}
+impl Future for Task {
+    type Item = Done;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match *self {
+            Task::Idle => Ok(Async::Ready(Done::Idle)),
+            Task::List(ref mut f) => track!(f.poll()).map(|t| t.map(Done::Listed)),
+            Task::Delete(ref mut f) => {
+                track!(f.poll()).map(|t| t.map(|(count, bytes)| Done::Deleted(count, bytes)))
+            }
+        }
+    }
+}
+
+/// `lump_id`が`node_id`のノードが保存したオブジェクトの中身なら、そのバージョンを返す。
+pub(crate) fn content_version(node_id: &NodeId, lump_id: LumpId) -> Option<ObjectVersion> {
+    let prefix = config::make_lump_id(node_id, ObjectVersion(0)).as_u128() >> 64;
+    if lump_id.as_u128() >> 64 == prefix {
+        Some(ObjectVersion(lump_id.as_u128() as u64))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::tests::make_node_id;
+
+    #[test]
+    fn content_version_works() {
+        let node0 = make_node_id(0);
+        let node1 = make_node_id(1);
+        let version = ObjectVersion(12345);
+
+        let lump_id = config::make_lump_id(&node0, version);
+        assert_eq!(content_version(&node0, lump_id), Some(version));
+        assert_eq!(content_version(&node1, lump_id), None);
+
+        // 中身以外の名前空間のlumpは対象外
+        let raft_lump_id = LumpId::new(lump_id.as_u128() & !(0xFF << 120));
+        assert_eq!(content_version(&node0, raft_lump_id), None);
+    }
+}
@@ -0,0 +1,182 @@
+//! オブジェクトの部分取得(バイト範囲指定)関連の構成要素。
+use libfrugalos::entity::object::ObjectVersion;
+use std::cmp;
+use std::ops::Range;
+
+use UserMetadata;
+
+/// 取得対象のバイト範囲。
+///
+/// HTTPの`Range`ヘッダ(RFC 7233)の`byte-range-spec`および`suffix-byte-range-spec`に対応する。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ByteRange {
+    /// `first`バイト目から`last`バイト目まで(両端を含む)。
+    FromTo(u64, u64),
+
+    /// `first`バイト目から末尾まで。
+    From(u64),
+
+    /// 末尾の`n`バイト。
+    Suffix(u64),
+}
+impl ByteRange {
+    /// オブジェクトのサイズを元に、実際に取得する範囲(半開区間)を求める。
+    ///
+    /// 範囲が充足不能な場合には`None`が返される。
+    pub fn resolve(&self, size: u64) -> Option<Range<u64>> {
+        match *self {
+            ByteRange::FromTo(first, last) => {
+                if first > last || first >= size {
+                    None
+                } else {
+                    Some(first..cmp::min(last.saturating_add(1), size))
+                }
+            }
+            ByteRange::From(first) => {
+                if first >= size {
+                    None
+                } else {
+                    Some(first..size)
+                }
+            }
+            ByteRange::Suffix(n) => {
+                if n == 0 || size == 0 {
+                    None
+                } else {
+                    Some(size.saturating_sub(n)..size)
+                }
+            }
+        }
+    }
+}
+
+/// 部分取得されたオブジェクトの値。
+#[derive(Debug, Clone)]
+pub struct PartialObjectValue {
+    /// バージョン番号。
+    pub version: ObjectVersion,
+
+    /// オブジェクト全体のサイズ。
+    pub size: u64,
+
+    /// 利用者定義のメタデータ。
+    pub user_metadata: UserMetadata,
+
+    /// 充足可能だった範囲とその中身の組。
+    ///
+    /// 要求された順番に並んでおり、充足不能な範囲は含まれない。
+    pub parts: Vec<(Range<u64>, Vec<u8>)>,
+}
+
+/// 充足可能な範囲のみを、要求された順番を保ったまま返す。
+pub(crate) fn resolve_ranges(ranges: &[ByteRange], size: u64) -> Vec<Range<u64>> {
+    ranges.iter().filter_map(|r| r.resolve(size)).collect()
+}
+
+/// オブジェクト全体の中身から、要求された範囲の部分を切り出す。
+pub(crate) fn extract_parts(
+    content: &[u8],
+    ranges: &[ByteRange],
+) -> (u64, Vec<(Range<u64>, Vec<u8>)>) {
+    let size = content.len() as u64;
+    let parts = resolve_ranges(ranges, size)
+        .into_iter()
+        .map(|r| {
+            let bytes = content[r.start as usize..r.end as usize].to_vec();
+            (r, bytes)
+        })
+        .collect();
+    (size, parts)
+}
+
+/// 各パートのサイズから、オブジェクト全体の中での各パートの範囲を求める。
+pub(crate) fn part_ranges(sizes: &[u64]) -> Vec<Range<u64>> {
+    let mut offset = 0;
+    sizes
+        .iter()
+        .map(|&size| {
+            let range = offset..offset + size;
+            offset += size;
+            range
+        })
+        .collect()
+}
+
+/// 取得済みのパート群(オブジェクト全体の中での範囲と中身の組)から、要求された範囲の部分を組み立てる。
+///
+/// `parts`は範囲の昇順に並んでおり、`ranges`と重なる全てのパートを含んでいる必要がある。
+pub(crate) fn assemble_parts(
+    parts: &[(Range<u64>, Vec<u8>)],
+    ranges: Vec<Range<u64>>,
+) -> Vec<(Range<u64>, Vec<u8>)> {
+    ranges
+        .into_iter()
+        .map(|r| {
+            let mut bytes = Vec::with_capacity((r.end - r.start) as usize);
+            for &(ref p, ref content) in parts {
+                if p.end <= r.start || r.end <= p.start {
+                    continue;
+                }
+                let start = cmp::max(r.start, p.start) - p.start;
+                let end = cmp::min(r.end, p.end) - p.start;
+                bytes.extend_from_slice(&content[start as usize..end as usize]);
+            }
+            (r, bytes)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_works() {
+        assert_eq!(ByteRange::FromTo(0, 9).resolve(100), Some(0..10));
+        assert_eq!(ByteRange::FromTo(90, 200).resolve(100), Some(90..100));
+        assert_eq!(ByteRange::FromTo(10, 9).resolve(100), None);
+        assert_eq!(ByteRange::FromTo(100, 101).resolve(100), None);
+        assert_eq!(
+            ByteRange::FromTo(0, u64::max_value()).resolve(10),
+            Some(0..10)
+        );
+
+        assert_eq!(ByteRange::From(10).resolve(100), Some(10..100));
+        assert_eq!(ByteRange::From(100).resolve(100), None);
+
+        assert_eq!(ByteRange::Suffix(10).resolve(100), Some(90..100));
+        assert_eq!(ByteRange::Suffix(200).resolve(100), Some(0..100));
+        assert_eq!(ByteRange::Suffix(0).resolve(100), None);
+        assert_eq!(ByteRange::Suffix(10).resolve(0), None);
+    }
+
+    #[test]
+    fn extract_parts_works() {
+        let content = (0..10).collect::<Vec<u8>>();
+        let ranges = [
+            ByteRange::Suffix(2),
+            ByteRange::From(20),
+            ByteRange::FromTo(1, 3),
+        ];
+        let (size, parts) = extract_parts(&content, &ranges);
+        assert_eq!(size, 10);
+        assert_eq!(parts, vec![(8..10, vec![8, 9]), (1..4, vec![1, 2, 3])]);
+    }
+
+    #[test]
+    fn assemble_parts_works() {
+        assert_eq!(part_ranges(&[3, 0, 4, 3]), vec![0..3, 3..3, 3..7, 7..10]);
+
+        // 一つ目のパート(0..3)は取得されていない
+        let parts = vec![(3..7, vec![3, 4, 5, 6]), (7..10, vec![7, 8, 9])];
+        let ranges = vec![8..10, 4..9, 5..6];
+        assert_eq!(
+            assemble_parts(&parts, ranges),
+            vec![
+                (8..10, vec![8, 9]),
+                (4..9, vec![4, 5, 6, 7, 8]),
+                (5..6, vec![5]),
+            ]
+        );
+    }
+}
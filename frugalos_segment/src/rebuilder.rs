@@ -0,0 +1,524 @@
+//! 空のデバイスで起動したノードに、保存すべきオブジェクトの中身を再構築するための処理。
+use byteorder::{BigEndian, ByteOrder};
+use cannyls;
+use cannyls::deadline::Deadline;
+use cannyls::device::DeviceHandle;
+use cannyls::lump::LumpHeader;
+use fibers::time::timer::{self, Timeout};
+use frugalos_mds::Event;
+use frugalos_raft::NodeId;
+use futures::{Async, Future, Poll};
+use libfrugalos::entity::object::ObjectVersion;
+use prometrics::metrics::{Counter, Gauge, MetricBuilder};
+use slog::Logger;
+use std::cmp;
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use client::storage::{GetFragment, MaybeFragment, StorageClient};
+use config;
+use util::Phase3;
+use {Error, ErrorKind, Result};
+
+/// 進捗を永続化する間隔(処理したバージョンの数)。
+const SAVE_PROGRESS_INTERVAL: usize = 1000;
+
+type BoxFuture<T> = Box<Future<Item = T, Error = Error> + Send + 'static>;
+
+/// 空のデバイスで起動したノードに、保存すべき複製ないしフラグメントを再構築する。
+///
+/// デバイスの交換等によって、ノードのRaftの状態(ballot)が存在しない状態で起動した場合に、
+/// MDSのスナップショット(ないしログ)の読み込み後に全てのバージョンを列挙して、
+/// ローカルに存在しない中身を他のノードから復元する。
+///
+/// 再構築の進捗(次に処理するバージョン)は定期的にデバイスに保存され、
+/// 途中でノードが再起動した場合には、その位置から再開される。
+///
+/// なお`Synchronizer`とは異なり、`FRUGALOS_REPAIR_ENABLED`の指定に関わらず実行される。
+///
+/// 中身がMDSに保存されているバージョン(マニフェストやインライン化されたオブジェクト)は、
+/// lumpを持たないので再構築の対象外となる。
+/// マニフェストが参照するパート群は、それぞれが通常のバージョンとして個別に再構築される。
+pub(crate) struct Rebuilder {
+    logger: Logger,
+    node_id: NodeId,
+    device: DeviceHandle,
+    client: StorageClient,
+    concurrency: usize,
+    lump_interval: Duration,
+    phase: RebuildPhase,
+    resume_from: ObjectVersion,
+    versions: BTreeSet<ObjectVersion>,
+    tasks: Vec<(ObjectVersion, RebuildLump)>,
+    wait: Option<Timeout>,
+    save: Option<BoxFuture<()>>,
+    processed_since_save: usize,
+    rebuilt_lumps: Counter,
+    skipped_lumps: Counter,
+    failed_lumps: Counter,
+    remaining_versions: Gauge,
+}
+impl Rebuilder {
+    /// 新しい`Rebuilder`インスタンスを生成する。
+    ///
+    /// `enabled`が`false`の場合には、再構築は行われない。
+    /// `concurrency`は同時に再構築するlumpの数の上限で、
+    /// `lump_interval`は、一つのlumpの再構築を開始してから、次のlumpの再構築を開始するまでの待ち時間。
+    pub fn new(
+        logger: Logger,
+        node_id: NodeId,
+        device: DeviceHandle,
+        client: StorageClient,
+        enabled: bool,
+        concurrency: usize,
+        lump_interval: Duration,
+    ) -> Self {
+        let metric_builder = MetricBuilder::new()
+            .namespace("frugalos")
+            .subsystem("rebuilder")
+            .label("node", &node_id.to_string())
+            .clone();
+        let phase = if enabled && !client.is_metadata() {
+            RebuildPhase::Checking(check_rebuild(&node_id, &device))
+        } else {
+            RebuildPhase::Done
+        };
+        Rebuilder {
+            logger,
+            node_id,
+            device,
+            client,
+            concurrency: cmp::max(concurrency, 1),
+            lump_interval,
+            phase,
+            resume_from: ObjectVersion(0),
+            versions: BTreeSet::new(),
+            tasks: Vec::new(),
+            wait: None,
+            save: None,
+            processed_since_save: 0,
+            rebuilt_lumps: metric_builder
+                .counter("rebuilt_lumps_total")
+                .finish()
+                .unwrap(),
+            skipped_lumps: metric_builder
+                .counter("skipped_lumps_total")
+                .finish()
+                .unwrap(),
+            failed_lumps: metric_builder
+                .counter("failed_lumps_total")
+                .finish()
+                .unwrap(),
+            remaining_versions: metric_builder.gauge("remaining_versions").finish().unwrap(),
+        }
+    }
+
+    /// 再構築を開始すべきかどうかを判定する。
+    ///
+    /// `true`が返された場合には、呼び出し側は`start`メソッドを呼び出す必要がある。
+    pub fn needs_versions(&self) -> bool {
+        if let RebuildPhase::Armed(Some(start_at)) = self.phase {
+            start_at <= Instant::now()
+        } else {
+            false
+        }
+    }
+
+    /// 指定のバージョン群を対象とした再構築を開始する。
+    ///
+    /// `versions`には、中身がMDSに保存されているバージョン(e.g., マニフェスト)を含めてはいけない。
+    pub fn start(&mut self, versions: Vec<ObjectVersion>) {
+        let resume_from = self.resume_from;
+        self.versions
+            .extend(versions.into_iter().filter(|v| *v >= resume_from));
+        info!(
+            self.logger,
+            "Starts rebuilding: versions={}, resume_from={:?}",
+            self.versions.len(),
+            self.resume_from
+        );
+        self.remaining_versions.set(self.versions.len() as f64);
+        self.phase = RebuildPhase::Running;
+    }
+
+    pub fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::Putted {
+                version,
+                put_content_timeout,
+                has_data,
+            } => match self.phase {
+                RebuildPhase::Armed(None) => {
+                    // MDSの状態が読み込まれ始めたので、書き込み中の中身と競合しないように、
+                    // `put_content_timeout`だけ待ってから再構築を開始する
+                    let delay = Duration::from_secs(put_content_timeout.0);
+                    self.phase = RebuildPhase::Armed(Some(Instant::now() + delay));
+                }
+                RebuildPhase::Running if !has_data => {
+                    // NOTE: 再構築の開始後に追加されたもの(e.g., ログの再適用分)も対象に含める
+                    // (中身がMDSに保存されているものは、lumpが存在しないので対象外)
+                    self.versions.insert(version);
+                    self.remaining_versions.set(self.versions.len() as f64);
+                }
+                _ => {}
+            },
+            Event::Deleted { version } => {
+                self.versions.remove(&version);
+            }
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        if let RebuildPhase::Running = self.phase {
+            true
+        } else {
+            false
+        }
+    }
+
+    fn handle_checked(&mut self, resume_from: Option<ObjectVersion>) {
+        if let Some(resume_from) = resume_from {
+            info!(
+                self.logger,
+                "The node needs to be rebuilt (waiting for the MDS to be loaded): resume_from={:?}",
+                resume_from
+            );
+            self.resume_from = resume_from;
+            self.phase = RebuildPhase::Armed(None);
+        } else {
+            self.phase = RebuildPhase::Done;
+        }
+    }
+
+    fn handle_outcome(&mut self, version: ObjectVersion, result: Result<bool>) {
+        self.processed_since_save += 1;
+        match result {
+            Ok(true) => self.rebuilt_lumps.increment(),
+            Ok(false) => self.skipped_lumps.increment(),
+            Err(e) => {
+                // NOTE: 失敗したものは、同期処理やread-repairによる修復に任せる
+                warn!(
+                    self.logger,
+                    "Cannot rebuild content: version={:?}, error={}", version, e
+                );
+                self.failed_lumps.increment();
+            }
+        }
+    }
+
+    /// 未処理のバージョンのうち、最小のものを返す。
+    fn next_unprocessed_version(&self) -> Option<ObjectVersion> {
+        let in_flight = self.tasks.iter().map(|t| t.0).min();
+        let pending = self.versions.iter().next().cloned();
+        match (in_flight, pending) {
+            (Some(a), Some(b)) => Some(cmp::min(a, b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn save_progress(&mut self) {
+        let lump_id = config::make_rebuild_lump_id(&self.node_id);
+        let future: BoxFuture<()> = if let Some(version) = self.next_unprocessed_version() {
+            debug!(
+                self.logger,
+                "Saves rebuilding progress: resume_from={:?}", version
+            );
+            let mut bytes = [0; 8];
+            BigEndian::write_u64(&mut bytes, version.0);
+            let data = match track!(self.device.allocate_lump_data_with_bytes(&bytes)) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!(self.logger, "Cannot save rebuilding progress: {}", e);
+                    return;
+                }
+            };
+            let future = self
+                .device
+                .request()
+                .deadline(Deadline::Infinity)
+                .put(lump_id, data);
+            Box::new(future.map(|_| ()).map_err(Error::from))
+        } else {
+            let future = self
+                .device
+                .request()
+                .deadline(Deadline::Infinity)
+                .delete(lump_id);
+            Box::new(future.map(|_| ()).map_err(Error::from))
+        };
+        self.processed_since_save = 0;
+        self.save = Some(future);
+    }
+
+    fn poll_save(&mut self) {
+        match self.save.poll() {
+            Err(e) => {
+                warn!(self.logger, "Cannot save rebuilding progress: {}", e);
+            }
+            Ok(Async::NotReady) => return,
+            Ok(Async::Ready(_)) => {}
+        }
+        self.save = None;
+    }
+
+    fn poll_tasks(&mut self) {
+        let mut i = 0;
+        while i < self.tasks.len() {
+            let result = match self.tasks[i].1.poll() {
+                Err(e) => Err(e),
+                Ok(Async::NotReady) => {
+                    i += 1;
+                    continue;
+                }
+                Ok(Async::Ready(rebuilt)) => Ok(rebuilt),
+            };
+            let (version, _) = self.tasks.swap_remove(i);
+            self.handle_outcome(version, result);
+        }
+    }
+
+    fn can_start_task(&mut self) -> bool {
+        if self.tasks.len() >= self.concurrency {
+            return false;
+        }
+        if let Ok(Async::NotReady) = self.wait.poll() {
+            return false;
+        }
+        self.wait = None;
+        true
+    }
+
+    fn complete(&mut self) {
+        info!(self.logger, "Completed rebuilding");
+        self.remaining_versions.set(0.0);
+        self.phase = RebuildPhase::Done;
+    }
+}
+impl Future for Rebuilder {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.poll_save();
+
+        let checked = if let RebuildPhase::Checking(ref mut f) = self.phase {
+            match f.poll() {
+                Err(e) => {
+                    // 再構築の可否の判定に失敗しても致命的ではないので、ログを出すだけに留める
+                    warn!(
+                        self.logger,
+                        "Cannot check whether rebuilding is needed: {}", e
+                    );
+                    Some(None)
+                }
+                Ok(Async::NotReady) => None,
+                Ok(Async::Ready(resume_from)) => Some(resume_from),
+            }
+        } else {
+            None
+        };
+        if let Some(resume_from) = checked {
+            self.handle_checked(resume_from);
+        }
+
+        if !self.is_running() {
+            return Ok(Async::NotReady);
+        }
+        loop {
+            self.poll_tasks();
+            let mut started = false;
+            while self.can_start_task() {
+                let version = if let Some(version) = self.versions.iter().next().cloned() {
+                    version
+                } else {
+                    break;
+                };
+                self.versions.remove(&version);
+                let task = RebuildLump::new(self, version);
+                self.tasks.push((version, task));
+                if self.lump_interval != Duration::from_secs(0) {
+                    self.wait = Some(timer::timeout(self.lump_interval));
+                }
+                started = true;
+            }
+            if !started {
+                break;
+            }
+            self.remaining_versions.set(self.versions.len() as f64);
+        }
+
+        if self.save.is_none() {
+            if self.tasks.is_empty() && self.versions.is_empty() {
+                // 完了したので、保存済みの進捗を削除する
+                self.save_progress();
+                self.complete();
+                self.poll_save();
+            } else if self.processed_since_save >= SAVE_PROGRESS_INTERVAL {
+                self.save_progress();
+                self.poll_save();
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+enum RebuildPhase {
+    // デバイスの状態から、再構築が必要かどうかを判定中
+    Checking(BoxFuture<Option<ObjectVersion>>),
+
+    // MDSの状態の読み込み待ち(読み込みが始まったら、再構築の開始時刻が設定される)
+    Armed(Option<Instant>),
+
+    Running,
+    Done,
+}
+
+/// ノードの再構築が必要かどうかを判定する。
+///
+/// 再構築が必要な場合には、再構築を開始(再開)すべきバージョンが返される。
+fn check_rebuild(node_id: &NodeId, device: &DeviceHandle) -> BoxFuture<Option<ObjectVersion>> {
+    let progress = device
+        .request()
+        .deadline(Deadline::Immediate)
+        .get(config::make_rebuild_lump_id(node_id))
+        .map_err(Error::from);
+    let ballot = device
+        .request()
+        .deadline(Deadline::Immediate)
+        .head(node_id.local_id.to_ballot_lump_id())
+        .map_err(Error::from);
+    let future = progress.join(ballot).and_then(|(progress, ballot)| {
+        if let Some(data) = progress {
+            // 中断された再構築を再開する
+            track_assert_eq!(data.as_bytes().len(), 8, ErrorKind::Invalid);
+            let version = ObjectVersion(BigEndian::read_u64(data.as_bytes()));
+            Ok(Some(version))
+        } else if ballot.is_none() {
+            // Raftの状態が存在しない(i.e., 空のデバイスで起動した)
+            Ok(Some(ObjectVersion(0)))
+        } else {
+            Ok(None)
+        }
+    });
+    Box::new(future)
+}
+
+/// 一つのバージョンの中身を再構築する。
+///
+/// 中身を再構築した場合には`true`が、既に存在していたか、このノードが保存対象ではない場合には`false`が返される。
+struct RebuildLump {
+    node_id: NodeId,
+    version: ObjectVersion,
+    client: StorageClient,
+    device: DeviceHandle,
+    phase: Phase3<BoxFuture<Option<LumpHeader>>, GetFragment, BoxFuture<bool>>,
+}
+impl RebuildLump {
+    fn new(rebuilder: &Rebuilder, version: ObjectVersion) -> Self {
+        let lump_id = config::make_lump_id(&rebuilder.node_id, version);
+        let future = rebuilder
+            .device
+            .request()
+            .deadline(Deadline::Infinity)
+            .head(lump_id);
+        RebuildLump {
+            node_id: rebuilder.node_id,
+            version,
+            client: rebuilder.client.clone(),
+            device: rebuilder.device.clone(),
+            phase: Phase3::A(into_box_future(future)),
+        }
+    }
+}
+impl Future for RebuildLump {
+    type Item = bool;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(phase) = track!(self.phase.poll())? {
+            let next = match phase {
+                Phase3::A(Some(_)) => return Ok(Async::Ready(false)),
+                Phase3::A(None) => {
+                    let future = self.client.clone().get_fragment(self.node_id, self.version);
+                    Phase3::B(future)
+                }
+                Phase3::B(MaybeFragment::NotParticipant) => return Ok(Async::Ready(false)),
+                Phase3::B(MaybeFragment::Fragment(content, compression)) => {
+                    let lump_id = config::make_lump_id(&self.node_id, self.version);
+                    let content = track!(self.client.encode_lump(content, compression, lump_id))?;
+                    let data = track!(self.device.allocate_lump_data_with_bytes(&content))?;
+                    let future = self
+                        .device
+                        .request()
+                        .deadline(Deadline::Infinity)
+                        .put(lump_id, data);
+                    Phase3::C(into_box_future(future))
+                }
+                Phase3::C(_) => return Ok(Async::Ready(true)),
+            };
+            self.phase = next;
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+fn into_box_future<F>(future: F) -> BoxFuture<F::Item>
+where
+    F: Future<Error = cannyls::Error> + Send + 'static,
+{
+    Box::new(future.map_err(Error::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cannyls::device::DeviceBuilder;
+    use cannyls::nvm::MemoryNvm;
+    use cannyls::storage::StorageBuilder;
+    use frugalos_raft::LocalNodeId;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use test_util::tests::wait;
+    use trackable::result::TestResult;
+
+    fn put(device: &DeviceHandle, lump_id: ::cannyls::lump::LumpId, bytes: &[u8]) -> TestResult {
+        let data = track!(device.allocate_lump_data_with_bytes(bytes))?;
+        let future = device
+            .request()
+            .deadline(Deadline::Infinity)
+            .put(lump_id, data);
+        track!(wait(into_box_future(future)))?;
+        Ok(())
+    }
+
+    #[test]
+    fn check_rebuild_works() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024 * 8]);
+        let storage = track!(StorageBuilder::new().journal_region_ratio(0.05).create(nvm))?;
+        let device = DeviceBuilder::new().spawn(|| Ok(storage));
+        let handle = device.handle();
+        let node_id = NodeId {
+            local_id: LocalNodeId::new([0, 0, 0, 0, 0, 0, 1]),
+            instance: 0,
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
+        };
+
+        // 空のデバイスなので、最初から再構築が必要
+        let resume_from = track!(wait(check_rebuild(&node_id, &handle)))?;
+        assert_eq!(resume_from, Some(ObjectVersion(0)));
+
+        // Raftの状態が存在するなら、再構築は不要
+        track!(put(
+            &handle,
+            node_id.local_id.to_ballot_lump_id(),
+            b"ballot"
+        ))?;
+        let resume_from = track!(wait(check_rebuild(&node_id, &handle)))?;
+        assert_eq!(resume_from, None);
+
+        // 中断された再構築は再開する
+        let mut bytes = [0; 8];
+        BigEndian::write_u64(&mut bytes, 42);
+        track!(put(&handle, config::make_rebuild_lump_id(&node_id), &bytes))?;
+        let resume_from = track!(wait(check_rebuild(&node_id, &handle)))?;
+        assert_eq!(resume_from, Some(ObjectVersion(42)));
+        Ok(())
+    }
+}
@@ -0,0 +1,427 @@
+//! 暗号鍵の変更時に、ノードが保存しているlumpを新しい鍵で暗号化し直すための処理。
+use byteorder::{BigEndian, ByteOrder};
+use cannyls;
+use cannyls::deadline::Deadline;
+use cannyls::device::DeviceHandle;
+use cannyls::lump::{LumpData, LumpId};
+use fibers::time::timer::{self, Timeout};
+use frugalos_raft::NodeId;
+use futures::{self, Async, Future, Poll};
+use prometrics::metrics::{Counter, Gauge, MetricBuilder};
+use slog::Logger;
+use std::mem;
+use std::time::Duration;
+
+use client::checksum::verify_and_remove_checksum;
+use client::encryption::KeyId;
+use client::storage::StorageClient;
+use config;
+use orphan_gc::content_version;
+use repair_control::RepairController;
+use util::Phase;
+use {Error, ErrorKind};
+
+/// 暗号鍵の変更の有無を確認する間隔(秒)。
+const CHECK_INTERVAL_SECS: u64 = 60;
+
+type BoxFuture<T> = Box<Future<Item = T, Error = Error> + Send + 'static>;
+
+/// バケツの暗号鍵が変更された場合に、ノードが保存している複製ないしフラグメントを新しい鍵で暗号化し直す。
+///
+/// 暗号化はlump単位で行われるので、再暗号化は他のノードとは独立に、ローカルのlumpのみを対象に行われる。
+/// 暗号化が無効化された場合には、暗号化されているlumpが復号された状態で保存し直される。
+///
+/// 全てのlumpの再暗号化が完了した鍵はデバイスに保存され、再起動後に同じ鍵で再度実行されることはない。
+/// 再暗号化による書き込みは、修復と同様に`RepairController`の帯域制限の対象となる。
+pub(crate) struct ReEncryptor {
+    logger: Logger,
+    node_id: NodeId,
+    device: DeviceHandle,
+    client: StorageClient,
+    controller: RepairController,
+    lump_interval: Duration,
+
+    // 全てのlumpの再暗号化が完了した鍵(`None`は不明であることを表す)
+    completed: Option<Option<KeyId>>,
+    phase: ReEncryptPhase,
+    wait: Option<Timeout>,
+    reencrypted_lumps: Counter,
+    failed_lumps: Counter,
+    completed_passes: Counter,
+    remaining_lumps: Gauge,
+}
+impl ReEncryptor {
+    /// 新しい`ReEncryptor`インスタンスを生成する。
+    ///
+    /// `lump_interval`は、一つのlumpの再暗号化を終えてから、次のlumpの再暗号化を開始するまでの待ち時間。
+    pub fn new(
+        logger: Logger,
+        node_id: NodeId,
+        device: DeviceHandle,
+        client: StorageClient,
+        controller: RepairController,
+        lump_interval: Duration,
+    ) -> Self {
+        let metric_builder = MetricBuilder::new()
+            .namespace("frugalos")
+            .subsystem("reencryptor")
+            .label("node", &node_id.to_string())
+            .clone();
+        let phase = if client.is_metadata() {
+            ReEncryptPhase::Disabled
+        } else {
+            ReEncryptPhase::Loading(load_completed_key(&node_id, &device))
+        };
+        ReEncryptor {
+            logger,
+            node_id,
+            device,
+            client,
+            controller,
+            lump_interval,
+            completed: None,
+            phase,
+            wait: None,
+            reencrypted_lumps: metric_builder
+                .counter("reencrypted_lumps_total")
+                .finish()
+                .unwrap(),
+            failed_lumps: metric_builder
+                .counter("failed_lumps_total")
+                .finish()
+                .unwrap(),
+            completed_passes: metric_builder
+                .counter("completed_passes_total")
+                .finish()
+                .unwrap(),
+            remaining_lumps: metric_builder.gauge("remaining_lumps").finish().unwrap(),
+        }
+    }
+
+    /// 再暗号化の処理を進める。
+    pub fn poll(&mut self) {
+        loop {
+            let phase = mem::replace(&mut self.phase, ReEncryptPhase::Disabled);
+            let next = match phase {
+                ReEncryptPhase::Disabled => return,
+                ReEncryptPhase::Loading(mut future) => match future.poll() {
+                    Err(e) => {
+                        // 不明な場合には、全てのlumpを確認し直す
+                        warn!(self.logger, "Cannot load re-encryption progress: {}", e);
+                        self.next_pass()
+                    }
+                    Ok(Async::NotReady) => {
+                        self.phase = ReEncryptPhase::Loading(future);
+                        return;
+                    }
+                    Ok(Async::Ready(key_id)) => {
+                        self.completed = Some(key_id);
+                        self.next_pass()
+                    }
+                },
+                ReEncryptPhase::Idle(mut timeout) => {
+                    if let Ok(Async::NotReady) = timeout.poll() {
+                        self.phase = ReEncryptPhase::Idle(timeout);
+                        return;
+                    }
+                    self.next_pass()
+                }
+                ReEncryptPhase::Listing(key_id, mut future) => match future.poll() {
+                    Err(e) => {
+                        warn!(self.logger, "Cannot list lumps: {}", e);
+                        self.idle()
+                    }
+                    Ok(Async::NotReady) => {
+                        self.phase = ReEncryptPhase::Listing(key_id, future);
+                        return;
+                    }
+                    Ok(Async::Ready(lumps)) => {
+                        let node_id = self.node_id;
+                        let mut lumps = lumps
+                            .into_iter()
+                            .filter(|&lump_id| content_version(&node_id, lump_id).is_some())
+                            .collect::<Vec<_>>();
+                        lumps.reverse();
+                        info!(
+                            self.logger,
+                            "Starts re-encrypting lumps: key_id={:?}, lumps={}",
+                            key_id,
+                            lumps.len()
+                        );
+                        self.remaining_lumps.set(lumps.len() as f64);
+                        ReEncryptPhase::Running(key_id, lumps, None)
+                    }
+                },
+                ReEncryptPhase::Running(key_id, lumps, Some(mut task)) => {
+                    match task.poll() {
+                        Err(e) => {
+                            // NOTE: 失敗したlumpは、次に鍵が変更されるまで古い鍵で暗号化されたままとなる
+                            warn!(
+                                self.logger,
+                                "Cannot re-encrypt lump: lump_id={:?}, error={}", task.lump_id, e
+                            );
+                            self.failed_lumps.increment();
+                        }
+                        Ok(Async::NotReady) => {
+                            self.phase = ReEncryptPhase::Running(key_id, lumps, Some(task));
+                            return;
+                        }
+                        Ok(Async::Ready(written)) => {
+                            if let Some(bytes) = written {
+                                self.reencrypted_lumps.increment();
+                                self.controller.consume(bytes);
+                            }
+                        }
+                    }
+                    self.remaining_lumps.set(lumps.len() as f64);
+                    if self.lump_interval != Duration::from_secs(0) {
+                        self.wait = Some(timer::timeout(self.lump_interval));
+                    }
+                    ReEncryptPhase::Running(key_id, lumps, None)
+                }
+                ReEncryptPhase::Running(key_id, mut lumps, None) => {
+                    if self.client.encryption_key_id() != key_id {
+                        info!(
+                            self.logger,
+                            "Encryption key has been changed during re-encryption (restarts)"
+                        );
+                        self.next_pass()
+                    } else if self.is_waiting() {
+                        self.phase = ReEncryptPhase::Running(key_id, lumps, None);
+                        return;
+                    } else if let Some(lump_id) = lumps.pop() {
+                        let task = ReEncryptLump::new(self, lump_id, key_id);
+                        ReEncryptPhase::Running(key_id, lumps, Some(task))
+                    } else {
+                        ReEncryptPhase::Saving(key_id, self.save_completed_key(key_id))
+                    }
+                }
+                ReEncryptPhase::Saving(key_id, mut future) => {
+                    match future.poll() {
+                        Err(e) => {
+                            warn!(self.logger, "Cannot save re-encryption progress: {}", e);
+                        }
+                        Ok(Async::NotReady) => {
+                            self.phase = ReEncryptPhase::Saving(key_id, future);
+                            return;
+                        }
+                        Ok(Async::Ready(())) => {}
+                    }
+                    info!(self.logger, "Completed re-encryption: key_id={:?}", key_id);
+                    self.completed = Some(key_id);
+                    self.completed_passes.increment();
+                    self.remaining_lumps.set(0.0);
+                    self.next_pass()
+                }
+            };
+            self.phase = next;
+        }
+    }
+
+    /// 鍵が変更されていれば再暗号化を開始し、そうでなければ待機状態に入る。
+    fn next_pass(&mut self) -> ReEncryptPhase {
+        let key_id = self.client.encryption_key_id();
+        if self.completed == Some(key_id) {
+            return self.idle();
+        }
+        let future = self
+            .device
+            .request()
+            .deadline(Deadline::Infinity)
+            .list()
+            .map_err(Error::from);
+        ReEncryptPhase::Listing(key_id, Box::new(future))
+    }
+
+    fn idle(&self) -> ReEncryptPhase {
+        ReEncryptPhase::Idle(timer::timeout(Duration::from_secs(CHECK_INTERVAL_SECS)))
+    }
+
+    fn is_waiting(&mut self) -> bool {
+        if let Ok(Async::NotReady) = self.wait.poll() {
+            return true;
+        }
+        self.wait = self.controller.wait_time().map(timer::timeout);
+        if let Ok(Async::NotReady) = self.wait.poll() {
+            return true;
+        }
+        self.wait = None;
+        false
+    }
+
+    fn save_completed_key(&self, key_id: Option<KeyId>) -> BoxFuture<()> {
+        let mut bytes = [0; 4];
+        BigEndian::write_u32(&mut bytes, key_id.unwrap_or(0));
+        let data = match track!(self.device.allocate_lump_data_with_bytes(&bytes)) {
+            Ok(data) => data,
+            Err(e) => return Box::new(futures::failed(Error::from(e))),
+        };
+        let future = self
+            .device
+            .request()
+            .deadline(Deadline::Infinity)
+            .put(config::make_reencryption_lump_id(&self.node_id), data);
+        Box::new(future.map(|_| ()).map_err(Error::from))
+    }
+}
+
+enum ReEncryptPhase {
+    // デバイスから、再暗号化が完了した鍵を読み込み中
+    Loading(BoxFuture<Option<KeyId>>),
+
+    // 鍵の変更待ち
+    Idle(Timeout),
+
+    // 再暗号化の対象のlumpを列挙中
+    Listing(Option<KeyId>, BoxFuture<Vec<LumpId>>),
+
+    // 列挙されたlumpを一つずつ再暗号化中
+    Running(Option<KeyId>, Vec<LumpId>, Option<ReEncryptLump>),
+
+    // 再暗号化が完了した鍵をデバイスに保存中
+    Saving(Option<KeyId>, BoxFuture<()>),
+
+    Disabled,
+}
+
+/// 全てのlumpの再暗号化が完了した鍵を、デバイスから読み込む。
+///
+/// 再暗号化が一度も完了していない場合には、全てのlumpが暗号化されていないものとみなす。
+fn load_completed_key(node_id: &NodeId, device: &DeviceHandle) -> BoxFuture<Option<KeyId>> {
+    let future = device
+        .request()
+        .deadline(Deadline::Immediate)
+        .get(config::make_reencryption_lump_id(node_id))
+        .map_err(Error::from)
+        .and_then(|data| {
+            if let Some(data) = data {
+                track_assert_eq!(data.as_bytes().len(), 4, ErrorKind::Invalid);
+                let key_id = BigEndian::read_u32(data.as_bytes());
+                Ok(if key_id == 0 { None } else { Some(key_id) })
+            } else {
+                Ok(None)
+            }
+        });
+    Box::new(future)
+}
+
+/// 一つのlumpを、現在選択されている鍵で暗号化し直す。
+///
+/// 再暗号化した場合には書き込んだデータのサイズが、
+/// lumpが存在しないか、既に`key_id`の鍵で暗号化されていた場合には`None`が返される。
+struct ReEncryptLump {
+    lump_id: LumpId,
+    key_id: Option<KeyId>,
+    client: StorageClient,
+    device: DeviceHandle,
+    written: u64,
+    phase: Phase<BoxFuture<Option<LumpData>>, BoxFuture<bool>>,
+}
+impl ReEncryptLump {
+    fn new(reencryptor: &ReEncryptor, lump_id: LumpId, key_id: Option<KeyId>) -> Self {
+        let future = reencryptor
+            .device
+            .request()
+            .deadline(Deadline::Infinity)
+            .get(lump_id);
+        ReEncryptLump {
+            lump_id,
+            key_id,
+            client: reencryptor.client.clone(),
+            device: reencryptor.device.clone(),
+            written: 0,
+            phase: Phase::A(into_box_future(future)),
+        }
+    }
+}
+impl Future for ReEncryptLump {
+    type Item = Option<u64>;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(phase) = track!(self.phase.poll())? {
+            let next = match phase {
+                Phase::A(None) => return Ok(Async::Ready(None)),
+                Phase::A(Some(data)) => {
+                    let mut payload = data.as_bytes().to_vec();
+                    let (compression, key_id) = match verify_and_remove_checksum(&mut payload) {
+                        Ok(trailer) => trailer,
+                        Err(_) => {
+                            // NOTE: 破損しているlumpの修復はスクラブに任せる
+                            return Ok(Async::Ready(None));
+                        }
+                    };
+                    if key_id == self.key_id {
+                        return Ok(Async::Ready(None));
+                    }
+
+                    // NOTE:
+                    // 読み込みの後にバージョンが削除された場合には、削除済みのlumpを書き戻してしまうことになるが、
+                    // そのようなlumpは孤児として`OrphanCollector`によって回収される.
+                    let payload = track!(self.client.decrypt(payload, key_id, self.lump_id))?;
+                    let lump = track!(self.client.encode_lump(payload, compression, self.lump_id))?;
+                    self.written = lump.len() as u64;
+                    let data = track!(self.device.allocate_lump_data_with_bytes(&lump))?;
+                    let future = self
+                        .device
+                        .request()
+                        .deadline(Deadline::Infinity)
+                        .put(self.lump_id, data);
+                    Phase::B(into_box_future(future))
+                }
+                Phase::B(_) => return Ok(Async::Ready(Some(self.written))),
+            };
+            self.phase = next;
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+fn into_box_future<F>(future: F) -> BoxFuture<F::Item>
+where
+    F: Future<Error = cannyls::Error> + Send + 'static,
+{
+    Box::new(future.map_err(Error::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cannyls::device::DeviceBuilder;
+    use cannyls::nvm::MemoryNvm;
+    use cannyls::storage::StorageBuilder;
+    use frugalos_raft::LocalNodeId;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use test_util::tests::wait;
+    use trackable::result::TestResult;
+
+    #[test]
+    fn completed_key_is_persisted() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024 * 8]);
+        let storage = track!(StorageBuilder::new().journal_region_ratio(0.05).create(nvm))?;
+        let device = DeviceBuilder::new().spawn(|| Ok(storage));
+        let handle = device.handle();
+        let node_id = NodeId {
+            local_id: LocalNodeId::new([0, 0, 0, 0, 0, 0, 1]),
+            instance: 0,
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
+        };
+
+        // 一度も再暗号化が完了していない
+        let key_id = track!(wait(load_completed_key(&node_id, &handle)))?;
+        assert_eq!(key_id, None);
+
+        let reencryptor = ReEncryptor::new(
+            ::slog::Logger::root(::slog::Discard, o!()),
+            node_id,
+            handle.clone(),
+            StorageClient::Metadata,
+            RepairController::default(),
+            Duration::from_secs(0),
+        );
+        for &key_id in &[Some(3), None] {
+            track!(wait(reencryptor.save_completed_key(key_id)))?;
+            assert_eq!(track!(wait(load_completed_key(&node_id, &handle)))?, key_id);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! 同期処理による修復の並列度や帯域を、実行時に調整するための処理。
+use std::cmp;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use {ErrorKind, Result};
+
+/// 修復処理の設定。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepairSettings {
+    /// ノード毎に同時に実行される、同期処理(修復および削除)の数の上限。
+    pub concurrency: usize,
+
+    /// サーバ全体で、修復のために書き込むデータ量の上限(バイト/秒)。
+    ///
+    /// `None`の場合は無制限。
+    #[serde(default)]
+    pub bytes_per_second: Option<u64>,
+}
+impl Default for RepairSettings {
+    fn default() -> Self {
+        RepairSettings {
+            concurrency: 1,
+            bytes_per_second: None,
+        }
+    }
+}
+
+/// 修復処理の設定を、サーバ上の全てのノードで共有・変更するためのハンドル。
+///
+/// 帯域の制限はトークンバケツ方式で行われ、一秒分のバーストが許容される。
+#[derive(Debug, Clone)]
+pub struct RepairController {
+    state: Arc<Mutex<ControllerState>>,
+}
+impl RepairController {
+    /// 新しい`RepairController`インスタンスを生成する。
+    pub fn new(settings: RepairSettings) -> Result<Self> {
+        track!(validate(&settings))?;
+        let state = ControllerState {
+            settings,
+            bucket: TokenBucket::new(Instant::now()),
+        };
+        Ok(RepairController {
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// 現在の設定を返す。
+    pub fn settings(&self) -> RepairSettings {
+        self.lock_state().settings
+    }
+
+    /// 設定を更新する。
+    ///
+    /// 新しい設定は、実行中の処理には影響せず、次に開始される処理から適用される。
+    pub fn update(&self, settings: RepairSettings) -> Result<()> {
+        track!(validate(&settings))?;
+        self.lock_state().settings = settings;
+        Ok(())
+    }
+
+    pub(crate) fn concurrency(&self) -> usize {
+        self.lock_state().settings.concurrency
+    }
+
+    /// 帯域の上限を超過している場合には、次の修復を開始するまでの待ち時間を返す。
+    pub(crate) fn wait_time(&self) -> Option<Duration> {
+        let mut state = self.lock_state();
+        let rate = state.settings.bytes_per_second?;
+        state.bucket.wait_time(rate, Instant::now())
+    }
+
+    /// 修復によって書き込まれたデータ量を記録する。
+    pub(crate) fn consume(&self, bytes: u64) {
+        let mut state = self.lock_state();
+        if let Some(rate) = state.settings.bytes_per_second {
+            state.bucket.consume(rate, bytes, Instant::now());
+        }
+    }
+
+    fn lock_state(&self) -> MutexGuard<ControllerState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+impl Default for RepairController {
+    fn default() -> Self {
+        RepairController::new(RepairSettings::default()).expect("Never fails")
+    }
+}
+
+fn validate(settings: &RepairSettings) -> Result<()> {
+    track_assert_ne!(
+        settings.concurrency,
+        0,
+        ErrorKind::Invalid,
+        "concurrency must be positive"
+    );
+    track_assert_ne!(
+        settings.bytes_per_second,
+        Some(0),
+        ErrorKind::Invalid,
+        "bytes_per_second must be positive"
+    );
+    Ok(())
+}
+
+#[derive(Debug)]
+struct ControllerState {
+    settings: RepairSettings,
+    bucket: TokenBucket,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    // 負の値は、超過分(次回以降の修復を遅らせる必要がある量)を表す
+    tokens: i64,
+    last_refill: Instant,
+}
+impl TokenBucket {
+    fn new(now: Instant) -> Self {
+        TokenBucket {
+            tokens: 0,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, rate: u64, now: Instant) {
+        if now <= self.last_refill {
+            return;
+        }
+        let elapsed = now - self.last_refill;
+        let elapsed_micros = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros());
+        let added = (u128::from(rate) * u128::from(elapsed_micros) / 1_000_000) as i64;
+        self.tokens = cmp::min(self.tokens.saturating_add(added), rate as i64);
+        self.last_refill = now;
+    }
+
+    fn consume(&mut self, rate: u64, bytes: u64, now: Instant) {
+        self.refill(rate, now);
+        self.tokens = self.tokens.saturating_sub(bytes as i64);
+    }
+
+    fn wait_time(&mut self, rate: u64, now: Instant) -> Option<Duration> {
+        self.refill(rate, now);
+        if self.tokens >= 0 {
+            return None;
+        }
+        let deficit = (-self.tokens) as u64;
+        let micros = (u128::from(deficit) * 1_000_000 / u128::from(rate)) as u64;
+        Some(Duration::from_micros(cmp::max(micros, 1)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket_works() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(now);
+        assert_eq!(bucket.wait_time(1000, now), None);
+
+        bucket.consume(1000, 1500, now);
+        assert_eq!(
+            bucket.wait_time(1000, now),
+            Some(Duration::from_millis(1500))
+        );
+
+        let later = now + Duration::from_millis(500);
+        assert_eq!(
+            bucket.wait_time(1000, later),
+            Some(Duration::from_millis(1000))
+        );
+
+        // バーストは一秒分まで
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(bucket.wait_time(1000, much_later), None);
+        assert_eq!(bucket.tokens, 1000);
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        let controller = RepairController::default();
+        assert_eq!(controller.settings(), RepairSettings::default());
+
+        let settings = RepairSettings {
+            concurrency: 0,
+            bytes_per_second: None,
+        };
+        assert!(controller.update(settings).is_err());
+
+        let settings = RepairSettings {
+            concurrency: 4,
+            bytes_per_second: Some(0),
+        };
+        assert!(controller.update(settings).is_err());
+
+        let settings = RepairSettings {
+            concurrency: 4,
+            bytes_per_second: Some(1024 * 1024),
+        };
+        assert!(controller.update(settings).is_ok());
+        assert_eq!(controller.settings(), settings);
+    }
+}
@@ -0,0 +1,720 @@
+//! ノードが保存しているオブジェクトの中身(lump)の破損を、定期的に検査するための処理。
+use cannyls;
+use cannyls::deadline::Deadline;
+use cannyls::device::DeviceHandle;
+use cannyls::lump::LumpData;
+use fibers::time::timer::{self, Timeout};
+use frugalos_mds::Event;
+use frugalos_raft::NodeId;
+use futures::{Async, Future, Poll};
+use libfrugalos::entity::object::ObjectVersion;
+use prometrics::metrics::{Counter, Gauge, MetricBuilder};
+use slog::Logger;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use client::checksum::verify_checksum;
+use client::storage::{GetFragment, MaybeFragment, StorageClient};
+use config;
+use util::Phase3;
+use Error;
+
+type BoxFuture<T> = Box<Future<Item = T, Error = Error> + Send + 'static>;
+
+/// 一つのノードの検査の状況。
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScrubStatus {
+    /// ノードID。
+    pub node: String,
+
+    /// 検査を実行中かどうか。
+    pub running: bool,
+
+    /// 実行中の検査で、未検査のバージョンの数。
+    pub remaining: usize,
+
+    /// 実行中(ないし直近)の検査で、検査したlumpの数。
+    pub scanned: u64,
+
+    /// 実行中(ないし直近)の検査で、破損が検出されたlumpの数。
+    pub corrupted: u64,
+
+    /// 実行中(ないし直近)の検査で、修復されたlumpの数。
+    pub repaired: u64,
+
+    /// 完了した検査の数。
+    pub completed_passes: u64,
+
+    /// 直近の検査が完了した時刻(UNIXタイムスタンプ、秒単位)。
+    pub last_completed_at: Option<u64>,
+}
+
+/// 検査を操作・監視するためのハンドル。
+#[derive(Debug, Clone)]
+pub struct ScrubberHandle {
+    status: Arc<Mutex<ScrubStatus>>,
+    requested: Arc<AtomicBool>,
+}
+impl ScrubberHandle {
+    /// 検査の状況を返す。
+    pub fn status(&self) -> ScrubStatus {
+        self.status.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    /// 検査の開始を要求する。
+    ///
+    /// 既に実行中の場合には、何も行われない。
+    pub fn request_scrub(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+}
+
+/// サーバ上の全てのノードの`ScrubberHandle`を保持するためのレジストリ。
+#[derive(Debug, Clone, Default)]
+pub struct ScrubberRegistry {
+    scrubbers: Arc<Mutex<BTreeMap<String, ScrubberHandle>>>,
+}
+impl ScrubberRegistry {
+    /// 全てのノードの検査の状況を返す。
+    pub fn statuses(&self) -> Vec<ScrubStatus> {
+        self.scrubbers
+            .lock()
+            .map(|s| s.values().map(|h| h.status()).collect())
+            .unwrap_or_default()
+    }
+
+    /// 全てのノードに、検査の開始を要求する。
+    pub fn request_scrub(&self) {
+        if let Ok(scrubbers) = self.scrubbers.lock() {
+            for h in scrubbers.values() {
+                h.request_scrub();
+            }
+        }
+    }
+
+    pub(crate) fn register(&self, node: String, handle: ScrubberHandle) {
+        if let Ok(mut scrubbers) = self.scrubbers.lock() {
+            scrubbers.insert(node, handle);
+        }
+    }
+
+    pub(crate) fn deregister(&self, node: &str) {
+        if let Ok(mut scrubbers) = self.scrubbers.lock() {
+            scrubbers.remove(node);
+        }
+    }
+}
+
+/// ノードが保持するオブジェクトの中身を、一つずつ読み込んでチェックサムを検証する。
+///
+/// 破損が検出され、かつ修復が有効な場合には、他のノードから取得したデータで上書きする。
+/// なおローカルに存在しない中身の復元は`Synchronizer`の担当なので、ここでは扱わない。
+///
+/// 中身がMDSに保存されているバージョン(マニフェストやインライン化されたオブジェクト)は、
+/// lumpを持たないので検査の対象外となる。
+/// マニフェストが参照するパート群は、それぞれが通常のバージョンとして個別に検査される。
+pub(crate) struct Scrubber {
+    logger: Logger,
+    node_id: NodeId,
+    device: DeviceHandle,
+    client: StorageClient,
+    repair_enabled: bool,
+    pass_interval: Option<Duration>,
+    lump_interval: Duration,
+    next_pass: Option<Instant>,
+    versions: BTreeSet<ObjectVersion>,
+    task: Task,
+    handle: ScrubberHandle,
+    scanned_lumps: Counter,
+    corrupted_lumps: Counter,
+    repaired_lumps: Counter,
+    completed_passes: Counter,
+    remaining_lumps: Gauge,
+}
+impl Scrubber {
+    /// 新しい`Scrubber`インスタンスを生成する。
+    ///
+    /// `pass_interval`が`None`の場合には、定期的な検査は行われず、明示的に要求された場合にのみ検査が行われる。
+    /// `lump_interval`は、一つのlumpの検査を終えてから、次のlumpの検査を開始するまでの待ち時間。
+    pub fn new(
+        logger: Logger,
+        node_id: NodeId,
+        device: DeviceHandle,
+        client: StorageClient,
+        repair_enabled: bool,
+        pass_interval: Option<Duration>,
+        lump_interval: Duration,
+    ) -> Self {
+        let metric_builder = MetricBuilder::new()
+            .namespace("frugalos")
+            .subsystem("scrubber")
+            .label("node", &node_id.to_string())
+            .clone();
+        let status = ScrubStatus {
+            node: node_id.to_string(),
+            ..Default::default()
+        };
+        Scrubber {
+            logger,
+            node_id,
+            device,
+            client,
+            repair_enabled,
+            pass_interval,
+            lump_interval,
+            next_pass: pass_interval.map(|d| Instant::now() + d),
+            versions: BTreeSet::new(),
+            task: Task::Idle,
+            handle: ScrubberHandle {
+                status: Arc::new(Mutex::new(status)),
+                requested: Arc::new(AtomicBool::new(false)),
+            },
+            scanned_lumps: metric_builder
+                .counter("scanned_lumps_total")
+                .finish()
+                .unwrap(),
+            corrupted_lumps: metric_builder
+                .counter("corrupted_lumps_total")
+                .finish()
+                .unwrap(),
+            repaired_lumps: metric_builder
+                .counter("repaired_lumps_total")
+                .finish()
+                .unwrap(),
+            completed_passes: metric_builder
+                .counter("completed_passes_total")
+                .finish()
+                .unwrap(),
+            remaining_lumps: metric_builder.gauge("remaining_lumps").finish().unwrap(),
+        }
+    }
+
+    /// この検査を操作・監視するためのハンドルを返す。
+    pub fn handle(&self) -> ScrubberHandle {
+        self.handle.clone()
+    }
+
+    /// 新しい検査を開始すべきかどうかを判定する。
+    ///
+    /// `true`が返された場合には、呼び出し側は`start`メソッドを呼び出す必要がある。
+    pub fn needs_versions(&self) -> bool {
+        if self.client.is_metadata() || self.is_running() {
+            return false;
+        }
+        self.handle.requested.load(Ordering::SeqCst)
+            || self.next_pass.map_or(false, |t| t <= Instant::now())
+    }
+
+    /// 指定のバージョン群を対象とした検査を開始する。
+    ///
+    /// `versions`には、中身がMDSに保存されているバージョン(e.g., マニフェスト)を含めてはいけない。
+    pub fn start(&mut self, versions: Vec<ObjectVersion>) {
+        info!(
+            self.logger,
+            "Starts scrubbing: {} versions (repair_enabled={})",
+            versions.len(),
+            self.repair_enabled
+        );
+        self.handle.requested.store(false, Ordering::SeqCst);
+        self.next_pass = None;
+        self.versions = versions.into_iter().collect();
+        self.remaining_lumps.set(self.versions.len() as f64);
+        self.update_status(|s| {
+            s.running = true;
+            s.remaining = self.versions.len();
+            s.scanned = 0;
+            s.corrupted = 0;
+            s.repaired = 0;
+        });
+    }
+
+    pub fn handle_event(&mut self, event: &Event) {
+        // NOTE: 検査対象の一覧の取得後に削除されたものは、検査しない
+        if let Event::Deleted { version } = *event {
+            self.versions.remove(&version);
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.handle.status.lock().map_or(false, |s| s.running)
+    }
+
+    fn update_status<F>(&self, f: F)
+    where
+        F: FnOnce(&mut ScrubStatus),
+    {
+        if let Ok(mut status) = self.handle.status.lock() {
+            f(&mut status);
+        }
+    }
+
+    fn handle_outcome(&mut self, outcome: LumpState) {
+        self.scanned_lumps.increment();
+        match outcome {
+            LumpState::Healthy | LumpState::Absent => {
+                self.update_status(|s| s.scanned += 1);
+            }
+            LumpState::Corrupted => {
+                self.corrupted_lumps.increment();
+                self.update_status(|s| {
+                    s.scanned += 1;
+                    s.corrupted += 1;
+                });
+            }
+            LumpState::Repaired => {
+                self.corrupted_lumps.increment();
+                self.repaired_lumps.increment();
+                self.update_status(|s| {
+                    s.scanned += 1;
+                    s.corrupted += 1;
+                    s.repaired += 1;
+                });
+            }
+        }
+    }
+
+    fn complete_pass(&mut self) {
+        self.completed_passes.increment();
+        self.next_pass = self.pass_interval.map(|d| Instant::now() + d);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.update_status(|s| {
+            s.running = false;
+            s.completed_passes += 1;
+            s.last_completed_at = Some(now);
+        });
+        let status = self.handle.status();
+        info!(
+            self.logger,
+            "Completed scrubbing: scanned={}, corrupted={}, repaired={}",
+            status.scanned,
+            status.corrupted,
+            status.repaired
+        );
+    }
+
+    fn next_version(&mut self) -> Option<ObjectVersion> {
+        let version = self.versions.iter().next().cloned()?;
+        self.versions.remove(&version);
+        self.remaining_lumps.set(self.versions.len() as f64);
+        let remaining = self.versions.len();
+        self.update_status(|s| s.remaining = remaining);
+        Some(version)
+    }
+}
+impl Future for Scrubber {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.task.poll() {
+                Err(e) => {
+                    // 検査のエラーは致命的ではないので、ログを出すだけに留める
+                    warn!(self.logger, "Scrubbing failure: {}", e);
+                    self.handle_outcome(LumpState::Corrupted);
+                }
+                Ok(Async::NotReady) => break,
+                Ok(Async::Ready(outcome)) => {
+                    if let Some(outcome) = outcome {
+                        self.handle_outcome(outcome);
+                        self.task = Task::Wait(timer::timeout(self.lump_interval));
+                        continue;
+                    }
+                }
+            }
+
+            self.task = Task::Idle;
+            if let Some(version) = self.next_version() {
+                self.task = Task::Scrub(ScrubLump::new(self, version));
+            } else {
+                if self.is_running() {
+                    self.complete_pass();
+                }
+                break;
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LumpState {
+    Healthy,
+    Absent,
+    Corrupted,
+    Repaired,
+}
+
+enum Task {
+    Idle,
+    Wait(Timeout),
+    Scrub(ScrubLump),
+}
+impl Future for Task {
+    type Item = Option<LumpState>;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match *self {
+            Task::Idle => Ok(Async::Ready(None)),
+            Task::Wait(ref mut f) => track!(f.poll().map_err(Error::from)).map(|t| t.map(|_| None)),
+            Task::Scrub(ref mut f) => track!(f.poll()).map(|t| t.map(Some)),
+        }
+    }
+}
+
+struct ScrubLump {
+    logger: Logger,
+    node_id: NodeId,
+    version: ObjectVersion,
+    client: StorageClient,
+    device: DeviceHandle,
+    repair_enabled: bool,
+    phase: Phase3<BoxFuture<Option<LumpData>>, GetFragment, BoxFuture<bool>>,
+}
+impl ScrubLump {
+    fn new(scrubber: &Scrubber, version: ObjectVersion) -> Self {
+        let lump_id = config::make_lump_id(&scrubber.node_id, version);
+        debug!(
+            scrubber.logger,
+            "Starts scrubbing content: version={:?}, lump_id={:?}", version, lump_id
+        );
+        let future = scrubber
+            .device
+            .request()
+            .deadline(Deadline::Infinity)
+            .get(lump_id);
+        ScrubLump {
+            logger: scrubber.logger.clone(),
+            node_id: scrubber.node_id,
+            version,
+            client: scrubber.client.clone(),
+            device: scrubber.device.clone(),
+            repair_enabled: scrubber.repair_enabled,
+            phase: Phase3::A(into_box_future(future)),
+        }
+    }
+}
+impl Future for ScrubLump {
+    type Item = LumpState;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(phase) = track!(self.phase.poll())? {
+            let next = match phase {
+                Phase3::A(None) => return Ok(Async::Ready(LumpState::Absent)),
+                Phase3::A(Some(data)) => {
+                    if verify_checksum(data.as_bytes()).is_ok() {
+                        return Ok(Async::Ready(LumpState::Healthy));
+                    }
+                    warn!(
+                        self.logger,
+                        "Corrupted content is detected: version={:?}, repair_enabled={}",
+                        self.version,
+                        self.repair_enabled
+                    );
+                    if !self.repair_enabled {
+                        return Ok(Async::Ready(LumpState::Corrupted));
+                    }
+                    let future = self.client.clone().get_fragment(self.node_id, self.version);
+                    Phase3::B(future)
+                }
+                Phase3::B(MaybeFragment::NotParticipant) => {
+                    warn!(
+                        self.logger,
+                        "The corrupted content {:?} should not be stored on this node",
+                        self.version
+                    );
+                    return Ok(Async::Ready(LumpState::Corrupted));
+                }
+                Phase3::B(MaybeFragment::Fragment(content, compression)) => {
+                    let lump_id = config::make_lump_id(&self.node_id, self.version);
+                    let content = track!(self.client.encode_lump(content, compression, lump_id))?;
+                    let data = track!(self.device.allocate_lump_data_with_bytes(&content))?;
+                    let future = self
+                        .device
+                        .request()
+                        .deadline(Deadline::Infinity)
+                        .put(lump_id, data);
+                    Phase3::C(into_box_future(future))
+                }
+                Phase3::C(_) => {
+                    info!(
+                        self.logger,
+                        "Repaired corrupted content: {:?}", self.version
+                    );
+                    return Ok(Async::Ready(LumpState::Repaired));
+                }
+            };
+            self.phase = next;
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+fn into_box_future<F>(future: F) -> BoxFuture<F::Item>
+where
+    F: Future<Error = cannyls::Error> + Send + 'static,
+{
+    Box::new(future.map_err(Error::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fibers_global;
+    use futures;
+    use rustracing_jaeger::span::Span;
+    use std::thread;
+    use test_util::tests::{setup_system, wait, System};
+    use trackable::result::TestResult;
+    use {ErrorKind, Result};
+
+    struct Env {
+        // NOTE: クラスタを維持するために保持しておく
+        _system: System,
+        node_id: NodeId,
+        device: DeviceHandle,
+        scrubber: Scrubber,
+    }
+    impl Env {
+        fn new(
+            repair_enabled: bool,
+            pass_interval: Option<Duration>,
+            lump_interval: Duration,
+        ) -> Result<Self> {
+            let fragments = 5;
+            let mut system = track!(System::new(fragments))?;
+            let (node_id, device, client) = track!(setup_system(&mut system, fragments as usize))?;
+            for version in 1..3 {
+                let content = vec![version as u8; 1024];
+                track!(wait(client.clone().put(
+                    ObjectVersion(version),
+                    content,
+                    Deadline::Infinity,
+                    Span::inactive().handle(),
+                )))?;
+            }
+            let scrubber = Scrubber::new(
+                system.logger(),
+                node_id,
+                device.clone(),
+                client,
+                repair_enabled,
+                pass_interval,
+                lump_interval,
+            );
+            Ok(Env {
+                _system: system,
+                node_id,
+                device,
+                scrubber,
+            })
+        }
+
+        // 指定のバージョンのlumpを(書き込まれるまで待った上で)取得する
+        fn get_lump(&self, version: ObjectVersion) -> Result<Vec<u8>> {
+            let lump_id = config::make_lump_id(&self.node_id, version);
+            let started_at = Instant::now();
+            loop {
+                let future = self
+                    .device
+                    .request()
+                    .deadline(Deadline::Infinity)
+                    .get(lump_id);
+                if let Some(data) = track!(wait(into_box_future(future)))? {
+                    return Ok(data.as_bytes().to_vec());
+                }
+                track_assert!(
+                    started_at.elapsed() < Duration::from_secs(10),
+                    ErrorKind::Other,
+                    "Lump not found: {:?}",
+                    version
+                );
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        fn put_lump(&self, version: ObjectVersion, bytes: &[u8]) -> Result<()> {
+            let lump_id = config::make_lump_id(&self.node_id, version);
+            let data = track!(self.device.allocate_lump_data_with_bytes(bytes))?;
+            let future = self
+                .device
+                .request()
+                .deadline(Deadline::Infinity)
+                .put(lump_id, data);
+            track!(wait(into_box_future(future)))?;
+            Ok(())
+        }
+
+        // 指定のバージョンのlumpを破損させ、破損前の中身を返す
+        fn corrupt_lump(&self, version: ObjectVersion) -> Result<Vec<u8>> {
+            let original = track!(self.get_lump(version))?;
+            let mut corrupted = original.clone();
+            corrupted[0] ^= 0xFF;
+            track!(self.put_lump(version, &corrupted))?;
+            Ok(original)
+        }
+
+        // 実行中の検査が完了するまで`Scrubber`を駆動する
+        fn run_pass(self) -> Result<Self> {
+            let mut env = Some(self);
+            let future = futures::future::poll_fn(move || -> Poll<Env, Error> {
+                let done = {
+                    let env = env.as_mut().expect("Never fails");
+                    track!(env.scrubber.poll())?;
+                    !env.scrubber.handle().status().running
+                };
+                if done {
+                    Ok(Async::Ready(env.take().expect("Never fails")))
+                } else {
+                    Ok(Async::NotReady)
+                }
+            });
+            track!(fibers_global::execute(future))
+        }
+    }
+
+    #[test]
+    fn scrubbing_detects_corrupted_lumps() -> TestResult {
+        let mut env = track!(Env::new(false, None, Duration::from_millis(0)))?;
+        track!(env.get_lump(ObjectVersion(1)))?;
+        track!(env.corrupt_lump(ObjectVersion(2)))?;
+
+        env.scrubber.start(vec![
+            ObjectVersion(1),
+            ObjectVersion(2),
+            ObjectVersion(3), // 存在しない
+        ]);
+        let status = env.scrubber.handle().status();
+        assert!(status.running);
+        assert_eq!(status.remaining, 3);
+
+        let env = track!(env.run_pass())?;
+        let status = env.scrubber.handle().status();
+        assert!(!status.running);
+        assert_eq!(status.remaining, 0);
+        assert_eq!(status.scanned, 3);
+        assert_eq!(status.corrupted, 1);
+        assert_eq!(status.repaired, 0);
+        assert_eq!(status.completed_passes, 1);
+        assert!(status.last_completed_at.is_some());
+
+        // 修復が無効なので、破損したままになっている
+        let lump = track!(env.get_lump(ObjectVersion(2)))?;
+        assert!(verify_checksum(&lump).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn scrubbing_repairs_corrupted_lumps() -> TestResult {
+        let mut env = track!(Env::new(true, None, Duration::from_millis(0)))?;
+        track!(env.get_lump(ObjectVersion(1)))?;
+        let original = track!(env.corrupt_lump(ObjectVersion(2)))?;
+
+        env.scrubber.start(vec![ObjectVersion(1), ObjectVersion(2)]);
+        let env = track!(env.run_pass())?;
+        let status = env.scrubber.handle().status();
+        assert_eq!(status.scanned, 2);
+        assert_eq!(status.corrupted, 1);
+        assert_eq!(status.repaired, 1);
+
+        // 他のノードのフラグメントから復元された中身に置き換わっている
+        let lump = track!(env.get_lump(ObjectVersion(2)))?;
+        assert!(verify_checksum(&lump).is_ok());
+        assert_eq!(lump, original);
+        Ok(())
+    }
+
+    #[test]
+    fn scrubbing_is_scheduled_periodically_or_on_request() -> TestResult {
+        // 定期的な検査が無効な場合には、要求された場合にのみ検査が行われる
+        let mut env = track!(Env::new(false, None, Duration::from_millis(0)))?;
+        assert!(!env.scrubber.needs_versions());
+        env.scrubber.handle().request_scrub();
+        assert!(env.scrubber.needs_versions());
+
+        env.scrubber.start(vec![ObjectVersion(1)]);
+        assert!(!env.scrubber.needs_versions());
+        let env = track!(env.run_pass())?;
+        assert!(!env.scrubber.needs_versions());
+        assert_eq!(env.scrubber.handle().status().completed_passes, 1);
+
+        // 定期的な検査が有効な場合には、前回の検査の完了から`pass_interval`が経過した時点で検査が行われる
+        let interval = Duration::from_millis(100);
+        let mut env = track!(Env::new(false, Some(interval), Duration::from_millis(0)))?;
+        assert!(!env.scrubber.needs_versions());
+        thread::sleep(interval);
+        assert!(env.scrubber.needs_versions());
+
+        env.scrubber.start(Vec::new());
+        let env = track!(env.run_pass())?;
+        assert!(!env.scrubber.needs_versions());
+        thread::sleep(interval);
+        assert!(env.scrubber.needs_versions());
+        Ok(())
+    }
+
+    #[test]
+    fn scrubbing_skips_deleted_versions() -> TestResult {
+        let mut env = track!(Env::new(false, None, Duration::from_millis(0)))?;
+        env.scrubber.start(vec![ObjectVersion(1), ObjectVersion(2)]);
+        env.scrubber.handle_event(&Event::Deleted {
+            version: ObjectVersion(2),
+        });
+
+        let env = track!(env.run_pass())?;
+        let status = env.scrubber.handle().status();
+        assert_eq!(status.scanned, 1);
+        assert_eq!(status.completed_passes, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn scrubbing_waits_lump_interval_between_lumps() -> TestResult {
+        let interval = Duration::from_millis(50);
+        let mut env = track!(Env::new(false, None, interval))?;
+        env.scrubber
+            .start(vec![ObjectVersion(1), ObjectVersion(2), ObjectVersion(3)]);
+
+        let started_at = Instant::now();
+        let env = track!(env.run_pass())?;
+        assert!(started_at.elapsed() >= interval * 3);
+        assert_eq!(env.scrubber.handle().status().scanned, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn registry_triggers_and_inspects_scrubbing() -> TestResult {
+        let mut env = track!(Env::new(false, None, Duration::from_millis(0)))?;
+        let registry = ScrubberRegistry::default();
+        let node = env.node_id.to_string();
+        registry.register(node.clone(), env.scrubber.handle());
+
+        let statuses = registry.statuses();
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].node, node);
+        assert!(!statuses[0].running);
+        assert_eq!(statuses[0].completed_passes, 0);
+
+        // `POST /v1/scrub`に相当
+        registry.request_scrub();
+        assert!(env.scrubber.needs_versions());
+        env.scrubber.start(vec![ObjectVersion(1), ObjectVersion(2)]);
+        assert!(registry.statuses()[0].running);
+
+        // `GET /v1/scrub`に相当
+        let _env = track!(env.run_pass())?;
+        let statuses = registry.statuses();
+        assert!(!statuses[0].running);
+        assert_eq!(statuses[0].scanned, 2);
+        assert_eq!(statuses[0].completed_passes, 1);
+        assert!(statuses[0].last_completed_at.is_some());
+
+        registry.deregister(&node);
+        assert!(registry.statuses().is_empty());
+        Ok(())
+    }
+}
@@ -5,16 +5,24 @@ use fibers::sync::mpsc;
 use fibers::Spawn;
 use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
 use fibers_rpc::server::ServerBuilder as RpcServerBuilder;
+use frugalos_mds::schema::SharedVersioningPolicy;
 use frugalos_mds::{Node, Service as RaftMdsService, ServiceHandle as MdsHandle};
 use frugalos_raft::{self, NodeId};
 use futures::{Async, Future, Poll, Stream};
 use raftlog::cluster::ClusterMembers;
 use slog::Logger;
 use std::env;
+use std::sync::Arc;
 use std::time::Duration;
 use trackable::error::ErrorKindExt;
 
+use client::encryption::{KeyFile, KeyProvider};
+use client::repair::{ReadRepairer, RepairTask};
 use client::storage::StorageClient;
+use orphan_gc::OrphanCollector;
+use rebuilder::Rebuilder;
+use repair_control::{RepairController, RepairSettings};
+use scrubber::{Scrubber, ScrubberRegistry};
 use synchronizer::Synchronizer;
 use {Client, Error, ErrorKind, Result};
 
@@ -29,6 +37,11 @@ pub struct Service<S> {
     command_tx: mpsc::Sender<Command>,
     command_rx: mpsc::Receiver<Command>,
     raft_metrics: frugalos_raft::RpcMetrics,
+    scrubbers: ScrubberRegistry,
+    read_repairer: Option<ReadRepairer>,
+    repair_rx: mpsc::Receiver<RepairTask>,
+    repair_controller: RepairController,
+    key_provider: Arc<KeyProvider>,
     mds_alive: bool,
 }
 impl<S> Service<S>
@@ -48,6 +61,36 @@ where
         CannyLsRpcServer::new(device_registry.handle()).register(rpc);
 
         let (command_tx, command_rx) = mpsc::channel();
+
+        // TODO: optionにする
+        let repair_enabled = env::var("FRUGALOS_REPAIR_ENABLED")
+            .ok()
+            .map_or(false, |v| v == "1");
+        let (read_repairer, repair_rx) = ReadRepairer::new(logger.clone());
+
+        // TODO: optionにする
+        let default_settings = RepairSettings::default();
+        let repair_settings = RepairSettings {
+            concurrency: env::var("FRUGALOS_REPAIR_CONCURRENCY")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default_settings.concurrency),
+            bytes_per_second: env::var("FRUGALOS_REPAIR_BYTES_PER_SECOND")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .and_then(|n| if n == 0 { None } else { Some(n) }),
+        };
+        info!(logger, "Repair settings: {:?}", repair_settings);
+        let repair_controller = track!(RepairController::new(repair_settings))?;
+
+        // TODO: optionにする
+        let key_file = if let Ok(path) = env::var("FRUGALOS_ENCRYPTION_KEYFILE") {
+            let key_file = track!(KeyFile::load(&path))?;
+            info!(logger, "Encryption keys are loaded: path={:?}", path);
+            key_file
+        } else {
+            KeyFile::default()
+        };
         Ok(Service {
             logger,
             rpc_service,
@@ -58,6 +101,15 @@ where
             command_tx,
             command_rx,
             raft_metrics: frugalos_raft::RpcMetrics::new(),
+            scrubbers: ScrubberRegistry::default(),
+            read_repairer: if repair_enabled {
+                Some(read_repairer)
+            } else {
+                None
+            },
+            repair_rx,
+            repair_controller,
+            key_provider: Arc::new(key_file),
             mds_alive: true,
         })
     }
@@ -84,6 +136,38 @@ where
         self.mds_service.take_snapshot();
     }
 
+    /// このサーバ上のノード群の検査(スクラブ)を操作するためのレジストリを返す。
+    pub fn scrubbers(&self) -> ScrubberRegistry {
+        self.scrubbers.clone()
+    }
+
+    /// GET時に見つかった欠損・破損の修復(read-repair)を行うためのハンドルを返す。
+    ///
+    /// 修復が無効(`FRUGALOS_REPAIR_ENABLED`が未指定)の場合には`None`が返される。
+    pub fn read_repairer(&self) -> Option<ReadRepairer> {
+        self.read_repairer.clone()
+    }
+
+    /// 同期処理による修復の並列度や帯域を、実行時に調整するためのハンドルを返す。
+    pub fn repair_controller(&self) -> RepairController {
+        self.repair_controller.clone()
+    }
+
+    /// バケツの暗号化に使用する鍵の提供元を返す。
+    pub fn key_provider(&self) -> Arc<KeyProvider> {
+        Arc::clone(&self.key_provider)
+    }
+
+    /// バケツの暗号化に使用する鍵の提供元を差し替える。
+    ///
+    /// デフォルトでは`FRUGALOS_ENCRYPTION_KEYFILE`で指定された`KeyFile`が使用される。
+    /// 外部の鍵管理サービス等を利用したい場合には、独自の`KeyProvider`実装をここで設定する。
+    ///
+    /// 変更は、これ以降に生成されるバケツに対してのみ反映される。
+    pub fn set_key_provider(&mut self, provider: Arc<KeyProvider>) {
+        self.key_provider = provider;
+    }
+
     /// デバイスレジストリへの破壊的な参照を返す。
     pub fn device_registry_mut(&mut self) -> &mut DeviceRegistry {
         &mut self.device_registry
@@ -96,7 +180,7 @@ where
 
     fn handle_command(&mut self, command: Command) {
         match command {
-            Command::AddNode(node_id, device, client, cluster) => {
+            Command::AddNode(node_id, device, client, versioning, cluster) => {
                 // TODO: error handling
                 let logger = self.logger.clone();
                 let logger0 = logger.clone();
@@ -105,6 +189,8 @@ where
                 let raft_service = self.raft_service.clone();
                 let raft_metrics = self.raft_metrics.clone();
                 let mds_service = self.mds_service.handle();
+                let scrubbers = self.scrubbers.clone();
+                let repair_controller = self.repair_controller.clone();
                 let future = device
                     .map_err(|e| track!(e))
                     .and_then(move |device| {
@@ -118,7 +204,10 @@ where
                             node_id,
                             device,
                             client,
+                            versioning,
                             cluster,
+                            scrubbers,
+                            repair_controller,
                         ))
                     })
                     .map_err(move |e| crit!(logger, "Error: {}", e))
@@ -152,6 +241,10 @@ where
             let command = command.expect("Never fails");
             self.handle_command(command);
         }
+        while let Async::Ready(task) = self.repair_rx.poll().expect("Never fails") {
+            let task = task.expect("Never fails");
+            self.spawner.spawn(task);
+        }
         Ok(Async::NotReady)
     }
 }
@@ -174,7 +267,7 @@ impl ServiceHandle {
         client: Client,
         cluster: ClusterMembers,
     ) -> Result<()> {
-        let command = Command::AddNode(node_id, device, client.storage, cluster);
+        let command = Command::AddNode(node_id, device, client.storage, client.versioning, cluster);
         track!(self
             .command_tx
             .send(command,)
@@ -186,13 +279,24 @@ impl ServiceHandle {
 pub type CreateDeviceHandle = Box<Future<Item = DeviceHandle, Error = Error> + Send + 'static>;
 
 pub enum Command {
-    AddNode(NodeId, CreateDeviceHandle, StorageClient, ClusterMembers),
+    AddNode(
+        NodeId,
+        CreateDeviceHandle,
+        StorageClient,
+        SharedVersioningPolicy,
+        ClusterMembers,
+    ),
 }
 
 struct SegmentNode {
     logger: Logger,
     node: Node,
     synchronizer: Synchronizer,
+    scrubber: Scrubber,
+    scrubbers: ScrubberRegistry,
+    orphan_collector: OrphanCollector,
+    rebuilder: Rebuilder,
+    node_name: String,
 }
 impl SegmentNode {
     #[allow(clippy::too_many_arguments)]
@@ -208,7 +312,10 @@ impl SegmentNode {
         node_id: NodeId,
         device: DeviceHandle,
         client: StorageClient,
+        versioning: SharedVersioningPolicy,
         cluster: ClusterMembers,
+        scrubbers: ScrubberRegistry,
+        repair_controller: RepairController,
     ) -> Result<Self>
     where
         S: Clone + Spawn + Send + 'static,
@@ -237,7 +344,7 @@ impl SegmentNode {
             mailer,
             timer
         ))?;
-        let node = track!(Node::new(
+        let mut node = track!(Node::new(
             logger.clone(),
             mds_service,
             node_id,
@@ -245,6 +352,7 @@ impl SegmentNode {
             io,
             rpc_service
         ))?;
+        node.set_versioning(versioning);
 
         // TODO: optionにする
         let repair_enabled = env::var("FRUGALOS_REPAIR_ENABLED")
@@ -252,27 +360,154 @@ impl SegmentNode {
             .map_or(false, |v| v == "1");
         info!(logger, "Repair enabled: {}", repair_enabled);
 
-        let synchronizer =
-            Synchronizer::new(logger.clone(), node_id, device, client, repair_enabled);
+        // TODO: optionにする
+        let scrub_interval_secs = env::var("FRUGALOS_SCRUB_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(7 * 24 * 60 * 60);
+        let scrub_lump_interval_millis = env::var("FRUGALOS_SCRUB_LUMP_INTERVAL_MILLIS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(100);
+        let scrubber = Scrubber::new(
+            logger.clone(),
+            node_id,
+            device.clone(),
+            client.clone(),
+            repair_enabled,
+            if scrub_interval_secs == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(scrub_interval_secs))
+            },
+            Duration::from_millis(scrub_lump_interval_millis),
+        );
+        // TODO: optionにする
+        let orphan_gc_interval_secs = env::var("FRUGALOS_ORPHAN_GC_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(6 * 60 * 60);
+        let orphan_gc_grace_secs = env::var("FRUGALOS_ORPHAN_GC_GRACE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(24 * 60 * 60);
+        // NOTE: 誤検出時の影響が大きいので、明示的に`0`が指定されない限りは削除を行わない
+        let orphan_gc_dry_run = env::var("FRUGALOS_ORPHAN_GC_DRY_RUN")
+            .ok()
+            .map_or(true, |v| v != "0");
+        info!(
+            logger,
+            "Orphan lump GC: interval_secs={}, grace_secs={}, dry_run={}",
+            orphan_gc_interval_secs,
+            orphan_gc_grace_secs,
+            orphan_gc_dry_run
+        );
+        let orphan_collector = OrphanCollector::new(
+            logger.clone(),
+            node_id,
+            device.clone(),
+            if orphan_gc_interval_secs == 0 || client.is_metadata() {
+                None
+            } else {
+                Some(Duration::from_secs(orphan_gc_interval_secs))
+            },
+            Duration::from_secs(orphan_gc_grace_secs),
+            orphan_gc_dry_run,
+        );
+
+        // TODO: optionにする
+        let rebuild_enabled = env::var("FRUGALOS_REBUILD_ENABLED")
+            .ok()
+            .map_or(true, |v| v != "0");
+        let rebuild_concurrency = env::var("FRUGALOS_REBUILD_CONCURRENCY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(4);
+        let rebuild_lump_interval_millis = env::var("FRUGALOS_REBUILD_LUMP_INTERVAL_MILLIS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10);
+        let rebuilder = Rebuilder::new(
+            logger.clone(),
+            node_id,
+            device.clone(),
+            client.clone(),
+            rebuild_enabled,
+            rebuild_concurrency,
+            Duration::from_millis(rebuild_lump_interval_millis),
+        );
+
+        let node_name = node_id.to_string();
+        scrubbers.register(node_name.clone(), scrubber.handle());
+
+        // TODO: optionにする
+        let reencryption_lump_interval_millis =
+            env::var("FRUGALOS_REENCRYPTION_LUMP_INTERVAL_MILLIS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(10);
+        let synchronizer = Synchronizer::new(
+            logger.clone(),
+            node_id,
+            device,
+            client,
+            repair_enabled,
+            repair_controller,
+            Duration::from_millis(reencryption_lump_interval_millis),
+        );
 
         Ok(SegmentNode {
             logger,
             node,
             synchronizer,
+            scrubber,
+            scrubbers,
+            orphan_collector,
+            rebuilder,
+            node_name,
         })
     }
     fn run_once(&mut self) -> Result<bool> {
         while let Async::Ready(event) = track!(self.node.poll())? {
             if let Some(event) = event {
+                self.scrubber.handle_event(&event);
+                self.orphan_collector.handle_event(&event);
+                self.rebuilder.handle_event(&event);
                 self.synchronizer.handle_event(&event);
             } else {
                 return Ok(false);
             }
         }
         track!(self.synchronizer.poll())?;
+
+        // NOTE: 中身がMDSに保存されているバージョン(マニフェストやインライン化されたオブジェクト)は
+        // lumpを持たないので、検査や再構築の対象外とする
+        if self.scrubber.needs_versions() {
+            let versions = self.node.versions_without_data();
+            self.scrubber.start(versions);
+        }
+        track!(self.scrubber.poll())?;
+
+        // NOTE: 参照されていないことの判定に使うので、MDSの状態が追いついてから開始する
+        if self.orphan_collector.needs_versions() && self.node.is_caught_up() {
+            let versions = self.node.versions();
+            self.orphan_collector.start(versions);
+        }
+        track!(self.orphan_collector.poll())?;
+
+        if self.rebuilder.needs_versions() {
+            let versions = self.node.versions_without_data();
+            self.rebuilder.start(versions);
+        }
+        track!(self.rebuilder.poll())?;
         Ok(true)
     }
 }
+impl Drop for SegmentNode {
+    fn drop(&mut self) {
+        self.scrubbers.deregister(&self.node_name);
+    }
+}
 impl Future for SegmentNode {
     type Item = ();
     type Error = ();
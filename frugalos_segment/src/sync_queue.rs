@@ -0,0 +1,251 @@
+//! `Synchronizer`の未処理の削除TODOを、ノードのデバイス上に永続化するための処理。
+//!
+//! 修復TODOは、ノードの起動時にMDSのスナップショットおよびログから再生成されるので永続化しない。
+//! 一方で、スナップショット取得前に削除されたバージョンは再生成できないので、削除TODOのみを保存する。
+//!
+//! 削除TODOは一つのバージョン毎に一つのlump(埋め込みlump)として保存され、
+//! 処理が完了した時点で削除される。
+//! ノードの起動時には、保存されている削除TODOが読み込まれて、処理が再開される。
+use byteorder::{BigEndian, ByteOrder};
+use cannyls::deadline::Deadline;
+use cannyls::device::DeviceHandle;
+use cannyls::lump::{LumpData, LumpId};
+use frugalos_raft::NodeId;
+use futures::{stream, Async, Future, Stream};
+use libfrugalos::entity::object::ObjectVersion;
+use prometrics::metrics::{Gauge, MetricBuilder};
+use slog::Logger;
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use config;
+use Error;
+
+const LOAD_CONCURRENCY: usize = 16;
+
+type BoxFuture<T> = Box<Future<Item = T, Error = Error> + Send + 'static>;
+
+/// `Synchronizer`の削除TODOを永続化するためのストア。
+pub(crate) struct SyncQueueStore {
+    logger: Logger,
+    node_id: NodeId,
+    device: DeviceHandle,
+    writes: Vec<BoxFuture<()>>,
+    enqueued_at: HashMap<LumpId, SystemTime>,
+    by_age: BTreeSet<(SystemTime, LumpId)>,
+    queued_items: Gauge,
+    oldest_item_age: Gauge,
+}
+impl SyncQueueStore {
+    pub fn new(logger: Logger, node_id: NodeId, device: DeviceHandle) -> Self {
+        let metric_builder = MetricBuilder::new()
+            .namespace("frugalos")
+            .subsystem("synchronizer")
+            .label("node", &node_id.to_string())
+            .clone();
+        SyncQueueStore {
+            logger,
+            node_id,
+            device,
+            writes: Vec::new(),
+            enqueued_at: HashMap::new(),
+            by_age: BTreeSet::new(),
+            queued_items: metric_builder.gauge("queued_items").finish().unwrap(),
+            oldest_item_age: metric_builder
+                .gauge("oldest_queued_item_age_seconds")
+                .finish()
+                .unwrap(),
+        }
+    }
+
+    /// デバイスに保存されている削除TODO群を読み込む。
+    ///
+    /// 結果は、削除対象のバージョンと、それが保存された時刻のペアの配列。
+    /// 読み込まれたTODOは`restore`メソッドに渡す必要がある。
+    pub fn load(&self) -> BoxFuture<Vec<(ObjectVersion, SystemTime)>> {
+        let device = self.device.clone();
+        let node_id = self.node_id;
+        let logger = self.logger.clone();
+        let future = self
+            .device
+            .request()
+            .deadline(Deadline::Infinity)
+            .list()
+            .map_err(Error::from)
+            .and_then(move |lump_ids| {
+                let lump_ids = lump_ids
+                    .into_iter()
+                    .filter(|&id| parse_lump_id(&node_id, id).is_some())
+                    .collect::<Vec<_>>();
+                stream::iter_ok(lump_ids)
+                    .map(move |lump_id| {
+                        device
+                            .request()
+                            .deadline(Deadline::Infinity)
+                            .get(lump_id)
+                            .map(move |data| (lump_id, data))
+                            .map_err(Error::from)
+                    })
+                    .buffer_unordered(LOAD_CONCURRENCY)
+                    .filter_map(move |(lump_id, data)| {
+                        let item = data.and_then(|data| decode_item(&node_id, lump_id, &data));
+                        if item.is_none() {
+                            warn!(logger, "Invalid queued item: lump_id={:?}", lump_id);
+                        }
+                        item
+                    })
+                    .collect()
+            });
+        Box::new(future)
+    }
+
+    /// `load`メソッドで読み込まれたTODOを、保存済みのものとして登録する。
+    pub fn restore(&mut self, version: ObjectVersion, enqueued_at: SystemTime) {
+        let lump_id = config::make_sync_queue_lump_id(&self.node_id, version);
+        self.track(lump_id, enqueued_at);
+    }
+
+    /// 削除TODOを保存する。
+    pub fn put_delete(&mut self, version: ObjectVersion) {
+        let now = SystemTime::now();
+        let lump_id = config::make_sync_queue_lump_id(&self.node_id, version);
+        let data = encode_item(now);
+        self.track(lump_id, now);
+        let future = self
+            .device
+            .request()
+            .deadline(Deadline::Infinity)
+            .put(lump_id, data)
+            .map(|_| ())
+            .map_err(Error::from);
+        self.writes.push(Box::new(future));
+    }
+
+    /// 保存済みの削除TODOを削除する。
+    ///
+    /// TODOが保存されていない場合には何も行わない。
+    pub fn remove_delete(&mut self, version: ObjectVersion) {
+        let lump_id = config::make_sync_queue_lump_id(&self.node_id, version);
+        if !self.untrack(lump_id) {
+            return;
+        }
+        let future = self
+            .device
+            .request()
+            .deadline(Deadline::Infinity)
+            .delete(lump_id)
+            .map(|_| ())
+            .map_err(Error::from);
+        self.writes.push(Box::new(future));
+    }
+
+    /// 実行中の書き込みを進めて、メトリクスを更新する。
+    pub fn poll(&mut self) {
+        let mut i = 0;
+        while i < self.writes.len() {
+            match self.writes[i].poll() {
+                Err(e) => {
+                    // NOTE: 永続化に失敗しても、メモリ上のTODOの処理には影響しない
+                    warn!(self.logger, "Cannot write a queued item: {}", e);
+                }
+                Ok(Async::NotReady) => {
+                    i += 1;
+                    continue;
+                }
+                Ok(Async::Ready(())) => {}
+            }
+            self.writes.swap_remove(i);
+        }
+
+        self.queued_items.set(self.enqueued_at.len() as f64);
+        let age = self
+            .by_age
+            .iter()
+            .next()
+            .map_or(Duration::from_secs(0), |t| {
+                SystemTime::now()
+                    .duration_since(t.0)
+                    .unwrap_or_else(|_| Duration::from_secs(0))
+            });
+        self.oldest_item_age.set(age.as_secs() as f64);
+    }
+
+    fn track(&mut self, lump_id: LumpId, enqueued_at: SystemTime) {
+        self.untrack(lump_id);
+        self.enqueued_at.insert(lump_id, enqueued_at);
+        self.by_age.insert((enqueued_at, lump_id));
+    }
+
+    fn untrack(&mut self, lump_id: LumpId) -> bool {
+        if let Some(enqueued_at) = self.enqueued_at.remove(&lump_id) {
+            self.by_age.remove(&(enqueued_at, lump_id));
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// `lump_id`が`node_id`のノードの削除TODOを保存するものなら、そのバージョンを返す。
+fn parse_lump_id(node_id: &NodeId, lump_id: LumpId) -> Option<ObjectVersion> {
+    let prefix = config::make_sync_queue_lump_id(node_id, ObjectVersion(0)).as_u128() >> 64;
+    if lump_id.as_u128() >> 64 != prefix {
+        return None;
+    }
+    Some(ObjectVersion(lump_id.as_u128() as u64))
+}
+
+// レイアウト: <<EnqueuedAt:64>> (UNIXタイムスタンプ、秒単位)
+fn encode_item(enqueued_at: SystemTime) -> LumpData {
+    let mut bytes = vec![0; 8];
+    BigEndian::write_u64(&mut bytes, to_unix_secs(enqueued_at));
+    LumpData::new_embedded(bytes).expect("Never fails")
+}
+
+fn decode_item(
+    node_id: &NodeId,
+    lump_id: LumpId,
+    data: &LumpData,
+) -> Option<(ObjectVersion, SystemTime)> {
+    let bytes = data.as_bytes();
+    if bytes.len() != 8 {
+        return None;
+    }
+    let version = parse_lump_id(node_id, lump_id)?;
+    Some((version, from_unix_secs(BigEndian::read_u64(bytes))))
+}
+
+fn to_unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn from_unix_secs(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::tests::make_node_id;
+
+    #[test]
+    fn encode_and_decode_items_works() {
+        let node0 = make_node_id(0);
+        let node1 = make_node_id(1);
+        let enqueued_at = from_unix_secs(1000);
+        let version = ObjectVersion(10);
+        let lump_id = config::make_sync_queue_lump_id(&node0, version);
+        let data = encode_item(enqueued_at);
+        assert_eq!(
+            decode_item(&node0, lump_id, &data),
+            Some((version, enqueued_at))
+        );
+        assert_eq!(decode_item(&node1, lump_id, &data), None);
+
+        // 中身のlumpは対象外
+        let lump_id = config::make_lump_id(&node0, ObjectVersion(10));
+        assert_eq!(parse_lump_id(&node0, lump_id), None);
+    }
+}
@@ -7,15 +7,18 @@ use frugalos_mds::Event;
 use frugalos_raft::NodeId;
 use futures::{Async, Future, Poll};
 use libfrugalos::entity::object::ObjectVersion;
-use prometrics::metrics::{Counter, MetricBuilder};
+use prometrics::metrics::{Counter, Gauge, MetricBuilder};
 use slog::Logger;
 use std::cmp::{self, Reverse};
-use std::collections::{BTreeSet, BinaryHeap};
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
 use std::time::{Duration, SystemTime};
 
 use client::storage::{GetFragment, MaybeFragment, StorageClient};
 use config;
-use util::Phase3;
+use reencryptor::ReEncryptor;
+use repair_control::RepairController;
+use sync_queue::SyncQueueStore;
+use util::Phase;
 use Error;
 
 const MAX_TIMEOUT_SECONDS: u64 = 60;
@@ -24,19 +27,52 @@ const DELETE_CONCURRENCY: usize = 16;
 type BoxFuture<T> = Box<Future<Item = T, Error = Error> + Send + 'static>;
 
 // TODO: 起動直後の確認は`device.list()`の結果を使った方が効率的
+//
+// NOTE: 未処理の削除TODOは`SyncQueueStore`によってデバイス上に保存され、起動時に読み込まれる.
+// 修復TODOは、起動時にMDSのスナップショットやログの再適用によって発行される`Event::Putted`から
+// 再生成されるので、保存はしない.
+// そのため、ノードが再起動しても、修復や削除が失われることはない.
+//
+// NOTE: 修復は「確認」と「復元」の二段階で行われる.
+// 確認によって中身の欠損が判明したバージョンは、残りの冗長度が低い順に復元される.
+// 同時に実行される処理の数や、復元に使用する帯域は`RepairController`によって制御される.
+//
+// NOTE: 中身がMDSに保存されているバージョン(マニフェストやインライン化されたオブジェクト)は、
+// lumpを持たないので修復の対象外となる(`Event::Putted`の`has_data`で判別する).
+// マニフェストが参照するパート群は、それぞれが通常のバージョンとして個別に修復される.
+// 一方で削除は、対応するlumpが存在しなくても害はないので、区別せずに行う.
+//
+// NOTE: バケツの暗号鍵が変更された場合には、`ReEncryptor`がローカルのlumpを新しい鍵で暗号化し直す.
+// 再暗号化による書き込みも、復元と同じ`RepairController`の帯域制限の対象となる.
 pub struct Synchronizer {
     logger: Logger,
     node_id: NodeId,
     device: DeviceHandle,
     client: StorageClient,
-    task: Task,
+    tasks: Vec<Task>,
+    todo_wait: Option<Timeout>,
+    throttle_wait: Option<Timeout>,
     todo: BinaryHeap<Reverse<TodoItem>>,
     repair_candidates: BTreeSet<ObjectVersion>,
     repair_enabled: bool,
+
+    // 確認中・復元待ち・復元中のいずれかの状態にあるバージョン群
+    busy_versions: HashSet<ObjectVersion>,
+
+    // 復元待ちのバージョン群 (残りの冗長度の昇順)
+    pending_repairs: BTreeSet<(usize, ObjectVersion)>,
+    pending_redundancy: HashMap<ObjectVersion, usize>,
+
+    controller: RepairController,
+    store: SyncQueueStore,
+    restoring: Option<BoxFuture<Vec<(ObjectVersion, SystemTime)>>>,
+    reencryptor: ReEncryptor,
     enqueued_repair: Counter,
     enqueued_delete: Counter,
     dequeued_repair: Counter,
     dequeued_delete: Counter,
+    repaired_bytes: Counter,
+    pending_repairs_gauge: Gauge,
 }
 impl Synchronizer {
     pub fn new(
@@ -45,21 +81,46 @@ impl Synchronizer {
         device: DeviceHandle,
         client: StorageClient,
         repair_enabled: bool,
+        controller: RepairController,
+        reencryption_lump_interval: Duration,
     ) -> Self {
         let metric_builder = MetricBuilder::new()
             .namespace("frugalos")
             .subsystem("synchronizer")
             .label("node", &node_id.to_string())
             .clone();
+        let store = SyncQueueStore::new(logger.clone(), node_id, device.clone());
+        let restoring = if client.is_metadata() {
+            None
+        } else {
+            Some(store.load())
+        };
+        let reencryptor = ReEncryptor::new(
+            logger.clone(),
+            node_id,
+            device.clone(),
+            client.clone(),
+            controller.clone(),
+            reencryption_lump_interval,
+        );
         Synchronizer {
             logger,
             node_id,
             device,
             client,
-            task: Task::Idle,
+            tasks: Vec::new(),
+            todo_wait: None,
+            throttle_wait: None,
             todo: BinaryHeap::new(),
             repair_candidates: BTreeSet::new(),
             repair_enabled,
+            busy_versions: HashSet::new(),
+            pending_repairs: BTreeSet::new(),
+            pending_redundancy: HashMap::new(),
+            controller,
+            store,
+            restoring,
+            reencryptor,
             enqueued_repair: metric_builder
                 .counter("enqueued_items")
                 .label("type", "repair")
@@ -80,6 +141,11 @@ impl Synchronizer {
                 .label("type", "delete")
                 .finish()
                 .unwrap(),
+            repaired_bytes: metric_builder
+                .counter("repaired_bytes_total")
+                .finish()
+                .unwrap(),
+            pending_repairs_gauge: metric_builder.gauge("pending_repairs").finish().unwrap(),
         }
     }
     pub fn handle_event(&mut self, event: &Event) {
@@ -92,6 +158,11 @@ impl Synchronizer {
         );
         if !self.client.is_metadata() {
             match *event {
+                Event::Putted { has_data: true, .. } => {
+                    // 中身がMDSに保存されている(インライン化されたオブジェクトやマニフェスト等)ので、
+                    // 修復すべきlumpは存在しない
+                    return;
+                }
                 Event::Putted { version, .. } => {
                     self.enqueued_repair.increment();
                     if self.repair_enabled {
@@ -100,6 +171,11 @@ impl Synchronizer {
                 }
                 Event::Deleted { version } => {
                     self.repair_candidates.remove(&version);
+                    if let Some(redundancy) = self.pending_redundancy.remove(&version) {
+                        self.pending_repairs.remove(&(redundancy, version));
+                        self.busy_versions.remove(&version);
+                    }
+                    self.store.put_delete(version);
                     if let Some(mut head) = self.todo.peek_mut() {
                         if let TodoItem::DeleteContent { ref mut versions } = head.0 {
                             if versions.len() < DELETE_CONCURRENCY {
@@ -114,6 +190,26 @@ impl Synchronizer {
             self.todo.push(Reverse(TodoItem::new(&event)));
         }
     }
+    fn restore_todo_items(&mut self, items: Vec<(ObjectVersion, SystemTime)>) {
+        let mut deletes = Vec::with_capacity(items.len());
+        for (version, enqueued_at) in items {
+            self.store.restore(version, enqueued_at);
+            deletes.push(version);
+        }
+        info!(self.logger, "Restores queued deletes: {}", deletes.len());
+
+        deletes.sort();
+        for &version in &deletes {
+            // 削除済みのバージョンに対する修復は不要
+            self.repair_candidates.remove(&version);
+        }
+        for versions in deletes.chunks(DELETE_CONCURRENCY) {
+            self.enqueued_delete.increment();
+            self.todo.push(Reverse(TodoItem::DeleteContent {
+                versions: versions.to_vec(),
+            }));
+        }
+    }
     fn next_todo_item(&mut self) -> Option<TodoItem> {
         let item = loop {
             if let Some(item) = self.todo.pop() {
@@ -123,6 +219,11 @@ impl Synchronizer {
                         self.dequeued_repair.increment();
                         continue;
                     }
+                    if self.busy_versions.contains(&version) {
+                        // 同じバージョンを処理中
+                        self.dequeued_repair.increment();
+                        continue;
+                    }
                 }
                 break item.0;
             } else {
@@ -130,10 +231,8 @@ impl Synchronizer {
             }
         };
         if let Some(duration) = item.wait_time() {
-            // NOTE: `assert_eq!(self.task, Task::Idel)`
-
             let duration = cmp::min(duration, Duration::from_secs(MAX_TIMEOUT_SECONDS));
-            self.task = Task::Wait(timer::timeout(duration));
+            self.todo_wait = Some(timer::timeout(duration));
             self.todo.push(Reverse(item));
 
             // NOTE:
@@ -149,37 +248,147 @@ impl Synchronizer {
                 self.todo.shrink_to_fit();
             }
             if let TodoItem::RepairContent { version, .. } = item {
-                self.repair_candidates.remove(&version);
+                self.busy_versions.insert(version);
             }
             Some(item)
         }
     }
+
+    /// 新しいタスクを開始できる場合には、それを開始する。
+    ///
+    /// タスクを開始したり、新たに待機状態に入った場合には`true`が返される。
+    fn start_next_task(&mut self) -> bool {
+        if self.tasks.len() >= self.controller.concurrency() {
+            return false;
+        }
+        let throttled = self.throttle_wait.is_some();
+        if let Some(task) = self.next_repair_task() {
+            self.tasks.push(task);
+            return true;
+        }
+        if !throttled && self.throttle_wait.is_some() {
+            // 新たに待機状態に入ったので、タイマーを登録させる
+            return true;
+        }
+        if self.todo_wait.is_some() {
+            return false;
+        }
+        if let Some(item) = self.next_todo_item() {
+            let task = match item {
+                TodoItem::DeleteContent { versions } => {
+                    self.dequeued_delete.increment();
+                    Task::Delete(DeleteContent::new(self, versions))
+                }
+                TodoItem::RepairContent { version, .. } => {
+                    self.dequeued_repair.increment();
+                    Task::Check(CheckContent::new(self, version))
+                }
+            };
+            self.tasks.push(task);
+            true
+        } else {
+            self.todo_wait.is_some()
+        }
+    }
+    fn next_repair_task(&mut self) -> Option<Task> {
+        if self.pending_repairs.is_empty() || self.throttle_wait.is_some() {
+            return None;
+        }
+        if let Some(duration) = self.controller.wait_time() {
+            self.throttle_wait = Some(timer::timeout(duration));
+            return None;
+        }
+        let (redundancy, version) = *self.pending_repairs.iter().next()?;
+        self.pending_repairs.remove(&(redundancy, version));
+        self.pending_redundancy.remove(&version);
+        self.repair_candidates.remove(&version);
+        Some(Task::Repair(RepairContent::new(self, version, redundancy)))
+    }
+    fn handle_task_result(&mut self, task: Task, result: Result<Done, Error>) {
+        if let Err(ref e) = result {
+            // 同期処理のエラーは致命的ではないので、ログを出すだけに留める
+            warn!(self.logger, "Task failure: {}", e);
+        }
+        match task {
+            Task::Delete(task) => {
+                for version in task.versions {
+                    self.store.remove_delete(version);
+                }
+            }
+            Task::Check(task) => {
+                let version = task.version;
+                match result {
+                    Ok(Done::Checked(Some(redundancy)))
+                        if self.repair_candidates.contains(&version) =>
+                    {
+                        self.pending_repairs.insert((redundancy, version));
+                        self.pending_redundancy.insert(version, redundancy);
+                        return;
+                    }
+                    _ => {
+                        self.repair_candidates.remove(&version);
+                        self.busy_versions.remove(&version);
+                    }
+                }
+            }
+            Task::Repair(task) => {
+                if let Ok(Done::Repaired(bytes)) = result {
+                    self.controller.consume(bytes);
+                    self.repaired_bytes.add_u64(bytes);
+                }
+                self.busy_versions.remove(&task.version);
+            }
+        }
+    }
 }
 impl Future for Synchronizer {
     type Item = ();
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        while let Async::Ready(()) = self.task.poll().unwrap_or_else(|e| {
-            // 同期処理のエラーは致命的ではないので、ログを出すだけに留める
-            warn!(self.logger, "Task failure: {}", e);
-            Async::Ready(())
-        }) {
-            self.task = Task::Idle;
-            if let Some(item) = self.next_todo_item() {
-                match item {
-                    TodoItem::DeleteContent { versions } => {
-                        self.dequeued_delete.increment();
-                        self.task = Task::Delete(DeleteContent::new(self, versions));
-                    }
-                    TodoItem::RepairContent { version, .. } => {
-                        self.dequeued_repair.increment();
-                        self.task = Task::Repair(RepairContent::new(self, version));
+        let restored = self.restoring.poll().unwrap_or_else(|e| {
+            // 読み込みに失敗したTODOは、孤児の回収処理やスクラブによって補われる
+            warn!(self.logger, "Cannot restore queued items: {}", e);
+            Async::Ready(Some(Vec::new()))
+        });
+        if let Async::Ready(Some(items)) = restored {
+            self.restoring = None;
+            self.restore_todo_items(items);
+        }
+
+        loop {
+            let mut i = 0;
+            while i < self.tasks.len() {
+                let result = match self.tasks[i].poll() {
+                    Ok(Async::NotReady) => {
+                        i += 1;
+                        continue;
                     }
-                }
-            } else if let Task::Idle = self.task {
+                    Ok(Async::Ready(done)) => Ok(done),
+                    Err(e) => Err(e),
+                };
+                let task = self.tasks.swap_remove(i);
+                self.handle_task_result(task, result);
+            }
+
+            if self.todo_wait.poll().ok().map_or(true, |t| t.is_ready()) {
+                self.todo_wait = None;
+            }
+            if self
+                .throttle_wait
+                .poll()
+                .ok()
+                .map_or(true, |t| t.is_ready())
+            {
+                self.throttle_wait = None;
+            }
+            if !self.start_next_task() {
                 break;
             }
         }
+        self.store.poll();
+        self.reencryptor.poll();
+        self.pending_repairs_gauge
+            .set(self.pending_repairs.len() as f64);
         Ok(Async::NotReady)
     }
 }
@@ -203,6 +412,7 @@ impl TodoItem {
             Event::Putted {
                 version,
                 put_content_timeout,
+                ..
             } => {
                 let start_time = SystemTime::now() + Duration::from_secs(put_content_timeout.0);
                 TodoItem::RepairContent {
@@ -222,28 +432,33 @@ impl TodoItem {
     }
 }
 
+enum Done {
+    Deleted,
+    Checked(Option<usize>),
+    Repaired(u64),
+}
+
 #[allow(clippy::large_enum_variant)]
 enum Task {
-    Idle,
-    Wait(Timeout),
     Delete(DeleteContent),
+    Check(CheckContent),
     Repair(RepairContent),
 }
 impl Future for Task {
-    type Item = ();
+    type Item = Done;
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         match *self {
-            Task::Idle => Ok(Async::Ready(())),
-            Task::Wait(ref mut f) => track!(f.poll().map_err(Error::from)),
-            Task::Delete(ref mut f) => track!(f.poll()),
-            Task::Repair(ref mut f) => track!(f.poll()),
+            Task::Delete(ref mut f) => track!(f.poll()).map(|t| t.map(|()| Done::Deleted)),
+            Task::Check(ref mut f) => track!(f.poll()).map(|t| t.map(Done::Checked)),
+            Task::Repair(ref mut f) => track!(f.poll()).map(|t| t.map(Done::Repaired)),
         }
     }
 }
 
 // #[derive(Debug)]
 struct DeleteContent {
+    versions: Vec<ObjectVersion>,
     futures: Vec<BoxFuture<bool>>,
 }
 impl DeleteContent {
@@ -254,8 +469,8 @@ impl DeleteContent {
         );
 
         let futures = versions
-            .into_iter()
-            .map(move |v| {
+            .iter()
+            .map(move |&v| {
                 let lump_id = config::make_lump_id(&synchronizer.node_id, v);
                 let future = synchronizer
                     .device
@@ -265,7 +480,7 @@ impl DeleteContent {
                 into_box_future(future)
             })
             .collect();
-        DeleteContent { futures }
+        DeleteContent { versions, futures }
     }
 }
 impl Future for DeleteContent {
@@ -296,82 +511,149 @@ where
     Box::new(future.map_err(Error::from))
 }
 
+// `version`に対応するオブジェクトの中身がローカルに存在するかをチェックし、
+// 存在しない場合には、他のノード群が保持している中身から、残りの冗長度を調べる.
+//
+// 結果が`None`の場合には、復元は不要.
+struct CheckContent {
+    logger: Logger,
+    version: ObjectVersion,
+    node_id: NodeId,
+    client: StorageClient,
+    phase: Phase<BoxFuture<Option<LumpHeader>>, BoxFuture<Option<usize>>>,
+}
+impl CheckContent {
+    pub fn new(synchronizer: &Synchronizer, version: ObjectVersion) -> Self {
+        let logger = synchronizer.logger.clone();
+        let node_id = synchronizer.node_id;
+        let lump_id = config::make_lump_id(&node_id, version);
+        debug!(
+            logger,
+            "Starts checking content: version={:?}, lump_id={:?}", version, lump_id
+        );
+        let phase = Phase::A(into_box_future(
+            synchronizer
+                .device
+                .request()
+                .deadline(Deadline::Infinity)
+                .head(lump_id),
+        ));
+        CheckContent {
+            logger,
+            version,
+            node_id,
+            client: synchronizer.client.clone(),
+            phase,
+        }
+    }
+}
+impl Future for CheckContent {
+    type Item = Option<usize>;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(phase) = track!(self.phase.poll())? {
+            let next = match phase {
+                Phase::A(Some(_)) => {
+                    debug!(self.logger, "The object {:?} already exists", self.version);
+                    return Ok(Async::Ready(None));
+                }
+                Phase::A(None) => {
+                    let future = self
+                        .client
+                        .clone()
+                        .remaining_redundancy(self.node_id, self.version);
+                    Phase::B(future)
+                }
+                Phase::B(None) => {
+                    debug!(
+                        self.logger,
+                        "The object {:?} should not be stored on this node: node_id={:?}",
+                        self.version,
+                        self.node_id
+                    );
+                    return Ok(Async::Ready(None));
+                }
+                Phase::B(Some(redundancy)) => {
+                    debug!(
+                        self.logger,
+                        "The object {:?} does not exist (try repairing): remaining_redundancy={}",
+                        self.version,
+                        redundancy
+                    );
+                    return Ok(Async::Ready(Some(redundancy)));
+                }
+            };
+            self.phase = next;
+        }
+        Ok(Async::NotReady)
+    }
+}
+
 // NOTE
 // ====
 //
-// 以下の処理を行う:
-// 1. `version`に対応するオブジェクトの中身が存在するかチェック
-// 2. 存在しないなら復元を試みる (以下は一番複雑なdispersedの場合のみの処理を記述)
-// 2-1. 一つ前のクラスタ構成での対応するノードからセグメントを移動する (クラスタ構成変更時; 未実装)
+// 以下の処理を行う(存在しないことは`CheckContent`で確認済み):
+// 1. `version`に対応するオブジェクトの中身の復元を試みる (以下は一番複雑なdispersedの場合のみの処理を記述)
+// 1-1. 一つ前のクラスタ構成での対応するノードからセグメントを移動する (クラスタ構成変更時; 未実装)
 //      => 移動が完了したら、古いノードのセグメントは削除する
-// 2-2. それが無理なら、クラスタ内の他のノードからセグメント群を集めて復元する
+// 1-2. それが無理なら、クラスタ内の他のノードからセグメント群を集めて復元する
+//
+// なお空のデバイスで起動したノード(e.g., デバイス交換後)に対しては、
+// この処理とは別に`Rebuilder`が全てのバージョンの中身を再構築する.
 //
 // なおバケツの種別が`metadata`の場合には、復元は不要.
 // `replicated`の場合には、クラスタ内の任意の一つのノードからコピーすれば良い
 // (クラスタ構成変更によって、完全にノード構成が変わった場合にだけ注意が必要).
+//
+// 結果は、書き込んだデータのサイズ.
 struct RepairContent {
     logger: Logger,
     node_id: NodeId,
     version: ObjectVersion,
-    client: StorageClient,
     device: DeviceHandle,
-    phase: Phase3<BoxFuture<Option<LumpHeader>>, GetFragment, BoxFuture<bool>>,
+    client: StorageClient,
+    written: u64,
+    phase: Phase<GetFragment, BoxFuture<bool>>,
 }
 impl RepairContent {
-    pub fn new(synchronizer: &Synchronizer, version: ObjectVersion) -> Self {
+    pub fn new(synchronizer: &Synchronizer, version: ObjectVersion, redundancy: usize) -> Self {
         let logger = synchronizer.logger.clone();
-        let device = synchronizer.device.clone();
         let node_id = synchronizer.node_id;
-        let lump_id = config::make_lump_id(&node_id, version);
         debug!(
             logger,
-            "Starts checking content: version={:?}, lump_id={:?}", version, lump_id
+            "Starts repairing content: version={:?}, remaining_redundancy={}", version, redundancy
         );
-        let phase = Phase3::A(into_box_future(
-            device.request().deadline(Deadline::Infinity).head(lump_id),
-        ));
+        let future = synchronizer.client.clone().get_fragment(node_id, version);
         RepairContent {
             logger,
             node_id,
             version,
+            device: synchronizer.device.clone(),
             client: synchronizer.client.clone(),
-            device,
-            phase,
+            written: 0,
+            phase: Phase::A(future),
         }
     }
 }
 impl Future for RepairContent {
-    type Item = ();
+    type Item = u64;
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         while let Async::Ready(phase) = track!(self.phase.poll().map_err(Error::from))? {
             let next = match phase {
-                Phase3::A(Some(_)) => {
-                    debug!(self.logger, "The object {:?} already exists", self.version);
-                    return Ok(Async::Ready(()));
-                }
-                Phase3::A(None) => {
-                    debug!(
-                        self.logger,
-                        "The object {:?} does not exist (try repairing)", self.version
-                    );
-
-                    let future = self.client.clone().get_fragment(self.node_id, self.version);
-                    Phase3::B(future)
-                }
-                Phase3::B(MaybeFragment::NotParticipant) => {
+                Phase::A(MaybeFragment::NotParticipant) => {
                     debug!(
                         self.logger,
                         "The object {:?} should not be stored on this node: node_id={:?}",
                         self.version,
                         self.node_id
                     );
-                    return Ok(Async::Ready(()));
+                    return Ok(Async::Ready(0));
                 }
-                Phase3::B(MaybeFragment::Fragment(mut content)) => {
-                    ::client::storage::append_checksum(&mut content); // TODO
-
+                Phase::A(MaybeFragment::Fragment(content, compression)) => {
                     let lump_id = config::make_lump_id(&self.node_id, self.version);
+                    let content = track!(self.client.encode_lump(content, compression, lump_id))?;
+
                     debug!(
                         self.logger,
                         "Puts repaired content: version={:?}, lump_id={:?}, content_size={}",
@@ -380,6 +662,7 @@ impl Future for RepairContent {
                         content.len()
                     );
 
+                    self.written = content.len() as u64;
                     let data = track!(self.device.allocate_lump_data_with_bytes(&content))
                         .expect("TODO: error handling");
                     let future = self
@@ -387,14 +670,14 @@ impl Future for RepairContent {
                         .request()
                         .deadline(Deadline::Infinity)
                         .put(lump_id, data);
-                    Phase3::C(into_box_future(future))
+                    Phase::B(into_box_future(future))
                 }
-                Phase3::C(_) => {
+                Phase::B(_) => {
                     debug!(
                         self.logger,
                         "Completed repairing content: {:?}", self.version
                     );
-                    return Ok(Async::Ready(()));
+                    return Ok(Async::Ready(self.written));
                 }
             };
             self.phase = next;
@@ -5,6 +5,8 @@ pub mod tests {
     use cannyls::storage::StorageBuilder;
     use cannyls_rpc;
     use cannyls_rpc::DeviceRegistryHandle;
+    use client::compression::Compressor;
+    use client::encryption::Encryptor;
     use client::storage::StorageClient;
     use client::Client;
     use config::*;
@@ -13,6 +15,7 @@ pub mod tests {
     use fibers_global;
     use fibers_rpc::client::{ClientService, ClientServiceHandle};
     use fibers_rpc::server::ServerBuilder;
+    use frugalos_mds::schema::SharedVersioningPolicy;
     use frugalos_raft;
     use frugalos_raft::{LocalNodeId, NodeId};
     use futures;
@@ -20,7 +23,7 @@ pub mod tests {
     use futures::Async;
     use libfrugalos::entity::device::DeviceId;
     use slog;
-    use std::net::SocketAddr;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
     use std::thread;
     use std::time::Duration;
     use trackable::error::ErrorKindExt;
@@ -38,6 +41,17 @@ pub mod tests {
         }
     }
 
+    /// Creates a `NodeId` whose local ID ends with `n`.
+    ///
+    /// The address of the node is a dummy one, so the node cannot be accessed via RPC.
+    pub fn make_node_id(n: u8) -> NodeId {
+        NodeId {
+            local_id: LocalNodeId::new([0, 0, 0, 0, 0, 0, n]),
+            instance: 0,
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
+        }
+    }
+
     /// Adds `ClusterMember`s to the given cluster.
     /// この関数は特定のテストシナリオに合わせて作られているので、他のユースケースでは別の関数を作る方がよい。
     pub fn setup_system(
@@ -118,6 +132,7 @@ pub mod tests {
                 device_no: 0,
                 cluster_config: ClusterConfig {
                     members: Vec::new(),
+                    locality: Locality::default(),
                 },
             })
         }
@@ -146,6 +161,38 @@ pub mod tests {
             Ok(self.make_storage_client())
         }
 
+        /// Boots this cluster with `cluster_size` new nodes, and returns a client of the segment.
+        ///
+        /// Unlike `setup_system`, the MDSs of the nodes form a Raft cluster,
+        /// so the returned client can be used for operations that need the MDS.
+        pub fn boot_segment(&mut self, cluster_size: usize) -> Result<Client> {
+            let mut devices = Vec::new();
+            for _ in 0..cluster_size {
+                let node_id = self.make_node_id();
+                let (device_id, device_handle) = self.spawn_new_memory_device()?;
+                self.cluster_config.members.push(ClusterMember {
+                    node: node_id,
+                    device: device_id,
+                });
+                devices.push((node_id, device_handle));
+            }
+
+            for (node_id, device_handle) in devices {
+                self.service_handle.add_node(
+                    node_id,
+                    Box::new(futures::future::ok::<DeviceHandle, Error>(device_handle)),
+                    self.make_segment_client(),
+                    self.cluster_config
+                        .members
+                        .iter()
+                        .map(|m| m.node.to_raft_node_id())
+                        .collect(),
+                )?;
+            }
+
+            Ok(self.make_segment_client())
+        }
+
         /// Returns a new node.
         pub fn make_node(&mut self) -> Result<(NodeId, DeviceId, DeviceHandle)> {
             let node_id = self.make_node_id();
@@ -200,20 +247,26 @@ pub mod tests {
                     cluster: self.cluster_config.clone(),
                     storage: self.make_dispersed_storage(),
                     mds: MdsClientConfig::default(),
+                    checksum: ChecksumSelector::default(),
+                    compression: Compressor::new("test", Compression::None),
+                    encryption: Encryptor::default(),
+                    inline_threshold: 0,
+                    versioning: SharedVersioningPolicy::default(),
                 },
                 None,
+                None,
+                None,
             )
         }
 
         /// Creates a new `NodeId`.
         fn make_node_id(&mut self) -> NodeId {
-            let local_node_id = LocalNodeId::new([0, 0, 0, 0, 0, 0, self.node_seqno]);
-            self.node_seqno += 1;
-            NodeId {
-                local_id: local_node_id,
-                instance: 0,
+            let node_id = NodeId {
                 addr: self.rpc_server_addr,
-            }
+                ..make_node_id(self.node_seqno)
+            };
+            self.node_seqno += 1;
+            node_id
         }
 
         /// Creates a new StorageClient.
@@ -224,9 +277,16 @@ pub mod tests {
                     cluster: self.cluster_config.clone(),
                     storage: self.make_dispersed_storage(),
                     mds: MdsClientConfig::default(),
+                    checksum: ChecksumSelector::default(),
+                    compression: Compressor::new("test", Compression::None),
+                    encryption: Encryptor::default(),
+                    inline_threshold: 0,
+                    versioning: SharedVersioningPolicy::default(),
                 },
                 self.rpc_service_handle.clone(),
                 None,
+                None,
+                None,
             )
         }
 
@@ -236,6 +296,8 @@ pub mod tests {
             Storage::Dispersed(DispersedConfig {
                 tolerable_faults: 1,
                 fragments: self.fragments(),
+                write_quorum: WriteQuorum::default(),
+                hedge: HedgePolicy::default(),
             })
         }
     }
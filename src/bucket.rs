@@ -1,29 +1,47 @@
 #![allow(clippy::ptr_arg)]
 use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
-use frugalos_segment::config::{ClusterMember, MdsClientConfig};
+use frugalos_config::{
+    BucketOptions, ChecksumAlgorithm, Compression, HedgePolicy, VersioningPolicy, WriteQuorum,
+};
+use frugalos_segment::config::{ChecksumSelector, ClusterConfig, MdsClientConfig};
 use frugalos_segment::Client as Segment;
-use frugalos_segment::{self, ErasureCoder};
+use frugalos_segment::{
+    self, Compressor, Encryptor, ErasureCoder, HedgeMonitor, KeyProvider, ReadRepairer,
+    SharedVersioningPolicy,
+};
 use libfrugalos::entity::bucket::Bucket as BucketConfig;
 use libfrugalos::entity::object::ObjectId;
 use siphasher;
 use slog::Logger;
 use std::iter;
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct Bucket {
     logger: Logger,
     rpc_service: RpcServiceHandle,
     ec: Option<ErasureCoder>,
+    hedge_monitor: Option<HedgeMonitor>,
+    read_repairer: Option<ReadRepairer>,
     storage_config: frugalos_segment::config::Storage,
     mds_client_config: MdsClientConfig,
+    checksum: ChecksumSelector,
+    compressor: Compressor,
+    encryptor: Encryptor,
+    inline_threshold: usize,
+    versioning: SharedVersioningPolicy,
     segments: Vec<Segment>,
+    segment_clusters: Vec<ClusterConfig>,
 }
 impl Bucket {
     pub fn new(
         logger: Logger,
         rpc_service: RpcServiceHandle,
         config: &BucketConfig,
+        options: &BucketOptions,
         mds_client_config: MdsClientConfig,
+        read_repairer: Option<ReadRepairer>,
+        key_provider: Arc<KeyProvider>,
     ) -> Self {
         let ec = match config {
             BucketConfig::Metadata(_) => None,
@@ -33,12 +51,14 @@ impl Bucket {
                 c.tolerable_faults as usize,
             )),
         };
+        let hedge_monitor = ec.as_ref().map(|_| HedgeMonitor::new(config.id()));
 
         let storage_config = match config {
             BucketConfig::Metadata(_) => frugalos_segment::config::Storage::Metadata,
             BucketConfig::Replicated(ref b) => {
                 let c = frugalos_segment::config::ReplicatedConfig {
                     tolerable_faults: b.tolerable_faults as u8,
+                    write_quorum: to_segment_write_quorum(options.write_quorum),
                 };
                 frugalos_segment::config::Storage::Replicated(c)
             }
@@ -46,58 +66,163 @@ impl Bucket {
                 let c = frugalos_segment::config::DispersedConfig {
                     tolerable_faults: b.tolerable_faults as u8,
                     fragments: (b.tolerable_faults + b.data_fragment_count) as u8,
+                    write_quorum: to_segment_write_quorum(options.write_quorum),
+                    hedge: to_segment_hedge_policy(&options.hedge_policy),
                 };
                 frugalos_segment::config::Storage::Dispersed(c)
             }
         };
 
+        // NOTE: セグメントのクライアントと、それを元に生成されるノードの間で共有される
+        let checksum =
+            ChecksumSelector::new(to_segment_checksum_algorithm(options.checksum_algorithm));
+        let compressor = Compressor::new(config.id(), to_segment_compression(options.compression));
+        let encryptor = Encryptor::new(key_provider, options.encryption_key_id);
+        let versioning =
+            SharedVersioningPolicy::new(to_segment_versioning_policy(&options.versioning));
+
+        let cluster = ClusterConfig {
+            members: Vec::new(),
+            locality: Default::default(),
+        };
         let segment_config = frugalos_segment::config::ClientConfig {
-            cluster: frugalos_segment::config::ClusterConfig {
-                members: Vec::new(),
-            },
+            cluster: cluster.clone(),
             storage: storage_config.clone(),
             mds: mds_client_config.clone(),
+            checksum: checksum.clone(),
+            compression: compressor.clone(),
+            encryption: encryptor.clone(),
+            inline_threshold: options.inline_threshold as usize,
+            versioning: versioning.clone(),
         };
         let segment = Segment::new(
             logger.clone(),
             rpc_service.clone(),
             segment_config,
             ec.clone(),
+            hedge_monitor.clone(),
+            read_repairer.clone(),
         );
         let segments = iter::repeat(segment)
             .take(config.segment_count() as usize)
             .collect();
+        let segment_clusters = iter::repeat(cluster)
+            .take(config.segment_count() as usize)
+            .collect();
         Bucket {
             logger,
             rpc_service,
             ec,
+            hedge_monitor,
+            read_repairer,
             storage_config,
             segments,
+            segment_clusters,
             mds_client_config,
+            checksum,
+            compressor,
+            encryptor,
+            inline_threshold: options.inline_threshold as usize,
+            versioning,
         }
     }
-    pub fn update_segment(&mut self, segment_no: u16, members: Vec<ClusterMember>) {
+    pub fn set_options(&mut self, options: &BucketOptions) {
+        self.checksum
+            .set(to_segment_checksum_algorithm(options.checksum_algorithm));
+        self.compressor
+            .set(to_segment_compression(options.compression));
+        self.encryptor.set(options.encryption_key_id);
+        self.inline_threshold = options.inline_threshold as usize;
+        self.versioning
+            .set(to_segment_versioning_policy(&options.versioning));
+        match self.storage_config {
+            frugalos_segment::config::Storage::Metadata => return,
+            frugalos_segment::config::Storage::Replicated(ref mut c) => {
+                c.write_quorum = to_segment_write_quorum(options.write_quorum);
+            }
+            frugalos_segment::config::Storage::Dispersed(ref mut c) => {
+                c.write_quorum = to_segment_write_quorum(options.write_quorum);
+                c.hedge = to_segment_hedge_policy(&options.hedge_policy);
+            }
+        }
+        for segment_no in 0..self.segments.len() {
+            let cluster = self.segment_clusters[segment_no].clone();
+            self.update_segment(segment_no as u16, cluster);
+        }
+    }
+    pub fn update_segment(&mut self, segment_no: u16, cluster: ClusterConfig) {
+        self.segment_clusters[segment_no as usize] = cluster.clone();
         let segment_config = frugalos_segment::config::ClientConfig {
-            cluster: frugalos_segment::config::ClusterConfig { members },
+            cluster,
             storage: self.storage_config.clone(),
             mds: self.mds_client_config.clone(),
+            checksum: self.checksum.clone(),
+            compression: self.compressor.clone(),
+            encryption: self.encryptor.clone(),
+            inline_threshold: self.inline_threshold,
+            versioning: self.versioning.clone(),
         };
         let segment = Segment::new(
             self.logger.clone(),
             self.rpc_service.clone(),
             segment_config,
             self.ec.clone(),
+            self.hedge_monitor.clone(),
+            self.read_repairer.clone(),
         );
         self.segments[segment_no as usize] = segment;
     }
     pub fn get_segment(&self, id: &ObjectId) -> &Segment {
+        &self.segments[self.get_segment_no(id)]
+    }
+    pub fn get_segment_no(&self, id: &ObjectId) -> usize {
         use std::hash::{Hash, Hasher};
         let mut hasher = siphasher::sip::SipHasher13::new();
         id.hash(&mut hasher);
-        let i = hasher.finish() as usize % self.segments.len();
-        &self.segments[i]
+        hasher.finish() as usize % self.segments.len()
     }
     pub fn segments(&self) -> &[Segment] {
         &self.segments
     }
 }
+
+fn to_segment_write_quorum(write_quorum: WriteQuorum) -> frugalos_segment::config::WriteQuorum {
+    match write_quorum {
+        WriteQuorum::One => frugalos_segment::config::WriteQuorum::One,
+        WriteQuorum::Majority => frugalos_segment::config::WriteQuorum::Majority,
+        WriteQuorum::All => frugalos_segment::config::WriteQuorum::All,
+    }
+}
+
+fn to_segment_versioning_policy(policy: &VersioningPolicy) -> frugalos_segment::VersioningPolicy {
+    frugalos_segment::VersioningPolicy {
+        max_versions: policy.max_versions,
+        max_age: policy.max_age,
+    }
+}
+
+fn to_segment_checksum_algorithm(
+    algorithm: ChecksumAlgorithm,
+) -> frugalos_segment::config::ChecksumAlgorithm {
+    match algorithm {
+        ChecksumAlgorithm::Adler32 => frugalos_segment::config::ChecksumAlgorithm::Adler32,
+        ChecksumAlgorithm::Crc32c => frugalos_segment::config::ChecksumAlgorithm::Crc32c,
+        ChecksumAlgorithm::XxHash64 => frugalos_segment::config::ChecksumAlgorithm::XxHash64,
+    }
+}
+
+fn to_segment_compression(compression: Compression) -> frugalos_segment::config::Compression {
+    match compression {
+        Compression::None => frugalos_segment::config::Compression::None,
+        Compression::Zstd => frugalos_segment::config::Compression::Zstd,
+        Compression::Lz4 => frugalos_segment::config::Compression::Lz4,
+    }
+}
+
+fn to_segment_hedge_policy(policy: &HedgePolicy) -> frugalos_segment::config::HedgePolicy {
+    frugalos_segment::config::HedgePolicy {
+        extra_requests: policy.extra_requests,
+        delay_millis: policy.delay_millis,
+        delay_percentile: policy.delay_percentile,
+    }
+}
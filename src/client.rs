@@ -2,7 +2,12 @@
 #![allow(clippy::needless_pass_by_value)]
 use atomic_immut::AtomicImmut;
 use cannyls::deadline::Deadline;
-use frugalos_segment::ObjectValue;
+use frugalos_mds::schema::ObjectPage;
+use frugalos_segment::{
+    is_internal_object_id, BatchOperation, BatchResult, ByteRange, ContentStream, ObjectAttributes,
+    ObjectStream, ObjectValue, ObjectVersionEntry, PartialObjectValue, ReadConsistency, UploadId,
+    UserMetadata,
+};
 use futures::{self, Future};
 use libfrugalos::entity::bucket::BucketId;
 use libfrugalos::entity::object::{
@@ -33,6 +38,9 @@ impl FrugalosClient {
     pub fn request(&self, bucket_id: BucketId) -> Request {
         Request::new(self, bucket_id)
     }
+    pub fn bucket_ids(&self) -> Vec<BucketId> {
+        self.buckets.load().keys().cloned().collect()
+    }
     pub fn segment_count(&self, bucket_id: &BucketId) -> Option<u16> {
         self.buckets
             .load()
@@ -64,6 +72,8 @@ pub struct Request<'a> {
     bucket_id: BucketId,
     deadline: Deadline,
     expect: Expect,
+    attributes: ObjectAttributes,
+    consistency: ReadConsistency,
     parent: SpanHandle,
 }
 impl<'a> Request<'a> {
@@ -73,6 +83,8 @@ impl<'a> Request<'a> {
             bucket_id,
             deadline: Deadline::Within(Duration::from_millis(5000)),
             expect: Expect::Any,
+            attributes: ObjectAttributes::default(),
+            consistency: ReadConsistency::default(),
             parent: Span::inactive().handle(),
         }
     }
@@ -84,6 +96,18 @@ impl<'a> Request<'a> {
         self.expect = expect;
         self
     }
+    pub fn user_metadata(&mut self, user_metadata: UserMetadata) -> &mut Self {
+        self.attributes.user_metadata = user_metadata;
+        self
+    }
+    pub fn ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.attributes.ttl = Some(ttl.as_secs());
+        self
+    }
+    pub fn consistency(&mut self, consistency: ReadConsistency) -> &mut Self {
+        self.consistency = consistency;
+        self
+    }
     pub fn span(&mut self, span: &Span) -> &mut Self {
         self.parent = span.handle();
         self
@@ -95,6 +119,54 @@ impl<'a> Request<'a> {
         let future = segment.get(object_id, self.deadline, self.parent.clone());
         Box::new(future.map_err(|e| track!(Error::from(e))))
     }
+    pub fn get_stream(&self, object_id: ObjectId) -> BoxFuture<Option<ObjectStream>> {
+        let buckets = self.client.buckets.load();
+        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let segment = bucket.get_segment(&object_id);
+        let future = segment.get_stream(
+            object_id,
+            self.consistency,
+            self.deadline,
+            self.parent.clone(),
+        );
+        Box::new(future.map_err(|e| track!(Error::from(e))))
+    }
+    pub fn get_version_stream(
+        &self,
+        object_id: ObjectId,
+        version: ObjectVersion,
+    ) -> BoxFuture<Option<ObjectStream>> {
+        let buckets = self.client.buckets.load();
+        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let segment = bucket.get_segment(&object_id);
+        let future =
+            segment.get_version_stream(object_id, version, self.deadline, self.parent.clone());
+        Box::new(future.map_err(|e| track!(Error::from(e))))
+    }
+    pub fn list_versions(&self, object_id: ObjectId) -> BoxFuture<Vec<ObjectVersionEntry>> {
+        let buckets = self.client.buckets.load();
+        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let segment = bucket.get_segment(&object_id);
+        let future = segment.list_versions(object_id, self.parent.clone());
+        Box::new(future.map_err(|e| track!(Error::from(e))))
+    }
+    pub fn get_range(
+        &self,
+        object_id: ObjectId,
+        ranges: Vec<ByteRange>,
+    ) -> BoxFuture<Option<PartialObjectValue>> {
+        let buckets = self.client.buckets.load();
+        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let segment = bucket.get_segment(&object_id);
+        let future = segment.get_range(
+            object_id,
+            ranges,
+            self.consistency,
+            self.deadline,
+            self.parent.clone(),
+        );
+        Box::new(future.map_err(|e| track!(Error::from(e))))
+    }
     pub fn head(&self, object_id: ObjectId) -> BoxFuture<Option<ObjectVersion>> {
         let buckets = self.client.buckets.load();
         let bucket = try_get_bucket!(buckets, self.bucket_id);
@@ -102,6 +174,17 @@ impl<'a> Request<'a> {
         let future = segment.head(object_id, self.parent.clone());
         Box::new(future.map_err(|e| track!(Error::from(e))))
     }
+    pub fn head_with_user_metadata(
+        &self,
+        object_id: ObjectId,
+    ) -> BoxFuture<Option<(ObjectVersion, UserMetadata)>> {
+        let buckets = self.client.buckets.load();
+        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let segment = bucket.get_segment(&object_id);
+        let future =
+            segment.head_with_user_metadata(object_id, self.consistency, self.parent.clone());
+        Box::new(future.map_err(|e| track!(Error::from(e))))
+    }
     pub fn put(&self, object_id: ObjectId, content: Vec<u8>) -> BoxFuture<(ObjectVersion, bool)> {
         let buckets = self.client.buckets.load();
         let bucket = try_get_bucket!(buckets, self.bucket_id);
@@ -109,12 +192,237 @@ impl<'a> Request<'a> {
         let future = segment.put(
             object_id,
             content,
+            self.attributes.clone(),
+            self.deadline,
+            self.expect.clone(),
+            self.parent.clone(),
+        );
+        Box::new(future.map_err(|e| track!(Error::from(e))))
+    }
+    pub fn put_stream(
+        &self,
+        object_id: ObjectId,
+        content: ContentStream,
+    ) -> BoxFuture<(ObjectVersion, bool)> {
+        let buckets = self.client.buckets.load();
+        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let segment = bucket.get_segment(&object_id);
+        let future = segment.put_stream(
+            object_id,
+            content,
+            self.attributes.clone(),
+            self.deadline,
+            self.expect.clone(),
+            self.parent.clone(),
+        );
+        Box::new(future.map_err(|e| track!(Error::from(e))))
+    }
+    /// `source_bucket_id`バケツの`source_id`オブジェクトを、`object_id`として複製する。
+    ///
+    /// 中身はサーバ側で複製され、保存形式が同一であればlump単位でそのまま複製される。
+    /// 利用者定義のメタデータが指定されていない場合には、複製元のものが引き継がれる。
+    /// 複製元が存在しない場合には`None`が返される。
+    pub fn copy(
+        &self,
+        source_bucket_id: BucketId,
+        source_id: ObjectId,
+        object_id: ObjectId,
+    ) -> BoxFuture<Option<(ObjectVersion, bool)>> {
+        if source_bucket_id == self.bucket_id && source_id == object_id {
+            let e = ErrorKind::InvalidInput.cause(format!(
+                "The source and destination of a copy are identical: {:?}",
+                object_id
+            ));
+            return Box::new(futures::failed(e.into()));
+        }
+        let buckets = self.client.buckets.load();
+        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let source_bucket = try_get_bucket!(buckets, source_bucket_id);
+        let source = source_bucket.get_segment(&source_id);
+        let segment = bucket.get_segment(&object_id);
+        let future = segment.copy(
+            source,
+            source_id,
+            object_id,
+            self.attributes.clone(),
+            self.deadline,
+            self.expect.clone(),
+            self.parent.clone(),
+        );
+        Box::new(future.map_err(|e| track!(Error::from(e))))
+    }
+    /// バケツ内でオブジェクトのIDを`from`から`to`に(アトミックに)変更する。
+    ///
+    /// 両者が同じセグメントに属している場合にのみ変更可能で、そうではない場合にはエラーとなる。
+    /// 変更元が存在しない場合には`None`が返される。
+    pub fn rename(&self, from: ObjectId, to: ObjectId) -> BoxFuture<Option<(ObjectVersion, bool)>> {
+        let buckets = self.client.buckets.load();
+        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let segment_no = bucket.get_segment_no(&from);
+        if segment_no != bucket.get_segment_no(&to) {
+            let e = ErrorKind::InvalidInput.cause(format!(
+                "Objects {:?} and {:?} belong to different segments",
+                from, to
+            ));
+            return Box::new(futures::failed(e.into()));
+        }
+        let segment = &bucket.segments()[segment_no];
+        let future = segment.rename(
+            from,
+            to,
+            self.deadline,
+            self.expect.clone(),
+            self.parent.clone(),
+        );
+        Box::new(future.map_err(|e| track!(Error::from(e))))
+    }
+    /// 複数の操作をまとめて実行する。
+    ///
+    /// 操作群は対象オブジェクトのセグメント毎にまとめられ、セグメント単位で(MDSの一つの提案として)実行される。
+    /// 期待するバージョン等は操作毎に指定するので、`expect`や`user_metadata`の設定は使用されない。
+    /// 結果は操作毎に、指定された順番通りに返される。
+    pub fn batch(
+        &self,
+        operations: Vec<BatchOperation>,
+    ) -> BoxFuture<Vec<Result<BatchResult, Error>>> {
+        let buckets = self.client.buckets.load();
+        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let size = operations.len();
+
+        // セグメント毎に、操作群とそれらの元の位置をまとめる
+        let mut groups = Vec::new();
+        let mut group_indices = HashMap::new();
+        for (i, operation) in operations.into_iter().enumerate() {
+            let segment_no = bucket.get_segment_no(operation.object_id());
+            let next = groups.len();
+            let group = *group_indices.entry(segment_no).or_insert(next);
+            if group == next {
+                groups.push((&bucket.segments()[segment_no], Vec::new(), Vec::new()));
+            }
+            groups[group].1.push(i);
+            groups[group].2.push(operation);
+        }
+
+        let batches = groups
+            .into_iter()
+            .map(|(segment, positions, operations)| {
+                segment
+                    .batch(operations, self.deadline, self.parent.clone())
+                    .then(move |result| -> Result<_, Error> { Ok((positions, result)) })
+            })
+            .collect::<Vec<_>>();
+        let future = futures::future::join_all(batches).map(move |groups| {
+            let mut results = (0..size).map(|_| None).collect::<Vec<_>>();
+            for (positions, group_result) in groups {
+                match group_result {
+                    Err(e) => {
+                        // セグメント単位で失敗した場合には、そのセグメントの全ての操作が失敗となる
+                        let e = track!(Error::from(e));
+                        for i in positions {
+                            results[i] = Some(Err(e.clone()));
+                        }
+                    }
+                    Ok(group_results) => {
+                        for (i, result) in positions.into_iter().zip(group_results) {
+                            results[i] = Some(result.map_err(|e| track!(Error::from(e))));
+                        }
+                    }
+                }
+            }
+            results
+                .into_iter()
+                .map(|result| {
+                    result.unwrap_or_else(|| {
+                        Err(ErrorKind::Other.cause("No result for the operation").into())
+                    })
+                })
+                .collect()
+        });
+        Box::new(future)
+    }
+
+    /// 複数のオブジェクトに対する保存ないし削除をアトミックに実行する。
+    ///
+    /// 全ての対象オブジェクトは同一のセグメントに属している必要があり、そうでない場合には失敗する。
+    /// 一つでも`Expect`が満たされない操作があれば、どのオブジェクトも変更されない。
+    pub fn transaction(&self, operations: Vec<BatchOperation>) -> BoxFuture<Vec<BatchResult>> {
+        let buckets = self.client.buckets.load();
+        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let segment_no = match operations.first() {
+            None => {
+                let e = ErrorKind::InvalidInput.cause("Empty transaction");
+                return Box::new(futures::failed(e.into()));
+            }
+            Some(operation) => bucket.get_segment_no(operation.object_id()),
+        };
+        for operation in &operations[1..] {
+            if segment_no != bucket.get_segment_no(operation.object_id()) {
+                let e = ErrorKind::InvalidInput.cause(format!(
+                    "Objects {:?} and {:?} belong to different segments",
+                    operations[0].object_id(),
+                    operation.object_id()
+                ));
+                return Box::new(futures::failed(e.into()));
+            }
+        }
+        let segment = &bucket.segments()[segment_no];
+        let future = segment.transaction(operations, self.deadline, self.parent.clone());
+        Box::new(future.map_err(|e| track!(Error::from(e))))
+    }
+    pub fn initiate_upload(&self, object_id: ObjectId) -> BoxFuture<UploadId> {
+        let buckets = self.client.buckets.load();
+        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let segment = bucket.get_segment(&object_id);
+        let future = segment.initiate_upload(object_id, self.deadline, self.parent.clone());
+        Box::new(future.map_err(|e| track!(Error::from(e))))
+    }
+    pub fn upload_part(
+        &self,
+        object_id: ObjectId,
+        upload_id: UploadId,
+        part_number: u32,
+        content: Vec<u8>,
+    ) -> BoxFuture<Option<ObjectVersion>> {
+        let buckets = self.client.buckets.load();
+        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let segment = bucket.get_segment(&object_id);
+        let future = segment.upload_part(
+            object_id,
+            upload_id,
+            part_number,
+            content,
+            self.deadline,
+            self.parent.clone(),
+        );
+        Box::new(future.map_err(|e| track!(Error::from(e))))
+    }
+    pub fn complete_upload(
+        &self,
+        object_id: ObjectId,
+        upload_id: UploadId,
+        part_numbers: Vec<u32>,
+    ) -> BoxFuture<Option<(ObjectVersion, bool)>> {
+        let buckets = self.client.buckets.load();
+        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let segment = bucket.get_segment(&object_id);
+        let future = segment.complete_upload(
+            object_id,
+            upload_id,
+            part_numbers,
+            self.attributes.clone(),
             self.deadline,
             self.expect.clone(),
             self.parent.clone(),
         );
         Box::new(future.map_err(|e| track!(Error::from(e))))
     }
+    pub fn abort_upload(&self, object_id: ObjectId, upload_id: UploadId) -> BoxFuture<bool> {
+        let buckets = self.client.buckets.load();
+        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let segment = bucket.get_segment(&object_id);
+        let future = segment.abort_upload(object_id, upload_id, self.parent.clone());
+        Box::new(future.map_err(|e| track!(Error::from(e))))
+    }
     pub fn delete(&self, object_id: ObjectId) -> BoxFuture<Option<ObjectVersion>> {
         let buckets = self.client.buckets.load();
         let bucket = try_get_bucket!(buckets, self.bucket_id);
@@ -187,13 +495,42 @@ impl<'a> Request<'a> {
         let buckets = self.client.buckets.load();
         let bucket = try_get_bucket!(buckets, self.bucket_id);
         if segment < bucket.segments().len() {
-            let future = bucket.segments()[segment].list();
+            // マルチパートアップロードのパート等の内部用のオブジェクトは除外する
+            let future = bucket.segments()[segment].list().map(|list| {
+                list.into_iter()
+                    .filter(|o| !is_internal_object_id(&o.id))
+                    .collect()
+            });
             Box::new(future.map_err(|e| track!(Error::from(e))))
         } else {
             let e = ErrorKind::InvalidInput.cause(format!("Too large segment number: {}", segment));
             Box::new(futures::failed(e.into()))
         }
     }
+    /// バケツ全体のオブジェクト一覧を、IDの辞書順にページ単位で取得する。
+    ///
+    /// 各セグメントから最大`limit`個ずつ取得した結果をマージするので、
+    /// セグメント内の全オブジェクトを一度に取得することはない。
+    pub fn list_page(
+        &self,
+        prefix: ObjectPrefix,
+        start_after: Option<ObjectId>,
+        limit: usize,
+    ) -> BoxFuture<ObjectPage> {
+        let buckets = self.client.buckets.load();
+        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let futures = bucket
+            .segments()
+            .iter()
+            .map(|segment| {
+                segment
+                    .list_page(prefix.clone(), start_after.clone(), limit, self.consistency)
+                    .map_err(|e| track!(Error::from(e)))
+            })
+            .collect::<Vec<_>>();
+        let future = futures::future::join_all(futures).map(move |pages| merge_pages(pages, limit));
+        Box::new(future)
+    }
     pub fn latest(&self, segment: usize) -> BoxFuture<Option<ObjectSummary>> {
         let buckets = self.client.buckets.load();
         let bucket = try_get_bucket!(buckets, self.bucket_id);
@@ -217,3 +554,41 @@ impl<'a> Request<'a> {
         }
     }
 }
+
+// 各セグメントのページ群を、一つのページにまとめる
+//
+// 途中で打ち切られたセグメントについては、その`next`以前のオブジェクトしか取得できていないので、
+// それらの最小値以前のオブジェクトのみを結果に含める。
+fn merge_pages(pages: Vec<ObjectPage>, limit: usize) -> ObjectPage {
+    let boundary = pages.iter().filter_map(|p| p.next.clone()).min();
+    let mut objects = Vec::new();
+    let mut all_user_metadata = HashMap::new();
+    for page in pages {
+        objects.extend(
+            page.objects
+                .into_iter()
+                .filter(|o| boundary.as_ref().map_or(true, |b| o.id <= *b)),
+        );
+        all_user_metadata.extend(page.user_metadata);
+    }
+    objects.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let next = if objects.len() > limit {
+        objects.truncate(limit);
+        objects.last().map(|o| o.id.clone())
+    } else {
+        boundary
+    };
+
+    // マルチパートアップロードのパート等の内部用のオブジェクトは除外する
+    objects.retain(|o| !is_internal_object_id(&o.id));
+    let user_metadata = objects
+        .iter()
+        .filter_map(|o| all_user_metadata.remove(&o.id).map(|m| (o.id.clone(), m)))
+        .collect();
+    ObjectPage {
+        objects,
+        next,
+        user_metadata,
+    }
+}
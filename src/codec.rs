@@ -1,12 +1,25 @@
 use bytecodec::bytes::BytesEncoder;
+use bytecodec::combinator::MaxBytes;
 use bytecodec::io::IoEncodeExt;
-use bytecodec::json_codec::JsonEncoder;
-use bytecodec::{ByteCount, Encode, Eos, ErrorKind, Result};
+use bytecodec::json_codec::{JsonDecoder, JsonEncoder};
+use bytecodec::{ByteCount, Decode, DecodeExt, Encode, Eos, ErrorKind, Result};
 use fibers_tasque::{AsyncCall, DefaultCpuTaskQueue, TaskQueueExt};
-use futures::{Async, Future};
+use frugalos_segment::{self, ContentStream, CHUNK_SIZE};
+use futures::{Async, Future, Poll, Stream};
+use std::cmp;
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::marker::PhantomData;
+use std::mem;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use trackable::error::ErrorKindExt;
 
-use http::HttpResult;
+use http::{BatchRequest, HttpResult, ObjectBody};
+use server::MAX_BATCH_REQUEST_SIZE;
 use Error;
 
 #[derive(Debug, Default)]
@@ -116,3 +129,350 @@ impl Default for ObjectResultEncoder {
         ObjectResultEncoder::Ok(Default::default())
     }
 }
+
+/// `ObjectBody`を扱うためのエンコーダ。
+///
+/// ストリームの場合には、各チャンクは取得され次第エンコードされる(`Transfer-Encoding: chunked`)。
+/// なお、途中でストリームがエラーとなった場合には、エンコードエラーとして扱われ接続が切断される。
+pub enum ObjectBodyEncoder {
+    Bytes(BytesEncoder<Vec<u8>>),
+    Stream {
+        stream: ContentStream,
+        bytes: BytesEncoder<Vec<u8>>,
+    },
+    Err(JsonEncoder<Error>),
+}
+impl Encode for ObjectBodyEncoder {
+    type Item = HttpResult<ObjectBody>;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> Result<usize> {
+        let mut offset = 0;
+        let finished = match *self {
+            ObjectBodyEncoder::Bytes(ref mut e) => return track!(e.encode(buf, eos)),
+            ObjectBodyEncoder::Err(ref mut e) => return track!(e.encode(buf, eos)),
+            ObjectBodyEncoder::Stream {
+                ref mut stream,
+                ref mut bytes,
+            } => loop {
+                offset += track!(bytes.encode(&mut buf[offset..], eos))?;
+                if !bytes.is_idle() || offset == buf.len() {
+                    break false;
+                }
+                match stream.poll() {
+                    Err(e) => track_panic!(ErrorKind::Other, "{}", e),
+                    Ok(Async::NotReady) => break false,
+                    Ok(Async::Ready(None)) => break true,
+                    Ok(Async::Ready(Some(chunk))) => track!(bytes.start_encoding(chunk))?,
+                }
+            },
+        };
+        if finished {
+            *self = ObjectBodyEncoder::default();
+        }
+        Ok(offset)
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> Result<()> {
+        track_assert!(self.is_idle(), ErrorKind::EncoderFull);
+        match item {
+            HttpResult::Ok(ObjectBody::Bytes(v)) => {
+                let mut e = BytesEncoder::default();
+                track!(e.start_encoding(v))?;
+                *self = ObjectBodyEncoder::Bytes(e);
+            }
+            HttpResult::Ok(ObjectBody::Stream(stream)) => {
+                *self = ObjectBodyEncoder::Stream {
+                    stream,
+                    bytes: BytesEncoder::default(),
+                };
+            }
+            HttpResult::Err(v) => {
+                let mut e = JsonEncoder::default();
+                track!(e.start_encoding(v))?;
+                *self = ObjectBodyEncoder::Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_idle(&self) -> bool {
+        match *self {
+            ObjectBodyEncoder::Bytes(ref e) => e.is_idle(),
+            ObjectBodyEncoder::Stream { .. } => false,
+            ObjectBodyEncoder::Err(ref e) => e.is_idle(),
+        }
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        match *self {
+            ObjectBodyEncoder::Bytes(ref e) => e.requiring_bytes(),
+            ObjectBodyEncoder::Stream { .. } => ByteCount::Unknown,
+            ObjectBodyEncoder::Err(ref e) => e.requiring_bytes(),
+        }
+    }
+}
+impl Default for ObjectBodyEncoder {
+    fn default() -> Self {
+        ObjectBodyEncoder::Bytes(Default::default())
+    }
+}
+impl fmt::Debug for ObjectBodyEncoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ObjectBodyEncoder::Bytes(ref e) => write!(f, "Bytes({:?})", e),
+            ObjectBodyEncoder::Stream { ref bytes, .. } => write!(f, "Stream({:?})", bytes),
+            ObjectBodyEncoder::Err(ref e) => write!(f, "Err({:?})", e),
+        }
+    }
+}
+
+/// リクエストボディを`CHUNK_SIZE`毎のチャンク列としてデコードするためのデコーダ。
+///
+/// `fibers_http_server`はボディのデコードが完了してからハンドラを呼び出すので、
+/// ボディ全体をメモリ上に保持しないように、埋まったチャンクは到着次第一時ファイル(スプール)に書き出される。
+/// そのため、ボディのサイズに関わらず、メモリ上に保持されるのは高々一チャンク分となる。
+/// なお、ボディが`CHUNK_SIZE`未満の場合には、一時ファイルは作成されない。
+///
+/// 一時ファイルは、環境変数`FRUGALOS_SPOOL_DIR`で指定されたディレクトリ(デフォルトはOSの一時ディレクトリ)に作成される。
+///
+/// TODO: `fibers_http_server`がボディのデコード完了前にハンドラを呼び出せるようになったら、
+/// 到着したチャンクを一時ファイルを経由せずに即座に保存するようにする
+#[derive(Debug, Default)]
+pub struct ChunkedBytesDecoder {
+    buf: Vec<u8>,
+    spool: Option<Spool>,
+    eos: bool,
+}
+impl Decode for ChunkedBytesDecoder {
+    type Item = RequestBody;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> Result<usize> {
+        track_assert!(!self.eos, ErrorKind::DecoderTerminated);
+        let mut offset = 0;
+        while offset < buf.len() {
+            let size = cmp::min(CHUNK_SIZE - self.buf.len(), buf.len() - offset);
+            self.buf.extend_from_slice(&buf[offset..][..size]);
+            offset += size;
+            if self.buf.len() == CHUNK_SIZE {
+                if self.spool.is_none() {
+                    self.spool = Some(track!(Spool::create())?);
+                }
+                let spool = self.spool.as_mut().expect("Never fails");
+                track!(spool.write_chunk(&self.buf))?;
+                self.buf.clear();
+            }
+        }
+        if eos.is_reached() {
+            self.eos = true;
+        }
+        Ok(offset)
+    }
+
+    fn finish_decoding(&mut self) -> Result<Self::Item> {
+        track_assert!(self.eos, ErrorKind::IncompleteDecoding);
+        self.eos = false;
+        Ok(RequestBody {
+            spool: self.spool.take(),
+            tail: mem::replace(&mut self.buf, Vec::new()),
+        })
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        if self.eos {
+            ByteCount::Finite(0)
+        } else {
+            ByteCount::Infinite
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.eos
+    }
+}
+
+/// `ChunkedBytesDecoder`によってデコードされたリクエストボディ。
+#[derive(Debug)]
+pub struct RequestBody {
+    // 埋まったチャンク群 (ボディが`CHUNK_SIZE`未満の場合には`None`)
+    spool: Option<Spool>,
+
+    // 末尾の端数
+    tail: Vec<u8>,
+}
+impl RequestBody {
+    /// ボディのサイズ(バイト単位)を返す。
+    pub fn len(&self) -> u64 {
+        let spooled = self.spool.as_ref().map_or(0, |s| s.chunks) as u64;
+        spooled * CHUNK_SIZE as u64 + self.tail.len() as u64
+    }
+
+    /// ボディが空の場合には`true`を返す。
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// ボディを`CHUNK_SIZE`毎のチャンク列のストリームに変換する。
+    ///
+    /// 一時ファイルからの読み込みは`DefaultCpuTaskQueue`上で、一チャンクずつ行われる。
+    pub fn into_stream(self) -> ContentStream {
+        Box::new(RequestBodyStream {
+            spool: self.spool,
+            reading: None,
+            tail: Some(self.tail),
+        })
+    }
+}
+
+struct RequestBodyStream {
+    spool: Option<Spool>,
+    reading: Option<AsyncCall<io::Result<(Spool, Vec<u8>)>>>,
+    tail: Option<Vec<u8>>,
+}
+impl Stream for RequestBodyStream {
+    type Item = Vec<u8>;
+    type Error = frugalos_segment::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(mut reading) = self.reading.take() {
+            match reading.poll() {
+                Err(e) => {
+                    let e = frugalos_segment::ErrorKind::Other.cause(e);
+                    return Err(track!(frugalos_segment::Error::from(e)));
+                }
+                Ok(Async::NotReady) => {
+                    self.reading = Some(reading);
+                    return Ok(Async::NotReady);
+                }
+                Ok(Async::Ready(result)) => {
+                    let (spool, chunk) = track!(result.map_err(frugalos_segment::Error::from))?;
+                    self.spool = Some(spool);
+                    return Ok(Async::Ready(Some(chunk)));
+                }
+            }
+        }
+        if let Some(mut spool) = self.spool.take() {
+            if spool.chunks > 0 {
+                self.reading = Some(DefaultCpuTaskQueue.async_call(move || {
+                    let chunk = spool.read_chunk()?;
+                    Ok((spool, chunk))
+                }));
+                return self.poll();
+            }
+        }
+        Ok(Async::Ready(self.tail.take().filter(|t| !t.is_empty())))
+    }
+}
+
+// チャンク群を一時的に保持するためのファイル
+//
+// ファイルは作成直後に(パスが)削除されるので、プロセスが異常終了した場合でも残らない。
+#[derive(Debug)]
+struct Spool {
+    file: File,
+
+    // 書き込まれた(読み込み時には未読の)チャンクの数
+    chunks: usize,
+
+    // 読み込みを開始しているかどうか
+    reading: bool,
+}
+impl Spool {
+    fn create() -> Result<Self> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let dir = env::var_os("FRUGALOS_SPOOL_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(env::temp_dir);
+        let path = dir.join(format!(
+            "frugalos-spool-{}-{}",
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::SeqCst)
+        ));
+        let file = track!(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| ErrorKind::Other.cause(e)))?;
+        track!(fs::remove_file(&path).map_err(|e| ErrorKind::Other.cause(e)))?;
+        Ok(Spool {
+            file,
+            chunks: 0,
+            reading: false,
+        })
+    }
+
+    fn write_chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        track!(self
+            .file
+            .write_all(chunk)
+            .map_err(|e| ErrorKind::Other.cause(e)))?;
+        self.chunks += 1;
+        Ok(())
+    }
+
+    fn read_chunk(&mut self) -> io::Result<Vec<u8>> {
+        if !self.reading {
+            self.file.seek(SeekFrom::Start(0))?;
+            self.reading = true;
+        }
+        let mut chunk = vec![0; CHUNK_SIZE];
+        self.file.read_exact(&mut chunk)?;
+        self.chunks -= 1;
+        Ok(chunk)
+    }
+}
+
+/// バッチ操作(およびトランザクション)の要求ボディ用のデコーダ。
+///
+/// ボディのサイズが`MAX_BATCH_REQUEST_SIZE`を超える場合には、全体を読み込む前にデコードに失敗する。
+#[derive(Debug)]
+pub struct BatchRequestDecoder(MaxBytes<JsonDecoder<BatchRequest>>);
+impl Decode for BatchRequestDecoder {
+    type Item = BatchRequest;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> Result<usize> {
+        track!(self.0.decode(buf, eos))
+    }
+
+    fn finish_decoding(&mut self) -> Result<Self::Item> {
+        track!(self.0.finish_decoding())
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+impl Default for BatchRequestDecoder {
+    fn default() -> Self {
+        BatchRequestDecoder(JsonDecoder::default().max_bytes(MAX_BATCH_REQUEST_SIZE as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytecodec::DecodeExt;
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    #[test]
+    fn chunked_bytes_decoder_works() -> TestResult {
+        for &size in &[0, 10, CHUNK_SIZE, CHUNK_SIZE * 2 + 10] {
+            let content = (0..size).map(|i| i as u8).collect::<Vec<_>>();
+            let mut decoder = ChunkedBytesDecoder::default();
+            let body = track!(decoder.decode_from_bytes(&content))?;
+            assert_eq!(body.len(), size as u64);
+            assert_eq!(body.spool.is_some(), size >= CHUNK_SIZE);
+
+            let chunks = track!(body.into_stream().collect().wait())?;
+            assert!(chunks.iter().all(|c| c.len() <= CHUNK_SIZE));
+            assert_eq!(chunks.concat(), content);
+        }
+        Ok(())
+    }
+}
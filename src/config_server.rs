@@ -2,7 +2,11 @@ use bytecodec::json_codec::{JsonDecoder, JsonEncoder};
 use bytecodec::null::NullDecoder;
 use fibers_http_server::{HandleRequest, Reply, Req, ServerBuilder as HttpServerBuilder, Status};
 use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
-use futures::Future;
+use fibers_rpc::Call;
+use frugalos_config::schema::{GetBucketOptionsRpc, PutBucketOptionsRequest, PutBucketOptionsRpc};
+use frugalos_config::BucketOptions;
+use futures::future::Either;
+use futures::{self, Future};
 use httpcodec::{BodyDecoder, BodyEncoder};
 use libfrugalos::client::config::Client as ConfigRpcClient;
 use libfrugalos::entity::bucket::{Bucket, BucketSummary};
@@ -38,6 +42,8 @@ impl ConfigServer {
         track!(builder.add_handler(ListBuckets(self.clone())))?;
         track!(builder.add_handler(PutBucket(self.clone())))?;
         track!(builder.add_handler(GetBucket(self.clone())))?;
+        track!(builder.add_handler(GetBucketOptions(self.clone())))?;
+        track!(builder.add_handler(PutBucketOptions(self.clone())))?;
 
         Ok(())
     }
@@ -262,6 +268,90 @@ impl HandleRequest for GetBucket {
     }
 }
 
+struct GetBucketOptions(ConfigServer);
+impl HandleRequest for GetBucketOptions {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/buckets/*/options";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<BucketOptions>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_id(&req.url());
+        let future = GetBucketOptionsRpc::client(&self.0.rpc_service)
+            .call(self.0.local_addr, bucket_id)
+            .then(|result| {
+                let (status, body) = match result {
+                    Err(e) => (Status::InternalServerError, Err(track!(Error::from(e)))),
+                    Ok(Err(e)) => (Status::InternalServerError, Err(track!(Error::from(e)))),
+                    Ok(Ok(None)) => (Status::NotFound, Err(track!(not_found()))),
+                    Ok(Ok(Some(options))) => (Status::Ok, Ok(options)),
+                };
+                Ok(make_json_response(status, body))
+            });
+        Box::new(future)
+    }
+}
+
+struct PutBucketOptions(ConfigServer);
+impl HandleRequest for PutBucketOptions {
+    const METHOD: &'static str = "PUT";
+    const PATH: &'static str = "/v1/buckets/*/options";
+
+    type ReqBody = BucketOptions;
+    type ResBody = HttpResult<BucketOptions>;
+    type Decoder = BodyDecoder<JsonDecoder<Self::ReqBody>>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_id(&req.url());
+        let request = PutBucketOptionsRequest {
+            bucket_id: bucket_id.clone(),
+            options: req.into_body(),
+        };
+        let server = self.0.clone();
+
+        // 存在しないバケツの場合には、リーダに問い合わせる前に`404`を返す
+        let future = self
+            .0
+            .client()
+            .get_bucket(bucket_id)
+            .map_err(Error::from)
+            .and_then(move |bucket| {
+                if bucket.is_none() {
+                    return Either::A(futures::finished(None));
+                }
+                let rpc_service = server.rpc_service.clone();
+                let future =
+                    server
+                        .client()
+                        .get_leader()
+                        .map_err(Error::from)
+                        .and_then(move |leader| {
+                            PutBucketOptionsRpc::client(&rpc_service)
+                                .call(leader, request)
+                                .map_err(Error::from)
+                                .and_then(|result| result.map_err(Error::from))
+                                .map(Some)
+                        });
+                Either::B(future)
+            })
+            .then(|result| {
+                let (status, body) = match track!(result) {
+                    Err(e) => (Status::InternalServerError, Err(e)),
+                    Ok(None) => (Status::NotFound, Err(track!(not_found()))),
+                    Ok(Some(options)) => (Status::Ok, Ok(options)),
+                };
+                Ok(make_json_response(status, body))
+            });
+        Box::new(future)
+    }
+}
+
 fn get_id(url: &Url) -> String {
     url.path_segments()
         .expect("Never fails")
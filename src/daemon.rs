@@ -48,6 +48,11 @@ pub struct FrugalosDaemonBuilder {
 
     /// `MdsClient` に与える Configuration。
     pub mds_client_config: frugalos_segment::config::MdsClientConfig,
+
+    /// S3互換APIを提供するHTTPサーバのアドレス。
+    ///
+    /// `None`の場合には、S3互換APIは提供されない。
+    pub s3_http_addr: Option<SocketAddr>,
 }
 impl FrugalosDaemonBuilder {
     /// 新しい`FrugalosDaemonBuilder`インスタンスを生成する。
@@ -58,6 +63,7 @@ impl FrugalosDaemonBuilder {
             sampling_rate: 0.001,
             rpc_client_channel_options: Default::default(),
             mds_client_config: Default::default(),
+            s3_http_addr: None,
         }
     }
 
@@ -75,6 +81,7 @@ impl FrugalosDaemonBuilder {
 pub struct FrugalosDaemon {
     service: service::Service<ThreadPoolExecutorHandle>,
     http_server_builder: HttpServerBuilder,
+    s3_http_server_builder: Option<HttpServerBuilder>,
     rpc_server_builder: RpcServerBuilder,
     rpc_service: RpcService,
     executor: ThreadPoolExecutor,
@@ -144,7 +151,21 @@ impl FrugalosDaemon {
             &mut rpc_server_builder,
         );
 
-        let server = Server::new(logger.clone(), client, tracer);
+        let server = Server::new(
+            logger.clone(),
+            client,
+            service.scrubbers(),
+            service.repair_controller(),
+            tracer,
+        );
+        let s3_http_server_builder = if let Some(s3_http_addr) = builder.s3_http_addr {
+            let mut s3_http_server_builder = HttpServerBuilder::new(s3_http_addr);
+            s3_http_server_builder.logger(logger.clone());
+            track!(server.register_s3(&mut s3_http_server_builder))?;
+            Some(s3_http_server_builder)
+        } else {
+            None
+        };
         track!(server.register(&mut http_server_builder))?;
 
         track!(http_server_builder.add_handler(WithMetrics::new(MetricsHandler)))?;
@@ -155,6 +176,7 @@ impl FrugalosDaemon {
         Ok(FrugalosDaemon {
             service,
             http_server_builder,
+            s3_http_server_builder,
             rpc_server_builder,
             rpc_service,
             executor,
@@ -195,10 +217,14 @@ impl FrugalosDaemon {
     pub fn run(mut self) -> Result<()> {
         track!(self.register_prometheus_metrics())?;
 
+        let executor_handle = self.executor.handle();
         let runner = DaemonRunner {
             service: self.service,
             rpc_server: self.rpc_server_builder.finish(self.executor.handle()),
             http_server: self.http_server_builder.finish(self.executor.handle()),
+            s3_http_server: self
+                .s3_http_server_builder
+                .map(|b| b.finish(executor_handle)),
             rpc_service: self.rpc_service,
             command_rx: self.command_rx,
             stop_notifications: Vec::new(),
@@ -213,6 +239,7 @@ impl FrugalosDaemon {
 struct DaemonRunner {
     service: service::Service<ThreadPoolExecutorHandle>,
     http_server: HttpServer,
+    s3_http_server: Option<HttpServer>,
     rpc_server: fibers_rpc::server::Server<ThreadPoolExecutorHandle>,
     rpc_service: fibers_rpc::client::ClientService,
     command_rx: mpsc::Receiver<DaemonCommand>,
@@ -238,6 +265,9 @@ impl Future for DaemonRunner {
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         track!(self.http_server.poll())?;
+        if let Some(ref mut s3_http_server) = self.s3_http_server {
+            track!(s3_http_server.poll())?;
+        }
         track!(self.rpc_server.poll())?;
         track!(self.rpc_service.poll())?;
         let ready = track!(self.service.poll())?.is_ready();
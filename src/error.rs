@@ -24,10 +24,12 @@ impl From<io::Error> for Error {
 }
 impl From<frugalos_segment::Error> for Error {
     fn from(f: frugalos_segment::Error) -> Self {
-        if let frugalos_segment::ErrorKind::UnexpectedVersion { current } = *f.kind() {
-            ErrorKind::Unexpected(current).takes_over(f).into()
-        } else {
-            ErrorKind::Other.takes_over(f).into()
+        match *f.kind() {
+            frugalos_segment::ErrorKind::UnexpectedVersion { current } => {
+                ErrorKind::Unexpected(current).takes_over(f).into()
+            }
+            frugalos_segment::ErrorKind::Invalid => ErrorKind::InvalidInput.takes_over(f).into(),
+            _ => ErrorKind::Other.takes_over(f).into(),
         }
     }
 }
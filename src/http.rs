@@ -1,11 +1,39 @@
 use fibers_http_server::{Res, Status};
+use frugalos_segment::{ContentStream, PartialObjectValue, UserMetadata};
+use futures::{self, Future, Stream};
 use httpcodec::{Header, HeaderField, HeaderFields};
-use libfrugalos::entity::object::ObjectVersion;
+use libfrugalos::entity::object::{ObjectSummary, ObjectVersion};
 use rustracing::carrier::IterHttpHeaderFields;
+use std::fmt;
 use trackable::error::ErrorKindExt;
 
 use {Error, ErrorKind, Result};
 
+/// 利用者定義のメタデータとして扱われるヘッダの接頭辞。
+pub const USER_METADATA_PREFIX: &str = "x-frugalos-meta-";
+
+/// 利用者定義のメタデータの最大サイズ(ヘッダ名と値のバイト数の合計)。
+pub const MAX_USER_METADATA_SIZE: usize = 2048;
+
+pub const CONTENT_TYPE: &str = "content-type";
+
+/// ゲートウェイがオブジェクトの保存時に記録するメタデータの接頭辞。
+///
+/// 利用者が指定することはできず、レスポンスヘッダにもそのままの名前では含まれない。
+pub const INTERNAL_METADATA_PREFIX: &str = "x-frugalos-internal-";
+
+/// 保存時のオブジェクトのサイズ(バイト数)を保持するメタデータの名前。
+pub const CONTENT_LENGTH_METADATA: &str = "x-frugalos-internal-content-length";
+
+/// 保存時刻(UNIXエポックからの秒数)を保持するメタデータの名前。
+pub const LAST_MODIFIED_METADATA: &str = "x-frugalos-internal-last-modified";
+
+/// サーバ側で複製する際の、複製元のオブジェクトを指定するヘッダ(`/{bucket_id}/{object_id}`形式)。
+pub const COPY_SOURCE_HEADER: &str = "x-frugalos-copy-source";
+
+/// 名前を変更する際の、変更元のオブジェクトを指定するヘッダ(`/{bucket_id}/{object_id}`形式)。
+pub const RENAME_SOURCE_HEADER: &str = "x-frugalos-rename-source";
+
 #[derive(Debug)]
 pub struct TraceHeader<'a>(pub Header<'a>);
 impl<'a> IterHttpHeaderFields<'a> for TraceHeader<'a> {
@@ -42,6 +70,14 @@ impl From<ContentTypeOctetStream> for HeaderField<'static, 'static> {
     }
 }
 
+#[derive(Debug)]
+pub struct AcceptRangesBytes;
+impl From<AcceptRangesBytes> for HeaderField<'static, 'static> {
+    fn from(_: AcceptRangesBytes) -> Self {
+        unsafe { HeaderField::new_unchecked("Accept-Ranges", "bytes") }
+    }
+}
+
 #[derive(Debug, Serialize)]
 #[serde(untagged)]
 pub enum HttpResult<T> {
@@ -59,11 +95,90 @@ pub fn make_json_response<T>(status: Status, body: Result<T>) -> Res<HttpResult<
     res
 }
 
+/// オブジェクトの中身を表すレスポンスボディ。
+pub enum ObjectBody {
+    /// メモリ上に全て保持されている中身。
+    Bytes(Vec<u8>),
+
+    /// チャンク単位で逐次取得される中身。
+    Stream(ContentStream),
+}
+impl ObjectBody {
+    /// ストリームから`ObjectBody`を生成する。
+    ///
+    /// ストリームの要素(チャンク)が一つだけの場合には、`Content-Length`を付与可能なように`Bytes`に変換する。
+    pub fn from_stream(stream: ContentStream) -> impl Future<Item = Self, Error = Error> {
+        stream
+            .into_future()
+            .and_then(|(first, rest)| {
+                rest.into_future()
+                    .map(move |(second, rest)| (first, second, rest))
+            })
+            .map(|(first, second, rest)| {
+                if let Some(second) = second {
+                    let first = first.expect("Never fails");
+                    let stream = futures::stream::iter_ok(vec![first, second]).chain(rest);
+                    ObjectBody::Stream(Box::new(stream))
+                } else {
+                    ObjectBody::Bytes(first.unwrap_or_default())
+                }
+            })
+            .map_err(|(e, _)| track!(Error::from(e)))
+    }
+}
+impl fmt::Debug for ObjectBody {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ObjectBody::Bytes(ref b) => write!(f, "Bytes({} bytes)", b.len()),
+            ObjectBody::Stream(_) => write!(f, "Stream(_)"),
+        }
+    }
+}
+
 pub fn make_object_response(
     status: Status,
     version: Option<ObjectVersion>,
     body: Result<Vec<u8>>,
 ) -> Res<HttpResult<Vec<u8>>> {
+    make_object_response_with_body(status, version, &UserMetadata::new(), body)
+}
+
+pub fn make_object_stream_response(
+    status: Status,
+    version: Option<ObjectVersion>,
+    body: Result<ObjectBody>,
+) -> Res<HttpResult<ObjectBody>> {
+    make_object_response_with_body(status, version, &UserMetadata::new(), body)
+}
+
+/// 利用者定義のメタデータをヘッダとして含むレスポンスを生成する。
+///
+/// メタデータに`content-type`が含まれる場合には、それがレスポンスの`Content-Type`となる。
+pub fn make_object_response_with_user_metadata(
+    status: Status,
+    version: Option<ObjectVersion>,
+    user_metadata: &UserMetadata,
+    body: Result<Vec<u8>>,
+) -> Res<HttpResult<Vec<u8>>> {
+    make_object_response_with_body(status, version, user_metadata, body)
+}
+
+/// `make_object_response_with_user_metadata`のストリーム版。
+pub fn make_object_stream_response_with_user_metadata(
+    status: Status,
+    version: Option<ObjectVersion>,
+    user_metadata: &UserMetadata,
+    body: Result<ObjectBody>,
+) -> Res<HttpResult<ObjectBody>> {
+    make_object_response_with_body(status, version, user_metadata, body)
+}
+
+fn make_object_response_with_body<T>(
+    status: Status,
+    version: Option<ObjectVersion>,
+    user_metadata: &UserMetadata,
+    body: Result<T>,
+) -> Res<HttpResult<T>> {
     let mut res = match body {
         Err(e) => {
             let mut res = Res::new(status, HttpResult::Err(e));
@@ -72,7 +187,13 @@ pub fn make_object_response(
         }
         Ok(content) => {
             let mut res = Res::new(status, HttpResult::Ok(content));
-            res.header_mut().add_field(ContentTypeOctetStream);
+            if let Some(content_type) = user_metadata.get(CONTENT_TYPE) {
+                res.header_mut()
+                    .add_field(unsafe { HeaderField::new_unchecked("Content-Type", content_type) });
+            } else {
+                res.header_mut().add_field(ContentTypeOctetStream);
+            }
+            add_user_metadata_fields(&mut res, user_metadata);
             res
         }
     };
@@ -84,6 +205,112 @@ pub fn make_object_response(
     res
 }
 
+/// 範囲指定GETに対するレスポンスを生成する。
+///
+/// 範囲が一つの場合には`Content-Range`ヘッダ付きで、
+/// 複数の場合には`multipart/byteranges`形式でボディを返す。
+/// 充足可能な範囲が一つもない場合は`416 Range Not Satisfiable`となる。
+pub fn make_partial_object_response(object: PartialObjectValue) -> Res<HttpResult<ObjectBody>> {
+    let PartialObjectValue {
+        version,
+        size,
+        user_metadata,
+        mut parts,
+    } = object;
+    let content_type = user_metadata
+        .get(CONTENT_TYPE)
+        .map_or("application/octet-stream", |t| t.as_str());
+    let mut res = if parts.is_empty() {
+        let e = ErrorKind::InvalidInput
+            .cause("Range Not Satisfiable")
+            .into();
+        let mut res = Res::new(Status::RangeNotSatisfiable, HttpResult::Err(e));
+        res.header_mut().add_field(ContentTypeJson);
+        res.header_mut().add_field(unsafe {
+            HeaderField::new_unchecked("Content-Range", &format!("bytes */{}", size))
+        });
+        res
+    } else if parts.len() == 1 {
+        let (range, content) = parts.pop().expect("Never fails");
+        let mut res = Res::new(
+            Status::PartialContent,
+            HttpResult::Ok(ObjectBody::Bytes(content)),
+        );
+        res.header_mut()
+            .add_field(unsafe { HeaderField::new_unchecked("Content-Type", content_type) });
+        res.header_mut().add_field(unsafe {
+            HeaderField::new_unchecked(
+                "Content-Range",
+                &format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+            )
+        });
+        res
+    } else {
+        let boundary = format!("frugalos_byteranges_{:x}", version.0);
+        let mut body = Vec::new();
+        for (range, content) in parts {
+            body.extend_from_slice(
+                format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary,
+                    content_type,
+                    range.start,
+                    range.end - 1,
+                    size
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(&content);
+        }
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+        let mut res = Res::new(
+            Status::PartialContent,
+            HttpResult::Ok(ObjectBody::Bytes(body)),
+        );
+        res.header_mut().add_field(unsafe {
+            HeaderField::new_unchecked(
+                "Content-Type",
+                &format!("multipart/byteranges; boundary={}", boundary),
+            )
+        });
+        res
+    };
+    res.header_mut().add_field(AcceptRangesBytes);
+    res.header_mut()
+        .add_field(unsafe { HeaderField::new_unchecked("ETag", &format!("\"{:x}\"", version.0)) });
+    add_user_metadata_fields(&mut res, &user_metadata);
+    res
+}
+
+/// 利用者定義のメタデータの接頭辞を`prefix`に置き換える。
+pub fn rename_user_metadata(user_metadata: UserMetadata, prefix: &str) -> UserMetadata {
+    user_metadata
+        .into_iter()
+        .map(|(name, value)| {
+            let name = if name.starts_with(USER_METADATA_PREFIX) {
+                format!("{}{}", prefix, &name[USER_METADATA_PREFIX.len()..])
+            } else {
+                name
+            };
+            (name, value)
+        })
+        .collect()
+}
+
+// `Content-Type`とゲートウェイが記録したもの以外のメタデータを、そのままの名前でレスポンスヘッダに追加する
+fn add_user_metadata_fields<T>(res: &mut Res<T>, user_metadata: &UserMetadata) {
+    for (name, value) in user_metadata {
+        if name == CONTENT_TYPE || name.starts_with(INTERNAL_METADATA_PREFIX) {
+            continue;
+        }
+
+        // NOTE: メタデータはリクエストヘッダから取り出されたものなので、名前と値は妥当なことが保証されている
+        res.header_mut()
+            .add_field(unsafe { HeaderField::new_unchecked(name, value) });
+    }
+}
+
 pub fn not_found() -> Error {
     ErrorKind::Other.cause("Not Found").into()
 }
@@ -93,3 +320,208 @@ pub struct BucketStatistics {
     /// バケツ内のオブジェクト数.
     pub objects: u64,
 }
+
+/// バケツ単位でのオブジェクト一覧の取得結果。
+#[derive(Debug, Serialize)]
+pub struct ObjectList {
+    /// IDの辞書順に並んだオブジェクト一覧.
+    pub objects: Vec<ObjectSummary>,
+
+    /// 続きが存在する場合に、次のページの取得時に指定すべき継続トークン.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continuation_token: Option<String>,
+}
+
+/// 次のページの先頭位置を示す継続トークンを作成する。
+///
+/// トークンの中身は、直前のページの最後のIDを16進数表記したもの。
+pub fn encode_continuation_token(start_after: &str) -> String {
+    encode_hex(start_after.as_bytes())
+}
+
+/// `encode_continuation_token`で作成された継続トークンを復元する。
+pub fn decode_continuation_token(token: &str) -> Result<String> {
+    let bytes = track!(
+        decode_hex(token),
+        "Malformed continuation token: {:?}",
+        token
+    )?;
+    let start_after =
+        track!(String::from_utf8(bytes).map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+    Ok(start_after)
+}
+
+/// バイト列を16進数表記の文字列に変換する。
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// 16進数表記の文字列をバイト列に復元する。
+pub fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    track_assert!(
+        s.is_ascii() && s.len() % 2 == 0,
+        ErrorKind::InvalidInput,
+        "Malformed hex string: {:?}",
+        s
+    );
+    let bytes = track!((0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+        .collect::<::std::result::Result<Vec<u8>, _>>()
+        .map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+    Ok(bytes)
+}
+
+/// マルチパートアップロードの開始結果。
+#[derive(Debug, Serialize)]
+pub struct MultipartUpload {
+    /// アップロードのID.
+    pub upload_id: String,
+}
+
+/// マルチパートアップロードの完了要求。
+#[derive(Debug, Deserialize)]
+pub struct CompleteUploadRequest {
+    /// オブジェクトを構成するパート番号の一覧 (この順番に連結される).
+    pub parts: Vec<u32>,
+}
+
+/// バッチ操作の要求。
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    /// 実行する操作群 (同一セグメント内の操作は、この順番に適用される).
+    pub operations: Vec<BatchRequestOperation>,
+}
+
+/// バッチ操作の要求内の個々の操作。
+#[derive(Debug, Deserialize)]
+pub struct BatchRequestOperation {
+    /// 操作の種類.
+    pub op: BatchOperationKind,
+
+    /// 対象のオブジェクトのID.
+    pub object_id: String,
+
+    /// 保存するオブジェクトの中身 (16進数表記、`put`の場合のみ).
+    pub content: Option<String>,
+
+    /// オブジェクトの有効期限 (保存時点からの秒数、`put`の場合のみ).
+    pub ttl: Option<u64>,
+
+    /// `If-Match`ヘッダと同じ形式の、期待するバージョン.
+    pub if_match: Option<String>,
+
+    /// `If-None-Match`ヘッダと同じ形式の、期待しないバージョン.
+    pub if_none_match: Option<String>,
+}
+
+/// バッチ操作内の操作の種類。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchOperationKind {
+    Get,
+    Head,
+    Put,
+    Delete,
+}
+
+/// バッチ操作の結果。
+#[derive(Debug, Serialize)]
+pub struct BatchResponse {
+    /// 要求内の操作と同じ順番に並んだ、操作毎の結果.
+    pub results: Vec<BatchResponseItem>,
+}
+
+/// バッチ操作内の個々の操作の結果。
+#[derive(Debug, Default, Serialize)]
+pub struct BatchResponseItem {
+    /// 操作を単独で実行した場合に返されるHTTPステータスコード.
+    pub status: u16,
+
+    /// オブジェクトのバージョン.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+
+    /// オブジェクトの中身 (16進数表記、`get`の場合のみ).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+
+    /// 利用者定義のメタデータ (`get`と`head`の場合で、空ではない場合のみ).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<UserMetadata>,
+
+    /// 操作が失敗した場合のエラー.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<Error>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial_object(parts: Vec<(::std::ops::Range<u64>, Vec<u8>)>) -> PartialObjectValue {
+        PartialObjectValue {
+            version: ObjectVersion(10),
+            size: 100,
+            user_metadata: UserMetadata::new(),
+            parts,
+        }
+    }
+
+    #[test]
+    fn unsatisfiable_range_response_works() {
+        let res = make_partial_object_response(partial_object(Vec::new())).into_inner();
+        assert_eq!(res.status_code().as_u16(), 416);
+        assert_eq!(res.header().get_field("Content-Range"), Some("bytes */100"));
+        assert_eq!(res.header().get_field("ETag"), Some("\"a\""));
+    }
+
+    #[test]
+    fn single_range_response_works() {
+        let res = make_partial_object_response(partial_object(vec![(10..15, b"hello".to_vec())]))
+            .into_inner();
+        assert_eq!(res.status_code().as_u16(), 206);
+        assert_eq!(
+            res.header().get_field("Content-Range"),
+            Some("bytes 10-14/100")
+        );
+        assert_eq!(
+            res.header().get_field("Content-Type"),
+            Some("application/octet-stream")
+        );
+        match *res.body() {
+            HttpResult::Ok(ObjectBody::Bytes(ref content)) => assert_eq!(content, b"hello"),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn multiple_ranges_response_works() {
+        let res = make_partial_object_response(partial_object(vec![
+            (10..12, b"ab".to_vec()),
+            (0..1, b"c".to_vec()),
+        ]))
+        .into_inner();
+        assert_eq!(res.status_code().as_u16(), 206);
+        assert_eq!(
+            res.header().get_field("Content-Type"),
+            Some("multipart/byteranges; boundary=frugalos_byteranges_a")
+        );
+        assert_eq!(res.header().get_field("Content-Range"), None);
+        match *res.body() {
+            HttpResult::Ok(ObjectBody::Bytes(ref body)) => {
+                let expected = concat!(
+                    "\r\n--frugalos_byteranges_a\r\n",
+                    "Content-Type: application/octet-stream\r\n",
+                    "Content-Range: bytes 10-11/100\r\n\r\nab",
+                    "\r\n--frugalos_byteranges_a\r\n",
+                    "Content-Type: application/octet-stream\r\n",
+                    "Content-Range: bytes 0-0/100\r\n\r\nc",
+                    "\r\n--frugalos_byteranges_a--\r\n"
+                );
+                assert_eq!(body.as_slice(), expected.as_bytes());
+            }
+            _ => panic!(),
+        }
+    }
+}
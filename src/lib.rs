@@ -48,7 +48,9 @@ mod codec;
 mod config_server;
 mod error;
 mod http;
+mod multipart;
 mod rpc_server;
+mod s3;
 mod server;
 mod service;
 
@@ -68,6 +68,12 @@ fn main() {
                         .takes_value(true)
                         .default_value("0.0.0.0:3000"),
                 )
+                .arg(
+                    Arg::with_name("S3_SERVER_BIND_ADDR")
+                        .long("s3-server-bind-addr")
+                        .takes_value(true)
+                        .help("Enables the S3 compatible API on the given address"),
+                )
                 .arg(
                     Arg::with_name("RPC_CONNECT_TIMEOUT_MILLIS")
                         .long("rpc-connect-timeout-millis")
@@ -215,6 +221,10 @@ fn main() {
         daemon.mds_client_config =
             track_try_unwrap!(track_any_err!(get_mds_client_config(&matches)));
 
+        if let Some(s3_addr) = matches.value_of("S3_SERVER_BIND_ADDR") {
+            let s3_addr: SocketAddr = track_try_unwrap!(track_any_err!(s3_addr.parse()));
+            daemon.s3_http_addr = Some(s3_addr);
+        }
         if let Some(threads) = matches.value_of("EXECUTOR_THREADS") {
             let threads: usize = track_try_unwrap!(track_any_err!(threads.parse()));
             daemon.executor_threads = threads;
@@ -0,0 +1,142 @@
+//! 放置されたマルチパートアップロードの後始末を行うための構成要素。
+use atomic_immut::AtomicImmut;
+use fibers::time::timer::{self, Timeout};
+use frugalos_segment::Client as Segment;
+use futures::{self, Async, Future, Poll, Stream};
+use libfrugalos::entity::bucket::BucketId;
+use prometrics::metrics::{Counter, CounterBuilder};
+use rustracing_jaeger::Span;
+use slog::Logger;
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
+
+use bucket::Bucket;
+use {Error, ErrorKind, Result};
+
+type BoxFuture<T> = Box<Future<Item = T, Error = Error> + Send + 'static>;
+
+/// 完了済み、あるいは期限切れとなったマルチパートアップロードを定期的に削除する.
+///
+/// 削除対象となるのは、このサーバが担当するセグメント群のみ。
+pub struct UploadCollector {
+    logger: Logger,
+    buckets: Arc<AtomicImmut<HashMap<BucketId, Bucket>>>,
+    targets: BTreeSet<(BucketId, u16)>,
+    interval: Duration,
+    expiration_secs: u64,
+    timeout: Timeout,
+    future: Option<BoxFuture<u64>>,
+    collected_uploads: Counter,
+}
+impl UploadCollector {
+    pub fn new(
+        logger: Logger,
+        buckets: Arc<AtomicImmut<HashMap<BucketId, Bucket>>>,
+    ) -> Result<Self> {
+        // TODO: 正式な口を用意する
+        let interval = env::var("FRUGALOS_MULTIPART_GC_INTERVAL")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(60 * 60);
+        let expiration_secs = env::var("FRUGALOS_MULTIPART_UPLOAD_EXPIRATION")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(24 * 60 * 60);
+        info!(
+            logger,
+            "Multipart upload GC: interval={}s, expiration={}s", interval, expiration_secs
+        );
+
+        let collected_uploads = track!(CounterBuilder::new("collected_uploads_total")
+            .namespace("frugalos")
+            .subsystem("multipart")
+            .default_registry()
+            .finish())?;
+        let interval = Duration::from_secs(interval);
+        Ok(UploadCollector {
+            logger,
+            buckets,
+            targets: BTreeSet::new(),
+            interval,
+            expiration_secs,
+            timeout: timer::timeout(interval),
+            future: None,
+            collected_uploads,
+        })
+    }
+
+    /// 後始末の対象となるセグメントを追加する.
+    pub fn add_target(&mut self, bucket_id: BucketId, segment_no: u16) {
+        self.targets.insert((bucket_id, segment_no));
+    }
+
+    fn start_collecting(&mut self) {
+        let buckets = self.buckets.load();
+        let segments = self
+            .targets
+            .iter()
+            .filter_map(|&(ref bucket_id, segment_no)| {
+                buckets
+                    .get(bucket_id)
+                    .and_then(|b| b.segments().get(segment_no as usize))
+                    .map(|s| (bucket_id.clone(), segment_no, s.clone()))
+            })
+            .collect::<Vec<(BucketId, u16, Segment)>>();
+
+        let logger = self.logger.clone();
+        let expiration_secs = self.expiration_secs;
+        let future = futures::stream::iter_ok(segments)
+            .and_then(move |(bucket_id, segment_no, segment)| {
+                let logger = logger.clone();
+                segment
+                    .collect_garbage_uploads(expiration_secs, Span::inactive().handle())
+                    .then(move |result| -> Result<u64> {
+                        match result {
+                            Ok(n) => Ok(n),
+                            Err(e) => {
+                                warn!(
+                                    logger,
+                                    "Cannot collect multipart uploads: {}",
+                                    dump!(bucket_id, segment_no, e)
+                                );
+                                Ok(0)
+                            }
+                        }
+                    })
+            })
+            .fold(0, |total, n| -> Result<u64> { Ok(total + n) });
+        self.future = Some(Box::new(future));
+    }
+}
+impl Future for UploadCollector {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some(mut future) = self.future.take() {
+                match track!(future.poll())? {
+                    Async::NotReady => {
+                        self.future = Some(future);
+                        return Ok(Async::NotReady);
+                    }
+                    Async::Ready(n) => {
+                        if n > 0 {
+                            info!(self.logger, "{} multipart uploads are collected", n);
+                        }
+                        self.collected_uploads.add_u64(n);
+                        self.timeout = timer::timeout(self.interval);
+                    }
+                }
+            }
+            if let Async::NotReady =
+                track!(self.timeout.poll().map_err(|e| ErrorKind::Other.cause(e)))?
+            {
+                return Ok(Async::NotReady);
+            }
+            self.start_collecting();
+        }
+    }
+}
@@ -0,0 +1,1103 @@
+//! S3互換のHTTPゲートウェイ。
+//!
+//! パス形式(`/{bucket}/{key}`)のリクエストのみをサポートする。
+//! バケツはFrugalosのバケツに、`ETag`はオブジェクトのバージョンに対応付けられる。
+//!
+//! 認証(署名)の検証は行わないので、信頼できるネットワーク内でのみ使用すること。
+use bytecodec::bytes::RemainingBytesDecoder;
+use bytecodec::null::NullDecoder;
+use fibers_http_server::metrics::WithMetrics;
+use fibers_http_server::{
+    HandleRequest, Reply, Req, Res, ServerBuilder as HttpServerBuilder, Status,
+};
+use futures::future::{loop_fn, Either, Loop};
+use futures::{self, Future, Stream};
+use httpcodec::{BodyDecoder, BodyEncoder, HeadBodyEncoder, HeaderField};
+use libfrugalos::entity::object::{ObjectPrefix, ObjectSummary, ObjectVersion};
+use slog::Logger;
+use std::cmp;
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
+use trackable::error::ErrorKindExt;
+use url::percent_encoding::percent_decode;
+use url::Url;
+
+use client::FrugalosClient;
+use codec::{ChunkedBytesDecoder, ObjectBodyEncoder, RequestBody};
+use frugalos_segment::{is_internal_object_id, UserMetadata};
+use http::{
+    decode_continuation_token, encode_continuation_token, make_object_stream_response,
+    make_object_stream_response_with_user_metadata, make_partial_object_response,
+    rename_user_metadata, AcceptRangesBytes, HttpResult, ObjectBody, CONTENT_LENGTH_METADATA,
+    LAST_MODIFIED_METADATA,
+};
+use server::{get_ranges, get_user_metadata};
+use {Error, ErrorKind, Result};
+
+// Frugalosはバケツの作成時刻を保持していないので、それらしい固定値を返す
+const DUMMY_DATE: &str = "1970-01-01T00:00:00.000Z";
+
+const DEFAULT_MAX_KEYS: usize = 1000;
+
+// S3のユーザ定義メタデータは、通常のAPIの`x-frugalos-meta-*`ヘッダと相互に読み替えられる
+const S3_USER_METADATA_PREFIX: &str = "x-amz-meta-";
+
+#[derive(Clone)]
+pub struct S3Server {
+    logger: Logger,
+    client: FrugalosClient,
+}
+impl S3Server {
+    pub fn new(logger: Logger, client: FrugalosClient) -> Self {
+        S3Server { logger, client }
+    }
+    pub fn register(self, builder: &mut HttpServerBuilder) -> Result<()> {
+        track!(builder.add_handler(WithMetrics::new(ListBuckets(self.clone()))))?;
+        track!(builder.add_handler(WithMetrics::new(ListObjectsV2(self.clone()))))?;
+        track!(builder.add_handler(WithMetrics::new(DeleteObjects(self.clone()))))?;
+        track!(builder.add_handler(WithMetrics::new(GetObject(self.clone()))))?;
+        track!(builder.add_handler(WithMetrics::new(HeadObject(self.clone()))))?;
+        track!(builder.add_handler(WithMetrics::new(PutObject(self.clone()))))?;
+        track!(builder.add_handler(WithMetrics::new(DeleteObject(self.clone()))))?;
+        Ok(())
+    }
+}
+
+type S3Res = Res<HttpResult<ObjectBody>>;
+
+macro_rules! try_s3 {
+    ($e:expr) => {
+        match track!($e) {
+            Err(e) => return Box::new(futures::finished(make_error_response(e))),
+            Ok(v) => v,
+        }
+    };
+}
+
+struct ListBuckets(S3Server);
+impl HandleRequest for ListBuckets {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<ObjectBody>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<ObjectBodyEncoder>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        let mut bucket_ids = self.0.client.bucket_ids();
+        bucket_ids.sort();
+
+        let mut xml = String::new();
+        xml.push_str("<ListAllMyBucketsResult><Owner><ID>frugalos</ID></Owner><Buckets>");
+        for id in bucket_ids {
+            xml.push_str(&format!(
+                "<Bucket><Name>{}</Name><CreationDate>{}</CreationDate></Bucket>",
+                escape_xml(&id),
+                DUMMY_DATE
+            ));
+        }
+        xml.push_str("</Buckets></ListAllMyBucketsResult>");
+        Box::new(futures::finished(make_xml_response(Status::Ok, xml)))
+    }
+}
+
+struct ListObjectsV2(S3Server);
+impl HandleRequest for ListObjectsV2 {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/*";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<ObjectBody>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<ObjectBodyEncoder>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = try_s3!(get_bucket_id(req.url()));
+        let params = try_s3!(ListParams::from_url(req.url()));
+        let logger = self.0.logger.clone();
+        let client = self.0.client.clone();
+        let list_bucket_id = bucket_id.clone();
+        let initial = (params.marker.clone(), ListPage::default());
+        let future = loop_fn(initial, move |(start_after, page)| {
+            let params = params.clone();
+            client
+                .request(list_bucket_id.clone())
+                .list_page(
+                    ObjectPrefix(params.prefix.clone()),
+                    start_after,
+                    cmp::max(params.max_keys, 1),
+                )
+                .map(move |objects| {
+                    let mut page = page;
+                    for object in objects.objects {
+                        let user_metadata = objects.user_metadata.get(&object.id);
+                        if !params.append(&mut page, object, user_metadata) {
+                            return Loop::Break((page, params));
+                        }
+                    }
+                    match objects.next {
+                        None => Loop::Break((page, params)),
+                        Some(next) => Loop::Continue((Some(next), page)),
+                    }
+                })
+        })
+        .then(move |result| {
+            let response = match track!(result) {
+                Ok((page, params)) => {
+                    make_xml_response(Status::Ok, page.to_xml(&bucket_id, &params))
+                }
+                Err(ref e) if *e.kind() == ErrorKind::NotFound => make_no_such_bucket_response(),
+                Err(e) => {
+                    warn!(
+                        logger,
+                        "Cannot list objects (bucket={:?}): {}", bucket_id, e
+                    );
+                    make_error_response(e)
+                }
+            };
+            Ok(response)
+        });
+        Box::new(future)
+    }
+}
+
+struct DeleteObjects(S3Server);
+impl HandleRequest for DeleteObjects {
+    const METHOD: &'static str = "POST";
+    const PATH: &'static str = "/*";
+
+    type ReqBody = Vec<u8>;
+    type ResBody = HttpResult<ObjectBody>;
+    type Decoder = BodyDecoder<RemainingBytesDecoder>;
+    type Encoder = BodyEncoder<ObjectBodyEncoder>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = try_s3!(get_bucket_id(req.url()));
+        if !req.url().query_pairs().any(|(k, _)| k == "delete") {
+            let e = ErrorKind::InvalidInput.cause("Unsupported bucket operation");
+            return Box::new(futures::finished(make_error_response(e.into())));
+        }
+        let (req, body) = req.take_body();
+        let body = try_s3!(
+            str::from_utf8(&body).map_err(|e| Error::from(ErrorKind::InvalidInput.cause(e)))
+        );
+        let keys = try_s3!(parse_delete_request_keys(body));
+        let quiet = extract_xml_elements(body, "Quiet")
+            .first()
+            .map_or(false, |v| v.trim() == "true");
+
+        let logger = self.0.logger.clone();
+        let client = self.0.client.clone();
+        let future = futures::stream::iter_ok(keys)
+            .and_then(move |key| {
+                client
+                    .request(bucket_id.clone())
+                    .delete(key.clone())
+                    .then(move |result| -> Result<_> { Ok((key, result)) })
+            })
+            .collect()
+            .then(move |results: Result<Vec<_>>| {
+                let results = results.expect("Never fails");
+                let mut xml = String::new();
+                xml.push_str("<DeleteResult>");
+                for (key, result) in results {
+                    match result {
+                        Ok(_) => {
+                            if !quiet {
+                                xml.push_str(&format!(
+                                    "<Deleted><Key>{}</Key></Deleted>",
+                                    escape_xml(&key)
+                                ));
+                            }
+                        }
+                        Err(e) => {
+                            warn!(
+                                logger,
+                                "Cannot delete object (url={}, object={:?}): {}",
+                                req.url(),
+                                key,
+                                e
+                            );
+                            let (_, code) = s3_error_code(&e);
+                            xml.push_str(&format!(
+                                "<Error><Key>{}</Key><Code>{}</Code><Message>{}</Message></Error>",
+                                escape_xml(&key),
+                                code,
+                                escape_xml(&e.to_string())
+                            ));
+                        }
+                    }
+                }
+                xml.push_str("</DeleteResult>");
+                Ok(make_xml_response(Status::Ok, xml))
+            });
+        Box::new(future)
+    }
+}
+
+struct GetObject(S3Server);
+impl HandleRequest for GetObject {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/*/**";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<ObjectBody>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<ObjectBodyEncoder>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = try_s3!(get_bucket_id(req.url()));
+        let key = try_s3!(get_key(req.url()));
+        let logger = self.0.logger.clone();
+        let request = self.0.client.request(bucket_id);
+
+        if let Some(ranges) = get_ranges(&req.header()) {
+            let future = request.get_range(key, ranges).then(move |result| {
+                let response = match track!(result) {
+                    Ok(None) => make_no_such_key_response(),
+                    Ok(Some(ref object)) if object.parts.is_empty() => {
+                        let mut res = make_s3_error_response(
+                            Status::RangeNotSatisfiable,
+                            "InvalidRange",
+                            "The requested range is not satisfiable",
+                        );
+                        res.header_mut().add_field(unsafe {
+                            HeaderField::new_unchecked(
+                                "Content-Range",
+                                &format!("bytes */{}", object.size),
+                            )
+                        });
+                        res
+                    }
+                    Ok(Some(mut object)) => {
+                        let last_modified = get_last_modified(&object.user_metadata);
+                        object.user_metadata =
+                            rename_user_metadata(object.user_metadata, S3_USER_METADATA_PREFIX);
+                        let mut res = make_partial_object_response(object);
+                        if let Some(t) = last_modified {
+                            add_last_modified_field(&mut res, t);
+                        }
+                        res
+                    }
+                    Err(e) => {
+                        warn!(logger, "Cannot get object range ({}): {}", req.url(), e);
+                        make_error_response(e)
+                    }
+                };
+                Ok(response)
+            });
+            return Box::new(future);
+        }
+
+        let future = request
+            .get_stream(key)
+            .and_then(|object| {
+                if let Some(object) = object {
+                    let version = object.version;
+                    let user_metadata =
+                        rename_user_metadata(object.user_metadata, S3_USER_METADATA_PREFIX);
+                    let future = ObjectBody::from_stream(object.content)
+                        .map(move |body| Some((version, user_metadata, body)));
+                    Either::A(future)
+                } else {
+                    Either::B(futures::finished(None))
+                }
+            })
+            .then(move |result| {
+                let response = match track!(result) {
+                    Ok(None) => make_no_such_key_response(),
+                    Ok(Some((version, user_metadata, body))) => {
+                        let mut res = make_object_stream_response_with_user_metadata(
+                            Status::Ok,
+                            Some(version),
+                            &user_metadata,
+                            Ok(body),
+                        );
+                        res.header_mut().add_field(AcceptRangesBytes);
+                        if let Some(t) = get_last_modified(&user_metadata) {
+                            add_last_modified_field(&mut res, t);
+                        }
+                        res
+                    }
+                    Err(e) => {
+                        warn!(logger, "Cannot get object ({}): {}", req.url(), e);
+                        make_error_response(e)
+                    }
+                };
+                Ok(response)
+            });
+        Box::new(future)
+    }
+}
+
+struct HeadObject(S3Server);
+impl HandleRequest for HeadObject {
+    const METHOD: &'static str = "HEAD";
+    const PATH: &'static str = "/*/**";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<ObjectBody>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = HeadBodyEncoder<BodyEncoder<ObjectBodyEncoder>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = try_s3!(get_bucket_id(req.url()));
+        let key = try_s3!(get_key(req.url()));
+        let logger = self.0.logger.clone();
+        let future = self
+            .0
+            .client
+            .request(bucket_id)
+            .head_with_user_metadata(key)
+            .then(move |result| {
+                let response = match track!(result) {
+                    Ok(None) => make_no_such_key_response(),
+                    Ok(Some((version, user_metadata))) => {
+                        let user_metadata =
+                            rename_user_metadata(user_metadata, S3_USER_METADATA_PREFIX);
+                        let body = Ok(ObjectBody::Bytes(Vec::new()));
+                        let mut res = make_object_stream_response_with_user_metadata(
+                            Status::Ok,
+                            Some(version),
+                            &user_metadata,
+                            body,
+                        );
+                        res.header_mut().add_field(AcceptRangesBytes);
+                        if let Some(t) = get_last_modified(&user_metadata) {
+                            add_last_modified_field(&mut res, t);
+                        }
+                        res
+                    }
+                    Err(e) => {
+                        warn!(logger, "Cannot head object ({}): {}", req.url(), e);
+                        make_error_response(e)
+                    }
+                };
+                Ok(response)
+            });
+        Box::new(future)
+    }
+}
+
+struct PutObject(S3Server);
+impl HandleRequest for PutObject {
+    const METHOD: &'static str = "PUT";
+    const PATH: &'static str = "/*/**";
+
+    type ReqBody = RequestBody;
+    type ResBody = HttpResult<ObjectBody>;
+    type Decoder = BodyDecoder<ChunkedBytesDecoder>;
+    type Encoder = BodyEncoder<ObjectBodyEncoder>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = try_s3!(get_bucket_id(req.url()));
+        let key = try_s3!(get_key(req.url()));
+        let (req, body) = req.take_body();
+
+        let mut user_metadata = try_s3!(get_user_metadata(&req.header(), S3_USER_METADATA_PREFIX));
+
+        // `ListObjectsV2`や`Last-Modified`ヘッダで返せるように、サイズと保存時刻を記録しておく
+        user_metadata.insert(CONTENT_LENGTH_METADATA.to_owned(), body.len().to_string());
+        user_metadata.insert(
+            LAST_MODIFIED_METADATA.to_owned(),
+            unix_time_secs().to_string(),
+        );
+        let logger = self.0.logger.clone();
+        let future = self
+            .0
+            .client
+            .request(bucket_id)
+            .user_metadata(user_metadata)
+            .put_stream(key, body.into_stream())
+            .then(move |result| {
+                let response = match track!(result) {
+                    Ok((version, _)) => {
+                        let body = Ok(ObjectBody::Bytes(Vec::new()));
+                        make_object_stream_response(Status::Ok, Some(version), body)
+                    }
+                    Err(e) => {
+                        warn!(logger, "Cannot put object ({}): {}", req.url(), e);
+                        make_error_response(e)
+                    }
+                };
+                Ok(response)
+            });
+        Box::new(future)
+    }
+}
+
+struct DeleteObject(S3Server);
+impl HandleRequest for DeleteObject {
+    const METHOD: &'static str = "DELETE";
+    const PATH: &'static str = "/*/**";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<ObjectBody>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<ObjectBodyEncoder>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = try_s3!(get_bucket_id(req.url()));
+        let key = try_s3!(get_key(req.url()));
+        let logger = self.0.logger.clone();
+        let future = self
+            .0
+            .client
+            .request(bucket_id)
+            .delete(key)
+            .then(move |result| {
+                // S3では、オブジェクトが存在しない場合にも削除は成功扱いとなる
+                let response = match track!(result) {
+                    Ok(_) => Res::new(
+                        Status::NoContent,
+                        HttpResult::Ok(ObjectBody::Bytes(Vec::new())),
+                    ),
+                    Err(e) => {
+                        warn!(logger, "Cannot delete object ({}): {}", req.url(), e);
+                        make_error_response(e)
+                    }
+                };
+                Ok(response)
+            });
+        Box::new(future)
+    }
+}
+
+/// `ListObjectsV2`の要求パラメータ。
+#[derive(Debug, Default, Clone)]
+struct ListParams {
+    prefix: String,
+    delimiter: Option<String>,
+    max_keys: usize,
+    continuation_token: Option<String>,
+    start_after: Option<String>,
+    url_encoding: bool,
+
+    // このキー(あるいは共通接頭辞)以前の結果は返さない
+    marker: Option<String>,
+}
+impl ListParams {
+    fn from_url(url: &Url) -> Result<Self> {
+        let mut params = ListParams {
+            max_keys: DEFAULT_MAX_KEYS,
+            ..Default::default()
+        };
+        for (k, v) in url.query_pairs() {
+            match k.as_ref() {
+                "prefix" => params.prefix = v.into_owned(),
+                "delimiter" if !v.is_empty() => params.delimiter = Some(v.into_owned()),
+                "max-keys" => {
+                    let n: usize = track!(v.parse().map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+                    params.max_keys = cmp::min(n, DEFAULT_MAX_KEYS);
+                }
+                "continuation-token" => params.continuation_token = Some(v.into_owned()),
+                "start-after" => params.start_after = Some(v.into_owned()),
+                "encoding-type" => params.url_encoding = v == "url",
+                _ => {}
+            }
+        }
+        params.marker = if let Some(ref token) = params.continuation_token {
+            Some(track!(decode_continuation_token(token))?)
+        } else {
+            params.start_after.clone()
+        };
+        Ok(params)
+    }
+
+    // ページにオブジェクトを追加する (既にページが埋まっている場合には`false`を返す)
+    //
+    // オブジェクトは、キーの辞書順に渡される必要がある
+    fn append(
+        &self,
+        page: &mut ListPage,
+        object: ObjectSummary,
+        user_metadata: Option<&UserMetadata>,
+    ) -> bool {
+        if self.marker.as_ref().map_or(false, |m| object.id <= *m) {
+            return true;
+        }
+
+        let common_prefix = self.delimiter.as_ref().and_then(|d| {
+            object.id[self.prefix.len()..]
+                .find(d.as_str())
+                .map(|i| object.id[..self.prefix.len() + i + d.len()].to_owned())
+        });
+        if let Some(ref p) = common_prefix {
+            if page.common_prefixes.last() == Some(p) {
+                return true;
+            }
+            if self.marker.as_ref().map_or(false, |m| p <= m) {
+                return true;
+            }
+        }
+
+        if page.contents.len() + page.common_prefixes.len() >= self.max_keys {
+            page.next_marker = page.last.take();
+            return false;
+        }
+        if let Some(p) = common_prefix {
+            page.last = Some(p.clone());
+            page.common_prefixes.push(p);
+        } else {
+            page.last = Some(object.id.clone());
+            page.contents.push(ListContent {
+                key: object.id,
+                version: object.version,
+                size: user_metadata.and_then(get_content_length),
+                last_modified: user_metadata.and_then(get_last_modified),
+            });
+        }
+        true
+    }
+}
+
+/// `ListObjectsV2`の結果に含まれるオブジェクト。
+///
+/// サイズと保存時刻は、S3互換APIで保存されたオブジェクトについてのみ記録されている。
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ListContent {
+    key: String,
+    version: ObjectVersion,
+    size: Option<u64>,
+    last_modified: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct ListPage {
+    contents: Vec<ListContent>,
+    common_prefixes: Vec<String>,
+    next_marker: Option<String>,
+    last: Option<String>,
+}
+impl ListPage {
+    fn to_xml(&self, bucket_id: &str, params: &ListParams) -> String {
+        let encode = |s: &str| {
+            if params.url_encoding {
+                encode_key(s)
+            } else {
+                escape_xml(s)
+            }
+        };
+
+        let mut xml = String::new();
+        xml.push_str("<ListBucketResult>");
+        xml.push_str(&format!("<Name>{}</Name>", escape_xml(bucket_id)));
+        xml.push_str(&format!("<Prefix>{}</Prefix>", encode(&params.prefix)));
+        if let Some(ref d) = params.delimiter {
+            xml.push_str(&format!("<Delimiter>{}</Delimiter>", encode(d)));
+        }
+        if params.url_encoding {
+            xml.push_str("<EncodingType>url</EncodingType>");
+        }
+        xml.push_str(&format!("<MaxKeys>{}</MaxKeys>", params.max_keys));
+        xml.push_str(&format!(
+            "<KeyCount>{}</KeyCount>",
+            self.contents.len() + self.common_prefixes.len()
+        ));
+        if let Some(ref token) = params.continuation_token {
+            xml.push_str(&format!(
+                "<ContinuationToken>{}</ContinuationToken>",
+                escape_xml(token)
+            ));
+        }
+        if let Some(ref start_after) = params.start_after {
+            xml.push_str(&format!("<StartAfter>{}</StartAfter>", encode(start_after)));
+        }
+        xml.push_str(&format!(
+            "<IsTruncated>{}</IsTruncated>",
+            self.next_marker.is_some()
+        ));
+        if let Some(ref marker) = self.next_marker {
+            xml.push_str(&format!(
+                "<NextContinuationToken>{}</NextContinuationToken>",
+                encode_continuation_token(marker)
+            ));
+        }
+        for content in &self.contents {
+            xml.push_str(&format!("<Contents><Key>{}</Key>", encode(&content.key)));
+            // NOTE: サイズや保存時刻が記録されていないオブジェクトについては、
+            // 不正確な値を返さないように`Size`と`LastModified`は省略する
+            if let Some(t) = content.last_modified {
+                xml.push_str(&format!(
+                    "<LastModified>{}</LastModified>",
+                    format_iso8601(t)
+                ));
+            }
+            xml.push_str(&format!("<ETag>\"{:x}\"</ETag>", content.version.0));
+            if let Some(size) = content.size {
+                xml.push_str(&format!("<Size>{}</Size>", size));
+            }
+            xml.push_str("<StorageClass>STANDARD</StorageClass></Contents>");
+        }
+        for prefix in &self.common_prefixes {
+            xml.push_str(&format!(
+                "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+                encode(prefix)
+            ));
+        }
+        xml.push_str("</ListBucketResult>");
+        xml
+    }
+}
+
+#[derive(Debug)]
+struct ContentTypeXml;
+impl From<ContentTypeXml> for HeaderField<'static, 'static> {
+    fn from(_: ContentTypeXml) -> Self {
+        unsafe { HeaderField::new_unchecked("Content-Type", "application/xml") }
+    }
+}
+
+fn add_last_modified_field(res: &mut S3Res, unix_secs: u64) {
+    res.header_mut().add_field(unsafe {
+        HeaderField::new_unchecked("Last-Modified", &format_http_date(unix_secs))
+    });
+}
+
+fn make_xml_response(status: Status, xml: String) -> S3Res {
+    let mut body = br#"<?xml version="1.0" encoding="UTF-8"?>"#.to_vec();
+    body.extend_from_slice(xml.as_bytes());
+    let mut res = Res::new(status, HttpResult::Ok(ObjectBody::Bytes(body)));
+    res.header_mut().add_field(ContentTypeXml);
+    res
+}
+
+fn make_s3_error_response(status: Status, code: &str, message: &str) -> S3Res {
+    let xml = format!(
+        "<Error><Code>{}</Code><Message>{}</Message></Error>",
+        code,
+        escape_xml(message)
+    );
+    make_xml_response(status, xml)
+}
+
+fn make_error_response(e: Error) -> S3Res {
+    let (status, code) = s3_error_code(&e);
+    make_s3_error_response(status, code, &e.to_string())
+}
+
+fn make_no_such_bucket_response() -> S3Res {
+    make_s3_error_response(
+        Status::NotFound,
+        "NoSuchBucket",
+        "The specified bucket does not exist",
+    )
+}
+
+fn make_no_such_key_response() -> S3Res {
+    make_s3_error_response(
+        Status::NotFound,
+        "NoSuchKey",
+        "The specified key does not exist",
+    )
+}
+
+fn s3_error_code(e: &Error) -> (Status, &'static str) {
+    match *e.kind() {
+        ErrorKind::InvalidInput => (Status::BadRequest, "InvalidArgument"),
+        ErrorKind::NotFound => (Status::NotFound, "NoSuchBucket"),
+        ErrorKind::Unexpected(_) => (Status::PreconditionFailed, "PreconditionFailed"),
+        ErrorKind::Other => (Status::InternalServerError, "InternalError"),
+    }
+}
+
+fn get_bucket_id(url: &Url) -> Result<String> {
+    let segment = url
+        .path_segments()
+        .expect("Never fails")
+        .next()
+        .expect("Never fails");
+    track!(decode_path(segment))
+}
+
+fn get_key(url: &Url) -> Result<String> {
+    let key = url.path()[1..].splitn(2, '/').nth(1).unwrap_or("");
+    let key = track!(decode_path(key))?;
+    track!(check_key(&key))?;
+    Ok(key)
+}
+
+// 内部用のオブジェクト(e.g., マルチパートアップロードのパート)はS3互換APIからは操作させない
+fn check_key(key: &str) -> Result<()> {
+    track_assert!(
+        !is_internal_object_id(key),
+        ErrorKind::InvalidInput,
+        "Reserved object key: {:?}",
+        key
+    );
+    Ok(())
+}
+
+fn decode_path(s: &str) -> Result<String> {
+    let s = track!(percent_decode(s.as_bytes())
+        .decode_utf8()
+        .map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+    Ok(s.into_owned())
+}
+
+// `encoding-type=url`が指定された場合のキーのエンコード
+fn encode_key(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape_xml(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn get_content_length(user_metadata: &UserMetadata) -> Option<u64> {
+    user_metadata
+        .get(CONTENT_LENGTH_METADATA)
+        .and_then(|v| v.parse().ok())
+}
+
+fn get_last_modified(user_metadata: &UserMetadata) -> Option<u64> {
+    user_metadata
+        .get(LAST_MODIFIED_METADATA)
+        .and_then(|v| v.parse().ok())
+}
+
+fn unix_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// UNIXエポックからの秒数を、UTCでの(年, 月, 日, 時, 分, 秒, 曜日)に変換する (曜日は日曜日が`0`)
+fn to_utc_datetime(unix_secs: u64) -> (u64, u64, u64, u64, u64, u64, u64) {
+    let days = unix_secs / 86_400;
+    let secs = unix_secs % 86_400;
+    let weekday = (days + 4) % 7;
+
+    // 3月始まりの400年周期を単位として、年月日を求める
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        weekday,
+    )
+}
+
+// `LastModified`要素の形式(e.g., `2009-10-12T17:50:30.000Z`)
+fn format_iso8601(unix_secs: u64) -> String {
+    let (year, month, day, hour, min, sec, _) = to_utc_datetime(unix_secs);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.000Z",
+        year, month, day, hour, min, sec
+    )
+}
+
+// `Last-Modified`ヘッダの形式(e.g., `Mon, 12 Oct 2009 17:50:30 GMT`)
+fn format_http_date(unix_secs: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day, hour, min, sec, weekday) = to_utc_datetime(unix_secs);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[weekday as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        min,
+        sec
+    )
+}
+
+// `DeleteObjects`の要求ボディは単純な構造なので、XMLパーサは使わずに要素を抜き出す
+fn extract_xml_elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let start_tag = format!("<{}>", tag);
+    let end_tag = format!("</{}>", tag);
+    let mut elements = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&start_tag) {
+        rest = &rest[start + start_tag.len()..];
+        if let Some(end) = rest.find(&end_tag) {
+            elements.push(&rest[..end]);
+            rest = &rest[end + end_tag.len()..];
+        } else {
+            break;
+        }
+    }
+    elements
+}
+
+fn parse_delete_request_keys(body: &str) -> Result<Vec<String>> {
+    let keys = extract_xml_elements(body, "Key")
+        .into_iter()
+        .map(unescape_xml)
+        .collect::<Vec<_>>();
+    track_assert!(
+        keys.len() <= 1000,
+        ErrorKind::InvalidInput,
+        "Too many keys: {}",
+        keys.len()
+    );
+    for key in &keys {
+        track!(check_key(key))?;
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    fn url(path_and_query: &str) -> Url {
+        Url::parse(&format!("http://localhost{}", path_and_query)).unwrap()
+    }
+
+    fn list(params: &ListParams, ids: &[&str]) -> ListPage {
+        let mut page = ListPage::default();
+        for (i, id) in ids.iter().enumerate() {
+            let object = ObjectSummary {
+                id: id.to_string(),
+                version: ObjectVersion(i as u64),
+            };
+            if !params.append(&mut page, object, None) {
+                break;
+            }
+        }
+        page
+    }
+
+    fn keys(page: &ListPage) -> Vec<&str> {
+        page.contents.iter().map(|c| c.key.as_str()).collect()
+    }
+
+    #[test]
+    fn get_key_works() -> TestResult {
+        let u = url("/bucket0/foo/bar%20baz");
+        assert_eq!(track!(get_bucket_id(&u))?, "bucket0");
+        assert_eq!(track!(get_key(&u))?, "foo/bar baz");
+        assert_eq!(track!(get_key(&url("/bucket0/%E3%81%82")))?, "あ");
+
+        // 不正なUTF-8列
+        let e = get_key(&url("/bucket0/%FF")).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+
+        // 内部用のオブジェクトは操作できない
+        let e = get_key(&url("/bucket0/%00multipart/1/0")).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_delete_request_keys_works() -> TestResult {
+        let body = "<Delete><Quiet>true</Quiet><Object><Key>a&amp;b</Key></Object><Object><Key>c</Key></Object></Delete>";
+        assert_eq!(track!(parse_delete_request_keys(body))?, ["a&b", "c"]);
+        assert_eq!(extract_xml_elements(body, "Quiet"), ["true"]);
+
+        let body = "<Delete><Object><Key>a</Key></Object><Object><Key>\0multipart/1/0</Key></Object></Delete>";
+        assert!(parse_delete_request_keys(body).is_err());
+
+        let body = (0..1001)
+            .map(|i| format!("<Object><Key>{}</Key></Object>", i))
+            .collect::<String>();
+        assert!(parse_delete_request_keys(&body).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn escape_works() {
+        assert_eq!(
+            escape_xml("<a & 'b'>\""),
+            "&lt;a &amp; &apos;b&apos;&gt;&quot;"
+        );
+        assert_eq!(unescape_xml(&escape_xml("<a & 'b'>\"")), "<a & 'b'>\"");
+        assert_eq!(encode_key("foo/bar baz+あ"), "foo/bar%20baz%2B%E3%81%82");
+    }
+
+    #[test]
+    fn list_pagination_works() -> TestResult {
+        let ids = ["a", "b/1", "b/2", "c"];
+
+        let params = track!(ListParams::from_url(&url(
+            "/bucket0?list-type=2&delimiter=/&max-keys=2"
+        )))?;
+        let page = list(&params, &ids);
+        assert_eq!(keys(&page), ["a"]);
+        assert_eq!(page.common_prefixes, ["b/"]);
+        assert_eq!(page.next_marker, Some("b/".to_owned()));
+
+        let token = encode_continuation_token("b/");
+        let xml = page.to_xml("bucket0", &params);
+        assert!(xml.contains("<IsTruncated>true</IsTruncated>"));
+        assert!(xml.contains(&format!(
+            "<NextContinuationToken>{}</NextContinuationToken>",
+            token
+        )));
+
+        // 継続トークンを指定して、次のページを取得する
+        let params = track!(ListParams::from_url(&url(&format!(
+            "/bucket0?list-type=2&delimiter=/&max-keys=2&continuation-token={}",
+            token
+        ))))?;
+        let page = list(&params, &ids);
+        assert_eq!(keys(&page), ["c"]);
+        assert_eq!(page.contents[0].version, ObjectVersion(3));
+        assert!(page.common_prefixes.is_empty());
+        assert_eq!(page.next_marker, None);
+        assert!(page
+            .to_xml("bucket0", &params)
+            .contains("<IsTruncated>false</IsTruncated>"));
+
+        // `start-after`の指定
+        let params = track!(ListParams::from_url(&url("/bucket0?start-after=b/1")))?;
+        let page = list(&params, &ids);
+        assert_eq!(keys(&page), ["b/2", "c"]);
+
+        // 不正な継続トークン
+        assert!(ListParams::from_url(&url("/bucket0?continuation-token=zz")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn list_page_to_xml_works() -> TestResult {
+        let params = track!(ListParams::from_url(&url(
+            "/bucket0?prefix=d&encoding-type=url"
+        )))?;
+        let page = ListPage {
+            contents: vec![
+                ListContent {
+                    key: "d e".to_owned(),
+                    version: ObjectVersion(10),
+                    size: Some(5),
+                    last_modified: Some(1_255_369_830),
+                },
+                ListContent {
+                    key: "d f".to_owned(),
+                    version: ObjectVersion(11),
+                    size: None,
+                    last_modified: None,
+                },
+            ],
+            common_prefixes: vec!["d/".to_owned()],
+            next_marker: None,
+            last: None,
+        };
+        assert_eq!(
+            page.to_xml("bucket0", &params),
+            concat!(
+                "<ListBucketResult><Name>bucket0</Name><Prefix>d</Prefix>",
+                "<EncodingType>url</EncodingType><MaxKeys>1000</MaxKeys><KeyCount>3</KeyCount>",
+                "<IsTruncated>false</IsTruncated>",
+                "<Contents><Key>d%20e</Key><LastModified>2009-10-12T17:50:30.000Z</LastModified>",
+                "<ETag>\"a\"</ETag><Size>5</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                "<Contents><Key>d%20f</Key><ETag>\"b\"</ETag><StorageClass>STANDARD</StorageClass></Contents>",
+                "<CommonPrefixes><Prefix>d/</Prefix></CommonPrefixes></ListBucketResult>"
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn list_records_size_and_last_modified() -> TestResult {
+        let params = track!(ListParams::from_url(&url("/bucket0")))?;
+        let mut user_metadata = UserMetadata::new();
+        user_metadata.insert(CONTENT_LENGTH_METADATA.to_owned(), "123".to_owned());
+        user_metadata.insert(LAST_MODIFIED_METADATA.to_owned(), "1500000000".to_owned());
+
+        let mut page = ListPage::default();
+        let object = |id: &str| ObjectSummary {
+            id: id.to_owned(),
+            version: ObjectVersion(1),
+        };
+        assert!(params.append(&mut page, object("a"), Some(&user_metadata)));
+        assert!(params.append(&mut page, object("b"), None));
+        assert_eq!(page.contents[0].size, Some(123));
+        assert_eq!(page.contents[0].last_modified, Some(1_500_000_000));
+        assert_eq!(page.contents[1].size, None);
+        assert_eq!(page.contents[1].last_modified, None);
+        Ok(())
+    }
+
+    #[test]
+    fn date_format_works() {
+        assert_eq!(format_iso8601(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(format_iso8601(1_255_369_830), "2009-10-12T17:50:30.000Z");
+        assert_eq!(
+            format_http_date(1_255_369_830),
+            "Mon, 12 Oct 2009 17:50:30 GMT"
+        );
+        assert_eq!(
+            format_http_date(951_782_400),
+            "Tue, 29 Feb 2000 00:00:00 GMT"
+        );
+        assert_eq!(format_iso8601(1_709_251_199), "2024-02-29T23:59:59.000Z");
+    }
+
+    #[test]
+    fn s3_error_code_works() {
+        let code = |kind: ErrorKind| s3_error_code(&Error::from(kind.error()));
+        assert_eq!(
+            code(ErrorKind::InvalidInput),
+            (Status::BadRequest, "InvalidArgument")
+        );
+        assert_eq!(
+            code(ErrorKind::NotFound),
+            (Status::NotFound, "NoSuchBucket")
+        );
+        assert_eq!(
+            code(ErrorKind::Unexpected(None)),
+            (Status::PreconditionFailed, "PreconditionFailed")
+        );
+        assert_eq!(
+            code(ErrorKind::Other),
+            (Status::InternalServerError, "InternalError")
+        );
+    }
+}
@@ -1,15 +1,21 @@
 use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
-use bytecodec::json_codec::JsonEncoder;
+use bytecodec::json_codec::{JsonDecoder, JsonEncoder};
 use bytecodec::null::NullDecoder;
 use cannyls::deadline::Deadline;
 use fibers_http_server::metrics::WithMetrics;
 use fibers_http_server::{
     HandleRequest, Reply, Req, Res, ServerBuilder as HttpServerBuilder, Status,
 };
+use frugalos_segment::{
+    is_internal_object_id, BatchOperation, BatchResult, ByteRange, ObjectAttributes,
+    ObjectVersionEntry, ReadConsistency, RepairController, RepairSettings, ScrubStatus,
+    ScrubberRegistry, UploadId, UserMetadata, MAX_BATCH_TOTAL_CONTENT_SIZE,
+};
+use futures::future::Either;
 use futures::{self, Future, Stream};
 use httpcodec::{BodyDecoder, BodyEncoder, HeadBodyEncoder, Header};
 use libfrugalos::entity::object::{
-    DeleteObjectsByPrefixSummary, ObjectPrefix, ObjectSummary, ObjectVersion,
+    DeleteObjectsByPrefixSummary, ObjectId, ObjectPrefix, ObjectSummary, ObjectVersion,
 };
 use libfrugalos::expect::Expect;
 use rustracing::tag::{StdTag, Tag};
@@ -19,7 +25,6 @@ use rustracing_jaeger::{Span, Tracer};
 use slog::Logger;
 use std::cell::RefCell;
 use std::str;
-use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
@@ -27,14 +32,39 @@ use trackable::error::ErrorKindExt;
 use url::Url;
 
 use client::FrugalosClient;
-use codec::{AsyncEncoder, ObjectResultEncoder};
+use codec::{
+    AsyncEncoder, BatchRequestDecoder, ChunkedBytesDecoder, ObjectBodyEncoder, ObjectResultEncoder,
+    RequestBody,
+};
 use http::{
-    make_json_response, make_object_response, not_found, BucketStatistics, HttpResult, TraceHeader,
+    decode_continuation_token, decode_hex, encode_continuation_token, encode_hex,
+    make_json_response, make_object_response, make_object_response_with_user_metadata,
+    make_object_stream_response, make_object_stream_response_with_user_metadata,
+    make_partial_object_response, not_found, AcceptRangesBytes, BatchOperationKind, BatchRequest,
+    BatchRequestOperation, BatchResponse, BatchResponseItem, BucketStatistics,
+    CompleteUploadRequest, HttpResult, MultipartUpload, ObjectBody, ObjectList, TraceHeader,
+    CONTENT_TYPE, COPY_SOURCE_HEADER, MAX_USER_METADATA_SIZE, RENAME_SOURCE_HEADER,
+    USER_METADATA_PREFIX,
 };
+use s3::S3Server;
 use {Error, ErrorKind, Result};
 
-// TODO: 冗長化設定等を反映した正確な上限を使用する
-const MAX_PUT_OBJECT_SIZE: usize = 50 * 1024 * 1024;
+// マルチパートアップロードの一つのパートの最大サイズ (パートはメモリ上に読み込まれた上で保存される)
+const MAX_UPLOAD_PART_SIZE: usize = 50 * 1024 * 1024;
+
+// 指定可能な有効期限(秒)の最大値 (100年)
+const MAX_TTL_SECS: u64 = 100 * 365 * 24 * 60 * 60;
+
+const DEFAULT_LIST_LIMIT: usize = 1000;
+const MAX_LIST_LIMIT: usize = 10_000;
+
+// 一度のバッチ操作で指定可能な操作の最大数
+const MAX_BATCH_OPERATIONS: usize = 1000;
+
+/// バッチ操作(およびトランザクション)の要求ボディの最大サイズ(バイト)。
+///
+/// 中身は16進数表記されるので、中身の合計の上限の二倍に、IDや条件等の記述分の余裕を加えた値としている。
+pub const MAX_BATCH_REQUEST_SIZE: usize = MAX_BATCH_TOTAL_CONTENT_SIZE * 2 + 1024 * 1024;
 
 thread_local! {
     static TRACER: RefCell<Option<Tracer>> = RefCell::new(None);
@@ -58,32 +88,58 @@ macro_rules! try_badarg {
 pub struct Server {
     logger: Logger,
     client: FrugalosClient,
+    scrubbers: ScrubberRegistry,
+    repair_controller: RepairController,
     tracer: Arc<Mutex<Tracer>>,
-
     // TODO: remove
-    large_object_count: Arc<AtomicUsize>,
 }
 impl Server {
-    pub fn new(logger: Logger, client: FrugalosClient, tracer: Tracer) -> Self {
+    pub fn new(
+        logger: Logger,
+        client: FrugalosClient,
+        scrubbers: ScrubberRegistry,
+        repair_controller: RepairController,
+        tracer: Tracer,
+    ) -> Self {
         Server {
             logger,
             client,
+            scrubbers,
+            repair_controller,
             tracer: Arc::new(Mutex::new(tracer)),
-            large_object_count: Arc::default(),
         }
     }
     pub fn register(self, builder: &mut HttpServerBuilder) -> Result<()> {
         track!(builder.add_handler(ListSegments(self.clone())))?;
         track!(builder.add_handler(WithMetrics::new(ListObjects(self.clone()))))?;
+        track!(builder.add_handler(WithMetrics::new(ListBucketObjects(self.clone()))))?;
         track!(builder.add_handler(WithMetrics::new(GetObject(self.clone()))))?;
         track!(builder.add_handler(WithMetrics::new(HeadObject(self.clone()))))?;
         track!(builder.add_handler(WithMetrics::new(DeleteObject(self.clone()))))?;
         track!(builder.add_handler(WithMetrics::new(DeleteObjectByPrefix(self.clone()))))?;
         track!(builder.add_handler(WithMetrics::new(PutObject(self.clone()))))?;
+        track!(builder.add_handler(WithMetrics::new(InitiateUpload(self.clone()))))?;
+        track!(builder.add_handler(WithMetrics::new(UploadPart(self.clone()))))?;
+        track!(builder.add_handler(WithMetrics::new(CompleteUpload(self.clone()))))?;
+        track!(builder.add_handler(WithMetrics::new(AbortUpload(self.clone()))))?;
         track!(builder.add_handler(WithMetrics::new(GetBucketStatistics(self.clone()))))?;
+        track!(builder.add_handler(WithMetrics::new(ListObjectVersions(self.clone()))))?;
+        track!(builder.add_handler(WithMetrics::new(Batch(self.clone()))))?;
+        track!(builder.add_handler(WithMetrics::new(Transaction(self.clone()))))?;
+        track!(builder.add_handler(GetScrubStatus(self.clone())))?;
+        track!(builder.add_handler(StartScrub(self.clone())))?;
+        track!(builder.add_handler(GetRepairSettings(self.clone())))?;
+        track!(builder.add_handler(PutRepairSettings(self.clone())))?;
         track!(builder.add_handler(JemallocStats))?;
         Ok(())
     }
+    /// S3互換APIのハンドラ群を登録する。
+    ///
+    /// パスが既存のAPIと衝突するので、`register`とは別のサーバ(ポート)に登録すること。
+    pub fn register_s3(&self, builder: &mut HttpServerBuilder) -> Result<()> {
+        let s3 = S3Server::new(self.logger.clone(), self.client.clone());
+        track!(s3.register(builder))
+    }
 
     fn tracer<F>(&self, f: F) -> Span
     where
@@ -160,6 +216,47 @@ impl HandleRequest for ListObjects {
     }
 }
 
+struct ListBucketObjects(Server);
+impl HandleRequest for ListBucketObjects {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/buckets/*/objects";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<ObjectList>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+        let (prefix, start_after, limit) = try_badarg!(get_list_params(req.url()));
+        let consistency = try_badarg!(get_read_consistency(req.url()));
+        let future = self
+            .0
+            .client
+            .request(bucket_id)
+            .consistency(consistency)
+            .list_page(prefix, start_after, limit)
+            .then(|result| {
+                let response = match track!(result) {
+                    Ok(page) => {
+                        let list = ObjectList {
+                            objects: page.objects,
+                            continuation_token: page.next.map(|id| encode_continuation_token(&id)),
+                        };
+                        make_json_response(Status::Ok, Ok(list))
+                    }
+                    Err(ref e) if *e.kind() == ErrorKind::NotFound => {
+                        make_json_response(Status::NotFound, Err(not_found()))
+                    }
+                    Err(e) => make_json_response(Status::InternalServerError, Err(e)),
+                };
+                Ok(response)
+            });
+        Box::new(future)
+    }
+}
+
 struct GetBucketStatistics(Server);
 impl HandleRequest for GetBucketStatistics {
     const METHOD: &'static str = "GET";
@@ -209,9 +306,9 @@ impl HandleRequest for GetObject {
     const PATH: &'static str = "/v1/buckets/*/objects/*";
 
     type ReqBody = ();
-    type ResBody = HttpResult<Vec<u8>>;
+    type ResBody = HttpResult<ObjectBody>;
     type Decoder = BodyDecoder<NullDecoder>;
-    type Encoder = BodyEncoder<ObjectResultEncoder>;
+    type Encoder = BodyEncoder<ObjectBodyEncoder>;
     type Reply = Reply<Self::ResBody>;
 
     fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
@@ -232,25 +329,55 @@ impl HandleRequest for GetObject {
         let logger = self.0.logger.clone();
         let expect = try_badarg!(get_expect(&req.header()));
         let deadline = try_badarg!(get_deadline(&req.url()));
-        let future = self
-            .0
-            .client
-            .request(bucket_id)
+        let version = try_badarg!(get_version(&req.url()));
+        let consistency = try_badarg!(get_read_consistency(&req.url()));
+        if let (None, Some(ranges)) = (version, get_ranges(&req.header())) {
+            return self.handle_range_request(req, ranges, expect, consistency, deadline, span);
+        }
+        let mut request = self.0.client.request(bucket_id);
+        request
             .deadline(deadline)
             .expect(expect)
-            .span(&span)
-            .get(object_id)
+            .consistency(consistency)
+            .span(&span);
+        let future = if let Some(version) = version {
+            span.set_tag(|| Tag::new("object.requested_version", version.0 as i64));
+            request.get_version_stream(object_id, version)
+        } else {
+            request.get_stream(object_id)
+        };
+        let future = future
+            .and_then(|object| {
+                if let Some(object) = object {
+                    let version = object.version;
+                    let user_metadata = object.user_metadata;
+                    let future = ObjectBody::from_stream(object.content)
+                        .map(move |body| Some((version, user_metadata, body)));
+                    Either::A(future)
+                } else {
+                    Either::B(futures::finished(None))
+                }
+            })
             .then(move |result| {
                 let response = match track!(result) {
                     Ok(None) => {
                         span.set_tag(|| StdTag::http_status_code(404));
-                        make_object_response(Status::NotFound, None, Err(not_found()))
+                        make_object_stream_response(Status::NotFound, None, Err(not_found()))
                     }
-                    Ok(Some(object)) => {
-                        span.set_tag(|| Tag::new("object.size", object.content.len() as i64));
-                        span.set_tag(|| Tag::new("object.version", object.version.0 as i64));
+                    Ok(Some((version, user_metadata, body))) => {
+                        if let ObjectBody::Bytes(ref content) = body {
+                            span.set_tag(|| Tag::new("object.size", content.len() as i64));
+                        }
+                        span.set_tag(|| Tag::new("object.version", version.0 as i64));
                         span.set_tag(|| StdTag::http_status_code(200));
-                        make_object_response(Status::Ok, Some(object.version), Ok(object.content))
+                        let mut res = make_object_stream_response_with_user_metadata(
+                            Status::Ok,
+                            Some(version),
+                            &user_metadata,
+                            Ok(body),
+                        );
+                        res.header_mut().add_field(AcceptRangesBytes);
+                        res
                     }
                     // NOTE:
                     // オブジェクトが存在しない場合と、バケツが存在しない(まだ起動処理中かもしれない)は分ける
@@ -268,7 +395,58 @@ impl HandleRequest for GetObject {
                             e
                         );
                         span.set_tag(|| StdTag::http_status_code(500));
-                        make_object_response(Status::InternalServerError, None, Err(e))
+                        make_object_stream_response(Status::InternalServerError, None, Err(e))
+                    }
+                };
+                Ok(response)
+            });
+        Box::new(future)
+    }
+}
+
+impl GetObject {
+    fn handle_range_request(
+        &self,
+        req: Req<()>,
+        ranges: Vec<ByteRange>,
+        expect: Expect,
+        consistency: ReadConsistency,
+        deadline: Deadline,
+        mut span: Span,
+    ) -> Reply<HttpResult<ObjectBody>> {
+        let logger = self.0.logger.clone();
+        span.set_tag(|| Tag::new("ranges", ranges.len() as i64));
+        let future = self
+            .0
+            .client
+            .request(get_bucket_id(req.url()))
+            .deadline(deadline)
+            .expect(expect)
+            .consistency(consistency)
+            .span(&span)
+            .get_range(get_object_id(req.url()), ranges)
+            .then(move |result| {
+                let response = match track!(result) {
+                    Ok(None) => {
+                        span.set_tag(|| StdTag::http_status_code(404));
+                        make_object_stream_response(Status::NotFound, None, Err(not_found()))
+                    }
+                    Ok(Some(object)) => {
+                        let status = if object.parts.is_empty() { 416 } else { 206 };
+                        span.set_tag(|| Tag::new("object.version", object.version.0 as i64));
+                        span.set_tag(|| StdTag::http_status_code(status));
+                        make_partial_object_response(object)
+                    }
+                    Err(e) => {
+                        warn!(
+                            logger,
+                            "Cannot get object range (bucket={:?}, object={:?}): {}",
+                            get_bucket_id(req.url()),
+                            get_object_id(req.url()),
+                            e
+                        );
+                        span.set_tag(|| StdTag::http_status_code(500));
+                        make_object_stream_response(Status::InternalServerError, None, Err(e))
                     }
                 };
                 Ok(response)
@@ -277,6 +455,46 @@ impl HandleRequest for GetObject {
     }
 }
 
+struct ListObjectVersions(Server);
+impl HandleRequest for ListObjectVersions {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/buckets/*/objects/*/versions";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<Vec<ObjectVersionEntry>>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+        let object_id = get_object_id(req.url());
+        let future = self
+            .0
+            .client
+            .request(bucket_id)
+            .list_versions(object_id)
+            .then(|result| Ok(make_object_versions_response(track!(result))));
+        Box::new(future)
+    }
+}
+
+// 保持されているバージョンが一つもない場合には、オブジェクトが存在しないものとして扱う
+fn make_object_versions_response(
+    result: Result<Vec<ObjectVersionEntry>>,
+) -> Res<HttpResult<Vec<ObjectVersionEntry>>> {
+    match result {
+        Ok(ref versions) if versions.is_empty() => {
+            make_json_response(Status::NotFound, Err(not_found()))
+        }
+        Ok(versions) => make_json_response(Status::Ok, Ok(versions)),
+        Err(ref e) if *e.kind() == ErrorKind::NotFound => {
+            make_json_response(Status::NotFound, Err(not_found()))
+        }
+        Err(e) => make_json_response(Status::InternalServerError, Err(e)),
+    }
+}
+
 struct HeadObject(Server);
 impl HandleRequest for HeadObject {
     const METHOD: &'static str = "HEAD";
@@ -306,24 +524,31 @@ impl HandleRequest for HeadObject {
         let logger = self.0.logger.clone();
         let expect = try_badarg!(get_expect(&req.header()));
         let deadline = try_badarg!(get_deadline(&req.url()));
+        let consistency = try_badarg!(get_read_consistency(&req.url()));
         let future = self
             .0
             .client
             .request(bucket_id)
             .deadline(deadline)
             .expect(expect)
+            .consistency(consistency)
             .span(&span)
-            .head(object_id)
+            .head_with_user_metadata(object_id)
             .then(move |result| {
                 let response = match track!(result) {
                     Ok(None) => {
                         span.set_tag(|| StdTag::http_status_code(404));
                         make_object_response(Status::NotFound, None, Err(not_found()))
                     }
-                    Ok(Some(version)) => {
+                    Ok(Some((version, user_metadata))) => {
                         span.set_tag(|| Tag::new("object.version", version.0 as i64));
                         span.set_tag(|| StdTag::http_status_code(200));
-                        make_object_response(Status::Ok, Some(version), Ok(Vec::new()))
+                        make_object_response_with_user_metadata(
+                            Status::Ok,
+                            Some(version),
+                            &user_metadata,
+                            Ok(Vec::new()),
+                        )
                     }
                     // Err(ref e) if *e.kind() == frugalos::ErrorKind::NotFound => {
                     //     span.set_tag(|| StdTag::http_status_code(404));
@@ -488,62 +713,17 @@ impl HandleRequest for PutObject {
     const METHOD: &'static str = "PUT";
     const PATH: &'static str = "/v1/buckets/*/objects/*";
 
-    type ReqBody = Vec<u8>;
+    type ReqBody = RequestBody;
     type ResBody = HttpResult<Vec<u8>>;
-    type Decoder = BodyDecoder<RemainingBytesDecoder>;
+    type Decoder = BodyDecoder<ChunkedBytesDecoder>;
     type Encoder = BodyEncoder<ObjectResultEncoder>;
     type Reply = Reply<Self::ResBody>;
 
-    fn handle_request_head(&self, req: &Req<()>) -> Option<Res<Self::ResBody>> {
-        // TODO: decoderにもチェックを入れる
-        let n: Option<Option<usize>> = req.header().parse_field("content-length").ok();
-        if let Some(Some(n)) = n {
-            if n > MAX_PUT_OBJECT_SIZE {
-                let count = self.0.large_object_count.fetch_add(1, Ordering::SeqCst);
-                warn!(
-                    self.0.logger,
-                    "Too large body size ({} bytes): {}",
-                    n,
-                    req.url()
-                );
-                if count != 0 {
-                    // 最初だけはオブジェクトをダンプしたいので即座にエラーにはしない
-                    return Some(make_object_response(
-                        Status::BadRequest,
-                        None,
-                        Err(track!(ErrorKind::InvalidInput.cause("Too large body size")).into()),
-                    ));
-                }
-            }
-        }
-        None
-    }
-
     fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
         let bucket_id = get_bucket_id(req.url());
         let object_id = get_object_id(req.url());
-        let (req, content) = req.take_body();
-        if content.len() > MAX_PUT_OBJECT_SIZE {
-            warn!(
-                self.0.logger,
-                "Too large body size ({} bytes; dumped): {}",
-                content.len(),
-                req.url()
-            );
-            {
-                use std::fs::File;
-                use std::io::Write;
-
-                // TODO: 調査用の一時コード
-                let _ = File::create("/tmp/frugalos.huge.object.dump")
-                    .and_then(|mut f| f.write_all(&content));
-            }
-            return Box::new(futures::finished(make_object_response(
-                Status::BadRequest,
-                None,
-                Err(track!(ErrorKind::InvalidInput.cause("Too large body size")).into()),
-            )));
-        }
+        let (req, body) = req.take_body();
+        let size = body.len();
 
         let client_span = SpanContext::extract_from_http_header(&TraceHeader(req.header()))
             .ok()
@@ -554,20 +734,86 @@ impl HandleRequest for PutObject {
         span.set_tag(|| StdTag::http_method("PUT"));
         span.set_tag(|| Tag::new("bucket.id", bucket_id.clone()));
         span.set_tag(|| Tag::new("object.id", object_id.clone()));
-        span.set_tag(|| Tag::new("object.size", content.len().to_string()));
+        span.set_tag(|| Tag::new("object.size", size.to_string()));
 
         // TODO: deadline and expect
         let logger = self.0.logger.clone();
         let expect = try_badarg!(get_expect(&req.header()));
         let deadline = try_badarg!(get_deadline(&req.url()));
-        let future = self
-            .0
-            .client
-            .request(bucket_id)
+        let user_metadata = try_badarg!(get_user_metadata(&req.header(), USER_METADATA_PREFIX));
+        let ttl = try_badarg!(get_ttl(&req.url()));
+        let copy_source = try_badarg!(get_source_object(&req.header(), COPY_SOURCE_HEADER));
+        let rename_source = try_badarg!(get_source_object(&req.header(), RENAME_SOURCE_HEADER));
+        let mut request = self.0.client.request(bucket_id.clone());
+        request
             .deadline(deadline)
             .expect(expect)
-            .span(&span)
-            .put(object_id, content)
+            .user_metadata(user_metadata)
+            .span(&span);
+        if let Some(ttl) = ttl {
+            request.ttl(ttl);
+        }
+
+        // サーバ側での複製ないし名前変更 (中身はリクエストボディではなく、指定のオブジェクトから引き継がれる)
+        if copy_source.is_some() || rename_source.is_some() {
+            try_badarg!(validate_source_request(
+                &bucket_id,
+                size,
+                &copy_source,
+                &rename_source
+            ));
+            let future = match (copy_source, rename_source) {
+                (Some((source_bucket_id, source_id)), _) => {
+                    span.set_tag(|| Tag::new("copy.source", source_id.clone()));
+                    request.copy(source_bucket_id, source_id, object_id)
+                }
+                (None, Some((_, source_id))) => {
+                    span.set_tag(|| Tag::new("rename.source", source_id.clone()));
+                    request.rename(source_id, object_id)
+                }
+                (None, None) => unreachable!(),
+            };
+            let future = future.then(move |result| {
+                let response = match track!(result) {
+                    Ok(Some((version, created))) => {
+                        let status = if created { Status::Created } else { Status::Ok };
+                        span.set_tag(|| Tag::new("object.version", version.0 as i64));
+                        span.set_tag(|| StdTag::http_status_code(status.code()));
+                        make_object_response(status, Some(version), Ok(Vec::new()))
+                    }
+                    Ok(None) => {
+                        span.set_tag(|| StdTag::http_status_code(404));
+                        make_object_response(Status::NotFound, None, Err(not_found()))
+                    }
+                    Err(e) => match *e.kind() {
+                        ErrorKind::Unexpected(version) => {
+                            span.set_tag(|| StdTag::http_status_code(412));
+                            make_object_response(Status::PreconditionFailed, version, Err(e))
+                        }
+                        ErrorKind::InvalidInput | ErrorKind::NotFound => {
+                            span.set_tag(|| StdTag::http_status_code(400));
+                            make_object_response(Status::BadRequest, None, Err(e))
+                        }
+                        _ => {
+                            warn!(
+                                logger,
+                                "Cannot copy object (bucket={:?}, object={:?}): {}",
+                                get_bucket_id(req.url()),
+                                get_object_id(req.url()),
+                                e
+                            );
+                            span.set_tag(|| StdTag::http_status_code(500));
+                            make_object_response(Status::InternalServerError, None, Err(e))
+                        }
+                    },
+                };
+                Ok(response)
+            });
+            return Box::new(future);
+        }
+
+        let future = request
+            .put_stream(object_id, body.into_stream())
             .then(move |result| {
                 let response = match track!(result) {
                     Ok((version, created)) => {
@@ -599,53 +845,588 @@ impl HandleRequest for PutObject {
     }
 }
 
-struct JemallocStats;
-impl HandleRequest for JemallocStats {
-    const METHOD: &'static str = "GET";
-    const PATH: &'static str = "/jemalloc/stats";
+struct InitiateUpload(Server);
+impl HandleRequest for InitiateUpload {
+    const METHOD: &'static str = "POST";
+    const PATH: &'static str = "/v1/buckets/*/objects/*/uploads";
 
     type ReqBody = ();
-    type ResBody = Vec<u8>;
+    type ResBody = HttpResult<MultipartUpload>;
     type Decoder = BodyDecoder<NullDecoder>;
-    type Encoder = BodyEncoder<BytesEncoder<Vec<u8>>>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
     type Reply = Reply<Self::ResBody>;
 
-    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
-        use jemalloc_ctl;
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+        let object_id = get_object_id(req.url());
 
-        // many statistics are cached and only updated when the epoch is advanced.
-        let _ = jemalloc_ctl::epoch();
+        let client_span = SpanContext::extract_from_http_header(&TraceHeader(req.header()))
+            .ok()
+            .and_then(|c| c);
+        let mut span = self
+            .0
+            .tracer(|t| t.span("initiate_upload").child_of(&client_span).start());
+        span.set_tag(|| StdTag::http_method("POST"));
+        span.set_tag(|| Tag::new("bucket.id", bucket_id.clone()));
+        span.set_tag(|| Tag::new("object.id", object_id.clone()));
 
-        let mut buf = Vec::new();
-        let _ = jemalloc_ctl::stats_print::stats_print(&mut buf, Default::default());
-        Box::new(futures::finished(Res::new(Status::Ok, buf)))
+        let logger = self.0.logger.clone();
+        let deadline = try_badarg!(get_deadline(&req.url()));
+        let future = self
+            .0
+            .client
+            .request(bucket_id)
+            .deadline(deadline)
+            .span(&span)
+            .initiate_upload(object_id)
+            .then(move |result| {
+                let response = match track!(result) {
+                    Ok(upload_id) => {
+                        span.set_tag(|| Tag::new("upload.id", upload_id.to_string()));
+                        span.set_tag(|| StdTag::http_status_code(200));
+                        let upload = MultipartUpload {
+                            upload_id: upload_id.to_string(),
+                        };
+                        make_json_response(Status::Ok, Ok(upload))
+                    }
+                    Err(e) => {
+                        warn!(
+                            logger,
+                            "Cannot initiate upload (bucket={:?}, object={:?}): {}",
+                            get_bucket_id(req.url()),
+                            get_object_id(req.url()),
+                            e
+                        );
+                        span.set_tag(|| StdTag::http_status_code(500));
+                        make_json_response(Status::InternalServerError, Err(e))
+                    }
+                };
+                Ok(response)
+            });
+        Box::new(future)
     }
 }
 
-pub fn spawn_report_spans_thread(rx: SpanReceiver) {
-    let reporter = track_try_unwrap!(JaegerCompactReporter::new("frugalos"));
-    thread::spawn(move || {
-        while let Ok(span) = rx.recv() {
-            let _ = reporter.report(&[span]);
-        }
-    });
-}
+struct UploadPart(Server);
+impl HandleRequest for UploadPart {
+    const METHOD: &'static str = "PUT";
+    const PATH: &'static str = "/v1/buckets/*/objects/*/uploads/*/parts/*";
 
-#[derive(Debug, Clone, Copy, Serialize)]
-struct Segment {
-    id: u16,
-}
+    type ReqBody = Vec<u8>;
+    type ResBody = HttpResult<Vec<u8>>;
+    type Decoder = BodyDecoder<RemainingBytesDecoder>;
+    type Encoder = BodyEncoder<ObjectResultEncoder>;
+    type Reply = Reply<Self::ResBody>;
 
-fn get_bucket_id(url: &Url) -> String {
-    url.path_segments()
-        .expect("Never fails")
-        .nth(2)
-        .expect("Never fails")
-        .to_string()
-}
+    fn handle_request_head(&self, req: &Req<()>) -> Option<Res<Self::ResBody>> {
+        let n: Option<Option<usize>> = req.header().parse_field("content-length").ok();
+        if let Some(Some(n)) = n {
+            if n > MAX_UPLOAD_PART_SIZE {
+                return Some(make_object_response(
+                    Status::BadRequest,
+                    None,
+                    Err(track!(ErrorKind::InvalidInput.cause("Too large part size")).into()),
+                ));
+            }
+        }
+        None
+    }
 
-fn get_object_id(url: &Url) -> String {
-    url.path_segments()
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+        let object_id = get_object_id(req.url());
+        let upload_id = try_badarg!(get_upload_id(req.url()));
+        let part_number = try_badarg!(get_part_number(req.url()));
+        let (req, content) = req.take_body();
+        if content.len() > MAX_UPLOAD_PART_SIZE {
+            return Box::new(futures::finished(make_object_response(
+                Status::BadRequest,
+                None,
+                Err(track!(ErrorKind::InvalidInput.cause("Too large part size")).into()),
+            )));
+        }
+
+        let client_span = SpanContext::extract_from_http_header(&TraceHeader(req.header()))
+            .ok()
+            .and_then(|c| c);
+        let mut span = self
+            .0
+            .tracer(|t| t.span("upload_part").child_of(&client_span).start());
+        span.set_tag(|| StdTag::http_method("PUT"));
+        span.set_tag(|| Tag::new("bucket.id", bucket_id.clone()));
+        span.set_tag(|| Tag::new("object.id", object_id.clone()));
+        span.set_tag(|| Tag::new("upload.id", upload_id.to_string()));
+        span.set_tag(|| Tag::new("part.number", i64::from(part_number)));
+        span.set_tag(|| Tag::new("object.size", content.len().to_string()));
+
+        let logger = self.0.logger.clone();
+        let deadline = try_badarg!(get_deadline(&req.url()));
+        let future = self
+            .0
+            .client
+            .request(bucket_id)
+            .deadline(deadline)
+            .span(&span)
+            .upload_part(object_id, upload_id, part_number, content)
+            .then(move |result| {
+                let response = match track!(result) {
+                    Ok(None) => {
+                        span.set_tag(|| StdTag::http_status_code(404));
+                        make_object_response(Status::NotFound, None, Err(not_found()))
+                    }
+                    Ok(Some(version)) => {
+                        span.set_tag(|| Tag::new("object.version", version.0 as i64));
+                        span.set_tag(|| StdTag::http_status_code(200));
+                        make_object_response(Status::Ok, Some(version), Ok(Vec::new()))
+                    }
+                    Err(e) => {
+                        if *e.kind() == ErrorKind::InvalidInput {
+                            span.set_tag(|| StdTag::http_status_code(400));
+                            make_object_response(Status::BadRequest, None, Err(e))
+                        } else {
+                            warn!(
+                                logger,
+                                "Cannot upload part (bucket={:?}, object={:?}, upload={}): {}",
+                                get_bucket_id(req.url()),
+                                get_object_id(req.url()),
+                                upload_id,
+                                e
+                            );
+                            span.set_tag(|| StdTag::http_status_code(500));
+                            make_object_response(Status::InternalServerError, None, Err(e))
+                        }
+                    }
+                };
+                Ok(response)
+            });
+        Box::new(future)
+    }
+}
+
+struct CompleteUpload(Server);
+impl HandleRequest for CompleteUpload {
+    const METHOD: &'static str = "POST";
+    const PATH: &'static str = "/v1/buckets/*/objects/*/uploads/*";
+
+    type ReqBody = CompleteUploadRequest;
+    type ResBody = HttpResult<Vec<u8>>;
+    type Decoder = BodyDecoder<JsonDecoder<Self::ReqBody>>;
+    type Encoder = BodyEncoder<ObjectResultEncoder>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+        let object_id = get_object_id(req.url());
+        let upload_id = try_badarg!(get_upload_id(req.url()));
+        let (req, body) = req.take_body();
+
+        let client_span = SpanContext::extract_from_http_header(&TraceHeader(req.header()))
+            .ok()
+            .and_then(|c| c);
+        let mut span = self
+            .0
+            .tracer(|t| t.span("complete_upload").child_of(&client_span).start());
+        span.set_tag(|| StdTag::http_method("POST"));
+        span.set_tag(|| Tag::new("bucket.id", bucket_id.clone()));
+        span.set_tag(|| Tag::new("object.id", object_id.clone()));
+        span.set_tag(|| Tag::new("upload.id", upload_id.to_string()));
+        span.set_tag(|| Tag::new("parts", body.parts.len() as i64));
+
+        let logger = self.0.logger.clone();
+        let expect = try_badarg!(get_expect(&req.header()));
+        let deadline = try_badarg!(get_deadline(&req.url()));
+        let mut user_metadata = try_badarg!(get_user_metadata(&req.header(), USER_METADATA_PREFIX));
+
+        // リクエストの`Content-Type`はボディ(JSON)に対するものなので、メタデータには含めない
+        user_metadata.remove(CONTENT_TYPE);
+        let ttl = try_badarg!(get_ttl(&req.url()));
+        let mut request = self.0.client.request(bucket_id);
+        request
+            .deadline(deadline)
+            .expect(expect)
+            .user_metadata(user_metadata)
+            .span(&span);
+        if let Some(ttl) = ttl {
+            request.ttl(ttl);
+        }
+        let future = request
+            .complete_upload(object_id, upload_id, body.parts)
+            .then(move |result| {
+                let response = match track!(result) {
+                    Ok(None) => {
+                        span.set_tag(|| StdTag::http_status_code(404));
+                        make_object_response(Status::NotFound, None, Err(not_found()))
+                    }
+                    Ok(Some((version, created))) => {
+                        let status = if created { Status::Created } else { Status::Ok };
+                        span.set_tag(|| Tag::new("object.version", version.0 as i64));
+                        span.set_tag(|| StdTag::http_status_code(status.code()));
+                        make_object_response(status, Some(version), Ok(Vec::new()))
+                    }
+                    Err(e) => match *e.kind() {
+                        ErrorKind::Unexpected(version) => {
+                            span.set_tag(|| StdTag::http_status_code(412));
+                            make_object_response(Status::PreconditionFailed, version, Err(e))
+                        }
+                        ErrorKind::InvalidInput => {
+                            span.set_tag(|| StdTag::http_status_code(400));
+                            make_object_response(Status::BadRequest, None, Err(e))
+                        }
+                        _ => {
+                            warn!(
+                                logger,
+                                "Cannot complete upload (bucket={:?}, object={:?}, upload={}): {}",
+                                get_bucket_id(req.url()),
+                                get_object_id(req.url()),
+                                upload_id,
+                                e
+                            );
+                            span.set_tag(|| StdTag::http_status_code(500));
+                            make_object_response(Status::InternalServerError, None, Err(e))
+                        }
+                    },
+                };
+                Ok(response)
+            });
+        Box::new(future)
+    }
+}
+
+struct AbortUpload(Server);
+impl HandleRequest for AbortUpload {
+    const METHOD: &'static str = "DELETE";
+    const PATH: &'static str = "/v1/buckets/*/objects/*/uploads/*";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<Vec<u8>>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<ObjectResultEncoder>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+        let object_id = get_object_id(req.url());
+        let upload_id = try_badarg!(get_upload_id(req.url()));
+
+        let client_span = SpanContext::extract_from_http_header(&TraceHeader(req.header()))
+            .ok()
+            .and_then(|c| c);
+        let mut span = self
+            .0
+            .tracer(|t| t.span("abort_upload").child_of(&client_span).start());
+        span.set_tag(|| StdTag::http_method("DELETE"));
+        span.set_tag(|| Tag::new("bucket.id", bucket_id.clone()));
+        span.set_tag(|| Tag::new("object.id", object_id.clone()));
+        span.set_tag(|| Tag::new("upload.id", upload_id.to_string()));
+
+        let logger = self.0.logger.clone();
+        let deadline = try_badarg!(get_deadline(&req.url()));
+        let future = self
+            .0
+            .client
+            .request(bucket_id)
+            .deadline(deadline)
+            .span(&span)
+            .abort_upload(object_id, upload_id)
+            .then(move |result| {
+                let response = match track!(result) {
+                    Ok(false) => {
+                        span.set_tag(|| StdTag::http_status_code(404));
+                        make_object_response(Status::NotFound, None, Err(not_found()))
+                    }
+                    Ok(true) => {
+                        span.set_tag(|| StdTag::http_status_code(200));
+                        make_object_response(Status::Ok, None, Ok(Vec::new()))
+                    }
+                    Err(e) => {
+                        warn!(
+                            logger,
+                            "Cannot abort upload (bucket={:?}, object={:?}, upload={}): {}",
+                            get_bucket_id(req.url()),
+                            get_object_id(req.url()),
+                            upload_id,
+                            e
+                        );
+                        span.set_tag(|| StdTag::http_status_code(500));
+                        make_object_response(Status::InternalServerError, None, Err(e))
+                    }
+                };
+                Ok(response)
+            });
+        Box::new(future)
+    }
+}
+
+struct Batch(Server);
+impl HandleRequest for Batch {
+    const METHOD: &'static str = "POST";
+    const PATH: &'static str = "/v1/buckets/*/batch";
+
+    type ReqBody = BatchRequest;
+    type ResBody = HttpResult<BatchResponse>;
+    type Decoder = BodyDecoder<BatchRequestDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+        let (req, body) = req.take_body();
+
+        let client_span = SpanContext::extract_from_http_header(&TraceHeader(req.header()))
+            .ok()
+            .and_then(|c| c);
+        let mut span = self
+            .0
+            .tracer(|t| t.span("batch").child_of(&client_span).start());
+        span.set_tag(|| StdTag::http_method("POST"));
+        span.set_tag(|| Tag::new("bucket.id", bucket_id.clone()));
+        span.set_tag(|| Tag::new("operations", body.operations.len() as i64));
+
+        let logger = self.0.logger.clone();
+        let deadline = try_badarg!(get_deadline(&req.url()));
+        let operations = try_badarg!(get_batch_operations(body));
+        let mut request = self.0.client.request(bucket_id.clone());
+        request.deadline(deadline).span(&span);
+        let future = request.batch(operations).then(move |result| {
+            let response = match track!(result) {
+                Ok(results) => {
+                    span.set_tag(|| StdTag::http_status_code(200));
+                    let results = results.into_iter().map(make_batch_response_item).collect();
+                    make_json_response(Status::Ok, Ok(BatchResponse { results }))
+                }
+                Err(ref e) if *e.kind() == ErrorKind::NotFound => {
+                    span.set_tag(|| StdTag::http_status_code(404));
+                    make_json_response(Status::NotFound, Err(not_found()))
+                }
+                Err(e) => {
+                    warn!(
+                        logger,
+                        "Cannot execute batch operations (bucket={:?}): {}", bucket_id, e
+                    );
+                    span.set_tag(|| StdTag::http_status_code(500));
+                    make_json_response(Status::InternalServerError, Err(e))
+                }
+            };
+            Ok(response)
+        });
+        Box::new(future)
+    }
+}
+
+struct Transaction(Server);
+impl HandleRequest for Transaction {
+    const METHOD: &'static str = "POST";
+    const PATH: &'static str = "/v1/buckets/*/transaction";
+
+    type ReqBody = BatchRequest;
+    type ResBody = HttpResult<BatchResponse>;
+    type Decoder = BodyDecoder<BatchRequestDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+        let (req, body) = req.take_body();
+
+        let client_span = SpanContext::extract_from_http_header(&TraceHeader(req.header()))
+            .ok()
+            .and_then(|c| c);
+        let mut span = self
+            .0
+            .tracer(|t| t.span("transaction").child_of(&client_span).start());
+        span.set_tag(|| StdTag::http_method("POST"));
+        span.set_tag(|| Tag::new("bucket.id", bucket_id.clone()));
+        span.set_tag(|| Tag::new("operations", body.operations.len() as i64));
+
+        let logger = self.0.logger.clone();
+        let deadline = try_badarg!(get_deadline(&req.url()));
+        let operations = try_badarg!(get_transaction_operations(body));
+        let mut request = self.0.client.request(bucket_id.clone());
+        request.deadline(deadline).span(&span);
+        let future = request.transaction(operations).then(move |result| {
+            let response = match track!(result) {
+                Ok(results) => {
+                    span.set_tag(|| StdTag::http_status_code(200));
+                    let results = results
+                        .into_iter()
+                        .map(|result| make_batch_response_item(Ok(result)))
+                        .collect();
+                    make_json_response(Status::Ok, Ok(BatchResponse { results }))
+                }
+                Err(ref e) if *e.kind() == ErrorKind::NotFound => {
+                    span.set_tag(|| StdTag::http_status_code(404));
+                    make_json_response(Status::NotFound, Err(not_found()))
+                }
+                Err(e) => match *e.kind() {
+                    ErrorKind::Unexpected(_) => {
+                        span.set_tag(|| StdTag::http_status_code(412));
+                        make_json_response(Status::PreconditionFailed, Err(e))
+                    }
+                    ErrorKind::InvalidInput => {
+                        span.set_tag(|| StdTag::http_status_code(400));
+                        make_json_response(Status::BadRequest, Err(e))
+                    }
+                    _ => {
+                        warn!(
+                            logger,
+                            "Cannot execute transaction (bucket={:?}): {}", bucket_id, e
+                        );
+                        span.set_tag(|| StdTag::http_status_code(500));
+                        make_json_response(Status::InternalServerError, Err(e))
+                    }
+                },
+            };
+            Ok(response)
+        });
+        Box::new(future)
+    }
+}
+
+struct GetScrubStatus(Server);
+impl HandleRequest for GetScrubStatus {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/scrub";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<Vec<ScrubStatus>>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        Box::new(futures::finished(make_scrub_response(
+            &self.0.scrubbers,
+            false,
+        )))
+    }
+}
+
+struct StartScrub(Server);
+impl HandleRequest for StartScrub {
+    const METHOD: &'static str = "POST";
+    const PATH: &'static str = "/v1/scrub";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<Vec<ScrubStatus>>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        info!(self.0.logger, "Scrubbing is requested");
+        Box::new(futures::finished(make_scrub_response(
+            &self.0.scrubbers,
+            true,
+        )))
+    }
+}
+
+// `start`が`true`の場合には、検査の開始を要求した上で、全てのノードの検査の状況を返す
+fn make_scrub_response(
+    scrubbers: &ScrubberRegistry,
+    start: bool,
+) -> Res<HttpResult<Vec<ScrubStatus>>> {
+    if start {
+        scrubbers.request_scrub();
+        make_json_response(Status::Accepted, Ok(scrubbers.statuses()))
+    } else {
+        make_json_response(Status::Ok, Ok(scrubbers.statuses()))
+    }
+}
+
+struct GetRepairSettings(Server);
+impl HandleRequest for GetRepairSettings {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/repair/settings";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<RepairSettings>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        let settings = self.0.repair_controller.settings();
+        Box::new(futures::finished(make_json_response(
+            Status::Ok,
+            Ok(settings),
+        )))
+    }
+}
+
+struct PutRepairSettings(Server);
+impl HandleRequest for PutRepairSettings {
+    const METHOD: &'static str = "PUT";
+    const PATH: &'static str = "/v1/repair/settings";
+
+    type ReqBody = RepairSettings;
+    type ResBody = HttpResult<RepairSettings>;
+    type Decoder = BodyDecoder<JsonDecoder<Self::ReqBody>>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let settings = req.into_body();
+        try_badarg!(self
+            .0
+            .repair_controller
+            .update(settings)
+            .map_err(Error::from));
+        info!(self.0.logger, "Repair settings are updated: {:?}", settings);
+        Box::new(futures::finished(make_json_response(
+            Status::Ok,
+            Ok(settings),
+        )))
+    }
+}
+
+struct JemallocStats;
+impl HandleRequest for JemallocStats {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/jemalloc/stats";
+
+    type ReqBody = ();
+    type ResBody = Vec<u8>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<BytesEncoder<Vec<u8>>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        use jemalloc_ctl;
+
+        // many statistics are cached and only updated when the epoch is advanced.
+        let _ = jemalloc_ctl::epoch();
+
+        let mut buf = Vec::new();
+        let _ = jemalloc_ctl::stats_print::stats_print(&mut buf, Default::default());
+        Box::new(futures::finished(Res::new(Status::Ok, buf)))
+    }
+}
+
+pub fn spawn_report_spans_thread(rx: SpanReceiver) {
+    let reporter = track_try_unwrap!(JaegerCompactReporter::new("frugalos"));
+    thread::spawn(move || {
+        while let Ok(span) = rx.recv() {
+            let _ = reporter.report(&[span]);
+        }
+    });
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+struct Segment {
+    id: u16,
+}
+
+fn get_bucket_id(url: &Url) -> String {
+    url.path_segments()
+        .expect("Never fails")
+        .nth(2)
+        .expect("Never fails")
+        .to_string()
+}
+
+fn get_object_id(url: &Url) -> String {
+    url.path_segments()
         .expect("Never fails")
         .nth(4)
         .expect("Never fails")
@@ -660,6 +1441,28 @@ fn get_object_prefix(url: &Url) -> String {
         .to_string()
 }
 
+fn get_upload_id(url: &Url) -> Result<UploadId> {
+    let id = track!(url
+        .path_segments()
+        .expect("Never fails")
+        .nth(6)
+        .expect("Never fails")
+        .parse()
+        .map_err(Error::from))?;
+    Ok(id)
+}
+
+fn get_part_number(url: &Url) -> Result<u32> {
+    let n = track!(url
+        .path_segments()
+        .expect("Never fails")
+        .nth(8)
+        .expect("Never fails")
+        .parse()
+        .map_err(Error::from))?;
+    Ok(n)
+}
+
 fn get_segment_num(url: &Url) -> Result<u16> {
     let n = track!(url
         .path_segments()
@@ -671,27 +1474,316 @@ fn get_segment_num(url: &Url) -> Result<u16> {
     Ok(n)
 }
 
+// `/{bucket_id}/{object_id}`形式で指定された、複製ないし名前変更の対象オブジェクトを取得する
+fn get_source_object(header: &Header, name: &str) -> Result<Option<(String, ObjectId)>> {
+    for field in header.fields() {
+        if !field.name().eq_ignore_ascii_case(name) {
+            continue;
+        }
+        let value = field.value();
+        let mut tokens = value.trim_start_matches('/').splitn(2, '/');
+        let bucket_id = tokens.next().unwrap_or("");
+        let object_id = tokens.next().unwrap_or("");
+        track_assert!(
+            !bucket_id.is_empty() && !object_id.is_empty() && !object_id.contains('/'),
+            ErrorKind::InvalidInput,
+            "Malformed {} header: {:?}",
+            name,
+            value
+        );
+        return Ok(Some((bucket_id.to_owned(), object_id.to_owned())));
+    }
+    Ok(None)
+}
+
+fn validate_source_request(
+    bucket_id: &str,
+    body_size: u64,
+    copy_source: &Option<(String, ObjectId)>,
+    rename_source: &Option<(String, ObjectId)>,
+) -> Result<()> {
+    track_assert!(
+        copy_source.is_none() || rename_source.is_none(),
+        ErrorKind::InvalidInput,
+        "{} and {} cannot be specified at the same time",
+        COPY_SOURCE_HEADER,
+        RENAME_SOURCE_HEADER
+    );
+    track_assert_eq!(
+        body_size,
+        0,
+        ErrorKind::InvalidInput,
+        "Request body must be empty when copying or renaming an object"
+    );
+    if let Some((ref source_bucket_id, _)) = *rename_source {
+        track_assert!(
+            source_bucket_id == bucket_id,
+            ErrorKind::InvalidInput,
+            "Objects cannot be renamed across buckets: from={:?}, to={:?}",
+            source_bucket_id,
+            bucket_id
+        );
+    }
+    Ok(())
+}
+
 fn get_expect(header: &Header) -> Result<Expect> {
     for field in header.fields() {
         if field.name().eq_ignore_ascii_case("if-match") {
-            if field.value() == "*" {
-                return Ok(Expect::Any);
-            } else {
-                let versions = track!(parse_etag_values(field.value()))?;
-                return Ok(Expect::IfMatch(versions));
-            }
+            return track!(parse_if_match(field.value()));
         } else if field.name().eq_ignore_ascii_case("if-none-match") {
-            if field.value() == "*" {
-                return Ok(Expect::None);
-            } else {
-                let versions = track!(parse_etag_values(field.value()))?;
-                return Ok(Expect::IfNoneMatch(versions));
-            }
+            return track!(parse_if_none_match(field.value()));
         }
     }
     Ok(Expect::Any)
 }
 
+fn parse_if_match(value: &str) -> Result<Expect> {
+    if value == "*" {
+        Ok(Expect::Any)
+    } else {
+        let versions = track!(parse_etag_values(value))?;
+        Ok(Expect::IfMatch(versions))
+    }
+}
+
+fn parse_if_none_match(value: &str) -> Result<Expect> {
+    if value == "*" {
+        Ok(Expect::None)
+    } else {
+        let versions = track!(parse_etag_values(value))?;
+        Ok(Expect::IfNoneMatch(versions))
+    }
+}
+
+/// バッチ操作の要求を、クライアントに渡す操作群に変換する。
+fn get_batch_operations(request: BatchRequest) -> Result<Vec<BatchOperation>> {
+    track_assert!(
+        request.operations.len() <= MAX_BATCH_OPERATIONS,
+        ErrorKind::InvalidInput,
+        "Too many operations: {} (the maximum is {})",
+        request.operations.len(),
+        MAX_BATCH_OPERATIONS
+    );
+    let content_size = request
+        .operations
+        .iter()
+        .filter_map(|operation| operation.content.as_ref())
+        .map(|content| content.len() / 2)
+        .sum::<usize>();
+    track_assert!(
+        content_size <= MAX_BATCH_TOTAL_CONTENT_SIZE,
+        ErrorKind::InvalidInput,
+        "Too large contents: {} bytes in total (the maximum is {} bytes)",
+        content_size,
+        MAX_BATCH_TOTAL_CONTENT_SIZE
+    );
+    request
+        .operations
+        .into_iter()
+        .map(|operation| track!(to_batch_operation(operation)))
+        .collect()
+}
+
+/// トランザクションの要求を、クライアントに渡す操作群に変換する。
+///
+/// トランザクションに含められるのは`put`と`delete`のみ。
+fn get_transaction_operations(request: BatchRequest) -> Result<Vec<BatchOperation>> {
+    track_assert!(
+        !request.operations.is_empty(),
+        ErrorKind::InvalidInput,
+        "Empty transaction"
+    );
+    for operation in &request.operations {
+        track_assert!(
+            operation.op == BatchOperationKind::Put || operation.op == BatchOperationKind::Delete,
+            ErrorKind::InvalidInput,
+            "Only `put` and `delete` are allowed in a transaction: object_id={:?}",
+            operation.object_id
+        );
+    }
+    track!(get_batch_operations(request))
+}
+
+fn to_batch_operation(operation: BatchRequestOperation) -> Result<BatchOperation> {
+    let object_id = operation.object_id;
+    track_assert!(
+        !is_internal_object_id(&object_id),
+        ErrorKind::InvalidInput,
+        "Reserved object ID: {:?}",
+        object_id
+    );
+    let expect = if let Some(ref value) = operation.if_match {
+        track!(parse_if_match(value))?
+    } else if let Some(ref value) = operation.if_none_match {
+        track!(parse_if_none_match(value))?
+    } else {
+        Expect::Any
+    };
+    track_assert!(
+        operation.op == BatchOperationKind::Put
+            || (operation.content.is_none() && operation.ttl.is_none()),
+        ErrorKind::InvalidInput,
+        "`content` and `ttl` are only allowed for `put`: object_id={:?}",
+        object_id
+    );
+    if let Some(ttl) = operation.ttl {
+        track!(validate_ttl(ttl), "object_id={:?}", object_id)?;
+    }
+    Ok(match operation.op {
+        BatchOperationKind::Get => BatchOperation::Get { object_id, expect },
+        BatchOperationKind::Head => BatchOperation::Head { object_id, expect },
+        BatchOperationKind::Put => {
+            let content = track_assert_some!(
+                operation.content,
+                ErrorKind::InvalidInput,
+                "`content` is required for `put`: object_id={:?}",
+                object_id
+            );
+            let data = track!(decode_hex(&content))?;
+            let attributes = ObjectAttributes {
+                user_metadata: UserMetadata::new(),
+                ttl: operation.ttl,
+            };
+            BatchOperation::Put {
+                object_id,
+                data,
+                attributes,
+                expect,
+            }
+        }
+        BatchOperationKind::Delete => BatchOperation::Delete { object_id, expect },
+    })
+}
+
+/// バッチ内の操作の結果を、単独の操作として実行された場合と同様のステータスコードと共に返す。
+fn make_batch_response_item(result: Result<BatchResult>) -> BatchResponseItem {
+    let found = |version: ObjectVersion| BatchResponseItem {
+        status: 200,
+        version: Some(version.0),
+        ..BatchResponseItem::default()
+    };
+    let missing = BatchResponseItem {
+        status: 404,
+        ..BatchResponseItem::default()
+    };
+    let non_empty = |user_metadata: UserMetadata| {
+        if user_metadata.is_empty() {
+            None
+        } else {
+            Some(user_metadata)
+        }
+    };
+    match result {
+        Ok(BatchResult::Get(Some((object, user_metadata)))) => BatchResponseItem {
+            content: Some(encode_hex(&object.content)),
+            metadata: non_empty(user_metadata),
+            ..found(object.version)
+        },
+        Ok(BatchResult::Head(Some((version, user_metadata)))) => BatchResponseItem {
+            metadata: non_empty(user_metadata),
+            ..found(version)
+        },
+        Ok(BatchResult::Delete(Some(version))) => found(version),
+        Ok(BatchResult::Get(None))
+        | Ok(BatchResult::Head(None))
+        | Ok(BatchResult::Delete(None)) => missing,
+        Ok(BatchResult::Put(version, created)) => BatchResponseItem {
+            status: if created { 201 } else { 200 },
+            ..found(version)
+        },
+        Err(e) => {
+            let (status, version) = match *e.kind() {
+                ErrorKind::Unexpected(version) => (412, version),
+                ErrorKind::InvalidInput => (400, None),
+                _ => (500, None),
+            };
+            BatchResponseItem {
+                status,
+                version: version.map(|v| v.0),
+                error: Some(e),
+                ..BatchResponseItem::default()
+            }
+        }
+    }
+}
+
+pub fn get_ranges(header: &Header) -> Option<Vec<ByteRange>> {
+    for field in header.fields() {
+        if field.name().eq_ignore_ascii_case("range") {
+            return parse_byte_ranges(field.value());
+        }
+    }
+    None
+}
+
+/// リクエストヘッダから、利用者定義のメタデータを取り出す。
+///
+/// 対象となるのは`Content-Type`ヘッダと、名前が`prefix`から始まるヘッダ。
+/// 後者は、接頭辞を`USER_METADATA_PREFIX`に置き換えた名前で保存される。
+pub fn get_user_metadata(header: &Header, prefix: &str) -> Result<UserMetadata> {
+    let mut user_metadata = UserMetadata::new();
+    let mut size = 0;
+    for field in header.fields() {
+        let name = field.name().to_ascii_lowercase();
+        let name = if name == CONTENT_TYPE {
+            name
+        } else if name.starts_with(prefix) && name.len() > prefix.len() {
+            format!("{}{}", USER_METADATA_PREFIX, &name[prefix.len()..])
+        } else {
+            continue;
+        };
+        size += name.len() + field.value().len();
+        user_metadata.insert(name, field.value().to_owned());
+    }
+    track_assert!(
+        size <= MAX_USER_METADATA_SIZE,
+        ErrorKind::InvalidInput,
+        "Too large user metadata: size={}, max={}",
+        size,
+        MAX_USER_METADATA_SIZE
+    );
+    Ok(user_metadata)
+}
+
+// 構文的に不正な値やバイト以外の単位が指定された場合には、
+// RFC 7233 に従いヘッダ自体を無視する(i.e., 全体を返す)。
+fn parse_byte_ranges(s: &str) -> Option<Vec<ByteRange>> {
+    let s = s.trim();
+    match s.get(..6) {
+        Some(unit) if unit.eq_ignore_ascii_case("bytes=") => {}
+        _ => return None,
+    }
+    let mut ranges = Vec::new();
+    for spec in s[6..].split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+        let pos = spec.find('-')?;
+        let first = spec[..pos].trim();
+        let last = spec[pos + 1..].trim();
+        let range = if first.is_empty() {
+            ByteRange::Suffix(last.parse().ok()?)
+        } else if last.is_empty() {
+            ByteRange::From(first.parse().ok()?)
+        } else {
+            let first = first.parse().ok()?;
+            let last = last.parse().ok()?;
+            if first > last {
+                return None;
+            }
+            ByteRange::FromTo(first, last)
+        };
+        ranges.push(range);
+    }
+    if ranges.is_empty() {
+        None
+    } else {
+        Some(ranges)
+    }
+}
+
 fn parse_etag_values(s: &str) -> Result<Vec<ObjectVersion>> {
     let mut versions = Vec::new();
     for token in s.split(',') {
@@ -710,6 +1802,84 @@ fn parse_etag_values(s: &str) -> Result<Vec<ObjectVersion>> {
     Ok(versions)
 }
 
+fn get_list_params(url: &Url) -> Result<(ObjectPrefix, Option<ObjectId>, usize)> {
+    let mut prefix = String::new();
+    let mut start_after = None;
+    let mut limit = DEFAULT_LIST_LIMIT;
+    for (k, v) in url.query_pairs() {
+        match k.as_ref() {
+            "prefix" => prefix = v.into_owned(),
+            "start_after" => start_after = Some(v.into_owned()),
+            "continuation_token" => start_after = Some(track!(decode_continuation_token(&v))?),
+            "limit" => {
+                limit = track!(v.parse().map_err(Error::from))?;
+                track_assert!(
+                    0 < limit && limit <= MAX_LIST_LIMIT,
+                    ErrorKind::InvalidInput,
+                    "Out of range: limit={}",
+                    limit
+                );
+            }
+            _ => {}
+        }
+    }
+    Ok((ObjectPrefix(prefix), start_after, limit))
+}
+
+fn get_ttl(url: &Url) -> Result<Option<Duration>> {
+    for (k, v) in url.query_pairs() {
+        if k == "ttl" {
+            let n: u64 = track!(v.parse().map_err(Error::from))?;
+            track!(validate_ttl(n))?;
+            return Ok(Some(Duration::from_secs(n)));
+        }
+    }
+    Ok(None)
+}
+
+fn validate_ttl(secs: u64) -> Result<()> {
+    track_assert_ne!(secs, 0, ErrorKind::InvalidInput, "TTL must be positive");
+    track_assert!(
+        secs <= MAX_TTL_SECS,
+        ErrorKind::InvalidInput,
+        "Too large TTL: {} seconds (the maximum is {} seconds)",
+        secs,
+        MAX_TTL_SECS
+    );
+    Ok(())
+}
+
+fn get_version(url: &Url) -> Result<Option<ObjectVersion>> {
+    for (k, v) in url.query_pairs() {
+        if k == "version" {
+            let n: u64 = track!(v.parse().map_err(Error::from))?;
+            return Ok(Some(ObjectVersion(n)));
+        }
+    }
+    Ok(None)
+}
+
+/// `consistency`クエリパラメータから、MDSからの読み込みの一貫性レベルを取得する。
+///
+/// 指定可能な値は`linearizable`、`lease`および`stale`で、省略時は`lease`となる。
+fn get_read_consistency(url: &Url) -> Result<ReadConsistency> {
+    for (k, v) in url.query_pairs() {
+        if k == "consistency" {
+            return match v.as_ref() {
+                "linearizable" => Ok(ReadConsistency::Linearizable),
+                "lease" => Ok(ReadConsistency::LeaderLease),
+                "stale" => Ok(ReadConsistency::Stale),
+                _ => track_panic!(
+                    ErrorKind::InvalidInput,
+                    "Unknown consistency level: {:?}",
+                    v
+                ),
+            };
+        }
+    }
+    Ok(ReadConsistency::default())
+}
+
 fn get_deadline(url: &Url) -> Result<Deadline> {
     for (k, v) in url.query_pairs() {
         if k == "deadline" {
@@ -719,3 +1889,209 @@ fn get_deadline(url: &Url) -> Result<Deadline> {
     }
     Ok(Deadline::Within(Duration::from_secs(5)))
 }
+
+#[cfg(test)]
+mod tests {
+    use bytecodec::DecodeExt;
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    fn decode_batch_request(json: &str) -> Result<BatchRequest> {
+        let request = track!(BatchRequestDecoder::default()
+            .decode_from_bytes(json.as_bytes())
+            .map_err(|e| ErrorKind::InvalidInput.takes_over(e)))?;
+        Ok(request)
+    }
+
+    #[test]
+    fn get_batch_operations_works() -> TestResult {
+        let request = track!(decode_batch_request(
+            r#"{"operations": [
+                {"op": "get", "object_id": "foo"},
+                {"op": "put", "object_id": "bar", "content": "68656c6c6f", "if_none_match": "*"},
+                {"op": "delete", "object_id": "baz", "if_match": "10"}
+            ]}"#
+        ))?;
+        let operations = track!(get_batch_operations(request))?;
+        assert_eq!(operations.len(), 3);
+        match operations[1] {
+            BatchOperation::Put {
+                ref object_id,
+                ref data,
+                expect: Expect::None,
+                ..
+            } => {
+                assert_eq!(object_id, "bar");
+                assert_eq!(data, b"hello");
+            }
+            _ => panic!(),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn internal_object_ids_are_rejected() -> TestResult {
+        let request = track!(decode_batch_request(
+            r#"{"operations": [{"op": "get", "object_id": "\u0000multipart/1/0"}]}"#
+        ))?;
+        let e = get_batch_operations(request).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+
+        let request = track!(decode_batch_request(
+            r#"{"operations": [{"op": "delete", "object_id": "\u0000multipart/1/0"}]}"#
+        ))?;
+        assert!(get_transaction_operations(request).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn get_ttl_works() -> TestResult {
+        let url = |query: &str| Url::parse(&format!("http://localhost/?{}", query)).unwrap();
+        assert_eq!(track!(get_ttl(&url("")))?, None);
+        assert_eq!(
+            track!(get_ttl(&url("ttl=3600")))?,
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(
+            track!(get_ttl(&url(&format!("ttl={}", MAX_TTL_SECS))))?,
+            Some(Duration::from_secs(MAX_TTL_SECS))
+        );
+        assert!(get_ttl(&url("ttl=0")).is_err());
+        assert!(get_ttl(&url(&format!("ttl={}", MAX_TTL_SECS + 1))).is_err());
+        assert!(get_ttl(&url(&format!("ttl={}", u64::max_value()))).is_err());
+
+        let request = track!(decode_batch_request(&format!(
+            r#"{{"operations": [{{"op": "put", "object_id": "foo", "content": "", "ttl": {}}}]}}"#,
+            u64::max_value()
+        )))?;
+        assert!(get_batch_operations(request).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn get_version_works() -> TestResult {
+        let url = |query: &str| Url::parse(&format!("http://localhost/?{}", query)).unwrap();
+        assert_eq!(track!(get_version(&url("")))?, None);
+        assert_eq!(
+            track!(get_version(&url("version=10")))?,
+            Some(ObjectVersion(10))
+        );
+        assert_eq!(
+            track!(get_version(&url("deadline=100&version=0")))?,
+            Some(ObjectVersion(0))
+        );
+        assert!(get_version(&url("version=")).is_err());
+        assert!(get_version(&url("version=-1")).is_err());
+        assert!(get_version(&url("version=abc")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn scrub_response_works() {
+        let scrubbers = ScrubberRegistry::default();
+        let res = make_scrub_response(&scrubbers, false).into_inner();
+        assert_eq!(res.status_code().as_u16(), 200);
+        match *res.body() {
+            HttpResult::Ok(ref statuses) => assert!(statuses.is_empty()),
+            HttpResult::Err(_) => panic!(),
+        }
+
+        let res = make_scrub_response(&scrubbers, true).into_inner();
+        assert_eq!(res.status_code().as_u16(), 202);
+        match *res.body() {
+            HttpResult::Ok(ref statuses) => assert!(statuses.is_empty()),
+            HttpResult::Err(_) => panic!(),
+        }
+    }
+
+    #[test]
+    fn object_versions_response_works() {
+        let status = |result| {
+            make_object_versions_response(result)
+                .into_inner()
+                .status_code()
+                .as_u16()
+        };
+        let versions = vec![
+            ObjectVersionEntry {
+                version: ObjectVersion(3),
+                is_latest: true,
+                superseded_at: None,
+            },
+            ObjectVersionEntry {
+                version: ObjectVersion(2),
+                is_latest: false,
+                superseded_at: Some(1_500_000_000),
+            },
+        ];
+        assert_eq!(status(Ok(versions)), 200);
+
+        // 過去バージョンも含めて一つも保持されていなければ`404`
+        assert_eq!(status(Ok(Vec::new())), 404);
+        assert_eq!(status(Err(ErrorKind::NotFound.error().into())), 404);
+        assert_eq!(status(Err(ErrorKind::Other.error().into())), 500);
+    }
+
+    #[test]
+    fn parse_byte_ranges_works() {
+        assert_eq!(
+            parse_byte_ranges("bytes=0-9"),
+            Some(vec![ByteRange::FromTo(0, 9)])
+        );
+        assert_eq!(
+            parse_byte_ranges(" Bytes=10-, -5 ,3-3"),
+            Some(vec![
+                ByteRange::From(10),
+                ByteRange::Suffix(5),
+                ByteRange::FromTo(3, 3),
+            ])
+        );
+        assert_eq!(
+            parse_byte_ranges("bytes=0-1,,2-3"),
+            Some(vec![ByteRange::FromTo(0, 1), ByteRange::FromTo(2, 3)])
+        );
+
+        // 不正な指定
+        assert_eq!(parse_byte_ranges(""), None);
+        assert_eq!(parse_byte_ranges("bytes="), None);
+        assert_eq!(parse_byte_ranges("bytes=-"), None);
+        assert_eq!(parse_byte_ranges("bytes=9-0"), None);
+        assert_eq!(parse_byte_ranges("bytes=a-b"), None);
+        assert_eq!(parse_byte_ranges("bytes=0-1,x"), None);
+        assert_eq!(parse_byte_ranges("items=0-9"), None);
+
+        // 六バイト目が文字の境界ではない場合でもパニックしない
+        assert_eq!(parse_byte_ranges("bytesあ0-9"), None);
+        assert_eq!(parse_byte_ranges("あい"), None);
+    }
+
+    #[test]
+    fn batch_limits_work() -> TestResult {
+        // 中身の合計の上限
+        let content = "00".repeat(MAX_BATCH_TOTAL_CONTENT_SIZE / 2 + 1);
+        let json = format!(
+            r#"{{"operations": [{{"op": "put", "object_id": "foo", "content": "{0}"}}, {{"op": "put", "object_id": "bar", "content": "{0}"}}]}}"#,
+            content
+        );
+        let request = track!(decode_batch_request(&json))?;
+        let e = get_batch_operations(request).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+
+        // ボディのサイズの上限
+        let json = format!(
+            r#"{{"operations": [], "padding": "{}"}}"#,
+            "0".repeat(MAX_BATCH_REQUEST_SIZE)
+        );
+        assert!(decode_batch_request(&json).is_err());
+
+        // 操作数の上限
+        let json = format!(
+            r#"{{"operations": [{}]}}"#,
+            vec![r#"{"op": "head", "object_id": "foo"}"#; MAX_BATCH_OPERATIONS + 1].join(",")
+        );
+        let request = track!(decode_batch_request(&json))?;
+        assert!(get_batch_operations(request).is_err());
+        Ok(())
+    }
+}
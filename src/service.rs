@@ -9,11 +9,12 @@ use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
 use fibers_rpc::server::ServerBuilder as RpcServerBuilder;
 use fibers_tasque;
 use fibers_tasque::TaskQueueExt;
-use frugalos_config::{DeviceGroup, Event as ConfigEvent, Service as ConfigService};
+use frugalos_config::{BucketOptions, DeviceGroup, Event as ConfigEvent, Service as ConfigService};
 use frugalos_raft::Service as RaftService;
 use frugalos_segment;
-use frugalos_segment::config::MdsClientConfig;
+use frugalos_segment::config::{Locality, MdsClientConfig};
 use frugalos_segment::Service as SegmentService;
+use frugalos_segment::{RepairController, ScrubberRegistry};
 use futures::future::Fuse;
 use futures::{Async, Future, Poll, Stream};
 use libfrugalos::entity::bucket::{Bucket as BucketConfig, BucketId};
@@ -23,12 +24,13 @@ use libfrugalos::entity::device::{
 use libfrugalos::entity::server::{Server, ServerId};
 use prometrics::metrics::MetricBuilder;
 use slog::Logger;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use trackable::error::ErrorKindExt;
 
 use bucket::Bucket;
 use client::FrugalosClient;
+use multipart::UploadCollector;
 use {Error, ErrorKind, Result};
 
 pub struct PhysicalDevice {
@@ -50,13 +52,17 @@ pub struct Service<S> {
 
     // クラスタ全体の情報
     seqno_to_device: HashMap<u32, PhysicalDevice>,
+    device_parents: HashMap<String, String>,
 
     buckets: Arc<AtomicImmut<HashMap<BucketId, Bucket>>>,
     bucket_no_to_id: HashMap<u32, BucketId>,
+    bucket_options: HashMap<BucketId, BucketOptions>,
 
     servers: HashMap<ServerId, Server>,
 
     mds_client_config: MdsClientConfig,
+
+    upload_collector: UploadCollector,
 }
 impl<S> Service<S>
 where
@@ -78,6 +84,8 @@ where
             rpc,
             raft_service.handle(),
         ))?;
+        let buckets = Arc::new(AtomicImmut::new(HashMap::new()));
+        let upload_collector = track!(UploadCollector::new(logger.clone(), buckets.clone()))?;
         Ok(Service {
             logger,
             local_server: config_service.local_server().clone(),
@@ -88,15 +96,24 @@ where
 
             local_devices: HashMap::new(),
             seqno_to_device: HashMap::new(),
-            buckets: Arc::new(AtomicImmut::new(HashMap::new())),
+            device_parents: HashMap::new(),
+            buckets,
             bucket_no_to_id: HashMap::new(),
+            bucket_options: HashMap::new(),
             servers: HashMap::new(),
             mds_client_config,
+            upload_collector,
         })
     }
     pub fn client(&self) -> FrugalosClient {
         FrugalosClient::new(self.buckets.clone())
     }
+    pub fn scrubbers(&self) -> ScrubberRegistry {
+        self.frugalos_segment_service.scrubbers()
+    }
+    pub fn repair_controller(&self) -> RepairController {
+        self.frugalos_segment_service.repair_controller()
+    }
     pub fn stop(&mut self) {
         self.frugalos_segment_service.stop();
     }
@@ -107,6 +124,13 @@ where
         info!(self.logger, "Configuration Event: {:?}", event);
         match event {
             ConfigEvent::PutDevice(device) => {
+                self.device_parents
+                    .retain(|_, parent| *parent != *device.id());
+                if let DeviceConfig::Virtual(ref d) = device {
+                    for child in &d.children {
+                        self.device_parents.insert(child.clone(), d.id.clone());
+                    }
+                }
                 if let Some(server) = device.server() {
                     let d = PhysicalDevice {
                         id: DeviceId::new(device.id().clone()),
@@ -129,6 +153,9 @@ where
             ConfigEvent::PutBucket(bucket) => {
                 track!(self.handle_put_bucket(&bucket))?;
             }
+            ConfigEvent::PutBucketOptions { bucket_id, options } => {
+                self.handle_put_bucket_options(bucket_id, options);
+            }
             ConfigEvent::DeleteBucket(bucket) => {
                 // TODO
                 track_panic!(ErrorKind::Other, "Unimplemented: {:?}", bucket);
@@ -159,13 +186,72 @@ where
             self.logger.clone(),
             self.rpc_service.clone(),
             &bucket_config,
+            &self.bucket_options.get(&id).cloned().unwrap_or_default(),
             self.mds_client_config.clone(),
+            self.frugalos_segment_service.read_repairer(),
+            self.frugalos_segment_service.key_provider(),
         );
         let mut buckets = (&*self.buckets.load()).clone();
         buckets.insert(id, bucket);
         self.buckets.store(buckets);
         Ok(())
     }
+    fn handle_put_bucket_options(&mut self, bucket_id: BucketId, options: BucketOptions) {
+        // NOTE: バケツの作成前に通知された場合には、作成時に反映される
+        let mut buckets = (&*self.buckets.load()).clone();
+        if let Some(bucket) = buckets.get_mut(&bucket_id) {
+            bucket.set_options(&options);
+            self.buckets.store(buckets);
+        }
+        self.bucket_options.insert(bucket_id, options);
+    }
+    /// グループ内の各デバイスの、このサーバからの近さを計算する。
+    ///
+    /// このサーバ上のデバイスの距離は`0`となる。
+    /// それ以外のデバイスの距離は、このサーバ上のデバイスも含む最も近い祖先の仮想デバイスまでの段数となる。
+    fn make_locality(&self, group: &DeviceGroup) -> Locality {
+        let local_devices = self
+            .seqno_to_device
+            .values()
+            .filter(|d| d.server == self.local_server.id)
+            .map(|d| d.id.clone().into_string())
+            .collect::<Vec<_>>();
+        let mut local_ancestors = HashSet::new();
+        for d in &local_devices {
+            local_ancestors.extend(self.device_ancestors(d));
+        }
+
+        let mut locality = Locality::new();
+        for device_no in &group.members {
+            let device = &self.seqno_to_device[device_no];
+            let device_id = device.id.clone().into_string();
+            let distance = if device.server == self.local_server.id {
+                Some(0)
+            } else {
+                self.device_ancestors(&device_id)
+                    .iter()
+                    .position(|a| local_ancestors.contains(a))
+                    .map(|i| i + 1)
+            };
+            if let Some(distance) = distance {
+                locality.set_distance(device_id, distance);
+            }
+        }
+        locality
+    }
+    fn device_ancestors<'a>(&'a self, device_id: &'a str) -> Vec<&'a str> {
+        let mut ancestors = Vec::new();
+        let mut current = device_id;
+        while let Some(parent) = self.device_parents.get(current) {
+            // NOTE: 循環参照がある場合でも停止するように、段数を制限している
+            if ancestors.len() == self.device_parents.len() {
+                break;
+            }
+            ancestors.push(parent.as_str());
+            current = parent;
+        }
+        ancestors
+    }
     fn handle_patch_segment(
         &mut self,
         bucket_no: u32,
@@ -191,7 +277,7 @@ where
             let mut buckets = (&*self.buckets.load()).clone();
             let segment;
             {
-                use frugalos_segment::config::ClusterMember;
+                use frugalos_segment::config::{ClusterConfig, ClusterMember};
                 let bucket = buckets.get_mut(id).expect("Never fails");
                 let members = members
                     .iter()
@@ -201,11 +287,21 @@ where
                         device: self.seqno_to_device[&device_no].id.clone().into_string(),
                     })
                     .collect();
-                bucket.update_segment(segment_no, members);
+                let locality = self.make_locality(group);
+                bucket.update_segment(segment_no, ClusterConfig { members, locality });
                 segment = bucket.segments()[segment_no as usize].clone();
             }
             self.buckets.store(buckets);
 
+            // マルチパートアップロードの後始末は、先頭メンバのデバイスを所有するサーバが担当する
+            if group
+                .members
+                .first()
+                .map_or(false, |d| self.local_devices.contains_key(d))
+            {
+                self.upload_collector.add_target(id.clone(), segment_no);
+            }
+
             // このサーバが扱うべきRaftノードを起動
             for (node, device_no) in members.iter().zip(group.members.iter()) {
                 let device_id = if let Some(id) = self.local_devices.get(&device_no).map(|d| d.id())
@@ -273,6 +369,7 @@ where
                 error!(self.logger, "Device error: {}", e; "device" => device.id().as_str());
             }
         }
+        track!(self.upload_collector.poll())?;
 
         Ok(Async::NotReady)
     }